@@ -0,0 +1,18 @@
+//! Embeds the git commit into the firmware, see `version.rs`.
+
+use std::process::Command;
+
+fn main() {
+    let hash = Command::new("git")
+        .args(["describe", "--always", "--dirty", "--abbrev=8"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=MITHRIL_GIT_HASH={}", hash);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+}
@@ -0,0 +1,23 @@
+//! Received LoRa packets, both with random and with valid MACs, so that deserialization of
+//! uplink and downlink messages is exercised as well.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use mithril::lora_packet::{self, RxHmac};
+use shared_types::*;
+
+const KEY: [u8; 16] = [0x42; 16];
+
+fuzz_target!(|data: &[u8]| {
+    let _ = lora_packet::decode::<UplinkMessage>(&mut data.to_vec(), &KEY, Some(0));
+
+    let serialized_end = data.iter().position(|b| *b == 0).map(|i| i + 1).unwrap_or(data.len());
+    let hmac = (lora_packet::mac(&KEY, Some(0), &data[..serialized_end]) as RxHmac).to_be_bytes();
+    let mut packet = [&hmac, data].concat();
+    let _ = lora_packet::decode::<UplinkMessage>(&mut packet, &KEY, Some(0));
+
+    // Downlink messages, as decoded by the ground station
+    let _ = postcard::from_bytes_cobs::<DownlinkMessage>(&mut data.to_vec());
+});
@@ -0,0 +1,15 @@
+//! Bytes received via the USB serial link, split into frames by the decoder.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use mithril::framing::FrameDecoder;
+use shared_types::*;
+
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = FrameDecoder::new();
+    for byte in data {
+        let _ = decoder.push::<UplinkMessage>(*byte);
+    }
+});
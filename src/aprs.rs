@@ -0,0 +1,194 @@
+//! APRS position beacon, sent via an external VHF transmitter after landing. This provides a
+//! recovery channel that is independent of our own LoRa link and ground station, since APRS
+//! packets are picked up by any APRS receiver or digipeater in range.
+//!
+//! Packets are AX.25 UI frames, sent as 1200 baud AFSK (Bell 202). The audio tones are generated
+//! using PWM on PA8 and should be low-pass filtered before being fed into the transmitter's
+//! microphone input. PB14 keys the transmitter (push-to-talk, active high).
+//!
+//! Only built with the `aprs` feature. Transmitting requires an amateur radio license, so make
+//! sure to set `CALLSIGN` accordingly.
+
+use core::fmt::Write;
+
+use embassy_stm32::gpio::Output;
+use embassy_stm32::peripherals::{PB14, TIM1};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::timer::simple_pwm::SimplePwm;
+use embassy_stm32::timer::Channel;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Ticker};
+use heapless::{String, Vec};
+use crc::{Crc, CRC_16_IBM_SDLC};
+use num_traits::Float;
+
+use defmt::*;
+
+const X25: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+
+pub const CALLSIGN: &str = "N0CALL";
+/// SSID 11 is commonly used for balloons, aircraft and rockets.
+const SSID: u8 = 11;
+/// Experimental destination address, identifies the software.
+const DESTINATION: &str = "APZMTH";
+const PATH: &str = "WIDE2";
+const PATH_SSID: u8 = 1;
+
+/// Time between beacons after landing (ms).
+pub const BEACON_INTERVAL: u32 = 60_000;
+
+const BAUD_RATE: u32 = 1200;
+const MARK_FREQUENCY: u32 = 1200;
+const SPACE_FREQUENCY: u32 = 2200;
+/// Flags sent before the frame, giving the receiver time to sync (~300ms).
+const PREAMBLE_FLAGS: usize = 45;
+const POSTAMBLE_FLAGS: usize = 3;
+const FLAG: u8 = 0x7e;
+
+const MAX_FRAME_SIZE: usize = 128;
+
+type Frame = Vec<u8, MAX_FRAME_SIZE>;
+
+static FRAME_SIGNAL: Signal<CriticalSectionRawMutex, Frame> = Signal::new();
+
+pub struct AprsTransmitter {
+    pwm: SimplePwm<'static, TIM1>,
+    ptt: Output<'static, PB14>,
+}
+
+fn push_address(frame: &mut Frame, callsign: &str, ssid: u8, last: bool) {
+    for i in 0..6 {
+        let c = callsign.as_bytes().get(i).copied().unwrap_or(b' ');
+        let _ = frame.push(c << 1);
+    }
+
+    let _ = frame.push(0x60 | ((ssid & 0x0f) << 1) | (last as u8));
+}
+
+/// Formats a coordinate as degrees and decimal minutes, e.g. `4952.51N`.
+fn push_coordinate(info: &mut String<64>, value: f32, degree_digits: usize, positive: char, negative: char) {
+    let hundredths_of_minutes = (value.abs() * 6000.0).round() as u32;
+    let degrees = hundredths_of_minutes / 6000;
+    let minutes = hundredths_of_minutes % 6000;
+    let hemisphere = if value < 0.0 { negative } else { positive };
+    let _ = core::write!(info, "{:0width$}{:02}.{:02}{}", degrees, minutes / 100, minutes % 100, hemisphere, width = degree_digits);
+}
+
+/// Builds an AX.25 UI frame containing an APRS position report, including the frame check
+/// sequence, but without flags or bit stuffing.
+fn position_report(latitude: f32, longitude: f32, altitude: Option<f32>) -> Frame {
+    let mut frame = Frame::new();
+    push_address(&mut frame, DESTINATION, 0, false);
+    push_address(&mut frame, CALLSIGN, SSID, false);
+    push_address(&mut frame, PATH, PATH_SSID, true);
+    let _ = frame.push(0x03); // UI frame
+    let _ = frame.push(0xf0); // no layer 3
+
+    // Position without timestamp, using the rocket symbol from the alternate table
+    let mut info: String<64> = String::new();
+    let _ = info.push('!');
+    push_coordinate(&mut info, latitude, 2, 'N', 'S');
+    let _ = info.push('\\');
+    push_coordinate(&mut info, longitude, 3, 'E', 'W');
+    let _ = info.push('O');
+    if let Some(altitude) = altitude {
+        let feet = (altitude * 3.28084).max(0.0) as u32;
+        let _ = core::write!(info, "/A={:06}", u32::min(feet, 999_999));
+    }
+    let _ = info.push_str(" landed");
+    let _ = frame.extend_from_slice(info.as_bytes());
+
+    let fcs = X25.checksum(&frame);
+    let _ = frame.extend_from_slice(&fcs.to_le_bytes());
+
+    frame
+}
+
+/// Bits of the given bytes, least significant bit first.
+fn bits(bytes: &[u8]) -> impl Iterator<Item = bool> + '_ {
+    bytes.iter().flat_map(|b| (0..8).map(move |i| (b >> i) & 1 == 1))
+}
+
+/// Tones (true for mark) to send for the given frame, including flags, bit stuffing and NRZI
+/// encoding.
+fn tones(frame: &[u8]) -> impl Iterator<Item = bool> + '_ {
+    // After five consecutive ones, a zero is inserted so the data never looks like a flag.
+    let stuffed = bits(frame)
+        .scan(0u8, |ones, bit| {
+            let mut out: Vec<bool, 2> = Vec::new();
+            let _ = out.push(bit);
+            *ones = if bit { *ones + 1 } else { 0 };
+            if *ones == 5 {
+                let _ = out.push(false);
+                *ones = 0;
+            }
+            Some(out)
+        })
+        .flatten();
+
+    let preamble = core::iter::repeat(FLAG).take(PREAMBLE_FLAGS).flat_map(|f| bits(&[f]).collect::<Vec<bool, 8>>());
+    let postamble = core::iter::repeat(FLAG).take(POSTAMBLE_FLAGS).flat_map(|f| bits(&[f]).collect::<Vec<bool, 8>>());
+
+    // NRZI: zeros are encoded by switching tones, ones by keeping the current tone.
+    preamble
+        .chain(stuffed)
+        .chain(postamble)
+        .scan(true, |mark, bit| {
+            if !bit {
+                *mark = !*mark;
+            }
+            Some(*mark)
+        })
+}
+
+/// Queues a position report for transmission. Reports that have not been sent yet are replaced.
+pub fn beacon(latitude: f32, longitude: f32, altitude: Option<f32>) {
+    FRAME_SIGNAL.signal(position_report(latitude, longitude, altitude));
+}
+
+impl AprsTransmitter {
+    pub fn init(pwm: SimplePwm<'static, TIM1>, ptt: Output<'static, PB14>) -> Self {
+        Self { pwm, ptt }
+    }
+
+    fn set_tone(&mut self, mark: bool) {
+        let frequency = if mark { MARK_FREQUENCY } else { SPACE_FREQUENCY };
+        self.pwm.set_frequency(Hertz::hz(frequency));
+        self.pwm.set_duty(Channel::Ch1, self.pwm.get_max_duty() / 2);
+    }
+
+    async fn transmit(&mut self, frame: &[u8]) {
+        info!("Sending APRS beacon ({} bytes)", frame.len());
+
+        self.ptt.set_high();
+        self.set_tone(true);
+        self.pwm.enable(Channel::Ch1);
+
+        // Only reconfigure the timer when the tone actually changes.
+        let mut current = true;
+        let mut ticker = Ticker::every(Duration::from_hz(BAUD_RATE as u64));
+        for mark in tones(frame) {
+            if mark != current {
+                self.set_tone(mark);
+                current = mark;
+            }
+            ticker.next().await;
+        }
+
+        self.pwm.disable(Channel::Ch1);
+        self.ptt.set_low();
+    }
+
+    async fn run(&mut self) -> ! {
+        loop {
+            let frame = FRAME_SIGNAL.wait().await;
+            self.transmit(&frame).await;
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn run(mut transmitter: AprsTransmitter) -> ! {
+    transmitter.run().await
+}
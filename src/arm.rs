@@ -0,0 +1,164 @@
+//! Hardware arm detection from the arm line voltage. The raw voltage is compared against separate
+//! thresholds for arming and disarming, and a change of state only counts once it has persisted
+//! for `DEBOUNCE` ms, so a bouncing key switch or noise on the line can't toggle the state.
+//!
+//! Changes that revert before the debounce time has passed point to a bad contact in the arm
+//! switch or its connector. If several of them happen within a short time, this is reported as a
+//! distinct warning, since it would otherwise go unnoticed until it disarms the vehicle at the
+//! wrong moment.
+
+use defmt::*;
+
+use crate::clock::Instant;
+
+/// Arm line voltage (mV) above which the vehicle counts as armed
+const ARM_THRESHOLD: u16 = 1000;
+/// Arm line voltage (mV) below which the vehicle counts as disarmed again
+const DISARM_THRESHOLD: u16 = 500;
+/// Time (ms) a change has to persist before it is accepted
+const DEBOUNCE: u32 = 100;
+/// Number of rejected changes within `INTERMITTENT_WINDOW` ms reported as intermittent contact
+const INTERMITTENT_GLITCHES: u32 = 3;
+const INTERMITTENT_WINDOW: u32 = 2000;
+
+pub struct ArmDetector {
+    armed: bool,
+    /// Raw state after applying the thresholds, and since when it differs from the accepted one
+    pending_since: Option<Instant>,
+    raw_armed: bool,
+    /// Start of the current window and the number of rejected changes within it
+    glitch_window: Option<(Instant, u32)>,
+    /// Total number of intermittent contact warnings
+    intermittent_count: u32,
+}
+
+impl ArmDetector {
+    pub fn new() -> Self {
+        Self {
+            armed: false,
+            pending_since: None,
+            raw_armed: false,
+            glitch_window: None,
+            intermittent_count: 0,
+        }
+    }
+
+    /// Takes the arm line voltage (mV). Returns true once when intermittent contact is detected.
+    pub fn tick(&mut self, time: Instant, voltage: Option<u16>) -> bool {
+        let Some(voltage) = voltage else {
+            return false;
+        };
+
+        self.raw_armed = if self.raw_armed { voltage >= DISARM_THRESHOLD } else { voltage > ARM_THRESHOLD };
+
+        if self.raw_armed == self.armed {
+            // A change that reverted before being accepted
+            return self.pending_since.take().map(|_| self.glitch(time)).unwrap_or(false);
+        }
+
+        let pending_since = *self.pending_since.get_or_insert(time);
+        if time.millis_since(pending_since) >= DEBOUNCE {
+            info!("Hardware arm state changed: {}", if self.raw_armed { "armed" } else { "disarmed" });
+            self.armed = self.raw_armed;
+            self.pending_since = None;
+        }
+
+        false
+    }
+
+    fn glitch(&mut self, time: Instant) -> bool {
+        let (start, count) = match self.glitch_window {
+            Some((start, count)) if time.millis_since(start) < INTERMITTENT_WINDOW => (start, count + 1),
+            _ => (time, 1),
+        };
+
+        if count >= INTERMITTENT_GLITCHES {
+            warn!("Intermittent arm line contact, check arm switch and connector.");
+            self.intermittent_count += 1;
+            self.glitch_window = None;
+            true
+        } else {
+            self.glitch_window = Some((start, count));
+            false
+        }
+    }
+
+    pub fn armed(&self) -> bool {
+        self.armed
+    }
+
+    /// Number of times intermittent contact was detected since startup
+    pub fn intermittent_count(&self) -> u32 {
+        self.intermittent_count
+    }
+}
+
+impl Default for ArmDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ArmDetector, Instant};
+
+    /// Feeds (time in ms, voltage) samples to a new detector, checking the armed state after each.
+    fn run(samples: &[(u64, Option<u16>, bool)]) {
+        let mut detector = ArmDetector::new();
+        for (time, voltage, armed) in samples {
+            detector.tick(Instant::from_millis(*time), *voltage);
+            assert_eq!(detector.armed(), *armed, "at {}ms", time);
+        }
+    }
+
+    #[test]
+    fn debounce() {
+        let cases: &[&[(u64, Option<u16>, bool)]] = &[
+            // Accepted once the change has persisted for exactly `DEBOUNCE` ms
+            &[(0, Some(1200), false), (50, Some(1200), false), (99, Some(1200), false), (100, Some(1200), true)],
+            // The arm threshold has to be exceeded, not just reached
+            &[(0, Some(1000), false), (200, Some(1000), false)],
+            // Between the thresholds the state is kept, disarming is debounced as well
+            &[
+                (0, Some(1200), false),
+                (100, Some(800), true),
+                (300, Some(500), true),
+                (400, Some(499), true),
+                (499, Some(499), true),
+                (500, Some(499), false),
+            ],
+            // A change that reverts restarts the debounce time
+            &[(0, Some(1200), false), (50, Some(0), false), (100, Some(1200), false), (199, Some(1200), false), (200, Some(1200), true)],
+            // Missing readings neither confirm nor revert a change
+            &[(0, Some(1200), false), (50, None, false), (100, Some(1200), true)],
+        ];
+
+        for samples in cases {
+            run(samples);
+        }
+    }
+
+    #[test]
+    fn intermittent_contact() {
+        let cases: &[(&[u64], bool)] = &[
+            // Three reverted changes within the window
+            (&[0, 10, 20, 30, 40, 50], true),
+            // The same spread over more than the window
+            (&[0, 10, 1500, 1510, 3000, 3010], false),
+        ];
+
+        for (times, reported) in cases {
+            let mut detector = ArmDetector::new();
+            let mut warnings = 0;
+            for (i, time) in times.iter().enumerate() {
+                let voltage = if i % 2 == 0 { 1200 } else { 0 };
+                warnings += detector.tick(Instant::from_millis(*time), Some(voltage)) as u32;
+            }
+
+            assert_eq!(warnings, *reported as u32, "{:?}", times);
+            assert_eq!(detector.intermittent_count(), *reported as u32);
+            assert!(!detector.armed());
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Backup apogee detection using only inertial data, protecting against static port failures
+//! (e.g. blocked or leaking ports, or transonic pressure effects) that would delay or prevent the
+//! barometer-based detection in the state estimator.
+//!
+//! The vertical acceleration is integrated from launch to obtain the vertical speed, and apogee is
+//! detected once it changes sign. Before launch, the accelerometer's bias along the vertical axis
+//! is averaged while stationary on the pad, and subtracted afterwards. Integration errors grow
+//! with time, so this is only meant as a backup: the drogue is deployed by whichever detector
+//! fires first, but this one is only permitted to after a minimum time since launch.
+//!
+//! The times of both detections are kept for comparison after the flight.
+
+use nalgebra::{UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+
+use shared_types::FlightMode;
+
+use crate::clock::Instant;
+
+const GRAVITY: f32 = 9.80665;
+/// Specific force (m/s²) above which the primary IMU accelerometer is considered saturated, and
+/// the high-g accelerometer is used instead.
+const IMU_SATURATION: f32 = 15.0 * GRAVITY;
+/// Time constant (s) of the bias average on the pad
+const BIAS_TIME_CONSTANT: f32 = 1.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BackupApogeeConfig {
+    /// Minimum time (ms) after launch before the backup detector may deploy
+    pub min_time_to_apogee: u32,
+    /// Vertical speed (m/s) that has to be exceeded before apogee can be detected
+    pub min_ascent_speed: f32,
+}
+
+impl Default for BackupApogeeConfig {
+    fn default() -> Self {
+        Self {
+            min_time_to_apogee: 5_000,
+            min_ascent_speed: 20.0,
+        }
+    }
+}
+
+pub struct BackupApogeeDetector {
+    config: BackupApogeeConfig,
+    /// Main loop interval (s)
+    dt: f32,
+    /// Vertical acceleration (m/s²) measured while stationary, subtracted after launch
+    bias: f32,
+    launch_time: Option<Instant>,
+    /// Integrated vertical speed since launch (m/s)
+    speed: f32,
+    max_speed: f32,
+    /// Time (ms after launch) apogee was detected from inertial data
+    inertial_apogee: Option<u32>,
+    /// Time (ms after launch) the state estimator detected apogee
+    baro_apogee: Option<u32>,
+    /// Whether the drogue was deployed by this detector
+    deployed: bool,
+}
+
+impl BackupApogeeDetector {
+    pub fn new(config: BackupApogeeConfig, frequency: f32) -> Self {
+        Self {
+            config,
+            dt: 1.0 / frequency,
+            bias: 0.0,
+            launch_time: None,
+            speed: 0.0,
+            max_speed: 0.0,
+            inertial_apogee: None,
+            baro_apogee: None,
+            deployed: false,
+        }
+    }
+
+    /// Vertical acceleration (m/s², without gravity) from the given body frame accelerometer
+    /// readings, preferring the more precise IMU unless it is saturated.
+    fn vertical_acceleration(
+        orientation: Option<UnitQuaternion<f32>>,
+        acc1: Option<Vector3<f32>>,
+        acc2: Option<Vector3<f32>>,
+    ) -> Option<f32> {
+        let acc = match (acc1, acc2) {
+            (Some(acc1), Some(acc2)) if acc1.norm() > IMU_SATURATION => acc2,
+            (acc1, acc2) => acc1.or(acc2)?,
+        };
+
+        // Before the orientation is known, the vehicle is assumed to be upright.
+        let specific_force = orientation.map(|q| q * acc).unwrap_or(acc);
+        Some(specific_force.z - GRAVITY)
+    }
+
+    /// Returns true once when apogee is detected from inertial data before the state estimator
+    /// detected it, and deploying is permitted.
+    pub fn tick(
+        &mut self,
+        time: Instant,
+        mode: FlightMode,
+        orientation: Option<UnitQuaternion<f32>>,
+        acc1: Option<Vector3<f32>>,
+        acc2: Option<Vector3<f32>>,
+    ) -> bool {
+        let acceleration = Self::vertical_acceleration(orientation, acc1, acc2);
+
+        if mode < FlightMode::Burn {
+            if let (Some(a), true) = (acceleration, mode >= FlightMode::Armed) {
+                self.bias += (a - self.bias) * self.dt / (BIAS_TIME_CONSTANT + self.dt);
+            }
+
+            self.launch_time = None;
+            self.speed = 0.0;
+            self.max_speed = 0.0;
+            self.inertial_apogee = None;
+            self.baro_apogee = None;
+            self.deployed = false;
+            return false;
+        }
+
+        let launch_time = *self.launch_time.get_or_insert(time);
+        let since_launch = time.millis_since(launch_time);
+
+        if mode >= FlightMode::RecoveryDrogue {
+            if self.baro_apogee.is_none() && !self.deployed {
+                self.baro_apogee = Some(since_launch);
+            }
+            return false;
+        }
+
+        // Without a reading, free fall is assumed, erring on the side of an early detection.
+        self.speed += acceleration.map(|a| a - self.bias).unwrap_or(-GRAVITY) * self.dt;
+        self.max_speed = f32::max(self.max_speed, self.speed);
+
+        if self.inertial_apogee.is_none() && self.max_speed > self.config.min_ascent_speed && self.speed <= 0.0 {
+            self.inertial_apogee = Some(since_launch);
+        }
+
+        self.deployed = self.inertial_apogee.is_some() && since_launch >= self.config.min_time_to_apogee;
+        self.deployed
+    }
+
+    /// Times (ms after launch) of the inertial and barometric apogee detections
+    pub fn detections(&self) -> (Option<u32>, Option<u32>) {
+        (self.inertial_apogee, self.baro_apogee)
+    }
+
+    /// Vertical speed (m/s) integrated since launch
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FREQUENCY: f32 = 100.0;
+
+    /// Flies a vertical trajectory with the given acceleration (m/s²) during the burn and free fall
+    /// afterwards, after 2s on the pad. The state estimator detects apogee at `baro_apogee` (ms
+    /// after launch), if given. Returns the time (ms after launch) the detector deployed.
+    fn fly(detector: &mut BackupApogeeDetector, acceleration: f32, burn: u64, baro_apogee: Option<u64>) -> Option<u64> {
+        const LAUNCH: u64 = 2_000;
+        for time in (0..30_000u64).step_by(10) {
+            let since_launch = time.checked_sub(LAUNCH);
+            let (mode, specific_force) = match since_launch {
+                None => (FlightMode::Armed, GRAVITY),
+                Some(t) if baro_apogee.is_some_and(|apogee| t >= apogee) => (FlightMode::RecoveryDrogue, 0.0),
+                Some(t) if t < burn => (FlightMode::Burn, GRAVITY + acceleration),
+                Some(_) => (FlightMode::Coast, 0.0),
+            };
+
+            let acc = Vector3::new(0.0, 0.0, specific_force);
+            if detector.tick(Instant::from_millis(time), mode, None, Some(acc), None) {
+                return since_launch;
+            }
+        }
+
+        None
+    }
+
+    #[test]
+    fn trigger() {
+        // Acceleration (m/s²) and duration (ms) of the burn, the barometric detection, and the
+        // expected deployment time range (ms after launch)
+        let cases = [
+            // 100m/s at burnout, apogee after about 12.2s
+            (50.0, 2_000, None, Some(12_100..12_300)),
+            // 30m/s at burnout, apogee after about 3.6s, held back until the minimum time
+            (60.0, 500, None, Some(5_000..5_001)),
+            // Never exceeds the minimum ascent speed
+            (30.0, 500, None, None),
+            // The state estimator was first
+            (50.0, 2_000, Some(11_000), None),
+        ];
+
+        for (acceleration, burn, baro_apogee, expected) in cases {
+            let mut detector = BackupApogeeDetector::new(BackupApogeeConfig::default(), FREQUENCY);
+            let deployed = fly(&mut detector, acceleration, burn, baro_apogee);
+            match (&expected, deployed) {
+                (Some(range), Some(time)) => assert!(range.contains(&time), "deployed at {}ms, expected {:?}", time, range),
+                (None, None) => {},
+                _ => panic!("deployed at {:?}, expected {:?}", deployed, expected),
+            }
+        }
+    }
+
+    #[test]
+    fn detections_after_baro_apogee() {
+        let mut detector = BackupApogeeDetector::new(BackupApogeeConfig::default(), FREQUENCY);
+        assert_eq!(fly(&mut detector, 50.0, 2_000, Some(11_000)), None);
+        assert_eq!(detector.detections(), (None, Some(11_000)));
+    }
+
+    #[test]
+    fn pad_bias_is_subtracted() {
+        let mut detector = BackupApogeeDetector::new(BackupApogeeConfig::default(), FREQUENCY);
+        let acc = Vector3::new(0.0, 0.0, GRAVITY + 0.5);
+        for time in (0..10_000u64).step_by(10) {
+            detector.tick(Instant::from_millis(time), FlightMode::Armed, None, Some(acc), None);
+        }
+
+        detector.tick(Instant::from_millis(10_000), FlightMode::Burn, None, Some(acc), None);
+        assert!(detector.speed().abs() < 0.01, "speed {}", detector.speed());
+    }
+}
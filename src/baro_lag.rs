@@ -0,0 +1,139 @@
+//! Compensation of barometer lag. The static port's plumbing and the sensor itself act as a
+//! low-pass filter, so the barometric altitude trails the true altitude by roughly the lag's time
+//! constant times the vertical speed. At high ascent and descent rates, this delays apogee
+//! detection noticeably.
+//!
+//! The lag is modelled as a first-order low-pass, compensated using a lead filter: the barometric
+//! altitude plus the time constant times its rate of change. The time constant is estimated during
+//! powered and coasting flight, by comparing the barometric vertical speed to the one obtained by
+//! integrating the vertical acceleration. Both are filtered identically, so the filter's own delay
+//! cancels out. Until enough data is available, the configured default is used.
+
+use serde::{Deserialize, Serialize};
+
+use defmt::*;
+
+use shared_types::FlightMode;
+
+/// Time constant (s) of the low-pass filter applied to both vertical speeds
+const SPEED_FILTER_TIME_CONSTANT: f32 = 0.05;
+/// Only samples with at least this vertical acceleration (m/s²) carry useful information.
+const MIN_ACCELERATION: f32 = 5.0;
+/// Accumulated squared acceleration over time (m²/s³) required before the estimate is used
+const MIN_EXCITATION: f32 = 500.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BaroLagConfig {
+    /// Time constant (s) assumed until it has been estimated in flight
+    pub time_constant: f32,
+    /// Whether to estimate the time constant in flight
+    pub estimate: bool,
+    /// Upper limit for the estimated time constant (s)
+    pub max_time_constant: f32,
+    /// Fraction of the lag to compensate, 0 disables compensation
+    pub gain: f32,
+}
+
+impl Default for BaroLagConfig {
+    fn default() -> Self {
+        Self {
+            time_constant: 0.05,
+            estimate: true,
+            max_time_constant: 0.5,
+            gain: 1.0,
+        }
+    }
+}
+
+pub struct BaroLagCompensator {
+    config: BaroLagConfig,
+    /// Main loop interval (s)
+    dt: f32,
+    last_altitude: Option<f32>,
+    /// Filtered barometric vertical speed (m/s)
+    baro_speed: f32,
+    /// Vertical speed integrated from acceleration since launch (m/s)
+    inertial_speed: f32,
+    /// Filtered inertial vertical speed (m/s)
+    inertial_speed_filtered: f32,
+    /// Least-squares sums for the estimate
+    sum_lag_accel: f32,
+    sum_accel_squared: f32,
+    estimate: Option<f32>,
+}
+
+impl BaroLagCompensator {
+    pub fn new(config: BaroLagConfig, frequency: f32) -> Self {
+        Self {
+            config,
+            dt: 1.0 / frequency,
+            last_altitude: None,
+            baro_speed: 0.0,
+            inertial_speed: 0.0,
+            inertial_speed_filtered: 0.0,
+            sum_lag_accel: 0.0,
+            sum_accel_squared: 0.0,
+            estimate: None,
+        }
+    }
+
+    /// Takes the raw barometric altitude (m) and the vertical acceleration (m/s², without gravity),
+    /// returning the compensated altitude.
+    pub fn tick(&mut self, mode: FlightMode, altitude: Option<f32>, vertical_acceleration: f32) -> Option<f32> {
+        let alpha = self.dt / (SPEED_FILTER_TIME_CONSTANT + self.dt);
+
+        let Some(altitude) = altitude else {
+            self.last_altitude = None;
+            return None;
+        };
+
+        let raw_speed = self.last_altitude.map(|last| (altitude - last) / self.dt).unwrap_or(0.0);
+        self.baro_speed += (raw_speed - self.baro_speed) * alpha;
+        self.last_altitude = Some(altitude);
+
+        if mode < FlightMode::Burn {
+            self.inertial_speed = 0.0;
+            self.inertial_speed_filtered = 0.0;
+            self.sum_lag_accel = 0.0;
+            self.sum_accel_squared = 0.0;
+            self.estimate = None;
+        } else {
+            self.inertial_speed += vertical_acceleration * self.dt;
+            self.inertial_speed_filtered += (self.inertial_speed - self.inertial_speed_filtered) * alpha;
+        }
+
+        let ascending = mode >= FlightMode::Burn && mode < FlightMode::RecoveryDrogue;
+        if self.config.estimate && ascending && f32::max(vertical_acceleration, -vertical_acceleration) > MIN_ACCELERATION {
+            self.update_estimate(vertical_acceleration);
+        }
+
+        Some(altitude + self.config.gain * self.time_constant() * self.baro_speed)
+    }
+
+    fn update_estimate(&mut self, acceleration: f32) {
+        // A first-order lag makes the barometric speed trail the inertial one by the time
+        // constant times the acceleration.
+        let difference = self.inertial_speed_filtered - self.baro_speed;
+        self.sum_lag_accel += difference * acceleration * self.dt;
+        self.sum_accel_squared += acceleration * acceleration * self.dt;
+
+        if self.sum_accel_squared < MIN_EXCITATION {
+            return;
+        }
+
+        let estimate = (self.sum_lag_accel / self.sum_accel_squared).clamp(0.0, self.config.max_time_constant);
+        if self.estimate.is_none() {
+            info!("Barometer lag estimated: {}s", estimate);
+        }
+        self.estimate = Some(estimate);
+    }
+
+    /// Time constant (s) used for compensation
+    pub fn time_constant(&self) -> f32 {
+        self.estimate.unwrap_or(self.config.time_constant)
+    }
+
+    pub fn estimated(&self) -> bool {
+        self.estimate.is_some()
+    }
+}
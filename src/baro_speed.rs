@@ -0,0 +1,167 @@
+//! Vertical speed from the barometer alone, independent of the state estimator, for cross-checking
+//! it and as a fallback apogee criterion in case the IMU fails in flight, which leaves the state
+//! estimator without a usable prediction.
+//!
+//! Differencing consecutive barometric altitudes amplifies their noise, so the speed is taken from
+//! a tracking differentiator instead: a second-order low-pass on the altitude, whose internal
+//! state includes the rate of change of its output. The cutoff frequency trades noise against
+//! delay. The residual between raw and smoothed altitude, minus its slowly varying part (the
+//! filter's tracking error), gives a running estimate of the barometer's noise, from which the
+//! uncertainty of the speed is derived, so the fallback only fires once the vehicle is descending
+//! beyond doubt.
+
+use defmt::*;
+use num_traits::Float;
+use serde::{Deserialize, Serialize};
+
+use shared_types::FlightMode;
+
+use crate::clock::Instant;
+
+/// Damping ratio of the differentiator, i.e. a Butterworth response
+const DAMPING: f32 = core::f32::consts::FRAC_1_SQRT_2;
+/// Time constant (s) of the average residual, i.e. the tracking error excluded from the noise
+const TRACKING_TIME_CONSTANT: f32 = 0.2;
+/// Time constant (s) of the noise estimate
+const NOISE_TIME_CONSTANT: f32 = 1.0;
+/// Without readings for this long (ms), the speed is unknown.
+const READING_TIMEOUT: u32 = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BaroSpeedConfig {
+    /// Cutoff frequency (Hz) of the differentiator
+    pub cutoff: f32,
+    /// Whether to deploy the drogue based on the barometric speed if the IMU fails
+    pub fallback: bool,
+    /// Time (ms) without IMU readings after which it counts as failed
+    pub imu_timeout: u32,
+    /// Minimum time (ms) after launch before the fallback may deploy
+    pub min_time_to_apogee: u32,
+    /// Vertical speed (m/s) that has to be exceeded before apogee can be detected
+    pub min_ascent_speed: f32,
+    /// Number of standard deviations the speed has to be below zero to detect apogee
+    pub confidence: f32,
+}
+
+impl Default for BaroSpeedConfig {
+    fn default() -> Self {
+        Self {
+            cutoff: 2.0,
+            fallback: true,
+            imu_timeout: 200,
+            min_time_to_apogee: 5_000,
+            min_ascent_speed: 20.0,
+            confidence: 3.0,
+        }
+    }
+}
+
+pub struct BaroSpeed {
+    config: BaroSpeedConfig,
+    /// Main loop interval (s)
+    dt: f32,
+    /// Smoothed altitude (m) and its rate of change (m/s)
+    state: Option<(f32, f32)>,
+    /// Average and variance (m²) of the raw altitude around the smoothed one
+    tracking_error: f32,
+    noise_variance: f32,
+    last_reading: Option<Instant>,
+    last_imu_reading: Option<Instant>,
+    launch_time: Option<Instant>,
+    max_speed: f32,
+    /// Time (ms after launch) apogee was detected from the barometric speed
+    apogee: Option<u32>,
+}
+
+impl BaroSpeed {
+    pub fn new(config: BaroSpeedConfig, frequency: f32) -> Self {
+        Self {
+            config,
+            dt: 1.0 / frequency,
+            state: None,
+            tracking_error: 0.0,
+            noise_variance: 0.0,
+            last_reading: None,
+            last_imu_reading: None,
+            launch_time: None,
+            max_speed: 0.0,
+            apogee: None,
+        }
+    }
+
+    /// Takes the raw barometric altitude (m) and whether the IMU delivered a reading. Returns true
+    /// while the drogue should be deployed because the IMU failed and the barometer indicates
+    /// apogee.
+    pub fn tick(&mut self, time: Instant, mode: FlightMode, altitude: Option<f32>, imu_reading: bool) -> bool {
+        if let Some(altitude) = altitude {
+            self.update(altitude);
+            self.last_reading = Some(time);
+        }
+
+        if imu_reading {
+            self.last_imu_reading = Some(time);
+        }
+
+        if mode < FlightMode::Burn {
+            self.launch_time = None;
+            self.max_speed = 0.0;
+            self.apogee = None;
+            return false;
+        }
+
+        let launch_time = *self.launch_time.get_or_insert(time);
+        let since_launch = time.millis_since(launch_time);
+        if mode >= FlightMode::RecoveryDrogue {
+            return false;
+        }
+
+        let Some(speed) = self.speed(time) else {
+            return false;
+        };
+        self.max_speed = f32::max(self.max_speed, speed);
+
+        if self.apogee.is_none() && self.max_speed > self.config.min_ascent_speed && speed < -self.config.confidence * self.uncertainty() {
+            info!("Apogee detected from barometer at {}ms after launch.", since_launch);
+            self.apogee = Some(since_launch);
+        }
+
+        let imu_failed = self.last_imu_reading.map(|t| time.millis_since(t) >= self.config.imu_timeout).unwrap_or(true);
+        self.config.fallback && imu_failed && self.apogee.is_some() && since_launch >= self.config.min_time_to_apogee
+    }
+
+    fn update(&mut self, altitude: f32) {
+        let (smoothed, speed) = *self.state.get_or_insert((altitude, 0.0));
+
+        let w = 2.0 * core::f32::consts::PI * self.config.cutoff;
+        let residual = altitude - smoothed;
+        let acceleration = w * w * residual - 2.0 * DAMPING * w * speed;
+
+        // Semi-implicit Euler, which stays stable as long as the cutoff is well below the loop
+        // frequency.
+        let speed = speed + acceleration * self.dt;
+        self.state = Some((smoothed + speed * self.dt, speed));
+
+        self.tracking_error += (residual - self.tracking_error) * self.dt / (TRACKING_TIME_CONSTANT + self.dt);
+        let noise = residual - self.tracking_error;
+        self.noise_variance += (noise * noise - self.noise_variance) * self.dt / (NOISE_TIME_CONSTANT + self.dt);
+    }
+
+    /// Barometric vertical speed (m/s), if there were recent readings
+    pub fn speed(&self, time: Instant) -> Option<f32> {
+        let recent = self.last_reading.map(|t| time.millis_since(t) < READING_TIMEOUT).unwrap_or(false);
+        self.state.filter(|_| recent).map(|(_, speed)| speed)
+    }
+
+    /// Standard deviation (m/s) of the speed due to barometer noise. Assumes independent readings
+    /// every tick, for which the differentiator passes a variance of w³·dt/4ζ times that of the
+    /// altitude. Readings held over several ticks make it somewhat larger in practice.
+    pub fn uncertainty(&self) -> f32 {
+        let w = 2.0 * core::f32::consts::PI * self.config.cutoff;
+        (self.noise_variance * w * w * w * self.dt / (4.0 * DAMPING)).sqrt()
+    }
+
+    /// Time (ms after launch) apogee was detected from the barometric speed
+    pub fn apogee(&self) -> Option<u32> {
+        self.apogee
+    }
+}
@@ -0,0 +1,41 @@
+//! Board support. Pin, timer and peripheral assignments that differ between hardware revisions
+//! live in one board file per revision, selected via feature flags. Everything else only uses
+//! what is exported here, so supporting a new revision means adding a board file providing:
+//!
+//! - `BuzzerTimer`, the timer driving the buzzer
+//! - `Resources`, the revision-specific peripherals, taken from the peripheral set by the
+//!   `board_resources!` macro
+//! - `SensorSpiResources::init` and `BuzzerResources::init`, which set these up
+//! - `OutputResources::init`, which maps the logical outputs to pins (see `outputs.rs`)
+//! - `UmbilicalResources::init`, for the `umbilical` feature
+//! - `FrontendResources::init`, for the `frontend` feature
+//!
+//! Assignments shared by all revisions stay in `main.rs`. Alternative sensor parts that can be
+//! fitted to any revision are selected via their own feature flags below.
+
+use embassy_stm32::peripherals::*;
+use embassy_stm32::spi::Spi;
+
+#[cfg(not(feature = "mmc5983"))]
+use crate::drivers::sensors::LIS3MDL;
+#[cfg(feature = "mmc5983")]
+use crate::drivers::sensors::MMC5983;
+
+#[cfg(feature = "rev1")]
+mod rev1;
+#[cfg(feature = "rev1")]
+pub use rev1::*;
+
+#[cfg(not(feature = "rev1"))]
+mod rev2;
+#[cfg(not(feature = "rev1"))]
+pub use rev2::*;
+
+/// SPI bus shared by the sensors, the LoRa transceiver and the SD card.
+pub type SensorSpi = Spi<'static, SPI1, DMA2_CH3, DMA2_CH2>;
+
+/// Magnetometer part, see `traits::Magnetometer`.
+#[cfg(not(feature = "mmc5983"))]
+pub type MagnetometerDriver<SPI> = LIS3MDL<SPI>;
+#[cfg(feature = "mmc5983")]
+pub type MagnetometerDriver<SPI> = MMC5983<SPI>;
@@ -0,0 +1,155 @@
+//! Board support for rev1 hardware.
+
+use embassy_stm32::gpio::OutputType;
+#[cfg(feature = "umbilical")]
+use embassy_stm32::gpio::{AnyPin, Input, Pull};
+#[cfg(any(feature = "umbilical", feature = "frontend", not(feature = "gcs")))]
+use embassy_stm32::gpio::Pin as _;
+use embassy_stm32::gpio::low_level::Pin;
+use embassy_stm32::peripherals::*;
+use embassy_stm32::spi::{Config, Spi};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::timer::Channel;
+use embassy_stm32::timer::simple_pwm::{PwmPin, SimplePwm};
+
+use crate::buzzer::PwmToneOutput;
+#[cfg(feature = "frontend")]
+use crate::frontend::Frontend;
+#[cfg(not(feature = "gcs"))]
+use crate::outputs::{LogicalOutput, Outputs};
+
+use super::SensorSpi;
+
+pub type BuzzerTimer = TIM4;
+
+pub struct SensorSpiResources {
+    pub spi: SPI1,
+    pub sck: PA5,
+    pub mosi: PA7,
+    pub miso: PB4,
+    pub tx_dma: DMA2_CH3,
+    pub rx_dma: DMA2_CH2,
+}
+
+impl SensorSpiResources {
+    pub fn init(self, config: Config) -> SensorSpi {
+        Spi::new(self.spi, self.sck, self.mosi, self.miso, self.tx_dma, self.rx_dma, config)
+    }
+}
+
+pub struct BuzzerResources {
+    pub timer: TIM4,
+    pub pin: PB9,
+}
+
+impl BuzzerResources {
+    pub fn init(self) -> PwmToneOutput<BuzzerTimer> {
+        let gpiob_block = self.pin.block();
+        let pwm_pin = PwmPin::new_ch4(self.pin, OutputType::PushPull);
+        let pwm = SimplePwm::new(self.timer, None, None, None, Some(pwm_pin), Hertz::hz(440), Default::default());
+        PwmToneOutput::new(pwm, Channel::Ch4, gpiob_block, 9)
+    }
+}
+
+/// Umbilical presence and breakwire inputs, see `umbilical.rs`.
+#[cfg(feature = "umbilical")]
+pub struct UmbilicalResources {
+    pub presence: PA6,
+    pub breakwire: PC7,
+}
+
+#[cfg(feature = "umbilical")]
+impl UmbilicalResources {
+    pub fn init(self) -> (Input<'static, AnyPin>, Input<'static, AnyPin>) {
+        (
+            Input::new(self.presence.degrade(), Pull::Up),
+            Input::new(self.breakwire.degrade(), Pull::Up),
+        )
+    }
+}
+
+/// Physical output channels, see `outputs.rs`. Separation and camera aren't fitted on this
+/// revision.
+#[cfg(not(feature = "gcs"))]
+pub struct OutputResources {
+    pub pyro1: PC8,
+    pub pyro2: PC9,
+    #[cfg(feature = "strobe")]
+    pub strobe: PB5,
+}
+
+#[cfg(not(feature = "gcs"))]
+impl OutputResources {
+    pub fn init(self) -> Outputs {
+        let mut outputs = Outputs::new();
+        outputs.map(LogicalOutput::Drogue, self.pyro1.degrade());
+        outputs.map(LogicalOutput::Main, self.pyro2.degrade());
+        #[cfg(feature = "strobe")]
+        outputs.map(LogicalOutput::Strobe, self.strobe.degrade());
+        outputs
+    }
+}
+
+/// Enable lines of the ground station's external LNA and PA, see `frontend.rs`.
+#[cfg(feature = "frontend")]
+pub struct FrontendResources {
+    pub lna: PA9,
+    pub pa: PA10,
+}
+
+#[cfg(feature = "frontend")]
+impl FrontendResources {
+    pub fn init(self) -> Frontend {
+        Frontend::new(Some(self.lna.degrade()), Some(self.pa.degrade()))
+    }
+}
+
+pub struct Resources {
+    pub sensor_spi: SensorSpiResources,
+    pub buzzer: BuzzerResources,
+    #[cfg(not(feature = "gcs"))]
+    pub outputs: OutputResources,
+    #[cfg(feature = "umbilical")]
+    pub umbilical: UmbilicalResources,
+    #[cfg(feature = "frontend")]
+    pub frontend: FrontendResources,
+}
+
+/// Takes the revision-specific peripherals out of the peripheral set returned by
+/// `embassy_stm32::init`.
+#[macro_export]
+macro_rules! board_resources {
+    ($p:ident) => {
+        $crate::board::Resources {
+            sensor_spi: $crate::board::SensorSpiResources {
+                spi: $p.SPI1,
+                sck: $p.PA5,
+                mosi: $p.PA7,
+                miso: $p.PB4,
+                tx_dma: $p.DMA2_CH3,
+                rx_dma: $p.DMA2_CH2,
+            },
+            buzzer: $crate::board::BuzzerResources {
+                timer: $p.TIM4,
+                pin: $p.PB9,
+            },
+            #[cfg(not(feature = "gcs"))]
+            outputs: $crate::board::OutputResources {
+                pyro1: $p.PC8,
+                pyro2: $p.PC9,
+                #[cfg(feature = "strobe")]
+                strobe: $p.PB5,
+            },
+            #[cfg(feature = "umbilical")]
+            umbilical: $crate::board::UmbilicalResources {
+                presence: $p.PA6,
+                breakwire: $p.PC7,
+            },
+            #[cfg(feature = "frontend")]
+            frontend: $crate::board::FrontendResources {
+                lna: $p.PA9,
+                pa: $p.PA10,
+            },
+        }
+    };
+}
@@ -0,0 +1,33 @@
+//! Rebooting into the STM32's built-in DFU bootloader.
+//!
+//! Jumping to the system memory directly from the running firmware is unreliable, since
+//! peripherals and clocks would have to be reset to their default state first. Instead, we
+//! leave a flag in uninitialized RAM, reset the processor, and jump to the bootloader right
+//! at the start of `main`, before anything else is initialized.
+
+use core::mem::MaybeUninit;
+
+const BOOTLOADER_MAGIC: u32 = 0xb007_10ad;
+const SYSTEM_MEMORY_ADDRESS: u32 = 0x1fff_0000;
+
+#[link_section = ".uninit.BOOTLOADER_FLAG"]
+static mut BOOTLOADER_FLAG: MaybeUninit<u32> = MaybeUninit::uninit();
+
+pub fn reboot_to_bootloader() -> ! {
+    unsafe { core::ptr::addr_of_mut!(BOOTLOADER_FLAG).cast::<u32>().write_volatile(BOOTLOADER_MAGIC) };
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Jumps to the bootloader if this reset was caused by `reboot_to_bootloader`. Has to be called
+/// before any hardware is initialized.
+pub fn jump_to_bootloader_if_requested() {
+    let flag = unsafe { core::ptr::addr_of_mut!(BOOTLOADER_FLAG).cast::<u32>() };
+    if unsafe { flag.read_volatile() } != BOOTLOADER_MAGIC {
+        return;
+    }
+
+    unsafe {
+        flag.write_volatile(0);
+        cortex_m::asm::bootload(SYSTEM_MEMORY_ADDRESS as *const u32)
+    }
+}
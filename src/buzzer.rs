@@ -1,40 +1,41 @@
 //! Driver for the on-board buzzer, responsible for playing mode change beeps and
-//! warning tones using the STM32's timers for PWM generation.
+//! warning tones. The tones themselves are generated by a `ToneOutput`, e.g. using
+//! the STM32's timers for PWM generation.
 //! TODO: maybe run melodies in a separate embassy task
 
 use embassy_stm32::timer::{simple_pwm::SimplePwm, Channel, CaptureCompare16bitInstance};
 use embassy_stm32::time::Hertz;
 use embassy_stm32::pac::gpio::Gpio;
 use embassy_stm32::pac::gpio::vals;
+use heapless::Vec;
 
 use num_traits::Float;
 
 use shared_types::*;
 
 use Semitone::*;
+use crate::clock::Instant;
 use crate::drivers::sensors::BatteryStatus;
 
-#[allow(dead_code)]
-const STARTUP: [Note; 6] = [
+static STARTUP: [Note; 6] = [
     Note::note(C, 4, 150), Note::pause(10),
     Note::note(E, 4, 150), Note::pause(10),
     Note::note(G, 4, 150), Note::pause(10),
 ];
 
-const HWARMED: [Note; 6] = [
+static HWARMED: [Note; 6] = [
     Note::note(A, 3, 150), Note::pause(10),
     Note::note(A, 3, 150), Note::pause(10),
     Note::note(A, 3, 150), Note::pause(10),
 ];
 
-const ARMED: [Note; 6] = [
+static ARMED: [Note; 6] = [
     Note::note(G, 4, 150), Note::pause(10),
     Note::note(G, 4, 150), Note::pause(10),
     Note::note(G, 4, 150), Note::pause(10),
 ];
 
-#[allow(dead_code)]
-const LANDED: [Note; 57] = [
+static LANDED: [Note; 57] = [
     Note::note(C, 4, 150 - 10), Note::pause(10),
     Note::note(D, 4, 150 - 10), Note::pause(10),
     Note::note(F, 4, 150 - 10), Note::pause(10),
@@ -70,8 +71,7 @@ const LANDED: [Note; 57] = [
     Note::note(F, 4, 600 - 50), Note::pause(50),
 ];
 
-#[allow(dead_code)]
-const REMNANTS: [Note; 40] = [
+static REMNANTS: [Note; 40] = [
     Note::note(E, 3, 200), Note::pause(10),
     Note::note(D, 3, 200), Note::pause(10),
     Note::note(A, 4, 400), Note::pause(10),
@@ -97,8 +97,7 @@ const REMNANTS: [Note; 40] = [
     Note::note(As, 4, 800), Note::pause(10),
 ];
 
-#[allow(dead_code)]
-const THUNDERSTRUCK: [Note; 64] = [
+static THUNDERSTRUCK: [Note; 64] = [
     Note::note(B, 4, 100), Note::pause(10),
     Note::note(B, 3, 100), Note::pause(10),
     Note::note(A, 4, 100), Note::pause(10),
@@ -134,8 +133,7 @@ const THUNDERSTRUCK: [Note; 64] = [
     Note::note(B, 3, 100), Note::pause(10),
 ];
 
-#[allow(dead_code)]
-const E1M1: [Note; 56] = [
+static E1M1: [Note; 56] = [
     Note::note(E, 3, 100), Note::pause(10),
     Note::note(E, 3, 100), Note::pause(10),
     Note::note(D, 4, 100), Note::pause(10),
@@ -173,71 +171,275 @@ const E1M1: [Note; 56] = [
     Note::note(E, 3, 100), Note::pause(10),
     Note::note(Gs, 3, 500), Note::pause(10),
 ];
-#[allow(dead_code)]
-const WARNING_MELODY: [Note; 4] = [
+static WARNING_MELODY: [Note; 4] = [
     Note::note(C, 5, 500),  Note::pause(200),
     Note::note(C, 5, 500), Note::pause(9000)];
 
-#[allow(dead_code)]
-const SHORT_WARNING_MELODY: [Note; 2] = [Note::note(C, 5, 500), Note::pause(500)];
-#[allow(dead_code)]
-const NO_BATTERY_ATTACHED_MELODY: [Note; 4] = [
+// Alternating high and low tones near the buzzer's resonance, which carry the furthest.
+static FIND_ME_MELODY: [Note; 4] = [
+    Note::note(D, 7, 400), Note::note(A, 6, 400),
+    Note::note(D, 7, 400), Note::note(A, 6, 400),
+];
+
+static SHORT_WARNING_MELODY: [Note; 2] = [Note::note(C, 5, 500), Note::pause(500)];
+static NO_BATTERY_ATTACHED_MELODY: [Note; 4] = [
     Note::note(F, 5, 400), Note::pause(10),
     Note::note(D, 5, 100), Note::pause(10)
 ];
 
-pub struct Buzzer<TIM: 'static> {
+/// Melodies that can be played on request, e.g. via the USB console.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Melody {
+    Startup,
+    HardwareArmed,
+    Armed,
+    Landed,
+    Warning,
+    Remnants,
+    Thunderstruck,
+    E1M1,
+}
+
+impl Melody {
+    pub const ALL: [Melody; 8] = [
+        Melody::Startup,
+        Melody::HardwareArmed,
+        Melody::Armed,
+        Melody::Landed,
+        Melody::Warning,
+        Melody::Remnants,
+        Melody::Thunderstruck,
+        Melody::E1M1,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Startup => "startup",
+            Self::HardwareArmed => "hwarmed",
+            Self::Armed => "armed",
+            Self::Landed => "landed",
+            Self::Warning => "warning",
+            Self::Remnants => "remnants",
+            Self::Thunderstruck => "thunderstruck",
+            Self::E1M1 => "e1m1",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|m| m.name() == name).copied()
+    }
+
+    fn notes(&self) -> &'static [Note] {
+        match self {
+            Self::Startup => &STARTUP,
+            Self::HardwareArmed => &HWARMED,
+            Self::Armed => &ARMED,
+            Self::Landed => &LANDED,
+            Self::Warning => &SHORT_WARNING_MELODY,
+            Self::Remnants => &REMNANTS,
+            Self::Thunderstruck => &THUNDERSTRUCK,
+            Self::E1M1 => &E1M1,
+        }
+    }
+}
+
+// Status chirps on the pad: first arm voltage (high double chirp if present, low chirp if not),
+// then GPS (rising chirp with fix, falling chirp without).
+static STATUS_ARMED_FIX: [Note; 8] = [
+    Note::note(E, 5, 50), Note::pause(50), Note::note(E, 5, 50), Note::pause(400),
+    Note::note(C, 5, 50), Note::pause(10), Note::note(G, 5, 50), Note::pause(10),
+];
+
+static STATUS_ARMED_NO_FIX: [Note; 8] = [
+    Note::note(E, 5, 50), Note::pause(50), Note::note(E, 5, 50), Note::pause(400),
+    Note::note(G, 5, 50), Note::pause(10), Note::note(C, 5, 50), Note::pause(10),
+];
+
+static STATUS_DISARMED_FIX: [Note; 6] = [
+    Note::note(E, 4, 100), Note::pause(400),
+    Note::note(C, 5, 50), Note::pause(10), Note::note(G, 5, 50), Note::pause(10),
+];
+
+static STATUS_DISARMED_NO_FIX: [Note; 6] = [
+    Note::note(E, 4, 100), Note::pause(400),
+    Note::note(G, 5, 50), Note::pause(10), Note::note(C, 5, 50), Note::pause(10),
+];
+
+/// Part of the flight report beeped out after landing, e.g. `3` is three short beeps.
+#[derive(Clone, Copy)]
+enum ReportSymbol {
+    Digit(u8, Semitone),
+    Pause(u32),
+}
+
+impl ReportSymbol {
+    fn notes(&self) -> Vec<Note, 20> {
+        let mut notes = Vec::new();
+        match *self {
+            // Zero is a single long beep, since no beeps at all would be hard to count
+            Self::Digit(0, semitone) => {
+                let _ = notes.push(Note::note(semitone, 5, 600));
+                let _ = notes.push(Note::pause(900));
+            }
+            Self::Digit(digit, semitone) => {
+                for _i in 0..digit {
+                    let _ = notes.push(Note::note(semitone, 5, 150));
+                    let _ = notes.push(Note::pause(150));
+                }
+                let _ = notes.push(Note::pause(750));
+            }
+            Self::Pause(duration) => {
+                let _ = notes.push(Note::pause(duration));
+            }
+        }
+        notes
+    }
+}
+
+/// Hardware used to generate tones, implemented per board.
+pub trait ToneOutput {
+    fn set_frequency(&mut self, frequency: Hertz);
+    /// Volume in percent
+    fn set_volume(&mut self, volume: u8);
+    fn enable(&mut self);
+    fn disable(&mut self);
+}
+
+/// Tone output using a PWM channel of one of the STM32's timers.
+pub struct PwmToneOutput<TIM: 'static> {
     pwm: SimplePwm<'static, TIM>,
     channel: Channel,
     block: Gpio,
     pin: usize,
+    volume: u8,
+}
+
+impl<TIM: CaptureCompare16bitInstance> PwmToneOutput<TIM> {
+    pub fn new(mut pwm: SimplePwm<'static, TIM>, channel: Channel, block: Gpio, pin: usize) -> Self {
+        pwm.set_duty(channel, pwm.get_max_duty() / 2);
+
+        Self {
+            pwm,
+            channel,
+            block,
+            pin,
+            volume: 100,
+        }
+    }
+}
+
+impl<TIM: CaptureCompare16bitInstance> ToneOutput for PwmToneOutput<TIM> {
+    fn set_frequency(&mut self, frequency: Hertz) {
+        // The max. duty depends on the frequency, so we have to set the duty cycle again.
+        // A duty cycle of 50% is the loudest we can get.
+        self.pwm.set_frequency(frequency);
+        let duty = (self.pwm.get_max_duty() as u32) * (self.volume as u32) / 200;
+        self.pwm.set_duty(self.channel, duty as u16);
+    }
+
+    fn set_volume(&mut self, volume: u8) {
+        self.volume = u8::min(volume, 100);
+    }
+
+    // We set the buzzer output pin into an open-drain state when not using it to
+    // reduce leakage current. Since the HAL doesn't provide a straight-forward way
+    // to do that, we do it manually.
+    fn enable(&mut self) {
+        self.block.moder().modify(|w| w.set_moder(self.pin, vals::Moder::ALTERNATE));
+        self.block.otyper().modify(|w| w.set_ot(self.pin, vals::Ot::PUSHPULL));
+        self.pwm.enable(self.channel);
+    }
+
+    fn disable(&mut self) {
+        self.block.moder().modify(|w| w.set_moder(self.pin, vals::Moder::OUTPUT));
+        self.block.otyper().modify(|w| w.set_ot(self.pin, vals::Ot::OPENDRAIN));
+        self.pwm.disable(self.channel);
+    }
+}
+
+pub struct Buzzer<OUT> {
+    output: OUT,
     drogue_warning_note: Note,
     main_warning_note: Note,
     current_tone: Option<Note>,
     current_melody: Option<&'static [Note]>,
+    /// Only play warnings and arming beeps, e.g. in the lab
+    quiet: bool,
+    /// Apogee and max. velocity, played whenever no other melody is playing after landing
+    flight_report: Vec<ReportSymbol, 16>,
     current_index: usize,
-    time_note_change: u32,
+    time_note_change: Instant,
     repeat: bool,
     is_warning: bool,
-    nba_already_played: bool //no_battery_attached_melody_already_played was too long for my taste
+    nba_already_played: bool, //no_battery_attached_melody_already_played was too long for my taste
+    /// Time until which the find-me siren plays, if active
+    find_me_until: Option<Instant>,
 }
 
-impl<TIM: CaptureCompare16bitInstance> Buzzer<TIM> {
-    pub fn init(mut pwm: SimplePwm<'static, TIM>, channel: Channel, block: Gpio, pin: usize) -> Self {
-        #[cfg(feature="rev1")]
-        pwm.set_duty(Channel::Ch4, pwm.get_max_duty() / 2);
-        #[cfg(not(feature="rev1"))]
-        pwm.set_duty(Channel::Ch2, pwm.get_max_duty() / 2);
-
+impl<OUT: ToneOutput> Buzzer<OUT> {
+    pub fn init(output: OUT) -> Self {
         let buzzer = Self {
-            pwm,
-            channel,
-            block,
-            pin,
+            output,
             drogue_warning_note: Note::note(C, 5, 500),
             main_warning_note: Note::note(C, 5, 500),
             current_tone: None,
             current_melody: Some(&STARTUP),
+            quiet: false,
+            flight_report: Vec::new(),
             current_index: 0,
-            time_note_change: 0,
+            time_note_change: Instant::ZERO,
             repeat: false,
             is_warning: true,
-            nba_already_played: false
+            nba_already_played: false,
+            find_me_until: None,
         };
         buzzer
     }
 
-    fn current_frequency(&self) -> Option<f32> {
-        let melody_note = self.current_melody
-            .map(|m| m.get(self.current_index))
-            .flatten();
+    fn melody_note(&self, index: usize) -> Option<Note> {
+        match self.current_melody {
+            Some(melody) => melody.get(index).cloned(),
+            None => self.flight_report.iter().flat_map(|s| s.notes()).nth(index),
+        }
+    }
 
-        self.current_tone.as_ref()
-            .or(melody_note)
+    fn melody_length(&self) -> Option<usize> {
+        match self.current_melody {
+            Some(melody) => Some(melody.len()),
+            None if !self.flight_report.is_empty() && !self.quiet => Some(self.flight_report.iter().map(|s| s.notes().len()).sum()),
+            None => None,
+        }
+    }
+
+    fn current_frequency(&self) -> Option<f32> {
+        self.current_tone.clone()
+            .or(self.melody_note(self.current_index))
             .map(|n| n.freq())
             .flatten()
     }
 
+    /// Sets the values to beep out digit by digit after landing: apogee (m) in a higher pitch,
+    /// followed by max. vertical velocity (m/s) in a lower one.
+    pub fn set_flight_report(&mut self, apogee: f32, max_velocity: f32) {
+        self.flight_report.clear();
+        for (value, semitone) in [(apogee, A), (max_velocity, E)] {
+            let value = value.max(0.0) as u32;
+            let mut divisor = 1;
+            while divisor * 10 <= value {
+                divisor *= 10;
+            }
+
+            while divisor > 0 {
+                let _ = self.flight_report.push(ReportSymbol::Digit(((value / divisor) % 10) as u8, semitone));
+                divisor /= 10;
+            }
+
+            let _ = self.flight_report.push(ReportSymbol::Pause(1500));
+        }
+
+        let _ = self.flight_report.push(ReportSymbol::Pause(3000));
+    }
+
     //TODO repair so that warn tone length is changable
     pub fn apply_settings(
         &mut self,
@@ -249,16 +451,16 @@ impl<TIM: CaptureCompare16bitInstance> Buzzer<TIM> {
     }
 
     //returns true if just finished playing note
-    fn has_note_just_finished(&mut self, time: u32, note: Option<&Note>) -> bool{
+    fn has_note_just_finished(&mut self, time: Instant, note: Option<&Note>) -> bool{
         if note.is_some(){
-            if time.wrapping_sub(self.time_note_change) > note.unwrap().duration{
+            if time.millis_since(self.time_note_change) > note.unwrap().duration{
                 return true;
             }
         }
         return false;
     }
 
-    fn increment_melody(&mut self, time: u32, length: usize){
+    fn increment_melody(&mut self, time: Instant, length: usize){
         self.current_index += 1;
         self.time_note_change = time;
 
@@ -274,7 +476,7 @@ impl<TIM: CaptureCompare16bitInstance> Buzzer<TIM> {
         }
     }
 
-    pub fn tick(&mut self, time: u32, battery_status: Option<BatteryStatus>) {
+    pub fn tick(&mut self, time: Instant, battery_status: Option<BatteryStatus>) {
         if let Some(status) = battery_status{
             match status {
                 BatteryStatus::Low => {
@@ -294,9 +496,16 @@ impl<TIM: CaptureCompare16bitInstance> Buzzer<TIM> {
             }
         }
 
-        if let Some(melody) = self.current_melody {
-            if self.has_note_just_finished(time, melody.get(self.current_index)){
-                self.increment_melody(time, melody.len());
+        if let Some(until) = self.find_me_until {
+            if time >= until {
+                self.find_me(time, None);
+            }
+        }
+
+        if let Some(length) = self.melody_length() {
+            let note = self.melody_note(self.current_index);
+            if self.has_note_just_finished(time, note.as_ref()){
+                self.increment_melody(time, length);
             }
         }
 
@@ -304,22 +513,89 @@ impl<TIM: CaptureCompare16bitInstance> Buzzer<TIM> {
             return;
         }
 
-        // We set the buzzer output pin into an open-drain state when not using it to
-        // reduce leakage current. Since the HAL doesn't provide a straight-forward way
-        // to do that, we do it manually.
         if let Some(freq) = self.current_frequency() {
-            self.block.moder().modify(|w| w.set_moder(self.pin, vals::Moder::ALTERNATE));
-            self.block.otyper().modify(|w| w.set_ot(self.pin, vals::Ot::PUSHPULL));
-            self.pwm.set_frequency(Hertz::hz(freq as u32));
-            self.pwm.enable(self.channel);
+            self.output.set_frequency(Hertz::hz(freq as u32));
+            self.output.enable();
         } else {
-            self.block.moder().modify(|w| w.set_moder(self.pin, vals::Moder::OUTPUT));
-            self.block.otyper().modify(|w| w.set_ot(self.pin, vals::Ot::OPENDRAIN));
-            self.pwm.disable(self.channel);
+            self.output.disable();
         }
     }
 
-    pub fn switch_mode(&mut self, time: u32, mode: FlightMode) {
+    /// Sets the volume in percent.
+    pub fn set_volume(&mut self, volume: u8) {
+        self.output.set_volume(volume);
+    }
+
+    /// In quiet mode, only warnings and arming beeps are played.
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+        if quiet && !self.current_melody.map(Self::is_critical).unwrap_or(true) {
+            self.current_melody = None;
+        }
+    }
+
+    fn is_critical(melody: &[Note]) -> bool {
+        [&FIND_ME_MELODY[..], &WARNING_MELODY[..], &SHORT_WARNING_MELODY[..], &NO_BATTERY_ATTACHED_MELODY[..], &HWARMED[..], &ARMED[..]]
+            .iter()
+            .any(|m| core::ptr::eq(*m, melody))
+    }
+
+    /// Plays the given melody once, unless a warning is playing.
+    pub fn play(&mut self, time: Instant, melody: Melody) {
+        self.change_melody(time, Some(melody.notes()));
+    }
+
+    /// Plays the given melody once even in quiet mode, for the self test. Returns whether it
+    /// started, which it doesn't while a warning is playing.
+    pub fn play_unmuted(&mut self, time: Instant, melody: Melody) -> bool {
+        if self.is_warning {
+            return false;
+        }
+
+        let quiet = core::mem::replace(&mut self.quiet, false);
+        self.change_melody(time, Some(melody.notes()));
+        self.quiet = quiet;
+        true
+    }
+
+    /// Starts the find-me siren for the given duration (ms), taking precedence over everything
+    /// else including quiet mode, or stops it with `None`.
+    #[cfg_attr(feature = "gcs", allow(dead_code))]
+    pub fn find_me(&mut self, time: Instant, duration: Option<u32>) {
+        if duration.is_none() && self.find_me_until.is_none() {
+            return;
+        }
+
+        self.find_me_until = duration.map(|d| time.plus_millis(d));
+        self.is_warning = false;
+        self.change_melody(time, duration.map(|_| &FIND_ME_MELODY[..]));
+        self.repeat = duration.is_some();
+        self.is_warning = duration.is_some();
+    }
+
+    /// Remaining time (ms) of the find-me siren, if active
+    #[cfg_attr(feature = "gcs", allow(dead_code))]
+    pub fn find_me_remaining(&self, time: Instant) -> Option<u32> {
+        self.find_me_until.map(|until| until.millis_since(time))
+    }
+
+    /// Plays a short status chirp, unless something else is playing.
+    pub fn play_status(&mut self, time: Instant, arm_voltage_present: bool, gps_fix: bool) {
+        if self.current_melody.is_some() || self.current_tone.is_some() {
+            return;
+        }
+
+        let melody: &'static [Note] = match (arm_voltage_present, gps_fix) {
+            (true, true) => &STATUS_ARMED_FIX,
+            (true, false) => &STATUS_ARMED_NO_FIX,
+            (false, true) => &STATUS_DISARMED_FIX,
+            (false, false) => &STATUS_DISARMED_NO_FIX,
+        };
+
+        self.change_melody(time, Some(melody));
+    }
+
+    pub fn switch_mode(&mut self, time: Instant, mode: FlightMode) {
         let new_melody:Option<&'static [Note]> = match mode {
             FlightMode::RecoveryDrogue | FlightMode::RecoveryMain => Some(&SHORT_WARNING_MELODY),
             FlightMode::HardwareArmed => Some(&HWARMED),
@@ -328,12 +604,19 @@ impl<TIM: CaptureCompare16bitInstance> Buzzer<TIM> {
             _ => None
         };
 
+        if mode != FlightMode::Landed {
+            self.flight_report.clear();
+        }
+
         self.change_melody(time, new_melody);
-        if !self.is_warning && mode == FlightMode::Landed{
+        // Without a flight report, keep playing the landing melody to help with finding the
+        // vehicle. Otherwise, the report repeats after the melody.
+        if !self.is_warning && mode == FlightMode::Landed && self.flight_report.is_empty() {
             self.repeat = true;
         }
     }
-    fn change_melody(&mut self, time: u32, new_melody: Option<&'static [Note]>){
+    fn change_melody(&mut self, time: Instant, new_melody: Option<&'static [Note]>){
+        let new_melody = new_melody.filter(|m| !self.quiet || Self::is_critical(m));
         if !self.is_warning {
             self.current_melody = new_melody;
             self.current_index = 0;
@@ -0,0 +1,72 @@
+//! Per-sensor calibration, stored in its own flash sector separately from the settings (see
+//! `flash.rs`), so recalibrating a sensor doesn't touch the flight parameters and vice versa.
+//!
+//! The stored data is prefixed with the schema version. Whenever `SensorCalibration` changes, the
+//! version has to be incremented, and the previous layout kept around for `decode` to convert it,
+//! so a firmware update doesn't throw away a calibration that took a while to obtain. Before this
+//! sector existed, sensor offsets were part of the settings, which `from_settings` takes over.
+
+use nalgebra::{Matrix3, Vector3};
+use serde::{Deserialize, Serialize};
+
+use shared_types::Settings;
+
+/// Current schema version of the stored calibration
+pub const CALIBRATION_VERSION: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SensorCalibration {
+    /// Gyroscope bias, subtracted from the readings
+    pub gyro_bias: Vector3<f32>,
+    /// Offsets of the IMU accelerometer and the high-g accelerometer
+    pub acc_offset: Vector3<f32>,
+    pub acc2_offset: Vector3<f32>,
+    /// Magnetometer hard-iron offset, subtracted from the readings
+    pub mag_offset: Vector3<f32>,
+    /// Magnetometer soft-iron correction, applied after removing the offset, mapping the measured
+    /// ellipsoid onto a sphere
+    pub mag_transform: Matrix3<f32>,
+    /// Correction (hPa) added to the barometer's pressure readings
+    pub baro_offset: f32,
+}
+
+impl Default for SensorCalibration {
+    fn default() -> Self {
+        Self {
+            gyro_bias: Vector3::zeros(),
+            acc_offset: Vector3::zeros(),
+            acc2_offset: Vector3::zeros(),
+            mag_offset: Vector3::zeros(),
+            mag_transform: Matrix3::identity(),
+            baro_offset: 0.0,
+        }
+    }
+}
+
+impl SensorCalibration {
+    /// Takes over the offsets previously stored in the settings.
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            gyro_bias: settings.gyro_offset,
+            acc_offset: settings.acc_offset,
+            acc2_offset: settings.acc2_offset,
+            mag_offset: settings.mag_offset,
+            ..Self::default()
+        }
+    }
+
+    /// Deserializes a calibration stored with the given schema version, converting it to the
+    /// current one if necessary. Returns `None` for unknown versions.
+    pub fn decode(version: u8, data: &[u8]) -> Option<Result<Self, postcard::Error>> {
+        match version {
+            CALIBRATION_VERSION => Some(postcard::from_bytes(data)),
+            // Conversions from older versions go here.
+            _ => None,
+        }
+    }
+
+    /// Applies the soft-iron correction to a magnetometer reading with the offset already removed.
+    pub fn correct_magnetometer(&self, mag: Vector3<f32>) -> Vector3<f32> {
+        self.mag_transform * mag
+    }
+}
@@ -14,11 +14,16 @@ use embedded_hal_async::spi::SpiDevice;
 
 use static_cell::StaticCell;
 
-use defmt::*;
-
 use shared_types::can::*;
 
 use crate::drivers::can::*;
+use crate::errors::{report, ErrorKind, Subsystem};
+#[cfg(not(feature="gcs"))]
+use crate::redundancy::*;
+
+/// Number of received messages buffered for the main loop. At 125 kbps, only about one frame
+/// arrives per millisecond, but a slow main loop iteration should not cause any to be dropped.
+pub const INCOMING_QUEUE_SIZE: usize = 8;
 
 pub struct CanTx<SPI: 'static> {
     driver: &'static Mutex<CriticalSectionRawMutex, MCP2517FD<SPI>>,
@@ -27,11 +32,11 @@ pub struct CanTx<SPI: 'static> {
 
 pub struct CanRx<SPI: 'static> {
     driver: &'static Mutex<CriticalSectionRawMutex, MCP2517FD<SPI>>,
-    sender: Sender<'static, CriticalSectionRawMutex, FcReceivedCanBusMessage, 3>,
+    sender: Sender<'static, CriticalSectionRawMutex, FcReceivedCanBusMessage, INCOMING_QUEUE_SIZE>,
 }
 
 pub struct CanHandle {
-    receiver: Receiver<'static, CriticalSectionRawMutex, FcReceivedCanBusMessage, 3>,
+    receiver: Receiver<'static, CriticalSectionRawMutex, FcReceivedCanBusMessage, INCOMING_QUEUE_SIZE>,
     sender: Sender<'static, CriticalSectionRawMutex, (u16, [u8; 8]), 3>,
 }
 
@@ -51,7 +56,7 @@ type CanRxInst = CanRx<SpiDeviceImplInst>;
 
 static DRIVER_SHARED: StaticCell<Mutex<CriticalSectionRawMutex, MCP2517FD<SpiDeviceImplInst>>> = StaticCell::new();
 
-static INCOMING_CHANNEL: StaticCell<Channel::<CriticalSectionRawMutex, FcReceivedCanBusMessage, 3>> = StaticCell::new();
+static INCOMING_CHANNEL: StaticCell<Channel::<CriticalSectionRawMutex, FcReceivedCanBusMessage, INCOMING_QUEUE_SIZE>> = StaticCell::new();
 static OUTGOING_CHANNEL: StaticCell<Channel::<CriticalSectionRawMutex, (u16, [u8; 8]), 3>> = StaticCell::new();
 
 impl CanHandle {
@@ -61,7 +66,7 @@ impl CanHandle {
 
     pub fn transmit(&mut self, id: u16, msg: [u8; 8]) {
         if let Err(_e) = self.sender.try_send((id, msg)) {
-            error!("Failed to enqueue CAN msg.");
+            report(Subsystem::Can, ErrorKind::QueueFull, "queueing message");
         }
     }
 }
@@ -72,7 +77,7 @@ impl<SPI: SpiDevice<u8>> CanTx<SPI> {
         loop {
             let (id, msg) = self.receiver.receive().await;
             if let Err(_e) = self.driver.lock().await.transmit(id, msg).await {
-                //error!("Failed to transmit CAN msg: {:?}", Debug2Format(&e));
+                report(Subsystem::Can, ErrorKind::Bus, "transmitting message");
             }
         }
     }
@@ -93,16 +98,16 @@ impl<SPI: SpiDevice<u8>> CanRx<SPI> {
                     Timer::after(Duration::from_micros(100)).await;
                     continue;
                 }
-                Err(e) => {
-                    error!("Failed to receive CAN msg: {:?}", Debug2Format(&e));
+                Err(_e) => {
+                    report(Subsystem::Can, ErrorKind::Bus, "receiving message");
                     continue;
                 }
             };
 
             let message_id = match CanBusMessageId::try_from(id) {
                 Ok(id) => id,
-                Err(e) => {
-                    error!("Unknown CAN bus message id: {}", e);
+                Err(_e) => {
+                    report(Subsystem::Can, ErrorKind::Unsupported, "parsing message id");
                     continue;
                 }
             };
@@ -110,7 +115,7 @@ impl<SPI: SpiDevice<u8>> CanRx<SPI> {
             let received_message = match message_id {
                 CanBusMessageId::IoBoardInput(role, 0xf) => {
                     let Ok(Some(parsed)) = IoBoardPowerMessage::parse(msg) else {
-                        error!("Malformed message");
+                        report(Subsystem::Can, ErrorKind::Deserialization, "parsing message");
                         continue;
                     };
 
@@ -118,7 +123,7 @@ impl<SPI: SpiDevice<u8>> CanRx<SPI> {
                 }
                 CanBusMessageId::IoBoardInput(role, id) => {
                     let Ok(Some(parsed)) = IoBoardSensorMessage::parse(msg) else {
-                        error!("Malformed message");
+                        report(Subsystem::Can, ErrorKind::Deserialization, "parsing message");
                         continue;
                     };
 
@@ -126,7 +131,7 @@ impl<SPI: SpiDevice<u8>> CanRx<SPI> {
                 }
                 CanBusMessageId::FinBoardInput(fin, id) => {
                     let Ok(Some(parsed)) = FinBoardDataMessage::parse(msg) else {
-                        error!("Malformed message");
+                        report(Subsystem::Can, ErrorKind::Deserialization, "parsing message");
                         continue;
                     };
 
@@ -134,14 +139,23 @@ impl<SPI: SpiDevice<u8>> CanRx<SPI> {
                 }
                 CanBusMessageId::BatteryBoardInput(id) => {
                     let Ok(Some(parsed)) = BatteryTelemetryMessage::parse(msg) else {
-                        error!("Malformed message");
+                        report(Subsystem::Can, ErrorKind::Deserialization, "parsing message");
                         continue;
                     };
 
                     FcReceivedCanBusMessage::BatteryTelemetry(id, parsed)
                 }
-                m_id => {
-                    error!("Unsupported CAN bus message id: {}", Debug2Format(&m_id));
+                CanBusMessageId::TelemetryBroadcast(id) if id == PARTNER_FC_ID => {
+                    let Ok(Some(parsed)) = TelemetryToPayloadMessage::parse(msg) else {
+                        report(Subsystem::Can, ErrorKind::Deserialization, "parsing message");
+                        continue;
+                    };
+
+                    PARTNER_SIGNAL.signal(parsed);
+                    continue;
+                }
+                _ => {
+                    report(Subsystem::Can, ErrorKind::Unsupported, "handling message");
                     continue;
                 }
             };
@@ -0,0 +1,112 @@
+//! Raw packet capture on the ground station, for post-flight link analysis. While enabled, every
+//! packet received via LoRa is forwarded over USB as is, along with the reception time, frequency
+//! and signal quality, including packets that failed the CRC or could not be decoded. These can
+//! then be examined offline, or decoding can be attempted with more lenient checks.
+//!
+//! Captured packets use the regular USB framing (see `framing.rs`), with the payload prefixed by
+//! `CAPTURE_FRAME_TAG`. Capture is toggled via the console (`capture <on|off>`), since it roughly
+//! doubles the USB traffic.
+//!
+//! For archiving a flight, the ground station can record the downlink instead. Each received
+//! packet is then forwarded as a single `RECORD_FRAME_TAG` frame holding both the raw packet and
+//! the message decoded from it, if any, along with the sequence number and hop count. The regular
+//! downlink messages are still sent as well, so the ground software keeps working, and recordings
+//! can be decoded again later on with improved parsers. Recording is toggled via the console
+//! (`record <on|off>`).
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use heapless::Vec;
+use serde::Serialize;
+
+use shared_types::DownlinkMessage;
+
+/// First payload byte of capture frames. Never valid as the start of a serialized downlink
+/// message.
+pub const CAPTURE_FRAME_TAG: u8 = 0xfd;
+/// First payload byte of recording frames, see `CAPTURE_FRAME_TAG`.
+pub const RECORD_FRAME_TAG: u8 = 0xfb;
+
+pub const CAPTURE_HELP_TEXT: &[&str] = &[
+    "capture <on|off>        forward all received packets over USB",
+    "record <on|off>         forward received packets and their decoded messages over USB",
+];
+
+/// Captured packets waiting to be sent via USB.
+pub static CAPTURE_CHANNEL: Channel<CriticalSectionRawMutex, RawPacket, 4> = Channel::new();
+/// Recorded packets waiting to be sent via USB.
+pub static RECORD_CHANNEL: Channel<CriticalSectionRawMutex, Recording, 4> = Channel::new();
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum PacketStatus {
+    /// Decoded successfully
+    Valid,
+    /// Failed the radio's CRC check
+    Crc,
+    /// Passed the CRC check, but could not be decoded, e.g. due to failed authentication
+    Invalid,
+    /// Decoded successfully, but a copy was already received, e.g. directly and via a relay
+    Duplicate,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RawPacket {
+    /// Ground station time (ms) of reception
+    pub time: u32,
+    /// Frequency (Hz) the packet was received on
+    pub frequency: u32,
+    pub rssi: u8,
+    pub rssi_signal: u8,
+    pub snr: i8,
+    pub status: PacketStatus,
+    pub data: Vec<u8, 64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Recording {
+    pub packet: RawPacket,
+    /// Downlink sequence number, if the packet could be authenticated
+    pub sequence_number: Option<u8>,
+    /// Relays the packet went through
+    pub hops: u8,
+    /// Message decoded from the packet. Missing for packets that failed to decode, duplicates
+    /// and link announcements.
+    pub message: Option<DownlinkMessage>,
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Queues a received packet to be sent via USB, if capture is enabled. Packets are dropped if
+/// the USB link can't keep up, since capturing should never stall reception.
+pub fn capture(packet: RawPacket) {
+    if enabled() {
+        let _ = CAPTURE_CHANNEL.try_send(packet);
+    }
+}
+
+pub fn set_recording(enabled: bool) {
+    RECORDING.store(enabled, Ordering::Relaxed);
+}
+
+pub fn recording() -> bool {
+    RECORDING.load(Ordering::Relaxed)
+}
+
+/// Queues a recorded packet to be sent via USB, if recording is enabled. Like captured packets,
+/// recordings are dropped if the USB link can't keep up.
+pub fn record(recording: Recording) {
+    if self::recording() {
+        let _ = RECORD_CHANNEL.try_send(recording);
+    }
+}
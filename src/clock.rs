@@ -0,0 +1,71 @@
+//! Monotonic time base. Time is kept as microseconds since startup in 64 bits, which doesn't wrap
+//! around within the lifetime of the hardware, so durations are computed by plain subtraction and
+//! comparisons just work. The main loops advance their time by the loop period in each iteration
+//! and pass it on to everything they drive.
+//!
+//! Telemetry, the flash log and the LoRa protocol keep using compact 32-bit millisecond
+//! timestamps (see `Instant::wire`), which wrap around after about 49 days. Wrapping arithmetic
+//! should only be needed where those are compared, e.g. in the ground station.
+
+use core::ops::{Add, AddAssign};
+use core::time::Duration;
+
+use defmt::Format;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Format)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub const ZERO: Self = Self(0);
+
+    pub const fn from_micros(micros: u64) -> Self {
+        Self(micros)
+    }
+
+    pub const fn from_millis(millis: u64) -> Self {
+        Self(millis * 1_000)
+    }
+
+    pub const fn as_micros(&self) -> u64 {
+        self.0
+    }
+
+    pub const fn as_millis(&self) -> u64 {
+        self.0 / 1_000
+    }
+
+    /// Compact timestamp (ms) as used in telemetry and the flash log, wraps after about 49 days
+    pub const fn wire(&self) -> u32 {
+        self.as_millis() as u32
+    }
+
+    /// Time elapsed since an earlier instant, zero if it is actually later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_micros(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Milliseconds elapsed since an earlier instant, zero if it is actually later, and
+    /// saturating instead of wrapping around for very long durations
+    pub fn millis_since(&self, earlier: Instant) -> u32 {
+        u32::try_from(self.0.saturating_sub(earlier.0) / 1_000).unwrap_or(u32::MAX)
+    }
+
+    /// Instant the given number of milliseconds later
+    pub const fn plus_millis(&self, millis: u32) -> Self {
+        Self(self.0 + millis as u64 * 1_000)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Self;
+
+    fn add(self, rhs: Duration) -> Self {
+        Self(self.0 + rhs.as_micros() as u64)
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
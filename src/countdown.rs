@@ -0,0 +1,240 @@
+//! Final checks before launch. When the ground station commands ArmedLaunchImminent, the vehicle
+//! counts down for the configured duration, checking the sensors, arm voltage, GPS fix and flash
+//! space on every iteration. If a check keeps failing for more than `CHECK_GRACE_TIME` ms, the
+//! countdown is aborted and the vehicle returns to Armed. The progress is downlinked once per
+//! second (see `lora.rs`), along with the outcome.
+//!
+//! The countdown is a sub-state of ArmedLaunchImminent, since the flight modes are defined in
+//! shared_types. Logging to flash starts with ArmedLaunchImminent, and the downlink switches to
+//! the fast profile while counting down. Launch detection isn't held back by a running countdown.
+
+use serde::{Deserialize, Serialize};
+
+use defmt::Format;
+
+#[cfg(not(feature = "gcs"))]
+use crate::clock::Instant;
+
+/// Time (ms) a check may fail before the countdown is aborted, so single missed samples don't
+/// abort it
+#[cfg(not(feature = "gcs"))]
+const CHECK_GRACE_TIME: u32 = 500;
+/// Interval (ms) between progress reports
+#[cfg(not(feature = "gcs"))]
+const REPORT_INTERVAL: u32 = 1_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub enum CountdownCheck {
+    /// No current readings from the IMU or barometer
+    Sensors,
+    /// The arm line voltage dropped. The recovery outputs have no continuity sensing, so this is
+    /// the closest we get.
+    Arming,
+    GpsFix,
+    /// Less than the reserve left for logging
+    FlashSpace,
+}
+
+impl CountdownCheck {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sensors => "sensors",
+            Self::Arming => "arming",
+            Self::GpsFix => "gps fix",
+            Self::FlashSpace => "flash space",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub enum CountdownStatus {
+    /// Counting down, with the remaining time (ms)
+    Running(u32),
+    /// All checks passed until the end, ready for launch
+    Complete,
+    /// A check failed, the vehicle returns to Armed
+    Aborted(CountdownCheck),
+}
+
+#[cfg(not(feature = "gcs"))]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CountdownConfig {
+    /// Length (ms) of the countdown, or 0 to enter ArmedLaunchImminent without one
+    pub duration: u32,
+    /// Whether a GPS fix is required for launch
+    pub require_gps_fix: bool,
+}
+
+#[cfg(not(feature = "gcs"))]
+impl Default for CountdownConfig {
+    fn default() -> Self {
+        Self {
+            duration: 10_000,
+            require_gps_fix: true,
+        }
+    }
+}
+
+/// Current results of the checks, true if passed
+#[cfg(not(feature = "gcs"))]
+pub struct CheckResults {
+    pub sensors: bool,
+    pub arming: bool,
+    pub gps_fix: bool,
+    pub flash_space: bool,
+}
+
+#[cfg(not(feature = "gcs"))]
+impl CheckResults {
+    fn first_failed(&self, config: &CountdownConfig) -> Option<CountdownCheck> {
+        [
+            (self.sensors, CountdownCheck::Sensors),
+            (self.arming, CountdownCheck::Arming),
+            (self.gps_fix || !config.require_gps_fix, CountdownCheck::GpsFix),
+            (self.flash_space, CountdownCheck::FlashSpace),
+        ]
+        .into_iter()
+        .find(|(passed, _)| !passed)
+        .map(|(_, check)| check)
+    }
+}
+
+#[cfg(not(feature = "gcs"))]
+pub struct Countdown {
+    config: CountdownConfig,
+    started: Option<Instant>,
+    last_report: Option<Instant>,
+    /// First failed check, and since when it has been failing
+    failing: Option<(CountdownCheck, Instant)>,
+}
+
+#[cfg(not(feature = "gcs"))]
+impl Countdown {
+    pub fn new(config: CountdownConfig) -> Self {
+        Self {
+            config,
+            started: None,
+            last_report: None,
+            failing: None,
+        }
+    }
+
+    /// Starts the countdown. Returns false if countdowns are disabled.
+    pub fn start(&mut self, time: Instant) -> bool {
+        if self.config.duration == 0 {
+            return false;
+        }
+
+        self.started = Some(time);
+        self.last_report = None;
+        self.failing = None;
+        true
+    }
+
+    pub fn cancel(&mut self) {
+        self.started = None;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// Runs the checks, returning the status to downlink, if any. The countdown ends with the
+    /// first `Complete` or `Aborted` status.
+    pub fn tick(&mut self, time: Instant, checks: CheckResults) -> Option<CountdownStatus> {
+        let started = self.started?;
+
+        self.failing = match (checks.first_failed(&self.config), self.failing) {
+            (Some(check), Some((failing, since))) if check == failing => Some((failing, since)),
+            (Some(check), _) => Some((check, time)),
+            (None, _) => None,
+        };
+
+        let status = match self.failing {
+            Some((check, since)) if time.millis_since(since) > CHECK_GRACE_TIME => CountdownStatus::Aborted(check),
+            _ if time.millis_since(started) >= self.config.duration => CountdownStatus::Complete,
+            _ => CountdownStatus::Running(self.config.duration - time.millis_since(started)),
+        };
+
+        if let CountdownStatus::Running(_) = status {
+            if self.last_report.map(|t| time.millis_since(t) < REPORT_INTERVAL).unwrap_or(false) {
+                return None;
+            }
+        } else {
+            self.started = None;
+        }
+
+        self.last_report = Some(time);
+        Some(status)
+    }
+}
+
+#[cfg(all(test, not(feature = "gcs")))]
+mod tests {
+    use super::*;
+
+    fn checks(sensors: bool, gps_fix: bool) -> CheckResults {
+        CheckResults { sensors, arming: true, gps_fix, flash_space: true }
+    }
+
+    /// Runs a countdown with the given config, with (time in ms, sensors, gps fix) samples, and
+    /// returns the statuses downlinked.
+    fn run(config: CountdownConfig, samples: &[(u64, bool, bool)]) -> std::vec::Vec<(u64, CountdownStatus)> {
+        let mut countdown = Countdown::new(config);
+        assert!(countdown.start(Instant::ZERO));
+
+        samples
+            .iter()
+            .filter_map(|(time, sensors, gps_fix)| {
+                countdown.tick(Instant::from_millis(*time), checks(*sensors, *gps_fix)).map(|status| (*time, status))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn countdown() {
+        let config = CountdownConfig { duration: 2_000, require_gps_fix: true };
+        let no_gps = CountdownConfig { require_gps_fix: false, ..config };
+        let cases: &[(CountdownConfig, &[(u64, bool, bool)], &[(u64, CountdownStatus)])] = &[
+            // Progress is reported once per second until complete
+            (
+                config,
+                &[(0, true, true), (500, true, true), (1_000, true, true), (1_500, true, true), (2_000, true, true), (2_500, true, true)],
+                &[(0, CountdownStatus::Running(2_000)), (1_000, CountdownStatus::Running(1_000)), (2_000, CountdownStatus::Complete)],
+            ),
+            // A check failing for no longer than the grace time doesn't abort
+            (
+                config,
+                &[(0, true, true), (400, false, true), (900, false, true), (1_000, true, true), (2_000, true, true)],
+                &[(0, CountdownStatus::Running(2_000)), (1_000, CountdownStatus::Running(1_000)), (2_000, CountdownStatus::Complete)],
+            ),
+            // A check failing for longer aborts, even after the countdown would have completed
+            (
+                config,
+                &[(0, true, true), (1_600, true, false), (2_101, true, false), (3_000, true, true)],
+                &[
+                    (0, CountdownStatus::Running(2_000)),
+                    (1_600, CountdownStatus::Running(400)),
+                    (2_101, CountdownStatus::Aborted(CountdownCheck::GpsFix)),
+                ],
+            ),
+            // The GPS fix is only checked if required
+            (
+                no_gps,
+                &[(0, true, false), (1_000, true, false), (2_000, true, false)],
+                &[(0, CountdownStatus::Running(2_000)), (1_000, CountdownStatus::Running(1_000)), (2_000, CountdownStatus::Complete)],
+            ),
+        ];
+
+        for (config, samples, expected) in cases {
+            assert_eq!(run(*config, samples), *expected, "{:?}", samples);
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let mut countdown = Countdown::new(CountdownConfig { duration: 0, require_gps_fix: true });
+        assert!(!countdown.start(Instant::ZERO));
+        assert_eq!(countdown.tick(Instant::ZERO, checks(true, true)), None);
+    }
+}
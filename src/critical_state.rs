@@ -0,0 +1,222 @@
+//! Mirror of the flight-critical state in the RTC's backup registers, which keep their contents
+//! across resets. If the FC is reset in flight, e.g. by the watchdog or a brownout during a
+//! deployment, it would otherwise start over in idle mode with a new ground reference, and would
+//! neither deploy the recovery system nor know which charges were already fired.
+//!
+//! The state is written to two alternating slots, each with a sequence number and a checksum, so
+//! a reset in the middle of a write leaves the previous copy intact. Only after a watchdog or
+//! brownout reset is the newest valid copy preferred over the defaults. Any other reset, e.g. via
+//! the reset pin or a debugger on the bench, invalidates the mirror, as do deliberate reboots.
+//!
+//! Brownouts are only told apart from power-on resets with the brownout reset enabled in the
+//! option bytes, which is done at startup (see `program_brownout_level`).
+
+use crc::{Crc, CRC_16_IBM_SDLC};
+use embassy_stm32::pac::{FLASH, RCC};
+use serde::{Deserialize, Serialize};
+
+use defmt::{error, info, warn, Debug2Format, Format};
+
+use shared_types::FlightMode;
+
+use crate::rtc::RealTimeClock;
+
+const X25: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+
+/// Backup registers used per slot, and the first register of the first slot
+const SLOT_REGISTERS: usize = 5;
+const FIRST_REGISTER: usize = 0;
+const SLOT_SIZE: usize = SLOT_REGISTERS * 4;
+/// Identifies the layout of a slot, to be changed whenever `CriticalState` changes
+const LAYOUT_VERSION: u8 = 1;
+
+/// Brownout reset level in the BOR_LEV option bits. Level 2 resets below about 2.5V, leaving
+/// margin for dips of the 3.3V rail during deployments.
+const BOR_LEVEL_2: u32 = 0b01;
+const BOR_LEV_SHIFT: u32 = 2;
+const BOR_LEV_MASK: u32 = 0b11 << BOR_LEV_SHIFT;
+const OPTCR_OPTLOCK: u32 = 1 << 0;
+const OPTCR_OPTSTRT: u32 = 1 << 1;
+/// Key sequence unlocking the option control register
+const OPT_KEYS: [u32; 2] = [0x0819_2a3b, 0x4c5d_6e7f];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum ResetCause {
+    PowerOn,
+    Brownout,
+    Watchdog,
+    Software,
+    Pin,
+}
+
+impl ResetCause {
+    /// Determines the cause of the last reset from the reset flags, and clears them for the next
+    /// one.
+    pub fn read() -> Self {
+        let csr = RCC.csr().read();
+        RCC.csr().modify(|w| w.set_rmvf(true));
+
+        // A power-on reset sets the brownout flag as well.
+        if csr.porrstf() {
+            Self::PowerOn
+        } else if csr.borrstf() {
+            Self::Brownout
+        } else if csr.iwdgrstf() || csr.wwdgrstf() {
+            Self::Watchdog
+        } else if csr.sftrstf() {
+            Self::Software
+        } else {
+            Self::Pin
+        }
+    }
+
+    /// Whether the reset can happen in flight without anyone asking for it, in which case the
+    /// flight is resumed.
+    pub fn is_unintended(&self) -> bool {
+        matches!(self, Self::Watchdog | Self::Brownout)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PowerOn => "power-on",
+            Self::Brownout => "brownout",
+            Self::Watchdog => "watchdog",
+            Self::Software => "software",
+            Self::Pin => "reset pin",
+        }
+    }
+}
+
+/// Programs the brownout reset level into the option bytes, unless already set. The MCU ships with
+/// the brownout reset disabled, in which case a supply dip only resets it once the voltage drops
+/// to the power-on reset threshold, and is then reported as a power-on reset.
+pub fn program_brownout_level() {
+    let level = BOR_LEVEL_2 << BOR_LEV_SHIFT;
+    if FLASH.optcr().read().0 & BOR_LEV_MASK == level {
+        return;
+    }
+
+    info!("Programming brownout reset level.");
+    for key in OPT_KEYS {
+        FLASH.optkeyr().write_value(key);
+    }
+
+    FLASH.optcr().modify(|w| w.0 = (w.0 & !BOR_LEV_MASK) | level);
+    FLASH.optcr().modify(|w| w.0 |= OPTCR_OPTSTRT);
+    while FLASH.sr().read().bsy() {}
+    FLASH.optcr().modify(|w| w.0 |= OPTCR_OPTLOCK);
+
+    if FLASH.optcr().read().0 & BOR_LEV_MASK != level {
+        error!("Failed to program brownout reset level.");
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CriticalState {
+    pub mode: FlightMode,
+    /// Ground reference of the state estimator (m ASL)
+    pub altitude_ground: f32,
+    /// Maximum altitude (m ASL) reached during the flight so far
+    pub max_altitude_asl: f32,
+    /// Whether the drogue and main outputs have been fired
+    pub drogue_fired: bool,
+    pub main_fired: bool,
+}
+
+pub struct CriticalStateMirror {
+    /// Last state written, and the sequence number it was written with
+    last: Option<(CriticalState, u8)>,
+    /// State restored at startup, and the reset it was restored after
+    restored: Option<(CriticalState, ResetCause)>,
+}
+
+impl CriticalStateMirror {
+    pub fn new() -> Self {
+        Self {
+            last: None,
+            restored: None,
+        }
+    }
+
+    /// Returns the mirrored state if it should be used instead of the defaults after the given
+    /// reset, otherwise invalidates it.
+    pub fn restore(&mut self, rtc: &mut RealTimeClock, cause: ResetCause) -> Option<CriticalState> {
+        let newest = [0, 1]
+            .into_iter()
+            .filter_map(|slot| Self::read_slot(rtc, slot))
+            .reduce(|a, b| if b.1.wrapping_sub(a.1) < 128 { b } else { a });
+
+        // Continue the sequence either way, so the next write goes into the older slot.
+        self.last = newest;
+
+        if !cause.is_unintended() {
+            if newest.is_some() {
+                info!("Not restoring state after {} reset.", cause);
+                self.clear(rtc);
+            }
+            return None;
+        }
+
+        let (state, _) = newest?;
+        warn!("Restoring {:?} after {} reset.", Debug2Format(&state), cause);
+        self.restored = Some((state, cause));
+        Some(state)
+    }
+
+    /// Writes the state to the backup registers if it changed.
+    pub fn tick(&mut self, rtc: &mut RealTimeClock, state: CriticalState) {
+        if self.last.map(|(last, _)| last == state).unwrap_or(false) {
+            return;
+        }
+
+        let sequence = self.last.map(|(_, s)| s.wrapping_add(1)).unwrap_or(0);
+        let mut buffer = [0x00; SLOT_SIZE];
+        buffer[0] = LAYOUT_VERSION;
+        buffer[1] = sequence;
+        if postcard::to_slice(&state, &mut buffer[2..(SLOT_SIZE - 2)]).is_err() {
+            return;
+        }
+
+        let crc = X25.checksum(&buffer[..(SLOT_SIZE - 2)]);
+        buffer[SLOT_SIZE - 2] = (crc >> 8) as u8;
+        buffer[SLOT_SIZE - 1] = crc as u8;
+
+        let first = FIRST_REGISTER + (sequence as usize % 2) * SLOT_REGISTERS;
+        for (i, word) in buffer.chunks_exact(4).enumerate() {
+            rtc.write_backup_register(first + i, u32::from_be_bytes([word[0], word[1], word[2], word[3]]));
+        }
+
+        self.last = Some((state, sequence));
+    }
+
+    /// Invalidates both slots, so the state isn't restored after a deliberate reboot.
+    pub fn clear(&mut self, rtc: &mut RealTimeClock) {
+        for register in FIRST_REGISTER..(FIRST_REGISTER + 2 * SLOT_REGISTERS) {
+            rtc.write_backup_register(register, 0);
+        }
+
+        info!("Critical state mirror cleared.");
+        self.last = None;
+    }
+
+    fn read_slot(rtc: &RealTimeClock, slot: usize) -> Option<(CriticalState, u8)> {
+        let mut buffer = [0x00; SLOT_SIZE];
+        for (i, word) in buffer.chunks_exact_mut(4).enumerate() {
+            let value = rtc.read_backup_register(FIRST_REGISTER + slot * SLOT_REGISTERS + i)?;
+            word.copy_from_slice(&value.to_be_bytes());
+        }
+
+        let (data, crc) = buffer.split_at(SLOT_SIZE - 2);
+        if u16::from_be_bytes([crc[0], crc[1]]) != X25.checksum(data) || data[0] != LAYOUT_VERSION {
+            return None;
+        }
+
+        let state = postcard::from_bytes(&data[2..]).ok()?;
+        Some((state, data[1]))
+    }
+
+    /// State restored at startup, and the reset it was restored after
+    pub fn restored(&self) -> Option<(CriticalState, ResetCause)> {
+        self.restored
+    }
+}
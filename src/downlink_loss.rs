@@ -0,0 +1,75 @@
+//! Loss statistics for the downlink, based on the per-kind sequence numbers of received packets
+//! (see `lora_packet.rs`). Since the FC only counts messages it actually sends, a gap in the
+//! sequence numbers means packets were lost, while a kind that isn't received at all simply
+//! wasn't sent. Gaps are reported to the host as events (see `events.rs`), so the ground
+//! software can tell a lossy link from a quiet vehicle and interpolate across them.
+
+use crate::lora_packet::DownlinkKind;
+
+/// Apparent gaps of this many packets or more are taken as a restart of the sequence, e.g. after
+/// the FC rebooted, rather than as lost packets.
+const MAX_GAP: u8 = 128;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KindStats {
+    /// Sequence number expected next, and the vehicle time (ms) of the last received message
+    next: Option<(u8, u32)>,
+    pub received: u32,
+    pub lost: u32,
+}
+
+impl KindStats {
+    /// Fraction of packets lost
+    pub fn loss(&self) -> f32 {
+        let total = self.received + self.lost;
+        if total == 0 {
+            0.0
+        } else {
+            self.lost as f32 / total as f32
+        }
+    }
+}
+
+/// A run of lost packets of one kind
+#[derive(Clone, Copy, Debug)]
+pub struct Gap {
+    pub kind: DownlinkKind,
+    pub lost: u32,
+    /// Vehicle time (ms) of the messages before and after the gap
+    pub since: u32,
+    pub until: u32,
+}
+
+pub struct LossMonitor {
+    stats: [KindStats; DownlinkKind::ALL.len()],
+}
+
+impl LossMonitor {
+    pub fn new() -> Self {
+        Self {
+            stats: [KindStats::default(); DownlinkKind::ALL.len()],
+        }
+    }
+
+    /// Takes the kind, sequence number and vehicle time (ms) of a received message. Returns the
+    /// gap before it, if any packets were lost.
+    pub fn tick(&mut self, kind: DownlinkKind, sequence_number: u8, time: u32) -> Option<Gap> {
+        let stats = &mut self.stats[kind.index()];
+        let previous = stats.next.replace((sequence_number.wrapping_add(1), time));
+        stats.received += 1;
+
+        // A vehicle time going backwards also means the FC rebooted.
+        let (expected, since) = previous.filter(|(_, since)| *since <= time)?;
+        let lost = sequence_number.wrapping_sub(expected);
+        if lost == 0 || lost >= MAX_GAP {
+            return None;
+        }
+
+        stats.lost += lost as u32;
+        Some(Gap { kind, lost: lost as u32, since, until: time })
+    }
+
+    pub fn stats(&self, kind: DownlinkKind) -> &KindStats {
+        &self.stats[kind.index()]
+    }
+}
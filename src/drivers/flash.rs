@@ -1,3 +1,8 @@
+//! Driver for SPI NOR flash chips. The part is identified by its JEDEC ID, and its geometry is
+//! passed on to the flash storage (see `flash.rs`), so W25Q and MT25Q parts from 16 to 64MB can
+//! be used interchangeably. Parts larger than 16MB are accessed with 4-byte address commands,
+//! smaller ones with 3-byte addresses.
+
 use embassy_time::Instant;
 use heapless::Vec;
 
@@ -5,74 +10,154 @@ use embedded_hal_async::spi::SpiDevice;
 
 use defmt::*;
 
+use shared_types::FLASH_SIZE;
+
 use crate::flash::FlashError;
 
-pub struct W25Q<SPI> {
+const PAGE_SIZE: usize = 256;
+const SECTOR_SIZE: u32 = 4096;
+/// Opcode, 4-byte address and one page of data.
+const MAX_COMMAND_SIZE: usize = 1 + 4 + PAGE_SIZE;
+
+/// Size and erase/program units of a flash chip (bytes)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct FlashGeometry {
+    pub size: u32,
+    /// Smallest erasable unit
+    pub sector_size: u32,
+    /// Largest unit programmable at once, writes must not cross its boundaries
+    pub page_size: u32,
+}
+
+/// NOR flash chip, as used by the flash storage. Reads, writes and erases have to be issued
+/// while the chip isn't busy with a previous write or erase.
+#[allow(async_fn_in_trait)]
+pub trait NorFlash {
+    type Error: core::fmt::Debug;
+
+    fn geometry(&self) -> FlashGeometry;
+    async fn is_busy(&mut self) -> bool;
+    async fn read(&mut self, address: u32, len: u32) -> Result<Vec<u8, 256>, FlashError<Self::Error>>;
+    async fn write(&mut self, address: usize, data: &[u8]) -> Result<(), FlashError<Self::Error>>;
+    async fn erase_sector(&mut self, address: u32) -> Result<(), FlashError<Self::Error>>;
+}
+
+struct Part {
+    name: &'static str,
+    manufacturer: u8,
+    device: u16,
+    size_mbit: u32,
+}
+
+const PARTS: [Part; 9] = [
+    Part { name: "W25Q128JV-IQ", manufacturer: 0xef, device: 0x4018, size_mbit: 128 },
+    Part { name: "W25Q128JV-IM", manufacturer: 0xef, device: 0x7018, size_mbit: 128 },
+    Part { name: "W25Q256JV-IQ", manufacturer: 0xef, device: 0x4019, size_mbit: 256 },
+    Part { name: "W25Q256JV-IM", manufacturer: 0xef, device: 0x7019, size_mbit: 256 },
+    Part { name: "W25Q512JV-IQ", manufacturer: 0xef, device: 0x4020, size_mbit: 512 },
+    Part { name: "W25Q512JV-IM", manufacturer: 0xef, device: 0x7020, size_mbit: 512 },
+    Part { name: "MT25QL128", manufacturer: 0x20, device: 0xba18, size_mbit: 128 },
+    Part { name: "MT25QL256", manufacturer: 0x20, device: 0xba19, size_mbit: 256 },
+    Part { name: "MT25QL512", manufacturer: 0x20, device: 0xba20, size_mbit: 512 },
+];
+
+/// Largest size (bytes) that can be addressed with 3 bytes
+const MAX_3_BYTE_ADDRESS_SIZE: u32 = 16 * 1024 * 1024;
+
+pub struct SpiNorFlash<SPI> {
     spi: SPI,
-    size: u32,
+    geometry: FlashGeometry,
+    four_byte_addresses: bool,
 }
 
-impl<SPI: SpiDevice> W25Q<SPI> {
+impl<SPI: SpiDevice> SpiNorFlash<SPI> {
     pub async fn init(spi: SPI) -> Result<Self, FlashError<SPI::Error>> {
-        let mut w25 = Self {
+        let mut flash = Self {
             spi,
-            size: 0,
+            geometry: FlashGeometry {
+                size: FLASH_SIZE,
+                sector_size: SECTOR_SIZE,
+                page_size: PAGE_SIZE as u32,
+            },
+            four_byte_addresses: true,
         };
 
-        let ids = w25.command(W25OpCode::JedecId, &[], 3).await?;
+        let ids = flash.command(OpCode::JedecId, &[], 3).await?;
         let (man_id, dev_id) = (ids[0], u16::from_le_bytes([ids[2], ids[1]]));
-        let (name, size_mbit) = match (man_id, dev_id) {
-            (0xef, 0x4019) => ("W25Q256JV-IQ", 256),
-            (0xef, 0x7019) => ("W25Q256JV-IM", 256),
-            _ => ("unknown", 0),
-        };
-
-        if size_mbit > 0 {
-            info!("{} initialized", name);
-        } else {
-            error!("Failed to initialize flash (0x{:02x}, 0x{:04x}).", man_id, dev_id);
+        match PARTS.iter().find(|p| p.manufacturer == man_id && p.device == dev_id) {
+            Some(part) => {
+                flash.geometry.size = part.size_mbit * 1024 * 1024 / 8;
+                info!("{} initialized ({}MiB)", part.name, flash.geometry.size / 1024 / 1024);
+            },
+            // Assume the part we used to have, so at least the log stays where it was.
+            None => error!("Failed to identify flash (0x{:02x}, 0x{:04x}), assuming {} bytes.", man_id, dev_id, FLASH_SIZE),
         }
 
-        w25.size = size_mbit * 1024 * 1024 / 8;
-
-        Ok(w25)
+        flash.four_byte_addresses = flash.geometry.size > MAX_3_BYTE_ADDRESS_SIZE;
+        Ok(flash)
     }
 
-    async fn command(&mut self, opcode: W25OpCode, params: &[u8], response_len: usize) -> Result<Vec<u8, 256>, FlashError<SPI::Error>> {
-        let mut payload = [&[opcode as u8], params, &[0x00].repeat(response_len)].concat();
+    async fn command(&mut self, opcode: OpCode, params: &[u8], response_len: usize) -> Result<Vec<u8, 256>, FlashError<SPI::Error>> {
+        let len = 1 + params.len() + response_len;
+        if len > MAX_COMMAND_SIZE {
+            return Err(FlashError::Overflow);
+        }
 
-        self.spi.transfer_in_place(&mut payload).await?;
+        let mut payload = [0x00; MAX_COMMAND_SIZE];
+        payload[0] = opcode as u8;
+        payload[1..(1 + params.len())].copy_from_slice(params);
+        self.spi.transfer_in_place(&mut payload[..len]).await?;
 
-        Ok(Vec::from_slice(&payload[(1 + params.len())..]).unwrap_or_default())
+        Ok(Vec::from_slice(&payload[(1 + params.len())..len]).unwrap_or_default())
     }
 
-    pub fn size(&self) -> u32 {
-        self.size
+    /// Address bytes in the width used for this part
+    fn address(&self, address: u32) -> Vec<u8, 4> {
+        let bytes = address.to_be_bytes();
+        let bytes = if self.four_byte_addresses { &bytes[..] } else { &bytes[1..] };
+        Vec::from_slice(bytes).unwrap_or_default()
     }
+}
+
+impl<SPI: SpiDevice> NorFlash for SpiNorFlash<SPI> {
+    type Error = SPI::Error;
 
-    // TODO: this should not have to be public
-    pub async fn is_busy(&mut self) -> bool {
-        let response = self.command(W25OpCode::ReadStatusRegister1, &[], 1).await;
+    fn geometry(&self) -> FlashGeometry {
+        self.geometry
+    }
+
+    async fn is_busy(&mut self) -> bool {
+        let response = self.command(OpCode::ReadStatusRegister1, &[], 1).await;
         response.map(|resp| resp[0] & 0x01 > 0).unwrap_or(true)
     }
 
-    pub async fn read(&mut self, address: u32, len: u32) -> Result<Vec<u8, 256>, FlashError<SPI::Error>> {
+    async fn read(&mut self, address: u32, len: u32) -> Result<Vec<u8, 256>, FlashError<SPI::Error>> {
         if self.is_busy().await {
             return Err(FlashError::Busy);
         }
 
-        let address = address.to_be_bytes();
-        self.command(W25OpCode::ReadData4BAddress, &address, len as usize).await
+        let opcode = if self.four_byte_addresses { OpCode::ReadData4BAddress } else { OpCode::ReadData };
+        let address = self.address(address);
+        self.command(opcode, &address, len as usize).await
     }
 
-    pub async fn write(&mut self, address: usize, data: &[u8]) -> Result<(), FlashError<SPI::Error>> {
+    async fn write(&mut self, address: usize, data: &[u8]) -> Result<(), FlashError<SPI::Error>> {
+        // Page programs wrap around at the end of the page instead of continuing in the next one.
+        let page_size = self.geometry.page_size as usize;
+        if address % page_size + data.len() > page_size {
+            return Err(FlashError::Overflow);
+        }
+
         if self.is_busy().await {
             return Err(FlashError::Busy);
         }
 
-        self.command(W25OpCode::WriteEnable, &[], 0).await?;
-        let cmd = [&address.to_be_bytes(), data].concat();
-        self.command(W25OpCode::PageProgram4BAddress, &cmd, 0).await?;
+        self.command(OpCode::WriteEnable, &[], 0).await?;
+        let opcode = if self.four_byte_addresses { OpCode::PageProgram4BAddress } else { OpCode::PageProgram };
+        let mut cmd: Vec<u8, { 4 + PAGE_SIZE }> = Vec::new();
+        let _ = cmd.extend_from_slice(&self.address(address as u32));
+        cmd.extend_from_slice(data).map_err(|_| FlashError::Overflow)?;
+        self.command(opcode, &cmd, 0).await?;
 
         let t = Instant::now();
         while t.elapsed().as_micros() < 1000 && self.is_busy().await {}
@@ -80,16 +165,19 @@ impl<SPI: SpiDevice> W25Q<SPI> {
         Ok(())
     }
 
-    pub async fn erase_sector(&mut self, address: u32) -> Result<(), FlashError<SPI::Error>> {
-        self.command(W25OpCode::WriteEnable, &[], 0).await?;
-        self.command(W25OpCode::SectorErase4KB4BAddress, &address.to_be_bytes(), 0).await?;
+    async fn erase_sector(&mut self, address: u32) -> Result<(), FlashError<SPI::Error>> {
+        let opcode = if self.four_byte_addresses { OpCode::SectorErase4KB4BAddress } else { OpCode::SectorErase4KB };
+        let address = self.address(address);
+        self.command(OpCode::WriteEnable, &[], 0).await?;
+        self.command(opcode, &address, 0).await?;
         Ok(())
     }
 }
 
+/// Opcodes shared by the W25Q and MT25Q series
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
-enum W25OpCode {
+enum OpCode {
     WriteEnable = 0x06,
     VolatileSrWriteEnable = 0x50,
     WriteDisable = 0x04,
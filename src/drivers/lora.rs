@@ -9,11 +9,12 @@ use heapless::Vec;
 use embedded_hal::digital::InputPin;
 use embedded_hal_async::spi::SpiDevice;
 
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 
 use shared_types::*;
 
 use crate::lora::RadioError;
+use crate::radio_diagnostics::RadioDiagnostics;
 
 // both RX and TX get half of the available 256 bytes
 const TX_BASE_ADDRESS: u8 = 0;
@@ -23,10 +24,14 @@ pub const TRANSMISSION_TIMEOUT_MS: u32 = 18;
 #[cfg(feature="gcs")]
 pub const FC_GCS_TIME_OFFSET_MS: i64 = 16;
 
-const DOWNLINK_PACKET_SIZE: u8 = 26;
+pub const DOWNLINK_PACKET_SIZE: u8 = 27;
 const UPLINK_PACKET_SIZE: u8 = 16;
+/// Downlink packets retransmitted by a relay, prefixed by the relay header (see `lora_packet.rs`)
+pub const RELAY_PACKET_SIZE: u8 = DOWNLINK_PACKET_SIZE + 1;
 
-const TX_PACKET_SIZE: u8 = if cfg!(feature = "gcs") {
+pub const TX_PACKET_SIZE: u8 = if cfg!(feature = "relay") {
+    RELAY_PACKET_SIZE
+} else if cfg!(feature = "gcs") {
     UPLINK_PACKET_SIZE
 } else {
     DOWNLINK_PACKET_SIZE
@@ -38,17 +43,53 @@ const RX_PACKET_SIZE: u8 = if cfg!(feature = "gcs") {
     UPLINK_PACKET_SIZE
 };
 
+/// Opcode, parameters and response of a single SPI command.
+const MAX_COMMAND_SIZE: usize = 64;
+
+/// Time (us) a command waits for the busy line to go low. During normal operation, mode changes
+/// keep it high the longest, for well below this.
+const BUSY_TIMEOUT_US: u64 = 500;
+/// Interval (us) at which the busy line is polled while waiting
+const BUSY_POLL_INTERVAL_US: u64 = 10;
+
 const RAMP_TIME: LLCC68RampTime = LLCC68RampTime::R800U;
 
+/// Sync word the LLCC68 starts up with, used by most private LoRa networks
+pub const DEFAULT_SYNC_WORD: u8 = 0x12;
+/// Sync word of public LoRaWAN networks
+pub const LORAWAN_SYNC_WORD: u8 = 0x34;
+
+const SYNC_WORD_REGISTER: u16 = 0x0740;
+/// Register holding the IQ polarity workaround (chapter 15.4, p. 98)
+const IQ_POLARITY_REGISTER: u16 = 0x0736;
+const RX_GAIN_REGISTER: u16 = 0x08ac;
+const TX_CLAMP_REGISTER: u16 = 0x08d8;
+
 pub struct LLCC68<SPI, IRQ, BUSY> {
     spi: SPI,
     irq: IRQ,
     busy: BUSY,
     ignore_busy: bool,
+    /// Whether the busy line timed out while being ignored, in which case commands don't wait for
+    /// it until it goes low again
+    busy_stuck: bool,
+    /// Number of commands that timed out waiting for the busy line
+    pub busy_timeouts: u32,
     frequency: u32,
+    rx_packet_size: u8,
+    sync_word: u8,
+    invert_iq: bool,
     pub rssi: u8,
     pub rssi_signal: u8,
     pub snr: i8,
+    /// Frequency error of the last received packet (Hz), positive if the transmitter's
+    /// frequency was higher than ours.
+    pub frequency_error: i32,
+    /// Start of the current transmission, for measuring the time on air
+    tx_started: Option<Instant>,
+    /// Contents of the last packet that failed the CRC check, kept for raw packet capture
+    #[cfg(feature = "gcs")]
+    pub corrupted_packet: Option<Vec<u8, 64>>,
 }
 
 impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> LLCC68<SPI, IRQ, BUSY> {
@@ -58,11 +99,20 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> LLCC68<SPI, IRQ, BUSY> {
             irq,
             busy,
             frequency,
+            rx_packet_size: RX_PACKET_SIZE,
+            sync_word: DEFAULT_SYNC_WORD,
+            invert_iq: false,
             ignore_busy: true,
+            busy_stuck: false,
+            busy_timeouts: 0,
             // TODO
             rssi: 255,
             rssi_signal: 255,
             snr: 0,
+            frequency_error: 0,
+            tx_started: None,
+            #[cfg(feature = "gcs")]
+            corrupted_packet: None,
         };
 
         llcc68.configure().await?;
@@ -101,7 +151,7 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> LLCC68<SPI, IRQ, BUSY> {
 
         self.command(LLCC68OpCode::SetDIO2AsRfSwitchCtrl, &[1], 0).await?;
         //self.command(LLCC68OpCode::CalibrateImage, &[0xd7, 0xdb], 0)?;
-        self.write_register(0x08ac, 0x96).await?; // boost rx gain (9.6, p. 53)
+        self.write_register(RX_GAIN_REGISTER, 0x96).await?; // boost rx gain (9.6, p. 53)
         self.set_packet_type(LLCC68PacketType::LoRa).await?;
         self.set_lora_mod_params(
             LLCC68LoRaModulationBandwidth::Bw500,
@@ -110,12 +160,13 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> LLCC68<SPI, IRQ, BUSY> {
             false,
         ).await?;
         self.set_frequency(self.frequency).await?;
+        self.set_sync_word(self.sync_word).await?;
+        self.set_iq_inversion(self.invert_iq).await?;
         self.set_buffer_base_addresses(TX_BASE_ADDRESS, RX_BASE_ADDRESS).await?;
         self.set_output_power(TransmitPower::P14dBm).await?;
-        self.set_dio1_interrupt(
-            (LLCC68Interrupt::RxDone as u16) | (LLCC68Interrupt::CrcErr as u16),
-            LLCC68Interrupt::RxDone as u16,
-        ).await?;
+        let dio1_mask =
+            (LLCC68Interrupt::RxDone as u16) | (LLCC68Interrupt::TxDone as u16) | (LLCC68Interrupt::Timeout as u16);
+        self.set_dio1_interrupt(dio1_mask | (LLCC68Interrupt::CrcErr as u16), dio1_mask).await?;
         self.switch_to_rx().await?;
 
         // After the configuration we can treat the busy line a bit more relaxed,
@@ -132,14 +183,62 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> LLCC68<SPI, IRQ, BUSY> {
         params: &[u8],
         response_len: usize,
     ) -> Result<Vec<u8, 64>, RadioError<SPI::Error>> {
-        if self.busy.is_high().unwrap_or(false) && !self.ignore_busy {
+        if !self.wait_until_ready().await && !self.ignore_busy {
             return Err(RadioError::Busy);
         }
 
-        let mut payload = [&[opcode as u8], params, &[0x00].repeat(response_len)].concat();
-        self.spi.transfer_in_place(&mut payload).await?;
+        let len = 1 + params.len() + response_len;
+        if len > MAX_COMMAND_SIZE {
+            return Err(RadioError::Overflow);
+        }
+
+        let mut payload = [0x00; MAX_COMMAND_SIZE];
+        payload[0] = opcode as u8;
+        payload[1..(1 + params.len())].copy_from_slice(params);
+        self.spi.transfer_in_place(&mut payload[..len]).await?;
 
-        Ok(Vec::from_slice(&payload[(1 + params.len())..]).unwrap_or_default())
+        Ok(Vec::from_slice(&payload[(1 + params.len())..len]).unwrap_or_default())
+    }
+
+    /// Waits for the busy line to go low, i.e. for the previous command to finish, yielding to
+    /// other tasks in the meantime. Returns false on timeout.
+    async fn wait_until_ready(&mut self) -> bool {
+        let busy = |trx: &mut Self| trx.busy.is_high().unwrap_or(false);
+        if !busy(self) {
+            self.busy_stuck = false;
+            return true;
+        }
+
+        // Don't wait on every single command if the line is stuck high, see `configure`.
+        if self.busy_stuck && self.ignore_busy {
+            return false;
+        }
+
+        let start = Instant::now();
+        while busy(self) {
+            if start.elapsed() > Duration::from_micros(BUSY_TIMEOUT_US) {
+                self.busy_stuck = self.ignore_busy;
+                self.busy_timeouts += 1;
+                return false;
+            }
+            Timer::after(Duration::from_micros(BUSY_POLL_INTERVAL_US)).await;
+        }
+
+        true
+    }
+
+    /// Reads the frequency error indicator. This is not documented in the LLCC68 datasheet, but
+    /// works like on the rest of the SX126x family: a 20-bit signed value, scaled according to the
+    /// bandwidth (see RadioLib's `SX126x::getFrequencyError`).
+    async fn read_frequency_error(&mut self) -> Result<i32, RadioError<SPI::Error>> {
+        const FREQ_ERROR_REGISTER: u16 = 0x076b;
+        const BANDWIDTH_KHZ: i64 = 500;
+
+        let response = self.command(LLCC68OpCode::ReadRegister, &FREQ_ERROR_REGISTER.to_be_bytes(), 4).await?;
+        let raw = u32::from_be_bytes([0, response[1], response[2], response[3]]);
+        let efe = ((raw << 12) as i32) >> 12; // sign-extend
+
+        Ok(((efe as i64) * 155 * BANDWIDTH_KHZ / 160_000) as i32)
     }
 
     async fn read_register(&mut self, address: u16) -> Result<u8, RadioError<SPI::Error>> {
@@ -256,18 +355,51 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> LLCC68<SPI, IRQ, BUSY> {
         self.command(LLCC68OpCode::SetTxParams, &[22, RAMP_TIME as u8], 0).await?;
 
         // workaround to prevent overly protective power clamping (chapter 15.2, p. 97)
-        let tx_clamp_config = self.read_register(0x08d8).await?;
-        self.write_register(0x08d8, tx_clamp_config | 0x1e).await?;
+        let tx_clamp_config = self.read_register(TX_CLAMP_REGISTER).await?;
+        self.write_register(TX_CLAMP_REGISTER, tx_clamp_config | 0x1e).await?;
 
         Ok(())
     }
 
     pub async fn switch_to_rx(&mut self) -> Result<(), RadioError<SPI::Error>> {
-        self.set_lora_packet_params(12, true, RX_PACKET_SIZE, true, false).await?;
+        self.set_lora_packet_params(12, true, self.rx_packet_size, true, self.invert_iq).await?;
         self.set_rx_mode(0).await?;
         Ok(())
     }
 
+    /// Sets the LoRa sync word. Packets with a different one are ignored by the receiver, which
+    /// keeps other LoRa networks on the same channels out. Takes effect on the next switch to RX
+    /// or TX mode.
+    pub async fn set_sync_word(&mut self, sync_word: u8) -> Result<(), RadioError<SPI::Error>> {
+        // Each nibble goes into its own register, with the lower nibbles fixed to 0x4 (see
+        // RadioLib's `SX126x::setSyncWord`).
+        let msb = (sync_word & 0xf0) | 0x04;
+        let lsb = (sync_word << 4) | 0x04;
+        self.write_register(SYNC_WORD_REGISTER, msb).await?;
+        self.write_register(SYNC_WORD_REGISTER + 1, lsb).await?;
+        self.sync_word = sync_word;
+        Ok(())
+    }
+
+    pub fn sync_word(&self) -> u8 {
+        self.sync_word
+    }
+
+    /// Selects inverted IQ for both transmitting and receiving, which, like the sync word, has to
+    /// match on both ends. Takes effect on the next switch to RX or TX mode.
+    pub async fn set_iq_inversion(&mut self, invert_iq: bool) -> Result<(), RadioError<SPI::Error>> {
+        // Without this workaround, packets with inverted IQ may be lost (chapter 15.4, p. 98).
+        let reg = self.read_register(IQ_POLARITY_REGISTER).await?;
+        let reg = if invert_iq { reg & 0xfb } else { reg | 0x04 };
+        self.write_register(IQ_POLARITY_REGISTER, reg).await?;
+        self.invert_iq = invert_iq;
+        Ok(())
+    }
+
+    pub fn iq_inversion(&self) -> bool {
+        self.invert_iq
+    }
+
     pub async fn set_frequency(&mut self, frequency: u32) -> Result<(), RadioError<SPI::Error>> {
         const XTAL_FREQ: u32 = 32_000_000;
         const PLL_STEP_SHIFT_AMOUNT: u32 = 14;
@@ -299,14 +431,47 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> LLCC68<SPI, IRQ, BUSY> {
             self.write_register(0x0889, reg & 0xfb).await?;
         }
 
-        self.set_lora_packet_params(12, true, TX_PACKET_SIZE, true, false).await?;
+        self.set_lora_packet_params(12, true, TX_PACKET_SIZE, true, self.invert_iq).await?;
         const CMD_SIZE: usize = (TX_PACKET_SIZE as usize) + 1;
         let mut params: [u8; CMD_SIZE] = [0x00; CMD_SIZE];
         params[0] = TX_BASE_ADDRESS;
         params[1..(msg.len()+1)].copy_from_slice(&msg);
         self.command(LLCC68OpCode::WriteBuffer, &params, 0).await?;
         self.set_tx_mode(TRANSMISSION_TIMEOUT_MS * 1000).await?;
+        self.tx_started = Some(Instant::now());
+
+        Ok(())
+    }
 
+    /// Checks whether the current transmission has finished, returning the time on air (us) once
+    /// the TxDone interrupt is seen. Since this is polled, the time includes the polling delay.
+    /// The interrupts are cleared, so they aren't mistaken for a received packet afterwards.
+    pub async fn transmission_done(&mut self) -> Result<Option<u32>, RadioError<SPI::Error>> {
+        if !self.irq.is_high().unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let irq_status = self.irq_status().await?;
+        self.clear_interrupts().await?;
+
+        let time_on_air = self.tx_started.take().map(|t| t.elapsed().as_micros() as u32).unwrap_or_default();
+        if irq_status & (LLCC68Interrupt::TxDone as u16) > 0 {
+            Ok(Some(time_on_air))
+        } else if irq_status & (LLCC68Interrupt::Timeout as u16) > 0 {
+            Err(RadioError::Timeout)
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn irq_status(&mut self) -> Result<u16, RadioError<SPI::Error>> {
+        let response = self.command(LLCC68OpCode::GetIrqStatus, &[], 3).await?;
+        Ok(u16::from_be_bytes([response[1], response[2]]))
+    }
+
+    /// Clears all interrupts, e.g. after a transmission ended without TxDone being seen.
+    pub async fn clear_interrupts(&mut self) -> Result<(), RadioError<SPI::Error>> {
+        self.command(LLCC68OpCode::ClearIrqStatus, &[0xff, 0xff], 0).await?;
         Ok(())
     }
 
@@ -318,12 +483,9 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> LLCC68<SPI, IRQ, BUSY> {
 
         // Get IRQ status to allow checking for CrcErr
         #[cfg(feature = "gcs")]
-        let irq_status = self
-            .command(LLCC68OpCode::GetIrqStatus, &[], 3).await
-            .map(|r| ((r[1] as u16) << 8) + (r[2] as u16))
-            .unwrap_or(0);
+        let irq_status = self.irq_status().await.unwrap_or(0);
 
-        self.command(LLCC68OpCode::ClearIrqStatus, &[0xff, 0xff], 0).await?;
+        self.clear_interrupts().await?;
 
         // Get the packet stats before the data, since this is useful even if the data is corrupted.
         // Sometimes the response data is shifted to the right for some reason, which is why we read
@@ -334,6 +496,7 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> LLCC68<SPI, IRQ, BUSY> {
         self.rssi = packet_status[1+offset];
         self.rssi_signal = packet_status[3+offset];
         self.snr = packet_status[2+offset] as i8;
+        self.frequency_error = self.read_frequency_error().await?;
 
         // Abort in case of a CRC mismatch
         // Sometimes this seems to trigger unnecessarily, especially for uplink
@@ -341,27 +504,72 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> LLCC68<SPI, IRQ, BUSY> {
         // CRC mismatches for uplink messages.
         #[cfg(feature = "gcs")]
         if irq_status & (LLCC68Interrupt::CrcErr as u16) > 0 {
+            self.corrupted_packet = self.read_rx_buffer().await.ok();
             return Err(RadioError::Crc);
         }
 
+        let buffer = self.read_rx_buffer().await?;
+        self.set_rx_mode(0).await?;
+
+        if buffer.len() < UPLINK_PACKET_SIZE as usize {
+            return Ok(None);
+        }
+
+        Ok(Some(buffer))
+    }
+
+    /// Reads the last received packet, prefixed by the status byte.
+    async fn read_rx_buffer(&mut self) -> Result<Vec<u8, 64>, RadioError<SPI::Error>> {
         // Get RX buffer status (this contains the length of the received data)
         let rx_buffer_status = self.command(LLCC68OpCode::GetRxBufferStatus, &[], 3).await?;
-        let len = u8::min(rx_buffer_status[1], RX_PACKET_SIZE);
+        let len = u8::min(rx_buffer_status[1], self.rx_packet_size);
 
         // Read received data
-        let buffer = self.command(
+        self.command(
             LLCC68OpCode::ReadBuffer,
             &[rx_buffer_status[2]],
             len as usize + 1,
-        ).await?;
+        ).await
+    }
 
-        self.set_rx_mode(0).await?;
+    /// Reads the current signal strength while in RX mode, in the same unit as `rssi` (-dBm * 2).
+    pub async fn instantaneous_rssi(&mut self) -> Result<u8, RadioError<SPI::Error>> {
+        Ok(self.command(LLCC68OpCode::GetRssiInst, &[], 2).await?[1])
+    }
 
-        if buffer.len() < UPLINK_PACKET_SIZE as usize {
-            return Ok(None);
-        }
+    /// Changes the expected length of received packets, taking effect on the next switch to RX
+    /// mode. Since packets use an implicit header, this has to match the transmitter.
+    #[cfg(all(feature = "gcs", not(feature = "relay")))]
+    pub fn set_rx_packet_size(&mut self, size: u8) {
+        self.rx_packet_size = size;
+    }
 
-        Ok(Some(buffer))
+    /// Reads the status, device errors, packet statistics and the registers changed during
+    /// configuration, see `radio_diagnostics.rs`.
+    pub async fn diagnostics(&mut self) -> Result<RadioDiagnostics, RadioError<SPI::Error>> {
+        let status = self.command(LLCC68OpCode::GetStatus, &[], 1).await?[0];
+        let errors = self.command(LLCC68OpCode::GetDeviceErrors, &[], 3).await?;
+        let stats = self.command(LLCC68OpCode::GetStats, &[], 7).await?;
+
+        Ok(RadioDiagnostics {
+            status,
+            device_errors: u16::from_be_bytes([errors[1], errors[2]]),
+            packets_received: u16::from_be_bytes([stats[1], stats[2]]),
+            crc_errors: u16::from_be_bytes([stats[3], stats[4]]),
+            header_errors: u16::from_be_bytes([stats[5], stats[6]]),
+            sync_word: [
+                self.read_register(SYNC_WORD_REGISTER).await?,
+                self.read_register(SYNC_WORD_REGISTER + 1).await?,
+            ],
+            rx_gain: self.read_register(RX_GAIN_REGISTER).await?,
+            tx_clamp: self.read_register(TX_CLAMP_REGISTER).await?,
+            iq_polarity: self.read_register(IQ_POLARITY_REGISTER).await?,
+        })
+    }
+
+    /// Frequency (Hz) the transceiver is currently tuned to
+    pub fn frequency(&self) -> u32 {
+        self.frequency
     }
 }
 
@@ -1,13 +1,26 @@
 pub mod accelerometer;
+pub mod adc;
 pub mod baro;
 pub mod compass;
 pub mod gps;
+#[cfg(feature = "loadcell")]
+pub mod hx711;
 pub mod imu;
+#[cfg(feature = "thermocouple")]
+pub mod max31855;
+#[cfg(feature = "mmc5983")]
+pub mod mmc5983;
 pub mod power;
 
 pub use accelerometer::*;
 pub use baro::*;
 pub use compass::*;
 pub use gps::*;
+#[cfg(feature = "loadcell")]
+pub use hx711::*;
 pub use imu::*;
+#[cfg(feature = "thermocouple")]
+pub use max31855::*;
+#[cfg(feature = "mmc5983")]
+pub use mmc5983::*;
 pub use power::*;
@@ -4,6 +4,8 @@ use nalgebra::Vector3;
 
 use defmt::*;
 
+use crate::errors::{report, ErrorKind, Subsystem};
+
 const G_TO_MS2: f32 = 9.80665;
 
 pub struct H3LIS331DL<SPI: SpiDevice<u8>> {
@@ -69,6 +71,7 @@ impl<SPI: SpiDevice<u8>> H3LIS331DL<SPI> {
 
     pub async fn tick(&mut self) {
         if let Err(_e) = self.read_sensor_data().await {
+            report(Subsystem::Sensors, ErrorKind::Bus, "reading accelerometer");
             self.acc = None;
         }
     }
@@ -80,6 +83,11 @@ impl<SPI: SpiDevice<u8>> H3LIS331DL<SPI> {
     pub fn accelerometer(&self) -> Option<Vector3<f32>> {
         self.acc.map(|acc| acc - self.offset)
     }
+
+    /// Checks that the sensor still responds with the expected ID.
+    pub async fn self_test(&mut self) -> bool {
+        self.read_u8(H3LIS331DLRegister::WhoAmI).await.map(|id| id == 0x32).unwrap_or(false)
+    }
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -0,0 +1,119 @@
+//! Helpers for calibrated, oversampled readings of the ADC. The STM32F401 has no hardware
+//! oversampling, so each channel is converted once per tick and averaged over `OVERSAMPLING`
+//! ticks instead, keeping the time spent converting per tick the same. Readings are corrected
+//! for the actual supply voltage using the factory calibration of the internal reference, and the
+//! internal temperature sensor uses its factory calibration points.
+
+/// Number of samples averaged for each reading
+pub const OVERSAMPLING: u32 = 16;
+
+const FULL_SCALE: f32 = 4095.0;
+
+// Factory calibration values in system memory, see datasheet sections 6.3.22 and 6.3.24
+const VREFINT_CAL: *const u16 = 0x1fff_7a2a as *const u16;
+const TS_CAL1: *const u16 = 0x1fff_7a2c as *const u16;
+const TS_CAL2: *const u16 = 0x1fff_7a2e as *const u16;
+/// Supply voltage (mV) the calibration values were measured at
+const CAL_VDDA: f32 = 3300.0;
+/// Temperatures (C) the temperature sensor calibration values were measured at
+const TS_CAL1_TEMP: f32 = 30.0;
+const TS_CAL2_TEMP: f32 = 110.0;
+/// Typical internal reference voltage (mV), used if the calibration value looks invalid
+const VREFINT_TYPICAL: f32 = 1210.0;
+
+/// Linear correction from the voltage at the pin (mV) to the measured quantity, e.g. to undo a
+/// voltage divider.
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelCalibration {
+    pub scale: f32,
+    pub offset: f32,
+}
+
+impl ChannelCalibration {
+    pub const fn new(scale: f32, offset: f32) -> Self {
+        Self { scale, offset }
+    }
+
+    pub fn apply(&self, millivolts: f32) -> f32 {
+        millivolts * self.scale + self.offset
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FactoryCalibration {
+    vrefint: u16,
+    ts_cal1: u16,
+    ts_cal2: u16,
+}
+
+impl FactoryCalibration {
+    pub fn read() -> Self {
+        // Safety: these addresses are in read-only system memory, present on every STM32F401.
+        unsafe {
+            Self {
+                vrefint: core::ptr::read_volatile(VREFINT_CAL),
+                ts_cal1: core::ptr::read_volatile(TS_CAL1),
+                ts_cal2: core::ptr::read_volatile(TS_CAL2),
+            }
+        }
+    }
+
+    fn valid(value: u16) -> bool {
+        value != 0x0000 && value != 0xffff
+    }
+
+    /// Actual analog supply voltage (mV), given a reading of the internal reference.
+    pub fn vdda(&self, vrefint_sample: f32) -> f32 {
+        if Self::valid(self.vrefint) {
+            CAL_VDDA * (self.vrefint as f32) / vrefint_sample
+        } else {
+            VREFINT_TYPICAL * FULL_SCALE / vrefint_sample
+        }
+    }
+
+    pub fn millivolts(&self, sample: f32, vdda: f32) -> f32 {
+        sample * vdda / FULL_SCALE
+    }
+
+    /// Temperature (C) of the internal sensor, given a reading of it.
+    pub fn celsius(&self, sample: f32, vdda: f32) -> Option<f32> {
+        if !Self::valid(self.ts_cal1) || !Self::valid(self.ts_cal2) || self.ts_cal1 == self.ts_cal2 {
+            return None;
+        }
+
+        // The calibration values were measured at a different supply voltage.
+        let sample = sample * vdda / CAL_VDDA;
+        let slope = (TS_CAL2_TEMP - TS_CAL1_TEMP) / (self.ts_cal2 as f32 - self.ts_cal1 as f32);
+        Some((sample - self.ts_cal1 as f32) * slope + TS_CAL1_TEMP)
+    }
+}
+
+/// Averages the samples of a single channel.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Oversampled {
+    sum: u32,
+    count: u32,
+    value: Option<f32>,
+}
+
+impl Oversampled {
+    /// Adds a sample, returning true if this completed a set and a new average is available.
+    pub fn add(&mut self, sample: u16) -> bool {
+        self.sum += sample as u32;
+        self.count += 1;
+
+        if self.count == OVERSAMPLING {
+            self.value = Some(self.sum as f32 / OVERSAMPLING as f32);
+            self.sum = 0;
+            self.count = 0;
+            return true;
+        }
+
+        false
+    }
+
+    /// Average of the last complete set of samples
+    pub fn value(&self) -> Option<f32> {
+        self.value
+    }
+}
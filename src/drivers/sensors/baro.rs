@@ -1,4 +1,4 @@
-use heapless::{Vec, Deque};
+use heapless::Vec;
 
 use embassy_time::{Timer, Duration};
 use embedded_hal_async::spi::SpiDevice;
@@ -7,6 +7,9 @@ use num_traits::float::Float;
 
 use defmt::*;
 
+use crate::errors::{report, ErrorKind, Subsystem};
+use crate::filters::MedianFilter;
+
 const BARO_MEDIAN_FILTER_LENGTH: usize = 20;
 
 struct MS5611CalibrationData {
@@ -45,6 +48,8 @@ pub struct MS5611<SPI: SpiDevice<u8>> {
     raw_pressure: Option<i32>,
     pressure: Option<i32>,
     baro_filter: BaroFilter,
+    /// Correction (hPa) added to pressure readings
+    offset: f32,
 }
 
 impl<SPI: SpiDevice<u8>> MS5611<SPI> {
@@ -58,6 +63,7 @@ impl<SPI: SpiDevice<u8>> MS5611<SPI> {
             raw_pressure: None,
             pressure: None,
             baro_filter: BaroFilter::new(),
+            offset: 0.0,
         };
 
         'outer: for _i in 0..3 { // did you know that rust has loop labels?
@@ -173,6 +179,7 @@ impl<SPI: SpiDevice<u8>> MS5611<SPI> {
 
     pub async fn tick(&mut self) {
         if let Err(_) = self.read_sensor_data().await {
+            report(Subsystem::Sensors, ErrorKind::Bus, "reading barometer");
             self.dt = None;
             self.temp = None;
             self.raw_pressure = None;
@@ -195,14 +202,29 @@ impl<SPI: SpiDevice<u8>> MS5611<SPI> {
         self.temp.map(|t| (t as f32) / 100.0)
     }
 
+    /// Sets a correction (hPa) added to pressure readings.
+    #[cfg_attr(feature = "gcs", allow(dead_code))]
+    pub fn set_offset(&mut self, offset: f32) {
+        self.offset = offset;
+    }
+
     pub fn pressure(&self) -> Option<f32> {
-        self.pressure.map(|p| (p as f32) / 100.0)
+        self.pressure.map(|p| (p as f32) / 100.0 + self.offset)
     }
 
     pub fn altitude(&self) -> Option<f32> {
         self.pressure()
             .map(|p| 44330.769 * (1.0 - (p / 1012.5).powf(0.190223)))
     }
+
+    /// Checks that the calibration data is valid and the last readings are plausible. The MS5611
+    /// has no ID register to query.
+    pub fn self_test(&self) -> bool {
+        let calibrated = self.calibration_data.as_ref().map(|d| d.valid()).unwrap_or(false);
+        let pressure_plausible = self.pressure().map(|p| (300.0..1100.0).contains(&p)).unwrap_or(false);
+        let temperature_plausible = self.temperature().map(|t| (-40.0..85.0).contains(&t)).unwrap_or(false);
+        calibrated && pressure_plausible && temperature_plausible
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -238,41 +260,33 @@ enum MS5611OSR {
 }
 
 pub struct BaroFilter{
-    previous_raw_values: Deque<i32, BARO_MEDIAN_FILTER_LENGTH>,
+    median: MedianFilter<BARO_MEDIAN_FILTER_LENGTH>,
     last_spike_warning_counter: u32,
 }
 
 impl BaroFilter {
     pub fn new() -> Self{
         Self{
-            previous_raw_values: Deque::new(),
+            median: MedianFilter::new(),
             last_spike_warning_counter: 0,
         }
     }
 
-    //median filter
     pub fn filter(&mut self, input_value: i32) -> i32 {
         const SPIKE_WARNING_THRESHOLD: i32 = 8000000;
 
-        while self.previous_raw_values.len() > (BARO_MEDIAN_FILTER_LENGTH - 1) {
-            let _ = self.previous_raw_values.pop_front();
-        }
-
-        let _ = self.previous_raw_values.push_back(input_value);
-
-        let mut sorted: Vec<_, BARO_MEDIAN_FILTER_LENGTH> = self.previous_raw_values.iter().collect();
-        sorted.sort();
+        let filtered = self.median.filter(input_value);
 
         if self.last_spike_warning_counter <= 100 {
             self.last_spike_warning_counter += 1;
         }
 
-        let diff = *sorted.last().unwrap() - *sorted.first().unwrap();
+        let diff = self.median.spread();
         if diff > SPIKE_WARNING_THRESHOLD && self.last_spike_warning_counter > 100 {
             defmt::warn!("Baro temp spike: {}", diff);
             self.last_spike_warning_counter = 0;
         }
 
-        *sorted[sorted.len() / 2]
+        filtered
     }
 }
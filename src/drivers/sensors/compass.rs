@@ -4,6 +4,9 @@ use nalgebra::Vector3;
 
 use defmt::*;
 
+use crate::errors::{report, ErrorKind, Subsystem};
+use crate::traits::Magnetometer;
+
 pub struct LIS3MDL<SPI: SpiDevice<u8>> {
     spi: SPI,
     scale: LIS3MDLFullScale,
@@ -77,20 +80,27 @@ impl<SPI: SpiDevice<u8>> LIS3MDL<SPI> {
 
         Ok(())
     }
+}
 
-    pub async fn tick(&mut self) {
+impl<SPI: SpiDevice<u8>> Magnetometer for LIS3MDL<SPI> {
+    async fn tick(&mut self) {
         if let Err(_e) = self.read_sensor_data().await {
+            report(Subsystem::Sensors, ErrorKind::Bus, "reading magnetometer");
             self.mag = None;
         }
     }
 
-    pub fn set_offset(&mut self, offset: Vector3<f32>) {
+    fn set_offset(&mut self, offset: Vector3<f32>) {
         self.offset = offset;
     }
 
-    pub fn magnetometer(&self) -> Option<Vector3<f32>> {
+    fn magnetometer(&self) -> Option<Vector3<f32>> {
         self.mag.map(|m| m - self.offset)
     }
+
+    async fn self_test(&mut self) -> bool {
+        self.read_u8(LIS3MDLRegister::WhoAmI).await.map(|id| id == 0x3d).unwrap_or(false)
+    }
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -1,4 +1,4 @@
-use heapless::{String, Vec};
+use heapless::Vec;
 
 use embassy_embedded_hal::SetConfig;
 use embassy_stm32::peripherals::*;
@@ -6,6 +6,7 @@ use embassy_stm32::usart::{Uart, Error};
 use embassy_stm32::bind_interrupts;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::{Sender, Receiver, Channel};
+use embassy_sync::signal::Signal;
 use embassy_time::{Timer, Duration, Instant};
 
 use static_cell::StaticCell;
@@ -14,6 +15,9 @@ use defmt::*;
 
 use shared_types::*;
 
+use crate::errors::{report, ErrorKind, Subsystem};
+use crate::traits::GpsReceiver;
+
 bind_interrupts!(struct Irqs {
     USART2 => embassy_stm32::usart::InterruptHandler<embassy_stm32::peripherals::USART2>;
 });
@@ -26,6 +30,206 @@ const DESIRED_BAUD_RATE_MESSAGE: &'static str = "$PUBX,41,1,0007,0003,115200,0*1
 
 static CHANNEL: StaticCell<Channel::<CriticalSectionRawMutex, GPSDatum, 5>> = StaticCell::new();
 
+/// Signal for passing the latest UTC time (and the instant it was received at) to the GPS handle.
+static TIME_SIGNAL: Signal<CriticalSectionRawMutex, (GPSTime, Instant)> = Signal::new();
+/// Signal for passing assistance data from the GPS handle to the GPS task.
+static ASSISTANCE_SIGNAL: Signal<CriticalSectionRawMutex, GpsAssistance> = Signal::new();
+/// Signal for passing the latest interference monitor status to the GPS handle.
+static INTERFERENCE_SIGNAL: Signal<CriticalSectionRawMutex, GpsInterference> = Signal::new();
+
+const UBX_CLASS_MGA: u8 = 0x13;
+const UBX_MGA_INI: u8 = 0x40;
+const UBX_CLASS_MON: u8 = 0x0a;
+const UBX_MON_RF: u8 = 0x38;
+/// Interval (ms) at which the interference monitor is polled
+const INTERFERENCE_POLL_INTERVAL: u64 = 1000;
+/// Accuracy we claim for assistance time (s) and position (cm). Overstating the accuracy can
+/// slow down acquisition, so these are generous.
+const ASSISTANCE_TIME_ACCURACY: u16 = 10;
+const ASSISTANCE_POSITION_ACCURACY: u32 = 1_000_000;
+
+/// UTC date and time as reported by the GPS receiver.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct GPSTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millisecond: u16,
+}
+
+impl GPSTime {
+    /// Parses NMEA time (hhmmss.ss) and date (ddmmyy) fields.
+    fn parse(time: &str, date: &str) -> Option<Self> {
+        if time.len() < 6 || date.len() != 6 {
+            return None;
+        }
+
+        let millisecond = time.get(6..)
+            .and_then(|s| s.parse::<f32>().ok())
+            .map(|s| (s * 1000.0) as u16)
+            .unwrap_or(0);
+
+        Some(Self {
+            year: 2000 + date.get(4..6)?.parse::<u16>().ok()?,
+            month: date.get(2..4)?.parse().ok()?,
+            day: date.get(0..2)?.parse().ok()?,
+            hour: time.get(0..2)?.parse().ok()?,
+            minute: time.get(2..4)?.parse().ok()?,
+            second: time.get(4..6)?.parse().ok()?,
+            millisecond,
+        })
+    }
+
+    /// Milliseconds since the unix epoch.
+    pub fn unix_millis(&self) -> u64 {
+        // Days since epoch from civil date, see http://howardhinnant.github.io/date_algorithms.html
+        let (month, day) = (self.month as i64, self.day as i64);
+        let year = (self.year as i64) - (month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        let seconds = (days as u64) * 86400
+            + (self.hour as u64) * 3600
+            + (self.minute as u64) * 60
+            + (self.second as u64);
+        seconds * 1000 + (self.millisecond as u64)
+    }
+
+    /// Day of the week, with 0 being Monday.
+    pub fn weekday(&self) -> u8 {
+        // 1970-01-01 was a Thursday
+        ((self.unix_millis() / 86_400_000 + 3) % 7) as u8
+    }
+
+    /// Inverse of `unix_millis`.
+    pub fn from_unix_millis(millis: u64) -> Self {
+        // Civil date from days since epoch, see link above
+        let days = (millis / 86_400_000) as i64 + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        let millis_of_day = millis % 86_400_000;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (millis_of_day / 3_600_000) as u8,
+            minute: (millis_of_day / 60_000 % 60) as u8,
+            second: (millis_of_day / 1000 % 60) as u8,
+            millisecond: (millis_of_day % 1000) as u16,
+        }
+    }
+}
+
+/// Rough time and position, given to the receiver after a cold start (e.g. after a battery swap)
+/// to cut down the time to first fix.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GpsAssistance {
+    pub time: GPSTime,
+    /// Latitude and longitude (deg), altitude (m)
+    pub latitude: f32,
+    pub longitude: f32,
+    pub altitude: f32,
+}
+
+/// State of the receiver's jamming detection, see UBX-MON-RF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum JammingState {
+    Unknown,
+    Ok,
+    Warning,
+    Critical,
+}
+
+/// Interference monitor status of the receiver's RF front end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct GpsInterference {
+    pub jamming_state: JammingState,
+    /// CW jamming indicator, 0 (no CW jamming) to 255 (strong CW jamming)
+    pub jamming_indicator: u8,
+    /// Noise level as measured by the receiver
+    pub noise_per_ms: u16,
+    /// AGC monitor, 0 to 8191
+    pub agc_count: u16,
+}
+
+impl GpsInterference {
+    /// Parses the first RF block of a UBX-MON-RF payload.
+    fn parse(payload: &[u8]) -> Option<Self> {
+        let block = payload.get(4..28)?;
+        let jamming_state = match block[1] & 0x03 {
+            1 => JammingState::Ok,
+            2 => JammingState::Warning,
+            3 => JammingState::Critical,
+            _ => JammingState::Unknown,
+        };
+
+        Some(Self {
+            jamming_state,
+            jamming_indicator: block[16],
+            noise_per_ms: u16::from_le_bytes([block[12], block[13]]),
+            agc_count: u16::from_le_bytes([block[14], block[15]]),
+        })
+    }
+}
+
+/// Wraps a UBX payload in a frame, i.e. sync chars, header and checksum.
+fn ubx_frame(class: u8, id: u8, payload: &[u8]) -> Vec<u8, 64> {
+    let mut frame: Vec<u8, 64> = Vec::new();
+    let _ = frame.extend_from_slice(&[0xb5, 0x62, class, id]);
+    let _ = frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    let _ = frame.extend_from_slice(payload);
+
+    let (ck_a, ck_b) = frame[2..].iter().fold((0u8, 0u8), |(a, b), byte| {
+        let a = a.wrapping_add(*byte);
+        (a, b.wrapping_add(a))
+    });
+    let _ = frame.extend_from_slice(&[ck_a, ck_b]);
+    frame
+}
+
+impl GpsAssistance {
+    /// UBX-MGA-INI-TIME_UTC message
+    fn time_message(&self) -> Vec<u8, 64> {
+        let mut payload = [0u8; 24];
+        payload[0] = 0x10; // type
+        payload[2] = 0x00; // time reference: on receipt of message
+        payload[3] = 0x80; // leap seconds: unknown (-128)
+        payload[4..6].copy_from_slice(&self.time.year.to_le_bytes());
+        payload[6] = self.time.month;
+        payload[7] = self.time.day;
+        payload[8] = self.time.hour;
+        payload[9] = self.time.minute;
+        payload[10] = self.time.second;
+        payload[12..16].copy_from_slice(&(self.time.millisecond as u32 * 1_000_000).to_le_bytes());
+        payload[16..18].copy_from_slice(&ASSISTANCE_TIME_ACCURACY.to_le_bytes());
+        ubx_frame(UBX_CLASS_MGA, UBX_MGA_INI, &payload)
+    }
+
+    /// UBX-MGA-INI-POS_LLH message
+    fn position_message(&self) -> Vec<u8, 64> {
+        let mut payload = [0u8; 20];
+        payload[0] = 0x01; // type
+        payload[4..8].copy_from_slice(&((self.latitude * 1e7) as i32).to_le_bytes());
+        payload[8..12].copy_from_slice(&((self.longitude * 1e7) as i32).to_le_bytes());
+        payload[12..16].copy_from_slice(&((self.altitude * 100.0) as i32).to_le_bytes());
+        payload[16..20].copy_from_slice(&ASSISTANCE_POSITION_ACCURACY.to_le_bytes());
+        ubx_frame(UBX_CLASS_MGA, UBX_MGA_INI, &payload)
+    }
+}
+
 pub struct GPS {
     uart: Uart<'static, USART2, DMA1_CH6, DMA1_CH5>,
     sender: Sender<'static, CriticalSectionRawMutex, GPSDatum, 5>,
@@ -35,13 +239,14 @@ pub struct GPSHandle {
     receiver: Receiver<'static, CriticalSectionRawMutex, GPSDatum, 5>,
     last_datum: Option<(GPSDatum, Instant)>,
     new_datum: bool,
+    interference: Option<GpsInterference>,
 }
 
 #[embassy_executor::task]
 pub async fn run(mut gps: GPS) -> ! {
     loop {
-        if let Err(e) = gps.run().await {
-            error!("{:?}", Debug2Format(&e));
+        if let Err(_e) = gps.run().await {
+            report(Subsystem::Gps, ErrorKind::Bus, "reading UART");
             Timer::after(Duration::from_millis(100)).await;
         }
     }
@@ -66,16 +271,16 @@ impl GPS {
             receiver: channel.receiver(),
             last_datum: None,
             new_datum: false,
+            interference: None,
         };
 
         (gps, handle)
     }
 
-    async fn read_gps_packet(&mut self) -> Result<String<512>, Error> {
+    async fn read_gps_packet(&mut self) -> Result<Vec<u8, 512>, Error> {
         let mut buffer: [u8; 512] = [0x00; 512];
         let n = self.uart.read_until_idle(&mut buffer).await?;
-        let buffer: Vec<u8, 512> = Vec::from_slice(&buffer[..n]).unwrap_or_default();
-        Ok(String::from_utf8(buffer).unwrap_or_default())
+        Ok(Vec::from_slice(&buffer[..n]).unwrap_or_default())
     }
 
     async fn find_baud_rate(&mut self) -> u32 {
@@ -141,8 +346,84 @@ impl GPS {
         Ok(())
     }
 
-    async fn process_nmea_line(&mut self, line: &str) {
-        // We only care about GGA messages, those contain coordinates/fix info
+    fn process_rmc_line(&mut self, line: &str, received: Instant) {
+        let segments: Vec<&str, 32> = line.split(',').collect();
+        if segments.len() < 10 {
+            return;
+        }
+
+        // Only trust the time once the receiver reports valid data, before that
+        // it may still be running on its own (unsynchronized) clock.
+        if segments[2] != "A" {
+            return;
+        }
+
+        if let Some(time) = GPSTime::parse(segments[1], segments[9]) {
+            TIME_SIGNAL.signal((time, received));
+        }
+    }
+
+    fn process_ubx_message(&mut self, class: u8, id: u8, payload: &[u8]) {
+        if (class, id) != (UBX_CLASS_MON, UBX_MON_RF) {
+            return;
+        }
+
+        let Some(interference) = GpsInterference::parse(payload) else {
+            report(Subsystem::Gps, ErrorKind::Deserialization, "parsing UBX-MON-RF");
+            return;
+        };
+
+        if matches!(interference.jamming_state, JammingState::Warning | JammingState::Critical) {
+            warn!("GPS interference: {:?}", interference);
+        }
+        INTERFERENCE_SIGNAL.signal(interference);
+    }
+
+    /// Splits a packet read from the UART into NMEA lines and UBX messages, which the receiver
+    /// sends interleaved.
+    async fn process_packet(&mut self, packet: &[u8], received: Instant) {
+        let mut rest = packet;
+        while !rest.is_empty() {
+            let ubx_start = rest.windows(2).position(|w| w == [0xb5, 0x62]).unwrap_or(rest.len());
+            let (text, ubx) = rest.split_at(ubx_start);
+
+            if let Ok(text) = core::str::from_utf8(text) {
+                for line in text.split("\r\n").filter(|str| str.len() > 0) {
+                    self.process_nmea_line(line, received).await;
+                }
+            }
+
+            // Sync chars, class, id, length, payload and checksum
+            let Some(len) = ubx.get(4..6).map(|l| u16::from_le_bytes([l[0], l[1]]) as usize) else {
+                break;
+            };
+            let Some(frame) = ubx.get(..(len + 8)) else {
+                report(Subsystem::Gps, ErrorKind::Overflow, "reading UBX message");
+                break;
+            };
+
+            let (ck_a, ck_b) = frame[2..(len + 6)].iter().fold((0u8, 0u8), |(a, b), byte| {
+                let a = a.wrapping_add(*byte);
+                (a, b.wrapping_add(a))
+            });
+            if [ck_a, ck_b] == frame[(len + 6)..] {
+                self.process_ubx_message(frame[2], frame[3], &frame[6..(len + 6)]);
+            } else {
+                report(Subsystem::Gps, ErrorKind::Crc, "reading UBX message");
+            }
+
+            rest = &ubx[(len + 8)..];
+        }
+    }
+
+    async fn process_nmea_line(&mut self, line: &str, received: Instant) {
+        // RMC messages contain the UTC date, which we use for our RTC
+        if line.get(3..=5) == Some("RMC") {
+            self.process_rmc_line(line, received);
+            return;
+        }
+
+        // Other than that we only care about GGA messages, those contain coordinates/fix info
         if line.get(3..=5).unwrap_or("XXX") != "GGA" {
             return;
         }
@@ -207,11 +488,22 @@ impl GPS {
         //    .into_packet_bytes();
         self.uart.write(&measurement_rate_msg).await?;
 
+        let mut last_interference_poll = Instant::now();
         loop {
-            if let Ok(str) = self.read_gps_packet().await {
-                for line in str.split("\r\n").filter(|str| str.len() > 0) {
-                    self.process_nmea_line(line).await;
-                }
+            if let Some(assistance) = ASSISTANCE_SIGNAL.try_take() {
+                info!("Sending assistance data to GPS");
+                self.uart.write(&assistance.time_message()).await?;
+                self.uart.write(&assistance.position_message()).await?;
+            }
+
+            // Receivers without the interference monitor simply won't respond to this.
+            if last_interference_poll.elapsed() > Duration::from_millis(INTERFERENCE_POLL_INTERVAL) {
+                self.uart.write(&ubx_frame(UBX_CLASS_MON, UBX_MON_RF, &[])).await?;
+                last_interference_poll = Instant::now();
+            }
+
+            if let Ok(packet) = self.read_gps_packet().await {
+                self.process_packet(&packet, Instant::now()).await;
             } else {
                 Timer::after(Duration::from_millis(1)).await;
             }
@@ -236,45 +528,65 @@ impl GPSHandle {
         }
     }
 
-    pub fn datum(&mut self) -> Option<GPSDatum> {
+    /// Returns the latest UTC time from the receiver together with the instant it was
+    /// received at, if a new one has arrived since this was called last.
+    pub fn new_time(&mut self) -> Option<(GPSTime, Instant)> {
+        TIME_SIGNAL.try_take()
+    }
+
+    /// Latest status of the receiver's interference monitor, if supported (u-blox M8 and newer).
+    pub fn interference(&mut self) -> Option<GpsInterference> {
+        if let Some(interference) = INTERFERENCE_SIGNAL.try_take() {
+            self.interference = Some(interference);
+        }
+        self.interference
+    }
+
+    /// Passes assistance data on to the receiver. Only supported by receivers with the MGA
+    /// message class (u-blox M8 and newer).
+    pub fn assist(&mut self, assistance: GpsAssistance) {
+        ASSISTANCE_SIGNAL.signal(assistance);
+    }
+}
+
+impl GpsReceiver for GPSHandle {
+    fn datum(&mut self) -> Option<GPSDatum> {
         self.check_for_new_values();
         self.last_datum.as_ref().map(|(datum, _t)| datum.clone())
     }
 
-    // Return the current datum only if it has been received since this was
-    // called last.
-    pub fn new_datum(&mut self) -> Option<GPSDatum> {
+    fn new_datum(&mut self) -> Option<GPSDatum> {
         let d = self.new_datum.then_some(self.datum()).flatten();
         self.new_datum = false;
         d
     }
 
-    pub fn latitude(&mut self) -> Option<f32> {
+    fn latitude(&mut self) -> Option<f32> {
         self.check_for_new_values();
         self.last_datum.as_ref().map(|(d, _)| d.latitude).flatten()
     }
 
-    pub fn longitude(&mut self) -> Option<f32> {
+    fn longitude(&mut self) -> Option<f32> {
         self.check_for_new_values();
         self.last_datum.as_ref().map(|(d, _)| d.longitude).flatten()
     }
 
-    pub fn altitude(&mut self) -> Option<f32> {
+    fn altitude(&mut self) -> Option<f32> {
         self.check_for_new_values();
         self.last_datum.as_ref().map(|(d, _)| d.altitude).flatten()
     }
 
-    pub fn fix(&mut self) -> Option<GPSFixType> {
+    fn fix(&mut self) -> Option<GPSFixType> {
         self.check_for_new_values();
         self.last_datum.as_ref().map(|(d, _)| d.fix)
     }
 
-    pub fn hdop(&mut self) -> Option<u16> {
+    fn hdop(&mut self) -> Option<u16> {
         self.check_for_new_values();
         self.last_datum.as_ref().map(|(d, _)| d.hdop)
     }
 
-    pub fn num_satellites(&mut self) -> Option<u8> {
+    fn num_satellites(&mut self) -> Option<u8> {
         self.check_for_new_values();
         self.last_datum.as_ref().map(|(d, _)| d.num_satellites)
     }
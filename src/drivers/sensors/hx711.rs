@@ -0,0 +1,173 @@
+//! HX711 load cell amplifier, for instrumenting static motor tests. The HX711 uses a simple
+//! two-wire interface: DOUT going low signals a new conversion, which is then clocked out bit by
+//! bit on PD_SCK. The output data rate (10 or 80 SPS) is selected in hardware via its RATE pin.
+//!
+//! Conversions are read by a separate task as soon as they are ready, and passed to the
+//! `LoadCellHandle` used by the main loop, which applies tare offset and scale.
+//!
+//! Only built with the `loadcell` feature. PD_SCK is connected to PB6 and DOUT to PB7 on the
+//! expansion header.
+
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::{Level, Output};
+use embassy_stm32::peripherals::{PB6, PB7};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Channel, Receiver};
+use embassy_time::{with_timeout, Duration, Instant};
+
+use defmt::*;
+
+use crate::errors::{report, ErrorKind, Subsystem};
+use crate::filters::MovingAverage;
+
+/// Samples not yet processed by the main loop. At 80 SPS and a 1kHz main loop, this never fills
+/// up unless the main loop stalls.
+static SAMPLES: Channel<CriticalSectionRawMutex, LoadCellSample, 16> = Channel::new();
+
+/// Time (ms) after which the HX711 is considered unresponsive. Long enough for the 10 SPS setting.
+const TIMEOUT: u64 = 500;
+/// Additional clock pulses after the data bits, selecting channel A with a gain of 128 for the
+/// next conversion
+const GAIN_PULSES: usize = 1;
+/// Raw values reported when the input is out of range
+const RAW_MAX: i32 = 0x7f_ffff;
+const RAW_MIN: i32 = -0x80_0000;
+/// Number of samples averaged for taring and calibration
+const AVERAGED_SAMPLES: usize = 16;
+
+#[derive(Clone, Copy, Debug, Format)]
+pub struct LoadCellSample {
+    /// Time since boot (ms)
+    pub time: u32,
+    pub raw: i32,
+}
+
+pub struct HX711 {
+    clock: Output<'static, PB6>,
+    data: ExtiInput<'static, PB7>,
+}
+
+#[embassy_executor::task]
+pub async fn run(mut hx711: HX711) -> ! {
+    loop {
+        hx711.tick().await;
+    }
+}
+
+impl HX711 {
+    pub fn init(clock: Output<'static, PB6>, data: ExtiInput<'static, PB7>) -> Self {
+        let mut hx711 = Self { clock, data };
+        // Holding PD_SCK high for more than 60us powers the HX711 down, so make sure it is low.
+        hx711.clock.set_low();
+        info!("HX711 initialized");
+        hx711
+    }
+
+    async fn tick(&mut self) {
+        if with_timeout(Duration::from_millis(TIMEOUT), self.data.wait_for_low()).await.is_err() {
+            report(Subsystem::Sensors, ErrorKind::Timeout, "waiting for load cell");
+            return;
+        }
+
+        let raw = self.read();
+        if raw == RAW_MAX || raw == RAW_MIN {
+            report(Subsystem::Sensors, ErrorKind::Overflow, "load cell out of range");
+        }
+
+        let sample = LoadCellSample {
+            time: Instant::now().as_millis() as u32,
+            raw,
+        };
+        if SAMPLES.try_send(sample).is_err() {
+            report(Subsystem::Sensors, ErrorKind::QueueFull, "queueing load cell sample");
+        }
+    }
+
+    /// Clocks out a single conversion. This has to finish without interruptions, since a long
+    /// clock high time would power down the HX711, so it takes about 50us with interrupts disabled.
+    fn read(&mut self) -> i32 {
+        cortex_m::interrupt::free(|_| {
+            let mut raw: u32 = 0;
+            for i in 0..(24 + GAIN_PULSES) {
+                self.clock.set_high();
+                cortex_m::asm::delay(84); // ~1us at 84MHz
+                let bit = self.data.get_level() == Level::High;
+                self.clock.set_low();
+                cortex_m::asm::delay(84);
+
+                if i < 24 {
+                    raw = (raw << 1) | (bit as u32);
+                }
+            }
+
+            // sign-extend the 24-bit two's complement value
+            ((raw << 8) as i32) >> 8
+        })
+    }
+}
+
+/// Processed load cell readings for use in the main loop.
+pub struct LoadCellHandle {
+    receiver: Receiver<'static, CriticalSectionRawMutex, LoadCellSample, 16>,
+    last_sample: Option<LoadCellSample>,
+    average: MovingAverage<AVERAGED_SAMPLES>,
+    offset: i32,
+    /// Raw counts per newton, unknown until calibrated
+    scale: Option<f32>,
+}
+
+impl LoadCellHandle {
+    pub fn new() -> Self {
+        Self {
+            receiver: SAMPLES.receiver(),
+            last_sample: None,
+            average: MovingAverage::new(),
+            offset: 0,
+            scale: None,
+        }
+    }
+
+    /// Returns the next sample received from the HX711 task, if any.
+    pub fn next_sample(&mut self) -> Option<LoadCellSample> {
+        let sample = self.receiver.try_receive().ok()?;
+        self.average.filter(sample.raw);
+        self.last_sample = Some(sample);
+        Some(sample)
+    }
+
+    pub fn last_sample(&self) -> Option<LoadCellSample> {
+        self.last_sample
+    }
+
+    /// Force (N) corresponding to a sample, if calibrated.
+    pub fn force(&self, sample: &LoadCellSample) -> Option<f32> {
+        self.scale.map(|scale| (sample.raw - self.offset) as f32 / scale)
+    }
+
+    /// Uses the current (averaged) reading as zero point. The load cell has to be unloaded.
+    pub fn tare(&mut self) -> Result<i32, ()> {
+        self.offset = self.average.value().ok_or(())?;
+        Ok(self.offset)
+    }
+
+    /// Determines the scale from the current (averaged) reading, with a known force (N) applied
+    /// to the tared load cell.
+    pub fn calibrate(&mut self, force: f32) -> Result<f32, ()> {
+        let average = self.average.value().ok_or(())?;
+        let scale = (average - self.offset) as f32 / force;
+        if !scale.is_normal() {
+            return Err(());
+        }
+
+        self.scale = Some(scale);
+        Ok(scale)
+    }
+
+    pub fn offset(&self) -> i32 {
+        self.offset
+    }
+
+    pub fn scale(&self) -> Option<f32> {
+        self.scale
+    }
+}
@@ -5,7 +5,21 @@ use nalgebra::Vector3;
 
 use defmt::*;
 
+use crate::errors::{report, ErrorKind, Subsystem};
+
 const G_TO_MS2: f32 = 9.80665;
+/// Raw gyroscope values this close to the end of the range are considered clipped.
+const GYRO_SATURATION_MARGIN: i16 = 16;
+
+/// Maximum number of FIFO words read per tick, to bound the time spent on the bus. This is well
+/// above the ~5 words produced per millisecond, so we catch up after long loop iterations.
+const MAX_FIFO_WORDS_PER_TICK: usize = 32;
+/// Timestamp counter increments (25us each) between two samples at 1667Hz
+const TIMESTAMP_TICKS_PER_SAMPLE: u32 = 24;
+
+const FIFO_TAG_GYROSCOPE: u8 = 0x01;
+const FIFO_TAG_ACCELEROMETER: u8 = 0x02;
+const FIFO_TAG_TIMESTAMP: u8 = 0x04;
 
 pub struct LSM6<SPI: SpiDevice<u8>> {
     spi: SPI,
@@ -13,6 +27,9 @@ pub struct LSM6<SPI: SpiDevice<u8>> {
     accel_scale: LSM6AccelerometerScale,
     gyro: Option<Vector3<f32>>,
     accel: Option<Vector3<f32>>,
+    gyro_saturated: bool,
+    /// Timestamp of the last batch read from the FIFO
+    last_timestamp: Option<u32>,
     gyro_offset: Vector3<f32>,
     accel_offset: Vector3<f32>,
 }
@@ -28,6 +45,8 @@ impl<SPI: SpiDevice<u8>> LSM6<SPI> {
             accel_scale,
             gyro: None,
             accel: None,
+            gyro_saturated: false,
+            last_timestamp: None,
             gyro_offset: Vector3::default(),
             accel_offset: Vector3::default(),
         };
@@ -56,6 +75,12 @@ impl<SPI: SpiDevice<u8>> LSM6<SPI> {
             false, // TODO
         ).await?;
 
+        // Batch both sensors at their full data rate into the FIFO, with a timestamp for every
+        // batch. In continuous mode, the oldest samples are overwritten if we fall behind.
+        imu.write_u8(LSM6RRegister::Ctrl10C, 0b0010_0000).await?;
+        imu.write_u8(LSM6RRegister::FifoCtrl3, 0b1000_1000).await?;
+        imu.write_u8(LSM6RRegister::FifoCtrl4, 0b0100_0110).await?;
+
         Ok(imu)
     }
 
@@ -71,23 +96,62 @@ impl<SPI: SpiDevice<u8>> LSM6<SPI> {
         Ok(())
     }
 
-    async fn read_sensor_data(&mut self) -> Result<(), SPI::Error> {
-        let mut payload = [(LSM6RRegister::OutTempL as u8) | 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-        self.spi.transfer_in_place(&mut payload).await?;
+    /// Drains the FIFO. Everything measured since the last tick is averaged, so no samples are
+    /// lost if an iteration of the main loop runs long.
+    async fn read_fifo(&mut self) -> Result<(), SPI::Error> {
+        let mut status = [LSM6RRegister::FifoStatus1 as u8 | 0x80, 0, 0];
+        self.spi.transfer_in_place(&mut status).await?;
+        let unread = u16::from_le_bytes([status[1], status[2] & 0b11]) as usize;
+        if status[2] & 0b0100_0000 != 0 {
+            report(Subsystem::Sensors, ErrorKind::Overflow, "IMU FIFO overrun");
+        }
 
-        // TODO: do we need this temp?
-        let _temp = ((payload[2] as i16) << 8) + (payload[1] as i16);
-        let gyro_x = ((payload[4] as i16) << 8) + (payload[3] as i16);
-        let gyro_y = ((payload[6] as i16) << 8) + (payload[5] as i16);
-        let gyro_z = ((payload[8] as i16) << 8) + (payload[7] as i16);
-        let accel_x = ((payload[10] as i16) << 8) + (payload[9] as i16);
-        let accel_y = ((payload[12] as i16) << 8) + (payload[11] as i16);
-        let accel_z = ((payload[14] as i16) << 8) + (payload[13] as i16);
+        let (mut gyro_sum, mut gyro_count) = (Vector3::<f32>::zeros(), 0);
+        let (mut accel_sum, mut accel_count) = (Vector3::<f32>::zeros(), 0);
+        let mut gyro_saturated = false;
+
+        for _i in 0..usize::min(unread, MAX_FIFO_WORDS_PER_TICK) {
+            let mut word = [LSM6RRegister::FifoDataOutTag as u8 | 0x80, 0, 0, 0, 0, 0, 0, 0];
+            self.spi.transfer_in_place(&mut word).await?;
+
+            let x = i16::from_le_bytes([word[2], word[3]]);
+            let y = i16::from_le_bytes([word[4], word[5]]);
+            let z = i16::from_le_bytes([word[6], word[7]]);
+
+            // rotate values to match vehicle coordinate system (invert x, swap y and z)
+            // and convert to m/s^2 and deg/s
+            match word[1] >> 3 {
+                FIFO_TAG_GYROSCOPE => {
+                    gyro_saturated |= [x, y, z]
+                        .iter()
+                        .any(|v| *v >= i16::MAX - GYRO_SATURATION_MARGIN || *v <= i16::MIN + GYRO_SATURATION_MARGIN);
+                    gyro_sum += self.gyro_scale.scale_raw_values(Vector3::new(x.saturating_neg(), z, y));
+                    gyro_count += 1;
+                }
+                FIFO_TAG_ACCELEROMETER => {
+                    accel_sum += self.accel_scale.scale_raw_values(Vector3::new(x.saturating_neg(), z, y));
+                    accel_count += 1;
+                }
+                FIFO_TAG_TIMESTAMP => {
+                    let timestamp = u32::from_le_bytes([word[2], word[3], word[4], word[5]]);
+                    let gap = self.last_timestamp.map(|t| timestamp.wrapping_sub(t)).unwrap_or(0);
+                    if gap > 2 * TIMESTAMP_TICKS_PER_SAMPLE {
+                        report(Subsystem::Sensors, ErrorKind::Timeout, "IMU samples lost");
+                    }
+                    self.last_timestamp = Some(timestamp);
+                }
+                _ => {}
+            }
+        }
 
-        // rotate values to match vehicle coordinate system (invert x, swap y and z)
-        // and convert to m/s^2 and deg/s
-        self.gyro = Some(self.gyro_scale.scale_raw_values(Vector3::new(gyro_x.saturating_neg(), gyro_z, gyro_y)));
-        self.accel = Some(self.accel_scale.scale_raw_values(Vector3::new(accel_x.saturating_neg(), accel_z, accel_y)));
+        // The FIFO may occasionally be empty, in which case we keep the last values.
+        if gyro_count > 0 {
+            self.gyro = Some(gyro_sum / gyro_count as f32);
+            self.gyro_saturated = gyro_saturated;
+        }
+        if accel_count > 0 {
+            self.accel = Some(accel_sum / accel_count as f32);
+        }
 
         Ok(())
     }
@@ -112,7 +176,8 @@ impl<SPI: SpiDevice<u8>> LSM6<SPI> {
     }
 
     pub async fn tick(&mut self) {
-        if let Err(_e) = self.read_sensor_data().await {
+        if let Err(_e) = self.read_fifo().await {
+            report(Subsystem::Sensors, ErrorKind::Bus, "reading IMU");
             self.gyro = None;
             self.accel = None;
         }
@@ -130,6 +195,17 @@ impl<SPI: SpiDevice<u8>> LSM6<SPI> {
     pub fn gyroscope(&self) -> Option<Vector3<f32>> {
         self.gyro.map(|g| g - self.gyro_offset)
     }
+
+    /// Whether any axis of the last gyroscope reading was at the end of the measurement range,
+    /// meaning the actual rate is unknown.
+    pub fn gyroscope_saturated(&self) -> bool {
+        self.gyro_saturated
+    }
+
+    /// Checks that the sensor still responds with the expected ID.
+    pub async fn self_test(&mut self) -> bool {
+        self.read_u8(LSM6RRegister::WhoAmI).await.map(|id| id == 0x6b).unwrap_or(false)
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
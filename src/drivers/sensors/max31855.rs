@@ -0,0 +1,159 @@
+//! MAX31855 thermocouple interface, for monitoring motor casing or airframe temperatures. The
+//! MAX31855 converts continuously and is read-only, each read returning the latest thermocouple
+//! and cold junction temperatures along with fault flags.
+//!
+//! Readings are taken by a separate task, since a conversion takes ~100ms anyway, and passed to
+//! the `ThermocoupleHandle` used by the main loop.
+//!
+//! Only built with the `thermocouple` feature. The chip select is connected to PB8, the MAX31855
+//! shares the sensor SPI bus but only supports clock rates up to 5MHz.
+
+use embassy_embedded_hal::shared_bus::asynch::spi::SpiDeviceWithConfig;
+use embassy_stm32::gpio::Output;
+use embassy_stm32::peripherals::PB8;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Ticker};
+use embedded_hal_async::spi::SpiDevice;
+
+use defmt::*;
+
+use crate::board::SensorSpi;
+use crate::errors::{report, ErrorKind, Subsystem};
+
+pub type ThermocoupleSpiDevice = SpiDeviceWithConfig<'static, CriticalSectionRawMutex, SensorSpi, Output<'static, PB8>>;
+
+/// Latest reading, passed from the thermocouple task to the handle.
+static READING_SIGNAL: Signal<CriticalSectionRawMutex, (ThermocoupleReading, Instant)> = Signal::new();
+
+/// Interval between reads (ms), matching the conversion time
+const READ_INTERVAL: u64 = 100;
+/// Age (ms) after which a reading is no longer reported
+const STALE_THRESHOLD: u64 = 1000;
+
+const FAULT_OPEN: u32 = 1 << 0;
+const FAULT_SHORT_GND: u32 = 1 << 1;
+const FAULT_SHORT_VCC: u32 = 1 << 2;
+const FAULT: u32 = 1 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum ThermocoupleFault {
+    Open,
+    ShortToGround,
+    ShortToVcc,
+}
+
+#[derive(Clone, Copy, Debug, Format)]
+pub struct ThermocoupleReading {
+    /// Thermocouple temperature (C), unless there is a fault
+    pub temperature: Result<f32, ThermocoupleFault>,
+    /// Cold junction (chip) temperature (C)
+    pub internal_temperature: f32,
+}
+
+impl ThermocoupleReading {
+    fn parse(raw: u32) -> Self {
+        let temperature = if raw & FAULT == 0 {
+            // 14-bit two's complement in the upper bits, 0.25C per LSB
+            Ok(((raw as i32) >> 18) as f32 * 0.25)
+        } else if raw & FAULT_OPEN != 0 {
+            Err(ThermocoupleFault::Open)
+        } else if raw & FAULT_SHORT_GND != 0 {
+            Err(ThermocoupleFault::ShortToGround)
+        } else {
+            Err(ThermocoupleFault::ShortToVcc)
+        };
+
+        // 12-bit two's complement in bits 4-15, 0.0625C per LSB
+        let internal_temperature = (((raw << 16) as i32) >> 20) as f32 * 0.0625;
+
+        Self {
+            temperature,
+            internal_temperature,
+        }
+    }
+}
+
+pub struct MAX31855<SPI: SpiDevice<u8>> {
+    spi: SPI,
+    fault: Option<ThermocoupleFault>,
+}
+
+#[embassy_executor::task]
+pub async fn run(mut max31855: MAX31855<ThermocoupleSpiDevice>) -> ! {
+    let mut ticker = Ticker::every(Duration::from_millis(READ_INTERVAL));
+    loop {
+        max31855.tick().await;
+        ticker.next().await;
+    }
+}
+
+impl<SPI: SpiDevice<u8>> MAX31855<SPI> {
+    pub async fn init(spi: SPI) -> Self {
+        let mut max31855 = Self { spi, fault: None };
+
+        match max31855.read().await {
+            // A bus without anything connected reads as all zeros or all ones.
+            Ok(0x0000_0000) | Ok(0xffff_ffff) | Err(_) => error!("Failed to initialize MAX31855"),
+            Ok(_) => info!("MAX31855 initialized"),
+        }
+
+        max31855
+    }
+
+    async fn read(&mut self) -> Result<u32, SPI::Error> {
+        let mut buffer = [0u8; 4];
+        self.spi.read(&mut buffer).await?;
+        Ok(u32::from_be_bytes(buffer))
+    }
+
+    async fn tick(&mut self) {
+        let raw = match self.read().await {
+            Ok(raw) => raw,
+            Err(_e) => {
+                report(Subsystem::Sensors, ErrorKind::Bus, "reading thermocouple");
+                return;
+            }
+        };
+
+        let reading = ThermocoupleReading::parse(raw);
+        let fault = reading.temperature.err();
+        if fault != self.fault {
+            if let Some(fault) = fault {
+                warn!("Thermocouple fault: {:?}", fault);
+            } else {
+                info!("Thermocouple fault cleared");
+            }
+            self.fault = fault;
+        }
+
+        READING_SIGNAL.signal((reading, Instant::now()));
+    }
+}
+
+/// Latest thermocouple reading for use in the main loop.
+#[derive(Default)]
+pub struct ThermocoupleHandle {
+    last_reading: Option<(ThermocoupleReading, Instant)>,
+}
+
+impl ThermocoupleHandle {
+    pub fn tick(&mut self) {
+        if let Some(reading) = READING_SIGNAL.try_take() {
+            self.last_reading = Some(reading);
+        }
+
+        if self.last_reading.map(|(_, t)| t.elapsed().as_millis() > STALE_THRESHOLD).unwrap_or(false) {
+            self.last_reading = None;
+        }
+    }
+
+    pub fn reading(&self) -> Option<ThermocoupleReading> {
+        self.last_reading.map(|(r, _)| r)
+    }
+
+    /// Thermocouple temperature (C), if available and not faulty.
+    pub fn temperature(&self) -> Option<f32> {
+        self.reading().and_then(|r| r.temperature.ok())
+    }
+}
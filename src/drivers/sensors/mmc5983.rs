@@ -0,0 +1,124 @@
+use embassy_time::{Timer, Duration};
+use embedded_hal_async::spi::SpiDevice;
+
+use nalgebra::Vector3;
+
+use defmt::*;
+
+use crate::errors::{report, ErrorKind, Subsystem};
+use crate::traits::Magnetometer;
+
+const PRODUCT_ID: u8 = 0x30;
+/// Output of the 18-bit measurement at zero field
+const ZERO_FIELD: i32 = 1 << 17;
+const LSB_PER_GAUSS: f32 = 16384.0;
+
+pub struct MMC5983<SPI: SpiDevice<u8>> {
+    spi: SPI,
+    mag: Option<Vector3<f32>>,
+    offset: Vector3<f32>
+}
+
+impl<SPI: SpiDevice<u8>> MMC5983<SPI> {
+    pub async fn init(spi: SPI) -> Result<Self, SPI::Error> {
+        let mut mmc = Self {
+            spi,
+            mag: None,
+            offset: Vector3::default()
+        };
+
+        mmc.write_u8(MMC5983Register::Control1, 0b1000_0000).await?; // software reset
+        Timer::after(Duration::from_millis(15)).await;
+
+        let product_id = mmc.read_u8(MMC5983Register::ProductId).await?;
+
+        // Set the maximum bandwidth (800Hz), required for 1kHz continuous measurements
+        mmc.write_u8(MMC5983Register::Control1, 0b0000_0011).await?;
+        // Enable automatic set/reset to remove offset drift
+        mmc.write_u8(MMC5983Register::Control0, 0b0010_0000).await?;
+        // Continuous measurements at 1kHz, periodic set every 100 measurements
+        mmc.write_u8(MMC5983Register::Control2, 0b1011_1111).await?;
+
+        if product_id != PRODUCT_ID {
+            error!("Failed to initialize MMC5983MA (0x{:02x} != 0x{:02x})", product_id, PRODUCT_ID);
+        } else {
+            info!("MMC5983MA initialized");
+        }
+
+        Ok(mmc)
+    }
+
+    async fn read_u8(&mut self, address: MMC5983Register) -> Result<u8, SPI::Error> {
+        let mut buffer = [address as u8 | 0x80, 0];
+        self.spi.transfer_in_place(&mut buffer).await?;
+        Ok(buffer[1])
+    }
+
+    async fn write_u8(&mut self, address: MMC5983Register, value: u8) -> Result<(), SPI::Error> {
+        let mut buffer = [address as u8, value];
+        self.spi.transfer_in_place(&mut buffer).await?;
+        Ok(())
+    }
+
+    async fn read_sensor_data(&mut self) -> Result<(), SPI::Error> {
+        let mut buffer: [u8; 8] = [0; 8];
+        buffer[0] = MMC5983Register::XOut0 as u8 | 0x80;
+
+        self.spi.transfer_in_place(&mut buffer).await?;
+
+        // The two lowest bits of each axis are packed into XYZOut2.
+        let axis = |high: u8, low: u8, shift: u8| {
+            let raw = ((high as i32) << 10) | ((low as i32) << 2) | ((buffer[7] >> shift) & 0b11) as i32;
+            (raw - ZERO_FIELD) as f32 / LSB_PER_GAUSS * 100.0
+        };
+
+        let mag_x = axis(buffer[1], buffer[2], 6);
+        let mag_y = axis(buffer[3], buffer[4], 4);
+        let mag_z = axis(buffer[5], buffer[6], 2);
+
+        // Assumes the same mounting orientation as the LIS3MDL
+        self.mag = Some(Vector3::new(-mag_x, mag_z, mag_y));
+
+        Ok(())
+    }
+}
+
+impl<SPI: SpiDevice<u8>> Magnetometer for MMC5983<SPI> {
+    async fn tick(&mut self) {
+        if let Err(_e) = self.read_sensor_data().await {
+            report(Subsystem::Sensors, ErrorKind::Bus, "reading magnetometer");
+            self.mag = None;
+        }
+    }
+
+    fn set_offset(&mut self, offset: Vector3<f32>) {
+        self.offset = offset;
+    }
+
+    fn magnetometer(&self) -> Option<Vector3<f32>> {
+        self.mag.map(|m| m - self.offset)
+    }
+
+    async fn self_test(&mut self) -> bool {
+        self.read_u8(MMC5983Register::ProductId).await.map(|id| id == PRODUCT_ID).unwrap_or(false)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+enum MMC5983Register {
+    XOut0 = 0x00,
+    XOut1 = 0x01,
+    YOut0 = 0x02,
+    YOut1 = 0x03,
+    ZOut0 = 0x04,
+    ZOut1 = 0x05,
+    XYZOut2 = 0x06,
+    TOut = 0x07,
+    Status = 0x08,
+    Control0 = 0x09,
+    Control1 = 0x0a,
+    Control2 = 0x0b,
+    Control3 = 0x0c,
+    ProductId = 0x2f,
+}
@@ -4,27 +4,46 @@ use embassy_time::{Timer, Duration, Instant};
 
 use shared_types::can::BatteryTelemetryMessage;
 
+pub use crate::traits::BatteryStatus;
+use crate::traits::PowerSupply;
+
+use crate::filters::{Biquad, BiquadCoefficients, Q_BUTTERWORTH};
+
+use super::adc::{ChannelCalibration, FactoryCalibration, Oversampled, OVERSAMPLING};
+
 const VDIV: f32 = 2.8;
 const RES: f32 = 0.01;
 
-const LOW_BATTERY_THRESHOLD: u16 = 7000;
-const NO_BATTERY_THRESHOLD: u16 = 5000;
+/// Rate (Hz) of new readings, with the power monitor ticked by the 1kHz main loop
+const READING_RATE: f32 = 1000.0 / OVERSAMPLING as f32;
+/// Cutoff frequency (Hz) of the low-pass applied to the battery current, which is the small
+/// difference of two noisy voltages.
+const CURRENT_FILTER_CUTOFF: f32 = 5.0;
 
-#[derive(PartialEq, Clone, Copy)]
-pub enum BatteryStatus{
-    Low,
-    High,
-    NoBatteryAttached
-}
+// Conversion from the voltage at each pin to the actual voltage (mV), given by the board's voltage
+// dividers.
+const BATTERY_HIGH_CALIBRATION: ChannelCalibration = ChannelCalibration::new(VDIV, 0.0);
+const BATTERY_LOW_CALIBRATION: ChannelCalibration = ChannelCalibration::new(VDIV, 0.0);
+const ARM_CALIBRATION: ChannelCalibration = ChannelCalibration::new(VDIV, 0.0);
 
 pub struct PowerMonitor<ADC: Instance, H, L, A> {
     adc: Adc<'static, ADC>,
-    vref_sample: u16,
+    calibration: FactoryCalibration,
+    internal_vref: VrefInt,
     internal_temperature: Temperature,
     pin_bat_high: H,
     pin_bat_low: L,
     pin_arm: A,
 
+    vref_samples: Oversampled,
+    temperature_samples: Oversampled,
+    bat_high_samples: Oversampled,
+    bat_low_samples: Oversampled,
+    arm_samples: Oversampled,
+    current_filter: Biquad,
+
+    /// Actual analog supply voltage (mV)
+    vdda: Option<f32>,
     battery_voltage: Option<u16>,
     battery_current: Option<i32>,
     arm_voltage: Option<u16>,
@@ -47,20 +66,26 @@ where
     pub async fn init(mut adc: Adc<'static, ADC>, pin_bat_high: H, pin_bat_low: L, pin_arm: A) -> Self {
         adc.set_sample_time(SampleTime::Cycles480);
 
-        let mut internal_vref = adc.enable_vrefint();
+        let internal_vref = adc.enable_vrefint();
         let internal_temperature = adc.enable_temperature();
         let start_time = Temperature::start_time_us().max(VrefInt::start_time_us());
         Timer::after(Duration::from_micros(start_time as u64)).await;
 
-        let vref_sample = adc.read(&mut internal_vref);
-
         Self {
             adc,
+            calibration: FactoryCalibration::read(),
+            internal_vref,
             internal_temperature,
-            vref_sample,
             pin_bat_high,
             pin_bat_low,
             pin_arm,
+            vref_samples: Oversampled::default(),
+            temperature_samples: Oversampled::default(),
+            bat_high_samples: Oversampled::default(),
+            bat_low_samples: Oversampled::default(),
+            arm_samples: Oversampled::default(),
+            current_filter: Biquad::new(BiquadCoefficients::lowpass(CURRENT_FILTER_CUTOFF, READING_RATE, Q_BUTTERWORTH)),
+            vdda: None,
             battery_voltage: None,
             battery_current: None,
             arm_voltage: None,
@@ -70,89 +95,104 @@ where
         }
     }
 
-    fn to_millivolts(&self, sample: u16) -> u16 {
-        // From http://www.st.com/resource/en/datasheet/DM00071990.pdf
-        // 6.3.24 Reference voltage
-        const VREFINT_MV: u32 = 1210; // mV
-
-        (u32::from(sample) * VREFINT_MV / u32::from(self.vref_sample)) as u16
+    /// Updates the readings from the averaged samples, after a new set has been collected.
+    fn update_readings(&mut self) {
+        let Some(vdda) = self.vref_samples.value().map(|s| self.calibration.vdda(s)) else {
+            return;
+        };
+        self.vdda = Some(vdda);
+
+        let millivolts = |samples: &Oversampled, calibration: &ChannelCalibration| {
+            samples.value().map(|s| calibration.apply(self.calibration.millivolts(s, vdda)))
+        };
+
+        let voltage_high = millivolts(&self.bat_high_samples, &BATTERY_HIGH_CALIBRATION);
+        let voltage_low = millivolts(&self.bat_low_samples, &BATTERY_LOW_CALIBRATION);
+        self.battery_voltage = voltage_high.map(|v| v as u16);
+        self.battery_current = voltage_high.zip(voltage_low)
+            .map(|(high, low)| self.current_filter.filter(((high - low) / RES) as i32));
+        self.arm_voltage = millivolts(&self.arm_samples, &ARM_CALIBRATION).map(|v| v as u16);
+        self.temperature = self.temperature_samples.value().and_then(|s| self.calibration.celsius(s, vdda));
     }
 
-    fn to_celcius(&self, sample: u16) -> f32 {
-        // From http://www.st.com/resource/en/datasheet/DM00071990.pdf
-        // 6.3.22 Temperature sensor characteristics
-        const V25: i32 = 760; // mV
-        const AVG_SLOPE: f32 = 2.5; // mV/C
-
-        let sample_mv = self.to_millivolts(sample) as i32;
-
-        (sample_mv - V25) as f32 / AVG_SLOPE + 25.0
+    // the time current, not the amperage current
+    fn current_can_msg<'a>(&'a self) -> Option<&'a BatteryTelemetryMessage> {
+        self.time_last_can_msg
+            .map(|i| i.elapsed().as_millis() < 250)?
+            .then_some(self.last_can_message.as_ref())
+            .flatten()
     }
+}
 
-    pub fn tick(&mut self) {
-        let sample = self.adc.read(&mut self.internal_temperature);
-        self.temperature = Some(self.to_celcius(sample));
+/// Access to the ADC for other analog inputs, which share it with the power monitor.
+impl<ADC: Instance, H, L, A> PowerMonitor<ADC, H, L, A> {
+    /// Takes a single sample of another channel.
+    pub fn read<P: Pin + AdcPin<ADC>>(&mut self, pin: &mut P) -> u16 {
+        self.adc.read(pin)
+    }
 
-        let sample = self.adc.read(&mut self.pin_bat_high);
-        let voltage_high = (self.to_millivolts(sample) as f32) * VDIV;
+    /// Actual analog supply voltage (mV), measured using the internal reference
+    pub fn vdda(&self) -> Option<f32> {
+        self.vdda
+    }
 
-        let sample = self.adc.read(&mut self.pin_bat_low);
-        let voltage_low = (self.to_millivolts(sample) as f32) * VDIV;
-        self.battery_voltage = Some(voltage_high as u16);
-        self.battery_current = Some(((voltage_high - voltage_low) / RES) as i32);
+    /// Converts a (possibly averaged) sample to the voltage at the pin (mV), corrected for the
+    /// actual supply voltage. Not available until the supply voltage has been measured.
+    pub fn millivolts(&self, sample: f32) -> Option<f32> {
+        self.vdda.map(|vdda| self.calibration.millivolts(sample, vdda))
+    }
+}
 
-        let sample = self.adc.read(&mut self.pin_arm);
-        self.arm_voltage = Some(((self.to_millivolts(sample) as f32) * VDIV) as u16);
+impl<
+    ADC: Instance,
+    H: Pin + AdcPin<ADC>,
+    L: Pin + AdcPin<ADC>,
+    A: Pin + AdcPin<ADC>,
+> PowerSupply for PowerMonitor<ADC, H, L, A>
+where
+    Temperature: AdcPin<ADC>,
+    VrefInt: AdcPin<ADC>,
+{
+    fn tick(&mut self) {
+        self.vref_samples.add(self.adc.read(&mut self.internal_vref));
+        self.temperature_samples.add(self.adc.read(&mut self.internal_temperature));
+        self.bat_high_samples.add(self.adc.read(&mut self.pin_bat_high));
+        self.bat_low_samples.add(self.adc.read(&mut self.pin_bat_low));
+        // All channels are sampled in lockstep, so they complete their sets together.
+        if self.arm_samples.add(self.adc.read(&mut self.pin_arm)) {
+            self.update_readings();
+        }
     }
 
-    pub fn battery_voltage(&self) -> Option<u16> {
+    fn battery_voltage(&self) -> Option<u16> {
         self.current_can_msg()
             .map(|msg| msg.voltage_battery)
             .or(self.battery_voltage)
     }
-    pub fn battery_status(&self) -> Option<BatteryStatus>{
-        self.battery_voltage.map(|n| {
-            #[allow(overlapping_range_endpoints)] // we can't use experimental features yet
-            match n {
-                LOW_BATTERY_THRESHOLD.. => BatteryStatus::High,
-                NO_BATTERY_THRESHOLD..=LOW_BATTERY_THRESHOLD => BatteryStatus::Low,
-                _ => BatteryStatus::NoBatteryAttached
-            }
-        })
+    fn battery_status(&self) -> Option<BatteryStatus>{
+        self.battery_voltage.map(BatteryStatus::from_voltage)
     }
 
-    pub fn battery_current(&self) -> Option<i32> {
+    fn battery_current(&self) -> Option<i32> {
         self.current_can_msg()
             .map(|msg| msg.current)
             .or(self.battery_current)
     }
 
-    pub fn arm_voltage(&self) -> Option<u16> {
+    fn arm_voltage(&self) -> Option<u16> {
         self.arm_voltage
     }
 
-    pub fn armed(&self) -> bool {
-        self.arm_voltage.map(|v| v > 50).unwrap_or(false)
-    }
-
-    pub fn temperature(&self) -> Option<f32> {
+    fn temperature(&self) -> Option<f32> {
         self.temperature
     }
 
-    pub fn charge_voltage(&self) -> Option<u16> {
+    fn charge_voltage(&self) -> Option<u16> {
         self.current_can_msg().map(|msg| msg.voltage_charge)
     }
 
-    pub fn handle_battery_can_msg(&mut self, msg: BatteryTelemetryMessage) {
+    fn handle_battery_can_msg(&mut self, msg: BatteryTelemetryMessage) {
         self.last_can_message = Some(msg);
         self.time_last_can_msg = Some(Instant::now());
     }
-
-    // the time current, not the amperage current
-    fn current_can_msg<'a>(&'a self) -> Option<&'a BatteryTelemetryMessage> {
-        self.time_last_can_msg
-            .map(|i| i.elapsed().as_millis() < 250)?
-            .then_some(self.last_can_message.as_ref())
-            .flatten()
-    }
 }
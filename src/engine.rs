@@ -0,0 +1,276 @@
+//! Valve sequencing for hybrid motor static fires, for test setups without a dedicated engine
+//! controller. The oxidizer fill and vent valves are solenoids switched via GPIO, the main valve
+//! is a servo-actuated ball valve on servo channel 0, and the igniter is switched via GPIO.
+//!
+//! The controller runs as its own task and only acts on explicit commands, moving between these
+//! states:
+//!
+//! - `Safe`: fill and main closed, vent open (the vent solenoid is normally open)
+//! - `Filling`: fill open, vent closed
+//! - `Ready`: all valves closed, holding the filled tank
+//! - `Venting`: vent open, everything else closed
+//! - `Firing`: igniter on, main valve opened after the ignition delay, closed after the burn
+//! - `Aborted`: like `Venting`, but only left via an explicit `safe` command
+//!
+//! Filling and firing require arm voltage, and losing it aborts. Filling stops on its own after
+//! `MAX_FILL_DURATION`, and the tank is vented after every burn.
+//!
+//! Once the vehicle is ready for flight (ArmedLaunchImminent or later), i.e. for ignition on the
+//! pad or in flight, firing also requires the attitude estimate to be within the maximum ignition
+//! tilt (see `parameters.rs`) of vertical, as most flight safety codes demand. Every decision of
+//! this tilt gate is logged to flash by the vehicle. Static fires on the test stand, which may
+//! well be horizontal, are not gated.
+//!
+//! Commands are accepted from the USB console and, authenticated like any uplink message, from
+//! the ground station (see `Radio::queue_engine_command`).
+//!
+//! Only built with the `engine` feature, which requires the `servo` feature. Fill is on PA8 (free
+//! since the `servo` feature excludes APRS), vent on PB2 and the igniter on PB3.
+
+use core::cell::Cell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_stm32::gpio::Output;
+use embassy_stm32::peripherals::{PA8, PB2, PB3};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Ticker};
+use heapless::String;
+
+use defmt::*;
+
+use crate::flash_log::{LogNote, LOG_NOTE_LENGTH};
+use crate::servo;
+pub use crate::usb_console::EngineCommand;
+
+/// Servo channel of the main valve
+const MAIN_VALVE_SERVO: usize = 0;
+const MAIN_VALVE_OPEN: f32 = 1.0;
+const MAIN_VALVE_CLOSED: f32 = 0.0;
+
+/// Time (ms) after which filling stops if not stopped by the operator
+const MAX_FILL_DURATION: u64 = 120_000;
+/// Time (ms) the igniter is powered for
+const IGNITER_DURATION: u64 = 2_000;
+/// Time (ms) between powering the igniter and opening the main valve
+const IGNITION_DELAY: u64 = 500;
+/// Time (ms) the main valve stays open
+const BURN_DURATION: u64 = 8_000;
+
+const UPDATE_RATE: u64 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum EngineState {
+    Safe,
+    Filling,
+    Ready,
+    Venting,
+    Firing,
+    Aborted,
+}
+
+static COMMANDS: Channel<CriticalSectionRawMutex, EngineCommand, 4> = Channel::new();
+static STATE: Mutex<CriticalSectionRawMutex, Cell<EngineState>> = Mutex::new(Cell::new(EngineState::Safe));
+static ARMED: AtomicBool = AtomicBool::new(false);
+static ABORT: AtomicBool = AtomicBool::new(false);
+/// Tilt (deg) from vertical if ignition is tilt-gated, see `set_tilt`
+static TILT: Mutex<CriticalSectionRawMutex, Cell<Option<Option<f32>>>> = Mutex::new(Cell::new(None));
+static GATE_DECISIONS: Channel<CriticalSectionRawMutex, GateDecision, 2> = Channel::new();
+/// Angle (deg) from vertical beyond which ignition is inhibited in flight configurations
+static MAX_TILT: Mutex<CriticalSectionRawMutex, Cell<f32>> = Mutex::new(Cell::new(20.0));
+
+/// Outcome of the tilt gate for a fire command
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GateDecision {
+    /// Tilt (deg) from vertical at the time, if known
+    pub tilt: Option<f32>,
+    pub max_tilt: f32,
+    pub permitted: bool,
+}
+
+impl GateDecision {
+    /// Log entry documenting the decision
+    pub fn note(&self, time: u32) -> LogNote {
+        let mut text: String<LOG_NOTE_LENGTH> = String::new();
+        let verdict = if self.permitted { "permitted" } else { "inhibited" };
+        let _ = match self.tilt {
+            Some(tilt) => core::write!(text, "ignition {}, tilt {:.1}deg (max {:.1}deg)", verdict, tilt, self.max_tilt),
+            None => core::write!(text, "ignition {}, tilt unknown", verdict),
+        };
+        LogNote::new(time, &text)
+    }
+}
+
+/// Queues a command for the engine controller. Aborts bypass the queue, so they can't be lost.
+pub fn command(cmd: EngineCommand) {
+    if cmd == EngineCommand::Abort {
+        ABORT.store(true, Ordering::Relaxed);
+    } else if COMMANDS.try_send(cmd).is_err() {
+        warn!("Engine command queue full");
+    }
+}
+
+pub fn state() -> EngineState {
+    STATE.lock(|s| s.get())
+}
+
+/// Filling and firing are only possible while armed, losing arm voltage aborts.
+pub fn set_armed(armed: bool) {
+    ARMED.store(armed, Ordering::Relaxed);
+}
+
+/// Sets the tilt (deg) from vertical, if known, for flight configurations, or `None` to disable
+/// the tilt gate, e.g. for static fires.
+pub fn set_tilt(tilt: Option<Option<f32>>) {
+    TILT.lock(|t| t.set(tilt));
+}
+
+/// Sets the angle (deg) from vertical beyond which ignition is inhibited.
+pub fn set_max_tilt(max_tilt: f32) {
+    MAX_TILT.lock(|t| t.set(max_tilt));
+}
+
+/// Returns the next tilt gate decision to be logged, if any.
+pub fn take_gate_decision() -> Option<GateDecision> {
+    GATE_DECISIONS.try_receive().ok()
+}
+
+/// Applies the tilt gate to a fire command. Ignition is inhibited if the tilt is unknown.
+fn tilt_gate() -> bool {
+    let Some(tilt) = TILT.lock(|t| t.get()) else {
+        return true;
+    };
+
+    let max_tilt = MAX_TILT.lock(|t| t.get());
+    let permitted = tilt.map(|t| t <= max_tilt).unwrap_or(false);
+    if permitted {
+        info!("Engine: ignition permitted at {:?}deg tilt", tilt);
+    } else {
+        warn!("Engine: ignition inhibited at {:?}deg tilt", tilt);
+    }
+    if GATE_DECISIONS.try_send(GateDecision { tilt, max_tilt, permitted }).is_err() {
+        warn!("Engine gate decision queue full");
+    }
+
+    permitted
+}
+
+pub struct EngineController {
+    fill: Output<'static, PA8>,
+    vent: Output<'static, PB2>,
+    igniter: Output<'static, PB3>,
+    /// Time the current state was entered
+    since: Instant,
+    /// Last main valve state sent to the servo task
+    main_open: Option<bool>,
+}
+
+#[embassy_executor::task]
+pub async fn run(mut controller: EngineController) -> ! {
+    controller.run().await
+}
+
+impl EngineController {
+    pub fn init(fill: Output<'static, PA8>, vent: Output<'static, PB2>, igniter: Output<'static, PB3>) -> Self {
+        let mut controller = Self {
+            fill,
+            vent,
+            igniter,
+            since: Instant::now(),
+            main_open: None,
+        };
+        controller.set_outputs(EngineState::Safe);
+        controller
+    }
+
+    fn set_outputs(&mut self, state: EngineState) {
+        let elapsed = self.since.elapsed().as_millis();
+        let (fill, vent_closed, igniter, main) = match state {
+            EngineState::Filling => (true, true, false, false),
+            EngineState::Ready => (false, true, false, false),
+            EngineState::Firing => (
+                false,
+                true,
+                elapsed < IGNITER_DURATION,
+                elapsed >= IGNITION_DELAY && elapsed < IGNITION_DELAY + BURN_DURATION,
+            ),
+            EngineState::Safe | EngineState::Venting | EngineState::Aborted => (false, false, false, false),
+        };
+
+        self.fill.set_level(fill.into());
+        self.vent.set_level(vent_closed.into());
+        self.igniter.set_level(igniter.into());
+        if self.main_open != Some(main) {
+            servo::set(MAIN_VALVE_SERVO, if main { MAIN_VALVE_OPEN } else { MAIN_VALVE_CLOSED });
+            self.main_open = Some(main);
+        }
+    }
+
+    /// State resulting from a command, or `None` if the command is not permitted right now.
+    fn transition(state: EngineState, cmd: EngineCommand, armed: bool) -> Option<EngineState> {
+        use EngineState::*;
+
+        match (state, cmd) {
+            (_, EngineCommand::Abort) => Some(Aborted),
+            (Aborted, EngineCommand::Safe) => Some(Safe),
+            (Aborted, _) => None,
+            (Firing, _) => None,
+            (Safe | Ready | Venting, EngineCommand::Fill) if armed => Some(Filling),
+            (Filling, EngineCommand::Hold) => Some(Ready),
+            (Safe | Filling | Ready, EngineCommand::Vent) => Some(Venting),
+            (Ready, EngineCommand::Fire) if armed && tilt_gate() => Some(Firing),
+            (Venting, EngineCommand::Safe) => Some(Safe),
+            _ => None,
+        }
+    }
+
+    fn set_state(&mut self, old: EngineState, new: EngineState) {
+        if new != old {
+            info!("Engine: {:?} -> {:?}", old, new);
+            self.since = Instant::now();
+        }
+        STATE.lock(|s| s.set(new));
+    }
+
+    fn tick(&mut self) {
+        let armed = ARMED.load(Ordering::Relaxed);
+        let mut state = state();
+
+        if ABORT.swap(false, Ordering::Relaxed) {
+            self.set_state(state, EngineState::Aborted);
+            state = EngineState::Aborted;
+        }
+
+        while let Ok(cmd) = COMMANDS.try_receive() {
+            match Self::transition(state, cmd, armed) {
+                Some(new) => {
+                    self.set_state(state, new);
+                    state = new;
+                },
+                None => warn!("Engine: {:?} not permitted in {:?}", cmd, state),
+            }
+        }
+
+        // Interlocks and timeouts
+        let elapsed = self.since.elapsed().as_millis();
+        let new = match state {
+            EngineState::Filling | EngineState::Firing if !armed => EngineState::Aborted,
+            EngineState::Filling if elapsed > MAX_FILL_DURATION => EngineState::Ready,
+            EngineState::Firing if elapsed > IGNITION_DELAY + BURN_DURATION => EngineState::Venting,
+            _ => state,
+        };
+        self.set_state(state, new);
+
+        self.set_outputs(new);
+    }
+
+    async fn run(&mut self) -> ! {
+        let mut ticker = Ticker::every(Duration::from_hz(UPDATE_RATE));
+        loop {
+            self.tick();
+            ticker.next().await;
+        }
+    }
+}
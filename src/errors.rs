@@ -0,0 +1,267 @@
+//! Crate-wide error reporting. Instead of logging errors where they occur, subsystems report them
+//! to a channel, which is drained by the main loop once per iteration. There, errors are counted
+//! per subsystem and kind, and logged at most once per second per subsystem, so a failing
+//! peripheral can't flood the logs. On the vehicle, logged errors are also written to the flash
+//! log as notes (see `flash_log.rs`). The counts are available via the console's `status`
+//! command, and the per-subsystem totals are downlinked along with the radio diagnostics.
+//!
+//! Reporting never blocks and works from any task. If the channel overflows, the errors are still
+//! counted, but not logged.
+//!
+//! Subsystems and error kinds have fixed numeric codes, so errors can be identified by a pair of
+//! bytes in a downlink, with the names looked up on the host. Existing codes must not change, new
+//! ones are appended.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+
+use heapless::Deque;
+use serde::{Deserialize, Serialize};
+
+use defmt::*;
+
+use crate::clock::Instant;
+use crate::flash::FlashError;
+use crate::framing::FrameError;
+use crate::lora::RadioError;
+use crate::lora_packet::PacketError;
+
+/// Minimum time between log messages for the same subsystem (ms)
+const LOG_INTERVAL: u32 = 1000;
+
+const NUM_SUBSYSTEMS: usize = 7;
+const NUM_KINDS: usize = 13;
+/// Logged errors waiting to be taken by `ErrorMonitor::take_logged`
+const LOGGED_QUEUE_LENGTH: usize = 4;
+
+static ERROR_CHANNEL: Channel<CriticalSectionRawMutex, Error, 16> = Channel::new();
+/// Errors that didn't fit in the channel, per subsystem
+static OVERFLOW_COUNTS: [AtomicU32; NUM_SUBSYSTEMS] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+#[allow(dead_code)]
+pub enum Subsystem {
+    Radio = 0,
+    Flash = 1,
+    Can = 2,
+    Usb = 3,
+    Gps = 4,
+    Sensors = 5,
+    /// Heap allocations, see `heap.rs`
+    Memory = 6,
+}
+
+pub const SUBSYSTEMS: [Subsystem; NUM_SUBSYSTEMS] = [
+    Subsystem::Radio,
+    Subsystem::Flash,
+    Subsystem::Can,
+    Subsystem::Usb,
+    Subsystem::Gps,
+    Subsystem::Sensors,
+    Subsystem::Memory,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+#[allow(dead_code)]
+pub enum ErrorKind {
+    /// Error on the underlying bus (SPI, UART, USB)
+    Bus = 0,
+    Busy = 1,
+    Timeout = 2,
+    Crc = 3,
+    Overflow = 4,
+    /// A queue between tasks was full
+    QueueFull = 5,
+    Authentication = 6,
+    Serialization = 7,
+    Deserialization = 8,
+    /// Received data we don't know how to handle
+    Unsupported = 9,
+    /// Peer uses a different protocol version
+    VersionMismatch = 10,
+    /// An allocation failed, or an operation was skipped to avoid one
+    OutOfMemory = 11,
+    /// Data read back after writing didn't match what was written
+    Verification = 12,
+}
+
+pub const ERROR_KINDS: [ErrorKind; NUM_KINDS] = [
+    ErrorKind::Bus,
+    ErrorKind::Busy,
+    ErrorKind::Timeout,
+    ErrorKind::Crc,
+    ErrorKind::Overflow,
+    ErrorKind::QueueFull,
+    ErrorKind::Authentication,
+    ErrorKind::Serialization,
+    ErrorKind::Deserialization,
+    ErrorKind::Unsupported,
+    ErrorKind::VersionMismatch,
+    ErrorKind::OutOfMemory,
+    ErrorKind::Verification,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct Error {
+    pub subsystem: Subsystem,
+    pub kind: ErrorKind,
+    /// What was being done when the error occured
+    pub context: &'static str,
+}
+
+/// Reports an error. Never blocks.
+pub fn report(subsystem: Subsystem, kind: impl Into<ErrorKind>, context: &'static str) {
+    let error = Error { subsystem, kind: kind.into(), context };
+    if ERROR_CHANNEL.try_send(error).is_err() {
+        OVERFLOW_COUNTS[subsystem as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Number of errors per subsystem since startup, in the order of `SUBSYSTEMS`, saturating.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorCounts(pub [u16; NUM_SUBSYSTEMS]);
+
+/// Collects reported errors. Owned by the main loop.
+pub struct ErrorMonitor {
+    counts: [u32; NUM_SUBSYSTEMS],
+    /// Counts per subsystem and kind. Errors that didn't fit in the channel are missing here.
+    kind_counts: [[u32; NUM_KINDS]; NUM_SUBSYSTEMS],
+    last_logged: [Option<Instant>; NUM_SUBSYSTEMS],
+    /// Errors not logged due to rate limiting, since the last log message
+    suppressed: [u32; NUM_SUBSYSTEMS],
+    /// Logged errors and the number of errors suppressed before them, see `take_logged`
+    logged: Deque<(Error, u32), LOGGED_QUEUE_LENGTH>,
+}
+
+impl ErrorMonitor {
+    pub fn new() -> Self {
+        Self {
+            counts: [0; NUM_SUBSYSTEMS],
+            kind_counts: [[0; NUM_KINDS]; NUM_SUBSYSTEMS],
+            last_logged: [None; NUM_SUBSYSTEMS],
+            suppressed: [0; NUM_SUBSYSTEMS],
+            logged: Deque::new(),
+        }
+    }
+
+    pub fn tick(&mut self, time: Instant) {
+        for subsystem in SUBSYSTEMS {
+            let overflowed = OVERFLOW_COUNTS[subsystem as usize].swap(0, Ordering::Relaxed);
+            self.counts[subsystem as usize] += overflowed;
+            self.suppressed[subsystem as usize] += overflowed;
+        }
+
+        while let Ok(error) = ERROR_CHANNEL.try_receive() {
+            let i = error.subsystem as usize;
+            self.counts[i] += 1;
+            self.kind_counts[i][error.kind as usize] += 1;
+
+            let rate_limited = self.last_logged[i]
+                .map(|t| time.millis_since(t) < LOG_INTERVAL)
+                .unwrap_or(false);
+            if rate_limited {
+                self.suppressed[i] += 1;
+                continue;
+            }
+
+            if self.suppressed[i] > 0 {
+                error!("{}: {} ({}), {} more since last report", error.subsystem, error.context, error.kind, self.suppressed[i]);
+            } else {
+                error!("{}: {} ({})", error.subsystem, error.context, error.kind);
+            }
+
+            // Only the most recent errors are kept if they aren't taken.
+            if self.logged.is_full() {
+                self.logged.pop_front();
+            }
+            let _ = self.logged.push_back((error, self.suppressed[i]));
+
+            self.last_logged[i] = Some(time);
+            self.suppressed[i] = 0;
+        }
+    }
+
+    /// Total number of errors reported by a subsystem since startup.
+    pub fn count(&self, subsystem: Subsystem) -> u32 {
+        self.counts[subsystem as usize]
+    }
+
+    /// Number of errors of one kind reported by a subsystem since startup.
+    pub fn count_of(&self, subsystem: Subsystem, kind: ErrorKind) -> u32 {
+        self.kind_counts[subsystem as usize][kind as usize]
+    }
+
+    pub fn total(&self) -> u32 {
+        self.counts.iter().sum()
+    }
+
+    pub fn counts(&self) -> ErrorCounts {
+        ErrorCounts(self.counts.map(|count| count.min(u16::MAX as u32) as u16))
+    }
+
+    /// Returns the next error logged since the last call, along with the number of errors of the
+    /// same subsystem suppressed before it, e.g. to record it in the flash log.
+    pub fn take_logged(&mut self) -> Option<(Error, u32)> {
+        self.logged.pop_front()
+    }
+}
+
+impl<E> From<RadioError<E>> for ErrorKind {
+    fn from(e: RadioError<E>) -> Self {
+        match e {
+            RadioError::Spi(_) => Self::Bus,
+            RadioError::Crc => Self::Crc,
+            RadioError::Busy => Self::Busy,
+            RadioError::Timeout => Self::Timeout,
+            RadioError::Overflow => Self::Overflow,
+        }
+    }
+}
+
+impl<E> From<FlashError<E>> for ErrorKind {
+    fn from(e: FlashError<E>) -> Self {
+        match e {
+            FlashError::Spi(_) => Self::Bus,
+            FlashError::Serialization(_) => Self::Deserialization,
+            FlashError::Busy => Self::Busy,
+            FlashError::Crc => Self::Crc,
+            FlashError::Overflow => Self::Overflow,
+            FlashError::UnsupportedVersion(_) => Self::Deserialization,
+            FlashError::Verification => Self::Verification,
+        }
+    }
+}
+
+impl From<PacketError> for ErrorKind {
+    fn from(e: PacketError) -> Self {
+        match e {
+            PacketError::TooShort => Self::Deserialization,
+            PacketError::Authentication => Self::Authentication,
+            PacketError::Serialization => Self::Serialization,
+            PacketError::Deserialization => Self::Deserialization,
+            PacketError::Version(_) => Self::VersionMismatch,
+        }
+    }
+}
+
+impl From<FrameError> for ErrorKind {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::Overflow => Self::Overflow,
+            FrameError::Encoding => Self::Deserialization,
+            FrameError::Checksum => Self::Crc,
+            FrameError::Serialization => Self::Serialization,
+            FrameError::Version(_) => Self::VersionMismatch,
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Structured events for the ground software, derived from the received telemetry on the ground
+//! station, so the host can drive audio cues (or similar) without having to diff telemetry
+//! itself.
+//!
+//! Events are sent over USB using the regular framing (see `framing.rs`), with the payload
+//! prefixed by `EVENT_FRAME_TAG` to tell them apart from downlink messages. Like other binary
+//! messages, they are discarded while the console is in use.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use serde::Serialize;
+
+use defmt::*;
+
+use shared_types::*;
+
+use crate::countdown::CountdownStatus;
+use crate::downlink_loss::Gap;
+use crate::geofence::{GeofenceAction, GeofenceViolation};
+use crate::errors::{report, ErrorCounts, ErrorKind, Subsystem};
+use crate::flash::LoggingStatus;
+use crate::flash_log::TimeReference;
+use crate::flight_summary::FlightSummaryReport;
+use crate::lora_packet::DownlinkKind;
+use crate::mode_guard::ModeRejection;
+use crate::redundancy::RedundancyStatus;
+use crate::self_test::SelfTestResult;
+use crate::traits::BatteryStatus;
+use crate::version::Heartbeat;
+
+/// First payload byte of event frames. Never valid as the start of a serialized downlink message.
+pub const EVENT_FRAME_TAG: u8 = 0xfe;
+
+/// Events waiting to be sent via USB.
+pub static EVENT_CHANNEL: Channel<CriticalSectionRawMutex, GcsEvent, 8> = Channel::new();
+
+#[derive(Clone, Debug, Serialize)]
+pub enum GcsEvent {
+    /// A downlink message was received, with the vehicle's time (ms) and link quality
+    TelemetryReceived { time: u32, rssi: u8, snr: i8 },
+    /// The vehicle reported a different flight mode
+    ModeChange { from: Option<FlightMode>, to: FlightMode },
+    /// The vehicle passed apogee, with the maximum altitude (m ASL) if known
+    Apogee { altitude: Option<f32> },
+    /// The vehicle's battery voltage (mV) dropped below the low battery threshold
+    LowBattery { voltage: u16 },
+    /// Downlink packets of one kind were lost between the given vehicle times (ms), see
+    /// `downlink_loss.rs`. Sent before the message following the gap.
+    Gap { kind: DownlinkKind, lost: u32, since: u32, until: u32 },
+    /// The vehicle rejected a commanded mode change, see `mode_guard.rs`
+    ModeRejected(ModeRejection),
+    /// Progress or outcome of the vehicle's pre-launch countdown, see `countdown.rs`
+    Countdown(CountdownStatus),
+    /// The vehicle left the geofence, and what it did about it, see `geofence.rs`
+    Geofence { violation: GeofenceViolation, action: GeofenceAction },
+    /// The vehicle's firmware or configuration changed, and whether its firmware or link
+    /// configuration differs from the ground station's, see `version.rs`
+    Heartbeat { heartbeat: Heartbeat, mismatch: bool },
+    /// The vehicle reported the state of its flash log, and whether it has new write errors,
+    /// stalled while armed or is almost full
+    LoggingStatus { status: LoggingStatus, warning: bool },
+    /// The vehicle landed and downlinked its flight summary, see `flight_summary.rs`
+    FlightSummary(FlightSummaryReport),
+    /// The vehicle downlinked the result of a self test, see `self_test.rs`
+    SelfTest(SelfTestResult),
+    /// One of the vehicle's FCs reported a change of its partner's state, see `redundancy.rs`
+    Redundancy(RedundancyStatus),
+    /// The vehicle downlinked the UTC time at one of its vehicle times, see `flash_log.rs`
+    TimeReference(TimeReference),
+    /// The vehicle downlinked its error counts per subsystem along with its radio diagnostics,
+    /// see `errors.rs`
+    ErrorCounts(ErrorCounts),
+}
+
+/// Derives events from the downlink messages received by the ground station.
+pub struct EventMonitor {
+    mode: Option<FlightMode>,
+    altitude_max: Option<f32>,
+    battery_low: bool,
+}
+
+/// Queues an event to be sent via USB.
+fn emit(event: GcsEvent) {
+    if EVENT_CHANNEL.try_send(event).is_err() {
+        report(Subsystem::Usb, ErrorKind::QueueFull, "queueing event");
+    }
+}
+
+impl EventMonitor {
+    pub fn new() -> Self {
+        Self {
+            mode: None,
+            altitude_max: None,
+            battery_low: false,
+        }
+    }
+
+    pub fn tick(&mut self, msg: &DownlinkMessage, rssi: u8, snr: i8) {
+        emit(GcsEvent::TelemetryReceived { time: msg.time(), rssi, snr });
+
+        match msg {
+            DownlinkMessage::TelemetryMain(m) => {
+                self.altitude_max = Some(m.altitude_max);
+                self.update_mode(m.mode);
+            },
+            DownlinkMessage::TelemetryFastCompressed(m) => self.update_mode(m.mode),
+            DownlinkMessage::TelemetryDiagnostics(m) => {
+                let low = BatteryStatus::from_voltage(m.battery_voltage) == BatteryStatus::Low;
+                if low && !self.battery_low {
+                    emit(GcsEvent::LowBattery { voltage: m.battery_voltage });
+                }
+                self.battery_low = low;
+            },
+            _ => {},
+        }
+    }
+
+    pub fn gap(&mut self, gap: Gap) {
+        emit(GcsEvent::Gap { kind: gap.kind, lost: gap.lost, since: gap.since, until: gap.until });
+    }
+
+    pub fn mode_rejected(&mut self, rejection: ModeRejection) {
+        emit(GcsEvent::ModeRejected(rejection));
+    }
+
+    pub fn countdown(&mut self, status: CountdownStatus) {
+        emit(GcsEvent::Countdown(status));
+    }
+
+    pub fn geofence(&mut self, violation: GeofenceViolation, action: GeofenceAction) {
+        emit(GcsEvent::Geofence { violation, action });
+    }
+
+    pub fn heartbeat(&mut self, heartbeat: Heartbeat, mismatch: bool) {
+        emit(GcsEvent::Heartbeat { heartbeat, mismatch });
+    }
+
+    pub fn logging_status(&mut self, status: LoggingStatus, warning: bool) {
+        emit(GcsEvent::LoggingStatus { status, warning });
+    }
+
+    pub fn flight_summary(&mut self, summary: FlightSummaryReport) {
+        emit(GcsEvent::FlightSummary(summary));
+    }
+
+    pub fn self_test(&mut self, result: SelfTestResult) {
+        emit(GcsEvent::SelfTest(result));
+    }
+
+    pub fn redundancy(&mut self, status: RedundancyStatus) {
+        emit(GcsEvent::Redundancy(status));
+    }
+
+    pub fn time_reference(&mut self, reference: TimeReference) {
+        emit(GcsEvent::TimeReference(reference));
+    }
+
+    pub fn error_counts(&mut self, counts: ErrorCounts) {
+        emit(GcsEvent::ErrorCounts(counts));
+    }
+
+    fn update_mode(&mut self, mode: FlightMode) {
+        if self.mode == Some(mode) {
+            return;
+        }
+
+        debug!("Vehicle mode changed: {:?} -> {:?}", Debug2Format(&self.mode), Debug2Format(&mode));
+        emit(GcsEvent::ModeChange { from: self.mode, to: mode });
+
+        // Apogee is detected by the vehicle, which deploys the drogue in response. Skipped if the
+        // ground station only started listening during descent.
+        if mode == FlightMode::RecoveryDrogue && self.mode.map(|m| m < FlightMode::RecoveryDrogue).unwrap_or(false) {
+            emit(GcsEvent::Apogee { altitude: self.altitude_max });
+        }
+
+        self.mode = Some(mode);
+    }
+}
@@ -0,0 +1,263 @@
+//! Reusable filters for sensor readings, so drivers and the main loop don't need ad-hoc
+//! implementations of their own. All filters work on raw integer readings, and their sizes are
+//! fixed at compile time.
+//!
+//! IIR filters are implemented as fixed-point biquads (second-order sections). Coefficients are
+//! stored with `FRACTIONAL_BITS` fractional bits, leaving enough integer range for the feedback
+//! coefficients of low-pass filters with low cutoff frequencies. The rounding error of each
+//! output is carried over to the next one, otherwise filters with low cutoff frequencies get
+//! stuck short of their steady state. Note that the precision still suffers if the cutoff
+//! frequency is a very small fraction of the sample rate, in which case the samples should be
+//! decimated first, e.g. using a moving average.
+
+use heapless::{HistoryBuffer, Vec};
+use num_traits::Float;
+
+/// Fractional bits of the biquad coefficients
+const FRACTIONAL_BITS: u32 = 28;
+const ONE: f32 = (1 << FRACTIONAL_BITS) as f32;
+
+/// Quality factor of a second-order Butterworth filter, i.e. a maximally flat pass band
+pub const Q_BUTTERWORTH: f32 = core::f32::consts::FRAC_1_SQRT_2;
+
+/// Coefficients of a biquad, normalized so that a0 is 1.
+#[derive(Clone, Copy, Debug)]
+pub struct BiquadCoefficients {
+    b: [i32; 3],
+    a: [i32; 2],
+}
+
+impl BiquadCoefficients {
+    pub fn from_float(b: [f32; 3], a: [f32; 2]) -> Self {
+        let fixed = |c: f32| (c * ONE).round() as i32;
+        Self {
+            b: [fixed(b[0]), fixed(b[1]), fixed(b[2])],
+            a: [fixed(a[0]), fixed(a[1])],
+        }
+    }
+
+    /// Shared part of the low- and high-pass designs, returning cos(w0) and the normalized
+    /// feedback coefficients.
+    fn design(cutoff: f32, sample_rate: f32, q: f32) -> (f32, f32, [f32; 2]) {
+        let w0 = 2.0 * core::f32::consts::PI * cutoff / sample_rate;
+        let cos = w0.cos();
+        let alpha = w0.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        (cos, a0, [-2.0 * cos / a0, (1.0 - alpha) / a0])
+    }
+
+    /// Second-order low-pass with the given cutoff frequency and quality factor.
+    pub fn lowpass(cutoff: f32, sample_rate: f32, q: f32) -> Self {
+        let (cos, a0, a) = Self::design(cutoff, sample_rate, q);
+        let b = (1.0 - cos) / 2.0 / a0;
+        Self::from_float([b, 2.0 * b, b], a)
+    }
+
+    /// Second-order high-pass with the given cutoff frequency and quality factor.
+    pub fn highpass(cutoff: f32, sample_rate: f32, q: f32) -> Self {
+        let (cos, a0, a) = Self::design(cutoff, sample_rate, q);
+        let b = (1.0 + cos) / 2.0 / a0;
+        Self::from_float([b, -2.0 * b, b], a)
+    }
+}
+
+/// Fixed-point biquad in direct form I, accumulating in 64 bits.
+#[derive(Clone, Copy, Debug)]
+pub struct Biquad {
+    coefficients: BiquadCoefficients,
+    x: [i32; 2],
+    y: [i32; 2],
+    /// Rounding error of the last output, with `FRACTIONAL_BITS` fractional bits
+    error: i64,
+    initialized: bool,
+}
+
+impl Biquad {
+    pub fn new(coefficients: BiquadCoefficients) -> Self {
+        Self { coefficients, x: [0; 2], y: [0; 2], error: 0, initialized: false }
+    }
+
+    pub fn filter(&mut self, input: i32) -> i32 {
+        // Start in the steady state for the first input, instead of ramping up from zero.
+        if !self.initialized {
+            self.reset(input);
+        }
+
+        let BiquadCoefficients { b, a } = self.coefficients;
+        let acc = b[0] as i64 * input as i64
+            + b[1] as i64 * self.x[0] as i64
+            + b[2] as i64 * self.x[1] as i64
+            - a[0] as i64 * self.y[0] as i64
+            - a[1] as i64 * self.y[1] as i64
+            + self.error;
+        // Round to nearest instead of towards negative infinity.
+        let output = ((acc + (1 << (FRACTIONAL_BITS - 1))) >> FRACTIONAL_BITS) as i32;
+        self.error = acc - ((output as i64) << FRACTIONAL_BITS);
+
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+
+    /// Sets the filter's history as if it had been fed the given value for a long time. This is
+    /// only exact for low-pass filters, other filters will settle after a few samples.
+    pub fn reset(&mut self, value: i32) {
+        self.x = [value; 2];
+        self.y = [value; 2];
+        self.error = 0;
+        self.initialized = true;
+    }
+}
+
+/// Several biquads in series, for higher-order filters.
+#[derive(Clone, Copy, Debug)]
+pub struct BiquadCascade<const N: usize> {
+    stages: [Biquad; N],
+}
+
+impl<const N: usize> BiquadCascade<N> {
+    pub fn new(coefficients: [BiquadCoefficients; N]) -> Self {
+        Self { stages: coefficients.map(Biquad::new) }
+    }
+
+    pub fn filter(&mut self, input: i32) -> i32 {
+        self.stages.iter_mut().fold(input, |value, stage| stage.filter(value))
+    }
+}
+
+/// Average of the last N values.
+pub struct MovingAverage<const N: usize> {
+    history: HistoryBuffer<i32, N>,
+    sum: i64,
+}
+
+impl<const N: usize> MovingAverage<N> {
+    pub fn new() -> Self {
+        Self { history: HistoryBuffer::new(), sum: 0 }
+    }
+
+    /// Adds a value, returning the average of the values added so far, at most N.
+    pub fn filter(&mut self, input: i32) -> i32 {
+        if self.history.len() == N {
+            self.sum -= self.history.oldest_ordered().next().copied().unwrap_or(0) as i64;
+        }
+        self.history.write(input);
+        self.sum += input as i64;
+
+        (self.sum / self.history.len() as i64) as i32
+    }
+
+    /// Average of the last N values, or None if fewer have been added.
+    pub fn value(&self) -> Option<i32> {
+        (self.history.len() == N).then(|| (self.sum / N as i64) as i32)
+    }
+}
+
+impl<const N: usize> Default for MovingAverage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Median of the last N values, which removes short spikes while keeping edges intact.
+pub struct MedianFilter<const N: usize> {
+    history: HistoryBuffer<i32, N>,
+}
+
+impl<const N: usize> MedianFilter<N> {
+    pub fn new() -> Self {
+        Self { history: HistoryBuffer::new() }
+    }
+
+    /// Adds a value, returning the median of the values added so far, at most N.
+    pub fn filter(&mut self, input: i32) -> i32 {
+        self.history.write(input);
+
+        let mut sorted: Vec<i32, N> = self.history.iter().copied().collect();
+        sorted.sort_unstable();
+        sorted[sorted.len() / 2]
+    }
+
+    /// Difference between the largest and smallest of the last N values
+    pub fn spread(&self) -> i32 {
+        let min = self.history.iter().min().copied().unwrap_or(0);
+        let max = self.history.iter().max().copied().unwrap_or(0);
+        max - min
+    }
+}
+
+impl<const N: usize> Default for MedianFilter<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 1000.0;
+
+    fn response(filter: &mut Biquad, input: impl Fn(usize) -> i32, samples: usize) -> std::vec::Vec<i32> {
+        (0..samples).map(|i| filter.filter(input(i))).collect()
+    }
+
+    #[test]
+    fn lowpass_step_response() {
+        let mut lowpass = Biquad::new(BiquadCoefficients::lowpass(10.0, SAMPLE_RATE, Q_BUTTERWORTH));
+        let output = response(&mut lowpass, |i| if i == 0 { 0 } else { 10_000 }, 1000);
+
+        // A second-order Butterworth filter overshoots by about 4.3%, then settles at unity gain.
+        let max = *output.iter().max().unwrap();
+        assert!(max > 10_300 && max < 10_500, "{}", max);
+        assert!(output[1] < 100);
+        assert!(output[500..].iter().all(|y| (y - 10_000).abs() <= 1));
+    }
+
+    #[test]
+    fn lowpass_attenuation() {
+        // 100Hz is a decade above the cutoff, i.e. attenuated by 40dB.
+        let mut lowpass = Biquad::new(BiquadCoefficients::lowpass(10.0, SAMPLE_RATE, Q_BUTTERWORTH));
+        let sine = |i: usize| (10_000.0 * (2.0 * core::f32::consts::PI * 100.0 * i as f32 / SAMPLE_RATE).sin()) as i32;
+        let output = response(&mut lowpass, sine, 1000);
+
+        let amplitude = output[500..].iter().map(|y| y.abs()).max().unwrap();
+        assert!(amplitude > 80 && amplitude < 120, "{}", amplitude);
+    }
+
+    #[test]
+    fn highpass_removes_offset() {
+        let mut highpass = Biquad::new(BiquadCoefficients::highpass(1.0, SAMPLE_RATE, Q_BUTTERWORTH));
+        let output = response(&mut highpass, |i| if i == 0 { 0 } else { 10_000 }, 5000);
+
+        assert!(output[1] > 9_900);
+        assert!(output[4000..].iter().all(|y| y.abs() <= 1));
+    }
+
+    #[test]
+    fn cascade_starts_settled() {
+        let coefficients = BiquadCoefficients::lowpass(10.0, SAMPLE_RATE, Q_BUTTERWORTH);
+        let mut cascade = BiquadCascade::new([coefficients; 2]);
+        assert!((0..100).all(|_| cascade.filter(-1234) == -1234));
+    }
+
+    #[test]
+    fn moving_average() {
+        let mut average = MovingAverage::<4>::new();
+        assert_eq!(average.filter(4), 4);
+        assert_eq!(average.filter(8), 6);
+        assert_eq!(average.value(), None);
+        average.filter(0);
+        average.filter(0);
+        assert_eq!(average.value(), Some(3));
+        assert_eq!(average.filter(12), 5);
+    }
+
+    #[test]
+    fn median_removes_spikes() {
+        let mut median = MedianFilter::<5>::new();
+        let output: std::vec::Vec<i32> = [10, 10, 500, 10, 10, 20, 20, 20].iter().map(|x| median.filter(*x)).collect();
+        assert_eq!(output, [10, 10, 10, 10, 10, 10, 20, 20]);
+        assert_eq!(median.spread(), 10);
+    }
+}
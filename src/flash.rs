@@ -1,12 +1,29 @@
 //! Flash storage implementation
 //!
-//! The first sector (4KiB) of the flash memory is reserved for storing settings, the rest is used
-//! for telemetry messages. Telemetry messages are buffered and written to memory in pages (256B).
+//! The first sector (4KiB) of the flash memory is reserved for storing settings, the last one for
+//! the summary of the last flight (see `flight_summary.rs`), the one before it for the sensor
+//! calibration (see `calibration.rs`), and the one before that for the LoRa link configuration
+//! (see `lora.rs`). The rest is used for telemetry messages. Telemetry messages
+//! are buffered and written to memory in pages (256B), see `flash_log.rs` for the format.
+//!
+//! The end of the flash depends on the chip that is fitted, so the reserved sectors are located
+//! using the geometry reported by the driver (see `drivers/flash.rs`). The sector before the link
+//! configuration holds the wear table, and the one before that the firmware parameters (see
+//! `parameters.rs`). Records in the reserved sectors are written to successive slots, and bad log
+//! sectors are skipped, see `flash_wear.rs`.
+//!
+//! During shock events, when the supply may sag (see `shock.rs`), page writes are deferred and
+//! the log is buffered in RAM instead, up to `DEFERRAL_PAGES` pages. The backlog is written one
+//! page per record afterwards, so catching up doesn't block the request queue.
 //!
 //! For reading, the flash implementation holds its own handle to the USB connection, which allows
 //! faster reading of flash.
 
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use heapless::Vec;
+use serde::{Deserialize, Serialize};
 
 use embassy_stm32::gpio::Output;
 use embassy_stm32::peripherals::*;
@@ -16,7 +33,6 @@ use embassy_sync::channel::{Channel, Receiver, Sender};
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice as SpiDeviceImpl;
 use embassy_sync::signal::Signal;
 use embassy_time::{Duration, Timer};
-use embedded_hal_async::spi::SpiDevice;
 
 use crc::{Crc, CRC_16_IBM_SDLC};
 use static_cell::StaticCell;
@@ -25,14 +41,40 @@ use defmt::*;
 
 use shared_types::*;
 
-use crate::drivers::flash::W25Q;
+use crate::drivers::flash::{NorFlash, SpiNorFlash};
+use crate::errors::{report, ErrorKind, Subsystem};
+#[cfg(not(feature = "gcs"))]
+use crate::calibration::{SensorCalibration, CALIBRATION_VERSION};
+#[cfg(not(feature = "gcs"))]
+use crate::flight_summary::FlightSummary;
+#[cfg(not(feature = "gcs"))]
+use crate::flash_log::{LogNote, TimeReference, LOG_NOTE_TAG, RADIO_DIAGNOSTICS_TAG, TIME_REFERENCE_TAG};
+use crate::flash_log::PAGE_SIZE;
+use crate::flash_wear::{Region, WearTable, WEAR_TABLE_VERSION};
+#[cfg(not(feature = "gcs"))]
+use crate::lora::LinkConfig;
+#[cfg(not(feature = "gcs"))]
+use crate::parameters::{FirmwareParameters, PARAMETERS_VERSION};
+#[cfg(not(feature = "gcs"))]
+use crate::radio_diagnostics::RadioDiagnostics;
+use crate::traits::LogStorage;
 use crate::usb::FlashUsbHandle;
+use crate::usb_console::ConsoleLine;
 
 const X25: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
 
-const PAGE_SIZE: usize = 256;
-const BUFFER_SIZE: usize = PAGE_SIZE * 2;
-const SECTOR_SIZE: u32 = 4096;
+/// Pages of log data held back in RAM while writes are deferred
+const DEFERRAL_PAGES: usize = 16;
+const BUFFER_SIZE: usize = PAGE_SIZE * (2 + DEFERRAL_PAGES);
+/// Largest serialized telemetry message, including COBS overhead and delimiter.
+const MAX_MESSAGE_SIZE: usize = PAGE_SIZE;
+/// Sectors reserved at the end of the flash, counted from the end.
+const FLIGHT_SUMMARY_SECTOR: u32 = 1;
+const CALIBRATION_SECTOR: u32 = 2;
+const LINK_CONFIG_SECTOR: u32 = 3;
+const WEAR_SECTOR: u32 = 4;
+/// The parameter sector also marks the end of the log.
+const PARAMETERS_SECTOR: u32 = 5;
 
 static REQUEST_CHANNEL: StaticCell<Channel::<CriticalSectionRawMutex, FlashRequest, 3>> = StaticCell::new();
 
@@ -40,29 +82,82 @@ static REQUEST_CHANNEL: StaticCell<Channel::<CriticalSectionRawMutex, FlashReque
 /// is probably a better way to do this.
 static FLASH_POINTER_SIGNAL: Signal<CriticalSectionRawMutex, u32> = Signal::new();
 
+/// Signal for passing the result of a self test back to the flash handle.
+static SELF_TEST_SIGNAL: Signal<CriticalSectionRawMutex, (bool, bool)> = Signal::new();
+
+/// Whether log page writes are currently deferred. Set directly instead of via a request, so it
+/// takes effect ahead of any queued messages.
+static WRITES_DEFERRED: AtomicBool = AtomicBool::new(false);
+
 /// Request sent to background flash task.
 enum FlashRequest {
     WriteMessage(DownlinkMessage),
     WriteSettings(Settings),
     Read(u32, u32),
+    Dump(u32, u32),
     Erase,
+    SelfTest,
+    #[cfg(not(feature = "gcs"))]
+    WriteFlightSummary(FlightSummary),
+    #[cfg(not(feature = "gcs"))]
+    PrintFlightSummary,
+    #[cfg(not(feature = "gcs"))]
+    WriteNote(LogNote),
+    #[cfg(not(feature = "gcs"))]
+    WriteRadioDiagnostics(u32, RadioDiagnostics),
+    #[cfg(not(feature = "gcs"))]
+    WriteTimeReference(TimeReference),
+    #[cfg(not(feature = "gcs"))]
+    WriteCalibration(SensorCalibration),
+    #[cfg(not(feature = "gcs"))]
+    PrintCalibration,
+    #[cfg(not(feature = "gcs"))]
+    ClearCalibration,
+    #[cfg(not(feature = "gcs"))]
+    WriteLinkConfig(LinkConfig),
+    #[cfg(not(feature = "gcs"))]
+    WriteParameters(FirmwareParameters),
+    #[cfg(not(feature = "gcs"))]
+    PrintWear,
 }
 
 /// Main flash struct. This is moved to a background task and handles interaction with the physical
 /// flash chip.
-pub struct Flash<SPI> {
+pub struct Flash<F> {
     request_receiver: Receiver<'static, CriticalSectionRawMutex, FlashRequest, 3>,
-    driver: W25Q<SPI>,
+    driver: F,
     usb: FlashUsbHandle,
     pointer: u32,
     write_buffer: Vec<u8, BUFFER_SIZE>,
+    wear: WearTable,
+    /// Whether the wear table has changed since it was last written
+    wear_changed: bool,
+    /// Whether records are read back after writing them, and how many slots are tried
+    verify_records: bool,
+    record_write_attempts: u32,
 }
 
 /// Flash handle returned by initialization and used by the rest of the firmware to interact with
 /// the flash.
 pub struct FlashHandle {
     request_sender: Sender<'static, CriticalSectionRawMutex, FlashRequest, 3>,
-    pub pointer: u32,
+    pointer: u32,
+    size: u32,
+    sector_size: u32,
+}
+
+/// State of the flash log, downlinked periodically so the GCS can warn if the flash fills up or
+/// logging stalls, see `Radio::send_logging_status`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub struct LoggingStatus {
+    /// Log data written so far (bytes)
+    pub bytes_written: u32,
+    pub sectors_used: u16,
+    pub sectors_free: u16,
+    /// Flash errors since startup, see `errors.rs`
+    pub write_errors: u16,
+    /// Growth of the log over the last second (bytes/s)
+    pub rate: u16,
 }
 
 #[derive(Debug)]
@@ -72,6 +167,11 @@ pub enum FlashError<E> {
     Serialization(postcard::Error),
     Busy,
     Crc,
+    Overflow,
+    /// Stored data uses a schema version this firmware can't read
+    UnsupportedVersion(u8),
+    /// Data still didn't read back correctly after rewriting it
+    Verification,
 }
 
 impl<E: Sized> From<E> for FlashError<E> {
@@ -83,42 +183,132 @@ impl<E: Sized> From<E> for FlashError<E> {
 // Embassy tasks cannot be generic for some reason, so for now we have to have these ugly type
 // signatures and a task outside of the struct here.
 type SpiInst = Spi<'static, SPI3, DMA1_CH7, DMA1_CH0>;
-type FlashInst = Flash<SpiDeviceImpl<'static, CriticalSectionRawMutex, SpiInst, Output<'static, PD2>>>;
+type FlashInst = Flash<SpiNorFlash<SpiDeviceImpl<'static, CriticalSectionRawMutex, SpiInst, Output<'static, PD2>>>>;
 
 #[embassy_executor::task]
 pub async fn run(mut flash: FlashInst) -> ! {
     flash.run().await
 }
 
-impl FlashHandle {
-    pub async fn tick(&mut self) {
+impl LogStorage for FlashHandle {
+    async fn tick(&mut self) {
         if FLASH_POINTER_SIGNAL.signaled() {
             self.pointer = FLASH_POINTER_SIGNAL.wait().await;
         }
     }
 
-    pub fn write_settings(&mut self, settings: Settings) -> Result<(), ()> {
+    fn pointer(&self) -> u32 {
+        self.pointer
+    }
+
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn write_settings(&mut self, settings: Settings) -> Result<(), ()> {
         self.request_sender.try_send(FlashRequest::WriteSettings(settings)).map_err(|_e| ())
     }
 
-    pub fn write_message(&mut self, msg: DownlinkMessage) -> Result<(), ()> {
+    fn write_message(&mut self, msg: DownlinkMessage) -> Result<(), ()> {
         self.request_sender.try_send(FlashRequest::WriteMessage(msg)).map_err(|_e| ())
     }
 
-    pub fn read(&mut self, address: u32, size: u32) -> Result<(), ()> {
+    fn read(&mut self, address: u32, size: u32) -> Result<(), ()> {
         self.request_sender.try_send(FlashRequest::Read(address, size)).map_err(|_e| ())
     }
 
-    pub fn erase(&mut self) -> Result<(), ()>{
+    fn dump(&mut self, address: u32, size: u32) -> Result<(), ()> {
+        self.request_sender.try_send(FlashRequest::Dump(address, size)).map_err(|_e| ())
+    }
+
+    fn erase(&mut self) -> Result<(), ()>{
         self.request_sender.try_send(FlashRequest::Erase).map_err(|_e| ())
     }
+
+    fn self_test(&mut self) -> Result<(), ()> {
+        SELF_TEST_SIGNAL.reset();
+        self.request_sender.try_send(FlashRequest::SelfTest).map_err(|_e| ())
+    }
+
+    fn self_test_result(&mut self) -> Option<(bool, bool)> {
+        SELF_TEST_SIGNAL.try_take()
+    }
 }
 
-impl<SPI: SpiDevice> Flash<SPI> {
-    pub async fn init(spi: SPI, usb: FlashUsbHandle) -> Result<(Self, FlashHandle, Settings), FlashError<SPI::Error>> {
-        let request_channel = REQUEST_CHANNEL.init(Channel::new());
+#[cfg(not(feature = "gcs"))]
+impl FlashHandle {
+    pub fn write_flight_summary(&mut self, summary: FlightSummary) -> Result<(), ()> {
+        self.request_sender.try_send(FlashRequest::WriteFlightSummary(summary)).map_err(|_e| ())
+    }
+
+    /// Prints the stored flight summary on the USB console.
+    pub fn print_flight_summary(&mut self) -> Result<(), ()> {
+        self.request_sender.try_send(FlashRequest::PrintFlightSummary).map_err(|_e| ())
+    }
+
+    /// Current state of the log, with the given error count and logging rate (bytes/s).
+    pub fn logging_status(&self, write_errors: u32, rate: u32) -> LoggingStatus {
+        LoggingStatus {
+            bytes_written: self.pointer,
+            sectors_used: self.pointer.div_ceil(self.sector_size) as u16,
+            sectors_free: (self.size.saturating_sub(self.pointer) / self.sector_size) as u16,
+            write_errors: write_errors.min(u16::MAX as u32) as u16,
+            rate: rate.min(u16::MAX as u32) as u16,
+        }
+    }
+
+    /// Appends an operator note to the log.
+    pub fn write_note(&mut self, note: LogNote) -> Result<(), ()> {
+        self.request_sender.try_send(FlashRequest::WriteNote(note)).map_err(|_e| ())
+    }
 
-        let driver = W25Q::init(spi).await?;
+    /// Appends diagnostics of the LoRa transceiver, read at the given vehicle time (ms), to the
+    /// log.
+    pub fn write_radio_diagnostics(&mut self, time: u32, diagnostics: RadioDiagnostics) -> Result<(), ()> {
+        self.request_sender.try_send(FlashRequest::WriteRadioDiagnostics(time, diagnostics)).map_err(|_e| ())
+    }
+
+    /// Appends the time reference to the log, see `flash_log::TimeReference`.
+    pub fn write_time_reference(&mut self, reference: TimeReference) -> Result<(), ()> {
+        self.request_sender.try_send(FlashRequest::WriteTimeReference(reference)).map_err(|_e| ())
+    }
+
+    pub fn write_calibration(&mut self, calibration: SensorCalibration) -> Result<(), ()> {
+        self.request_sender.try_send(FlashRequest::WriteCalibration(calibration)).map_err(|_e| ())
+    }
+
+    /// Prints the stored sensor calibration on the USB console.
+    pub fn print_calibration(&mut self) -> Result<(), ()> {
+        self.request_sender.try_send(FlashRequest::PrintCalibration).map_err(|_e| ())
+    }
+
+    /// Resets the stored sensor calibration to defaults.
+    pub fn clear_calibration(&mut self) -> Result<(), ()> {
+        self.request_sender.try_send(FlashRequest::ClearCalibration).map_err(|_e| ())
+    }
+
+    pub fn write_link_config(&mut self, link: LinkConfig) -> Result<(), ()> {
+        self.request_sender.try_send(FlashRequest::WriteLinkConfig(link)).map_err(|_e| ())
+    }
+
+    pub fn write_parameters(&mut self, parameters: FirmwareParameters) -> Result<(), ()> {
+        self.request_sender.try_send(FlashRequest::WriteParameters(parameters)).map_err(|_e| ())
+    }
+
+    /// Prints erase counts and bad sectors on the USB console.
+    pub fn print_wear(&mut self) -> Result<(), ()> {
+        self.request_sender.try_send(FlashRequest::PrintWear).map_err(|_e| ())
+    }
+
+    /// Holds back log page writes in RAM while set, e.g. during shock events.
+    pub fn defer_writes(&mut self, deferred: bool) {
+        WRITES_DEFERRED.store(deferred, Ordering::Relaxed);
+    }
+}
+
+impl<F: NorFlash> Flash<F> {
+    pub async fn init(driver: F, usb: FlashUsbHandle) -> Result<(Self, FlashHandle, Settings), FlashError<F::Error>> {
+        let request_channel = REQUEST_CHANNEL.init(Channel::new());
 
         let mut flash = Self {
             request_receiver: request_channel.receiver(),
@@ -126,6 +316,19 @@ impl<SPI: SpiDevice> Flash<SPI> {
             usb,
             pointer: 0,
             write_buffer: Vec::new(),
+            wear: WearTable::default(),
+            wear_changed: false,
+            verify_records: true,
+            record_write_attempts: 3,
+        };
+
+        flash.wear = match flash.read_wear_table().await {
+            Ok(wear) => wear,
+            Err(FlashError::Crc) => WearTable::default(),
+            Err(e) => {
+                error!("Failed to read wear table from flash ({:?}).", Debug2Format(&e));
+                WearTable::default()
+            }
         };
 
         flash.determine_pointer().await?;
@@ -147,18 +350,55 @@ impl<SPI: SpiDevice> Flash<SPI> {
         let flash_handle = FlashHandle {
             request_sender: request_channel.sender(),
             pointer: flash.pointer,
+            size: flash.driver.geometry().size,
+            sector_size: flash.driver.geometry().sector_size,
         };
 
         Ok((flash, flash_handle, settings))
     }
 
-    async fn determine_pointer(&mut self) -> Result<(), FlashError<SPI::Error>> {
+    /// Start of the given reserved sector, counted from the end of the flash.
+    fn reserved_sector(&self, sector: u32) -> u32 {
+        let geometry = self.driver.geometry();
+        geometry.size - sector * geometry.sector_size
+    }
+
+    fn log_end(&self) -> u32 {
+        self.reserved_sector(PARAMETERS_SECTOR)
+    }
+
+    fn region_address(&self, region: Region) -> u32 {
+        match region {
+            Region::Settings => 0x00,
+            Region::FlightSummary => self.reserved_sector(FLIGHT_SUMMARY_SECTOR),
+            Region::Calibration => self.reserved_sector(CALIBRATION_SECTOR),
+            Region::LinkConfig => self.reserved_sector(LINK_CONFIG_SECTOR),
+            Region::Wear => self.reserved_sector(WEAR_SECTOR),
+            Region::Parameters => self.reserved_sector(PARAMETERS_SECTOR),
+        }
+    }
+
+    fn slot_address(&self, region: Region, slot: u32) -> u32 {
+        self.region_address(region) + slot * region.slot_size()
+    }
+
+    fn slots(&self, region: Region) -> u32 {
+        self.driver.geometry().sector_size / region.slot_size()
+    }
+
+    /// Whether the sector containing the given address is known to be bad
+    fn in_bad_sector(&self, address: u32) -> bool {
+        self.wear.is_bad(address / self.driver.geometry().sector_size)
+    }
+
+    async fn determine_pointer(&mut self) -> Result<(), FlashError<F::Error>> {
         // Determine first unwritten page by binary search
-        let (mut a, mut b) = (FLASH_HEADER_SIZE, self.driver.size());
+        let (mut a, mut b) = (FLASH_HEADER_SIZE, self.log_end());
         while b - a > 2*PAGE_SIZE as u32 {
             let mid = (a + b) / 2;
             let mid = mid - (mid % PAGE_SIZE as u32);
-            if self.driver.read(mid, 1).await?[0] == 0xff {
+            // Bad sectors may contain unwritten pages, but are followed by more of the log.
+            if !self.in_bad_sector(mid) && self.driver.read(mid, 1).await?[0] == 0xff {
                 b = mid;
             } else {
                 a = mid;
@@ -175,146 +415,565 @@ impl<SPI: SpiDevice> Flash<SPI> {
         FLASH_POINTER_SIGNAL.signal(self.pointer);
     }
 
-    async fn flush_page(&mut self) -> Result<(), FlashError<SPI::Error>> {
+    async fn flush_page(&mut self) -> Result<(), FlashError<F::Error>> {
         // We're full, do nothing
-        if self.pointer >= FLASH_SIZE {
+        if self.pointer >= self.log_end() {
             return Ok(());
         }
 
-        let result = {
-            let data = &self.write_buffer[..(PAGE_SIZE - 3)];
-            let crc = X25.checksum(&data);
+        let data = &self.write_buffer[..(PAGE_SIZE - 3)];
+        let crc = X25.checksum(&data);
 
-            let mut page = [0x00; PAGE_SIZE];
-            page[1..PAGE_SIZE-2].copy_from_slice(data);
-            page[PAGE_SIZE-2] = (crc >> 8) as u8;
-            page[PAGE_SIZE-1] = crc as u8;
+        let mut page = [0x00; PAGE_SIZE];
+        page[1..PAGE_SIZE-2].copy_from_slice(data);
+        page[PAGE_SIZE-2] = (crc >> 8) as u8;
+        page[PAGE_SIZE-1] = crc as u8;
 
-            const CHUNK_SIZE: usize = 32;
+        // A page that doesn't read back correctly is written again in the next sector.
+        let result = loop {
+            while self.pointer < self.log_end() && self.in_bad_sector(self.pointer) {
+                self.skip_sector().await;
+            }
 
-            let mut result = Ok(());
-            for i in 0..(PAGE_SIZE/CHUNK_SIZE) {
-                let chunk = &page[(i*CHUNK_SIZE)..((i+1)*CHUNK_SIZE)];
+            if self.pointer >= self.log_end() {
+                return Ok(());
+            }
 
-                for attempt in 0..3 {
-                    result = self.driver.write((self.pointer as usize) + (i*CHUNK_SIZE), &chunk).await;
-                    if result.is_err() {
-                        continue;
-                    }
+            match self.write_page(&page).await {
+                Ok(false) => self.mark_bad_sector().await,
+                result => {
+                    self.update_pointer(self.pointer + PAGE_SIZE as u32);
+                    break result.map(|_| ());
+                }
+            }
+        };
 
-                    let read_back = self.driver.read(self.pointer + (i*CHUNK_SIZE) as u32, CHUNK_SIZE as u32).await;
-                    if read_back.map(|b| b == chunk).unwrap_or(false) {
-                        break;
-                    }
+        // Shifted in place, a copy of the buffer would be too large for the stack.
+        let remaining = self.write_buffer.len() - (PAGE_SIZE - 3);
+        self.write_buffer.rotate_left(PAGE_SIZE - 3);
+        self.write_buffer.truncate(remaining);
 
-                    Timer::after(Duration::from_micros(100)).await;
+        result
+    }
 
-                    defmt::warn!("Chunk write 0x{:02x}+0x{} failed. ({}/{})", self.pointer, i*CHUNK_SIZE, attempt+1, 3);
-                }
+    /// Writes a log page at the pointer in chunks, retrying each one a few times. Returns whether
+    /// the page reads back correctly. Failed reads are returned as errors rather than as a
+    /// mismatch, so they don't get a healthy sector marked as bad.
+    async fn write_page(&mut self, page: &[u8; PAGE_SIZE]) -> Result<bool, FlashError<F::Error>> {
+        const CHUNK_SIZE: usize = 32;
 
+        for i in 0..(PAGE_SIZE/CHUNK_SIZE) {
+            let chunk = &page[(i*CHUNK_SIZE)..((i+1)*CHUNK_SIZE)];
+
+            let mut result = Ok(false);
+            for attempt in 0..3 {
+                result = self.driver.write((self.pointer as usize) + (i*CHUNK_SIZE), &chunk).await.map(|_| false);
                 if result.is_err() {
-                    break;
+                    continue;
+                }
+
+                // The driver only waits briefly for the program to finish, and reads fail while the
+                // flash is still busy.
+                self.wait_until_ready(10).await;
+                match self.driver.read(self.pointer + (i*CHUNK_SIZE) as u32, CHUNK_SIZE as u32).await {
+                    Ok(read_back) if read_back == chunk => {
+                        result = Ok(true);
+                        break;
+                    },
+                    Ok(_) => {},
+                    Err(e) => {
+                        result = Err(e);
+                        continue;
+                    }
                 }
+
+                Timer::after(Duration::from_micros(100)).await;
+
+                report(Subsystem::Flash, ErrorKind::Verification, "verifying log page");
+                defmt::warn!("Chunk write 0x{:02x}+0x{} failed. ({}/{})", self.pointer, i*CHUNK_SIZE, attempt+1, 3);
             }
 
-            self.update_pointer(self.pointer + PAGE_SIZE as u32);
-            result
-        };
+            match result {
+                Ok(true) => {},
+                result => return result,
+            }
+        }
 
-        let mut new_buffer: Vec<u8, BUFFER_SIZE> = Vec::new();
-        let _ = new_buffer.extend_from_slice(&self.write_buffer[(PAGE_SIZE - 3)..]);
-        self.write_buffer = new_buffer;
+        Ok(true)
+    }
 
-        result
+    /// Records the sector at the pointer as bad after a page failed verification, and continues
+    /// the log in the next sector.
+    async fn mark_bad_sector(&mut self) {
+        let sector_size = self.driver.geometry().sector_size;
+        warn!("Flash sector 0x{:08x} failed verification, skipping it.", self.pointer - self.pointer % sector_size);
+
+        self.wear.write_failures += 1;
+        if !self.wear.mark_bad(self.pointer / sector_size) {
+            report(Subsystem::Flash, ErrorKind::Overflow, "recording bad sector");
+        }
+        self.wear_changed = true;
+
+        self.skip_sector().await;
     }
 
-    pub async fn write_message(&mut self, msg: DownlinkMessage) -> Result<(), FlashError<SPI::Error>> {
-        let serialized = msg.serialize().unwrap_or_default();
-        if serialized.len() > 2 * PAGE_SIZE - self.write_buffer.len() {
-            //error!("Flash message too big.");
+    /// Overwrites the rest of the sector at the pointer with zeros, which fail the page checksum,
+    /// so neither the pointer search nor the log decoder stop at its unwritten pages.
+    async fn skip_sector(&mut self) {
+        let sector_size = self.driver.geometry().sector_size;
+        let next_sector = self.pointer - self.pointer % sector_size + sector_size;
+
+        for address in (self.pointer..next_sector).step_by(PAGE_SIZE) {
+            let _ = self.driver.write(address as usize, &[0x00; PAGE_SIZE]).await;
+            self.wait_until_ready(10).await;
+        }
+
+        self.update_pointer(next_sector);
+    }
+
+    pub async fn write_message(&mut self, msg: DownlinkMessage) -> Result<(), FlashError<F::Error>> {
+        self.write_record(&msg, "buffering message").await
+    }
+
+    #[cfg(not(feature = "gcs"))]
+    async fn write_note(&mut self, note: &LogNote) -> Result<(), FlashError<F::Error>> {
+        info!("Logging note at {}ms: {}", note.time, note.text.as_str());
+        self.write_record(&(LOG_NOTE_TAG, note), "buffering note").await
+    }
+
+    /// Appends a record to the log, flushing a page once enough data has accumulated.
+    async fn write_record<T: Serialize>(&mut self, record: &T, context: &'static str) -> Result<(), FlashError<F::Error>> {
+        let mut buffer = [0u8; MAX_MESSAGE_SIZE];
+        let serialized: &[u8] = postcard::to_slice_cobs(record, &mut buffer).map(|s| &*s).unwrap_or_default();
+        if serialized.len() > BUFFER_SIZE - self.write_buffer.len() {
+            report(Subsystem::Flash, ErrorKind::Overflow, context);
             return Ok(());
         }
 
+        // Once the RAM buffer runs out, writing despite a deferral is the lesser evil.
+        let deferred = WRITES_DEFERRED.load(Ordering::Relaxed)
+            && self.write_buffer.len() + serialized.len() + PAGE_SIZE <= BUFFER_SIZE;
+
         self.write_buffer.extend(serialized);
-        if self.write_buffer.len() > PAGE_SIZE - 3 {
+        if self.write_buffer.len() > PAGE_SIZE - 3 && !deferred {
             self.flush_page().await
         } else {
             Ok(())
         }
     }
 
-    pub async fn read_settings(&mut self) -> Result<Settings, FlashError<SPI::Error>> {
-        const DATA_SIZE: usize = FLASH_SETTINGS_SIZE as usize;
-        let mut settings_data: Vec<u8, DATA_SIZE> = Vec::new();
-        for i in 0..(DATA_SIZE / 256) {
-            let page = self.driver.read((i * 256) as u32, 256).await?;
-            settings_data.extend(page);
-        }
+    pub async fn read_settings(&mut self) -> Result<Settings, FlashError<F::Error>> {
+        let mut slot = [0x00; FLASH_SETTINGS_SIZE as usize];
+        self.read_region(Region::Settings, &mut slot).await?;
+        Ok(postcard::from_bytes(&slot[..(FLASH_SETTINGS_SIZE as usize - 2)]).map_err(|e| FlashError::Serialization(e))?)
+    }
+
+    async fn write_settings(&mut self, settings: &Settings) -> Result<(), FlashError<F::Error>> {
+        let mut buffer = [0u8; 512];
+        let serialized = postcard::to_slice(settings, &mut buffer).unwrap();
+
+        let mut slot: [u8; FLASH_SETTINGS_SIZE as usize] = [0x00; FLASH_SETTINGS_SIZE as usize];
+        slot[..serialized.len()].copy_from_slice(serialized);
+        self.write_region(Region::Settings, &mut slot).await
+    }
 
-        let (settings, crc) = settings_data.split_at(FLASH_SETTINGS_SIZE as usize - 2);
-        let crc = u16::from_be_bytes([crc[0], crc[1]]);
+    /// Reads the newest copy of a record with a valid checksum from its region, including the
+    /// checksum. Fails with `FlashError::Crc` if there is none.
+    async fn read_region(&mut self, region: Region, slot: &mut [u8]) -> Result<(), FlashError<F::Error>> {
+        let len = slot.len();
+        for i in (0..self.slots(region)).rev() {
+            let address = self.slot_address(region, i);
+            for (j, chunk) in slot.chunks_mut(PAGE_SIZE).enumerate() {
+                let page = self.driver.read(address + (j * PAGE_SIZE) as u32, chunk.len() as u32).await?;
+                chunk.copy_from_slice(&page);
+            }
 
-        if crc != X25.checksum(&settings) {
-            return Err(FlashError::Crc);
+            // Unused slots are skipped, as are ones whose write was interrupted.
+            let (data, crc) = slot.split_at(len - 2);
+            if u16::from_be_bytes([crc[0], crc[1]]) == X25.checksum(data) {
+                return Ok(());
+            }
         }
 
-        Ok(postcard::from_bytes(&settings).map_err(|e| FlashError::Serialization(e))?)
+        Err(FlashError::Crc)
     }
 
-    async fn write_settings(&mut self, settings: &Settings) -> Result<(), FlashError<SPI::Error>> {
-        let mut res = Ok(());
-        for _i in 0..3 {
-            res = self.driver.erase_sector(0x00).await;
-            if res.is_ok() {
+    /// Writes a record to the slot after the newest one in its region, filling in the checksum in
+    /// the last two bytes. The region's sector is only erased once all of its slots are used.
+    async fn write_region(&mut self, region: Region, slot: &mut [u8]) -> Result<(), FlashError<F::Error>> {
+        let mut next = 0;
+        for i in (0..self.slots(region)).rev() {
+            let page = self.driver.read(self.slot_address(region, i), PAGE_SIZE as u32).await?;
+            if page.iter().any(|b| *b != 0xff) {
+                next = i + 1;
                 break;
             }
         }
-        res?;
 
-        // Wait for flash to finish erasing before writing
-        for _i in 0..10 {
+        let len = slot.len();
+        let crc = X25.checksum(&slot[..(len - 2)]);
+        slot[len - 2] = (crc >> 8) as u8;
+        slot[len - 1] = crc as u8;
+
+        for attempt in 0..self.record_write_attempts {
+            if next >= self.slots(region) {
+                self.driver.erase_sector(self.region_address(region)).await?;
+                self.wear.region_erases[region.index()] += 1;
+                self.wear_changed = true;
+                next = 0;
+
+                // Sector erases take a while, wait for it to finish before writing
+                self.wait_until_ready(500).await;
+            }
+
+            // We can only write a single page at a time
+            let address = self.slot_address(region, next) as usize;
+            for (i, page) in slot.chunks(PAGE_SIZE).enumerate() {
+                self.driver.write(address + i * PAGE_SIZE, page).await?;
+                self.wait_until_ready(10).await;
+            }
+
+            if !self.verify_records || self.verify_slot(region, next, slot).await? {
+                return Ok(());
+            }
+
+            // A partially written slot fails its checksum, so it is simply skipped when reading.
+            warn!("Writing {} to slot {} failed verification. ({}/{})", region.name(), next, attempt + 1, self.record_write_attempts);
+            report(Subsystem::Flash, ErrorKind::Verification, "verifying record");
+            next += 1;
+        }
+
+        Err(FlashError::Verification)
+    }
+
+    /// Reads the settings and writes them back as a scratch copy to the next slot of their region,
+    /// which exercises programming and read-back without touching the log. The copy is always
+    /// verified, regardless of `verify_records`. Returns whether reading and writing succeeded.
+    async fn self_test(&mut self) -> (bool, bool) {
+        let settings = match self.read_settings().await {
+            Ok(settings) => settings,
+            Err(e) => {
+                report(Subsystem::Flash, e, "reading settings");
+                return (false, false);
+            }
+        };
+
+        let verify_records = core::mem::replace(&mut self.verify_records, true);
+        let result = self.write_settings(&settings).await;
+        self.verify_records = verify_records;
+
+        if let Err(e) = result {
+            report(Subsystem::Flash, e, "writing scratch settings");
+            return (true, false);
+        }
+
+        (true, true)
+    }
+
+    /// Checks whether the given slot of a region reads back as the given data.
+    async fn verify_slot(&mut self, region: Region, index: u32, slot: &[u8]) -> Result<bool, FlashError<F::Error>> {
+        let address = self.slot_address(region, index);
+        for (i, page) in slot.chunks(PAGE_SIZE).enumerate() {
+            let read_back = self.driver.read(address + (i * PAGE_SIZE) as u32, page.len() as u32).await?;
+            if &read_back[..] != page {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Waits up to the given time (ms) for a write or erase to finish.
+    async fn wait_until_ready(&mut self, timeout: u32) {
+        for _i in 0..timeout {
             if !self.driver.is_busy().await {
                 break;
             }
             Timer::after(Duration::from_millis(1)).await;
         }
+    }
 
-        // separate scope so we get rid of these buffers before reading back
-        {
-            let mut buffer = [0u8; 512];
-            let serialized = postcard::to_slice(settings, &mut buffer).unwrap();
+    async fn read_wear_table(&mut self) -> Result<WearTable, FlashError<F::Error>> {
+        let mut page = [0x00; PAGE_SIZE];
+        self.read_region(Region::Wear, &mut page).await?;
+        match WearTable::decode(page[0], &page[1..(PAGE_SIZE - 2)]) {
+            Some(wear) => {
+                // Written in the current version with the next change.
+                self.wear_changed = page[0] != WEAR_TABLE_VERSION;
+                Ok(wear.map_err(|e| FlashError::Serialization(e))?)
+            },
+            None => Err(FlashError::UnsupportedVersion(page[0])),
+        }
+    }
 
-            let mut sector: [u8; FLASH_SETTINGS_SIZE as usize] = [0x00; FLASH_SETTINGS_SIZE as usize];
-            sector[..serialized.len()].copy_from_slice(serialized);
+    /// Writes the wear table if it has changed.
+    async fn save_wear_table(&mut self) {
+        if !self.wear_changed {
+            return;
+        }
 
-            let crc = X25.checksum(&sector[..(FLASH_SETTINGS_SIZE as usize - 2)]);
-            sector[FLASH_SETTINGS_SIZE as usize - 2] = (crc >> 8) as u8;
-            sector[FLASH_SETTINGS_SIZE as usize - 1] = crc as u8;
+        // Cleared first, so an erase of the wear table's own sector is saved with the next change.
+        self.wear_changed = false;
+        let mut page = [0x00; PAGE_SIZE];
+        page[0] = WEAR_TABLE_VERSION;
+        let result = match postcard::to_slice(&self.wear, &mut page[1..(PAGE_SIZE - 2)]) {
+            Ok(_) => self.write_region(Region::Wear, &mut page).await,
+            Err(e) => Err(FlashError::Serialization(e)),
+        };
 
-            // We can only write a single page at a time
-            for i in 0..(FLASH_SETTINGS_SIZE as usize / PAGE_SIZE) {
-                self.driver.write(PAGE_SIZE * i, &sector[(PAGE_SIZE * i)..(PAGE_SIZE * (i+1))]).await?;
+        if let Err(e) = result {
+            report(Subsystem::Flash, e, "writing wear table");
+        }
+    }
 
-                // Wait for flash to finish writing
-                for _i in 0..10 {
-                    if !self.driver.is_busy().await {
-                        break;
-                    }
-                    Timer::after(Duration::from_millis(1)).await;
+    #[cfg(not(feature = "gcs"))]
+    async fn print_wear(&mut self) {
+        let mut line = ConsoleLine::new();
+        let _ = core::write!(line, "log: erased {} times, {} failed pages", self.wear.log_erases, self.wear.write_failures);
+        self.usb.console_print(line).await;
+
+        for region in Region::ALL {
+            let mut line = ConsoleLine::new();
+            let _ = core::write!(line, "{}: erased {} times", region.name(), self.wear.region_erases[region.index()]);
+            self.usb.console_print(line).await;
+        }
+
+        let sector_size = self.driver.geometry().sector_size;
+        for sector in self.wear.bad_sectors.iter() {
+            let mut line = ConsoleLine::new();
+            let _ = core::write!(line, "bad sector: 0x{:08x}", sector * sector_size);
+            self.usb.console_print(line).await;
+        }
+    }
+
+    #[cfg(not(feature = "gcs"))]
+    async fn read_flight_summary(&mut self) -> Result<FlightSummary, FlashError<F::Error>> {
+        let mut page = [0x00; PAGE_SIZE];
+        self.read_region(Region::FlightSummary, &mut page).await?;
+        Ok(postcard::from_bytes(&page[..(PAGE_SIZE - 2)]).map_err(|e| FlashError::Serialization(e))?)
+    }
+
+    #[cfg(not(feature = "gcs"))]
+    async fn write_flight_summary(&mut self, summary: &FlightSummary) -> Result<(), FlashError<F::Error>> {
+        let mut page = [0x00; PAGE_SIZE];
+        postcard::to_slice(summary, &mut page[..(PAGE_SIZE - 2)]).map_err(|e| FlashError::Serialization(e))?;
+        self.write_region(Region::FlightSummary, &mut page).await
+    }
+
+    #[cfg(not(feature = "gcs"))]
+    async fn print_flight_summary(&mut self) {
+        let summary = match self.read_flight_summary().await {
+            Ok(summary) => summary,
+            Err(_e) => {
+                let mut line = ConsoleLine::new();
+                let _ = core::write!(line, "No flight summary stored.");
+                self.usb.console_print(line).await;
+                return;
+            }
+        };
+
+        let mut line = ConsoleLine::new();
+        let _ = core::write!(
+            line,
+            "apogee: {:.1}m AGL, max. speed: {:.1}m/s, max. acceleration: {:.1}m/s^2",
+            summary.apogee,
+            summary.max_vertical_speed,
+            summary.max_acceleration
+        );
+        self.usb.console_print(line).await;
+
+        let mut line = ConsoleLine::new();
+        let _ = core::write!(
+            line,
+            "burn: {}ms, descent: {:?}m/s (drogue), {:?}m/s (main), duration: {}ms",
+            summary.burn_time,
+            summary.drogue_descent_rate,
+            summary.main_descent_rate,
+            summary.flight_duration
+        );
+        self.usb.console_print(line).await;
+
+        if let Some((azimuth, elevation)) = summary.rail {
+            let mut line = ConsoleLine::new();
+            let _ = core::write!(line, "rail: azimuth {:.1}deg, elevation {:.1}deg", azimuth, elevation);
+            self.usb.console_print(line).await;
+        }
+    }
+
+    /// Reads the sensor calibration, migrating it from an older schema version or from the
+    /// settings if necessary. Only to be used during initialization, before the flash task runs.
+    #[cfg(not(feature = "gcs"))]
+    pub async fn load_calibration(&mut self, settings: &Settings) -> SensorCalibration {
+        let (calibration, migrate) = match self.read_calibration().await {
+            Ok((version, calibration)) => {
+                if version != CALIBRATION_VERSION {
+                    info!("Migrating calibration from version {} to {}.", version, CALIBRATION_VERSION);
                 }
+                (calibration, version != CALIBRATION_VERSION)
+            },
+            Err(FlashError::Crc) if self.calibration_erased().await => {
+                info!("No calibration stored, taking over offsets from settings.");
+                (SensorCalibration::from_settings(settings), true)
+            },
+            Err(e) => {
+                error!("Failed to read calibration from flash ({:?}), reverting to defaults.", Debug2Format(&e));
+                report(Subsystem::Flash, e, "reading calibration");
+                return SensorCalibration::default();
+            }
+        };
+
+        if migrate {
+            if let Err(e) = self.write_calibration(&calibration).await {
+                report(Subsystem::Flash, e, "writing calibration");
             }
         }
 
-        // Read back what we have written to make sure the changes are persisted.
-        if &self.read_settings().await? != settings {
-            Err(FlashError::Crc)
-        } else {
-            Ok(())
+        calibration
+    }
+
+    /// Returns the stored calibration and the schema version it was stored with.
+    #[cfg(not(feature = "gcs"))]
+    async fn read_calibration(&mut self) -> Result<(u8, SensorCalibration), FlashError<F::Error>> {
+        let mut page = [0x00; PAGE_SIZE];
+        self.read_region(Region::Calibration, &mut page).await?;
+        let data = &page[..(PAGE_SIZE - 2)];
+
+        let version = data[0];
+        match SensorCalibration::decode(version, &data[1..]) {
+            Some(calibration) => Ok((version, calibration.map_err(|e| FlashError::Serialization(e))?)),
+            None => Err(FlashError::UnsupportedVersion(version)),
         }
     }
 
+    #[cfg(not(feature = "gcs"))]
+    async fn calibration_erased(&mut self) -> bool {
+        // The first slot is written first, see `write_region`.
+        let page = self.driver.read(self.region_address(Region::Calibration), PAGE_SIZE as u32).await;
+        page.map(|p| p.iter().all(|b| *b == 0xff)).unwrap_or(false)
+    }
+
+    #[cfg(not(feature = "gcs"))]
+    async fn write_calibration(&mut self, calibration: &SensorCalibration) -> Result<(), FlashError<F::Error>> {
+        let mut page = [0x00; PAGE_SIZE];
+        page[0] = CALIBRATION_VERSION;
+        postcard::to_slice(calibration, &mut page[1..(PAGE_SIZE - 2)]).map_err(|e| FlashError::Serialization(e))?;
+        self.write_region(Region::Calibration, &mut page).await
+    }
+
+    #[cfg(not(feature = "gcs"))]
+    async fn print_calibration(&mut self) {
+        let mut line = ConsoleLine::new();
+        let calibration = match self.read_calibration().await {
+            Ok((version, calibration)) => {
+                let _ = core::write!(line, "calibration: version {}", version);
+                self.usb.console_print(line).await;
+                calibration
+            },
+            Err(e) => {
+                let _ = core::write!(line, "No valid calibration stored ({:?}).", e);
+                self.usb.console_print(line).await;
+                return;
+            }
+        };
+
+        let vectors = [
+            ("gyro bias", calibration.gyro_bias),
+            ("acc offset", calibration.acc_offset),
+            ("acc2 offset", calibration.acc2_offset),
+            ("mag offset", calibration.mag_offset),
+        ];
+        for (name, v) in vectors {
+            let mut line = ConsoleLine::new();
+            let _ = core::write!(line, "{}: {} {} {}", name, v.x, v.y, v.z);
+            self.usb.console_print(line).await;
+        }
+
+        for (i, row) in calibration.mag_transform.row_iter().enumerate() {
+            let mut line = ConsoleLine::new();
+            let _ = core::write!(line, "mag transform {}: {} {} {}", i, row[0], row[1], row[2]);
+            self.usb.console_print(line).await;
+        }
+
+        let mut line = ConsoleLine::new();
+        let _ = core::write!(line, "baro offset: {}hPa", calibration.baro_offset);
+        self.usb.console_print(line).await;
+    }
+
+    /// Reads the link configuration, falling back to the default one if none is stored. Only to
+    /// be used during initialization, before the flash task runs.
+    #[cfg(not(feature = "gcs"))]
+    pub async fn load_link_config(&mut self) -> LinkConfig {
+        match self.read_link_config().await {
+            Ok(link) if link.is_valid() => link,
+            Ok(link) => {
+                error!("Invalid link configuration {} stored, reverting to defaults.", link);
+                LinkConfig::default()
+            },
+            Err(FlashError::Crc) => LinkConfig::default(),
+            Err(e) => {
+                report(Subsystem::Flash, e, "reading link configuration");
+                LinkConfig::default()
+            }
+        }
+    }
+
+    #[cfg(not(feature = "gcs"))]
+    async fn read_link_config(&mut self) -> Result<LinkConfig, FlashError<F::Error>> {
+        let mut page = [0x00; PAGE_SIZE];
+        self.read_region(Region::LinkConfig, &mut page).await?;
+        Ok(postcard::from_bytes(&page[..(PAGE_SIZE - 2)]).map_err(|e| FlashError::Serialization(e))?)
+    }
+
+    #[cfg(not(feature = "gcs"))]
+    async fn write_link_config(&mut self, link: &LinkConfig) -> Result<(), FlashError<F::Error>> {
+        let mut page = [0x00; PAGE_SIZE];
+        postcard::to_slice(link, &mut page[..(PAGE_SIZE - 2)]).map_err(|e| FlashError::Serialization(e))?;
+        self.write_region(Region::LinkConfig, &mut page).await
+    }
+
+    /// Reads the firmware parameters, falling back to the defaults if none or an older version
+    /// are stored, and applies the ones concerning the flash itself. Only to be used during
+    /// initialization, before the flash task runs.
+    #[cfg(not(feature = "gcs"))]
+    pub async fn load_parameters(&mut self) -> FirmwareParameters {
+        let parameters = match self.read_parameters().await {
+            Ok(parameters) => parameters,
+            Err(FlashError::Crc) => FirmwareParameters::default(),
+            Err(e) => {
+                error!("Failed to read parameters from flash ({:?}), reverting to defaults.", Debug2Format(&e));
+                report(Subsystem::Flash, e, "reading parameters");
+                FirmwareParameters::default()
+            }
+        };
+
+        self.verify_records = parameters.verify_records;
+        self.record_write_attempts = parameters.record_write_attempts.max(1);
+        parameters
+    }
+
+    #[cfg(not(feature = "gcs"))]
+    async fn read_parameters(&mut self) -> Result<FirmwareParameters, FlashError<F::Error>> {
+        let mut page = [0x00; PAGE_SIZE];
+        self.read_region(Region::Parameters, &mut page).await?;
+        if page[0] != PARAMETERS_VERSION {
+            return Err(FlashError::UnsupportedVersion(page[0]));
+        }
+
+        Ok(postcard::from_bytes(&page[1..(PAGE_SIZE - 2)]).map_err(|e| FlashError::Serialization(e))?)
+    }
+
+    #[cfg(not(feature = "gcs"))]
+    async fn write_parameters(&mut self, parameters: &FirmwareParameters) -> Result<(), FlashError<F::Error>> {
+        let mut page = [0x00; PAGE_SIZE];
+        page[0] = PARAMETERS_VERSION;
+        postcard::to_slice(parameters, &mut page[1..(PAGE_SIZE - 2)]).map_err(|e| FlashError::Serialization(e))?;
+        self.write_region(Region::Parameters, &mut page).await
+    }
+
     async fn erase(&mut self) {
-        self.update_pointer(self.driver.size() - SECTOR_SIZE);
+        self.wear.log_erases += 1;
+        self.wear_changed = true;
+        self.update_pointer(self.log_end());
 
         loop {
             if self.pointer == FLASH_HEADER_SIZE {
@@ -327,10 +986,11 @@ impl<SPI: SpiDevice> Flash<SPI> {
                 continue;
             }
 
-            let next_pointer = self.pointer - SECTOR_SIZE;
+            let sector_size = self.driver.geometry().sector_size;
+            let next_pointer = self.pointer - sector_size;
 
             let mut sector_needs_erasing = false;
-            for address in (next_pointer..(next_pointer + SECTOR_SIZE)).step_by(PAGE_SIZE) {
+            for address in (next_pointer..(next_pointer + sector_size)).step_by(PAGE_SIZE) {
                 let content = self.driver.read(address, 256).await.ok();
                 if content.map(|c| c.iter().any(|b| *b != 0xff)).unwrap_or(true) {
                     sector_needs_erasing = true;
@@ -340,7 +1000,7 @@ impl<SPI: SpiDevice> Flash<SPI> {
 
             if sector_needs_erasing {
                 if let Err(e) = self.driver.erase_sector(next_pointer).await {
-                    error!("Error erasing sector {}: {:?}", next_pointer, Debug2Format(&e));
+                    report(Subsystem::Flash, e, "erasing sector");
                 } else {
                     self.update_pointer(next_pointer);
                 }
@@ -350,13 +1010,42 @@ impl<SPI: SpiDevice> Flash<SPI> {
         }
     }
 
+    async fn dump(&mut self, address: u32, size: u32) {
+        const CHUNK_SIZE: u32 = 256;
+        const LINE_SIZE: usize = 16;
+
+        let end = address.saturating_add(size);
+        for chunk_address in (address..end).step_by(CHUNK_SIZE as usize) {
+            let len = u32::min(CHUNK_SIZE, end - chunk_address);
+            let data = match self.driver.read(chunk_address, len).await {
+                Ok(data) => data,
+                Err(e) => {
+                    report(Subsystem::Flash, e, "reading flash");
+                    let mut line = ConsoleLine::new();
+                    let _ = core::write!(line, "Failed to read flash at 0x{:08x}.", chunk_address);
+                    self.usb.console_print(line).await;
+                    return;
+                }
+            };
+
+            for (i, bytes) in data.chunks(LINE_SIZE).enumerate() {
+                let mut line = ConsoleLine::new();
+                let _ = core::write!(line, "{:08x}:", chunk_address + (i * LINE_SIZE) as u32);
+                for b in bytes {
+                    let _ = core::write!(line, " {:02x}", b);
+                }
+                self.usb.console_print(line).await;
+            }
+        }
+    }
+
     async fn run(&mut self) -> ! {
         loop {
             let request = self.request_receiver.receive().await;
             match request {
                 FlashRequest::WriteMessage(msg) => {
                     if let Err(e) = self.write_message(msg).await {
-                        error!("Failed to write flash msg: {:?}", Debug2Format(&e));
+                        report(Subsystem::Flash, e, "writing message");
                     }
                 },
                 FlashRequest::WriteSettings(settings) => {
@@ -365,6 +1054,7 @@ impl<SPI: SpiDevice> Flash<SPI> {
                             break;
                         }
                     }
+                    self.save_wear_table().await;
 
                     // reboot to apply settings
                     cortex_m::peripheral::SCB::sys_reset();
@@ -380,15 +1070,83 @@ impl<SPI: SpiDevice> Flash<SPI> {
                             }
                         },
                         Err(e) => {
-                            error!("Failed to read flash: {:?}", Debug2Format(&e));
+                            report(Subsystem::Flash, e, "reading flash");
                         }
                     }
                 },
+                FlashRequest::Dump(address, size) => self.dump(address, size).await,
                 FlashRequest::Erase => {
                     info!("Erasing flash.");
                     self.erase().await
-                }
+                },
+                FlashRequest::SelfTest => {
+                    let (read, write) = self.self_test().await;
+                    SELF_TEST_SIGNAL.signal((read, write));
+                    let result = |ok: bool| if ok { "ok" } else { "FAIL" };
+                    let mut line = ConsoleLine::new();
+                    let _ = core::write!(line, "flash: read {}, write {}", result(read), result(write));
+                    self.usb.console_print(line).await;
+                },
+                #[cfg(not(feature = "gcs"))]
+                FlashRequest::WriteFlightSummary(summary) => {
+                    if let Err(e) = self.write_flight_summary(&summary).await {
+                        report(Subsystem::Flash, e, "writing flight summary");
+                    }
+                },
+                #[cfg(not(feature = "gcs"))]
+                FlashRequest::PrintFlightSummary => self.print_flight_summary().await,
+                #[cfg(not(feature = "gcs"))]
+                FlashRequest::WriteNote(note) => {
+                    if let Err(e) = self.write_note(&note).await {
+                        report(Subsystem::Flash, e, "writing note");
+                    }
+                },
+                #[cfg(not(feature = "gcs"))]
+                FlashRequest::WriteRadioDiagnostics(time, diagnostics) => {
+                    let record = (RADIO_DIAGNOSTICS_TAG, time, diagnostics);
+                    if let Err(e) = self.write_record(&record, "buffering radio diagnostics").await {
+                        report(Subsystem::Flash, e, "writing radio diagnostics");
+                    }
+                },
+                #[cfg(not(feature = "gcs"))]
+                FlashRequest::WriteTimeReference(reference) => {
+                    info!("Logging time reference: {}ms is {}ms UTC", reference.time, reference.utc);
+                    if let Err(e) = self.write_record(&(TIME_REFERENCE_TAG, reference), "buffering time reference").await {
+                        report(Subsystem::Flash, e, "writing time reference");
+                    }
+                },
+                #[cfg(not(feature = "gcs"))]
+                FlashRequest::WriteCalibration(calibration) => {
+                    if let Err(e) = self.write_calibration(&calibration).await {
+                        report(Subsystem::Flash, e, "writing calibration");
+                    }
+                },
+                #[cfg(not(feature = "gcs"))]
+                FlashRequest::PrintCalibration => self.print_calibration().await,
+                #[cfg(not(feature = "gcs"))]
+                FlashRequest::ClearCalibration => {
+                    info!("Clearing calibration.");
+                    if let Err(e) = self.write_calibration(&SensorCalibration::default()).await {
+                        report(Subsystem::Flash, e, "clearing calibration");
+                    }
+                },
+                #[cfg(not(feature = "gcs"))]
+                FlashRequest::WriteLinkConfig(link) => {
+                    if let Err(e) = self.write_link_config(&link).await {
+                        report(Subsystem::Flash, e, "writing link configuration");
+                    }
+                },
+                #[cfg(not(feature = "gcs"))]
+                FlashRequest::WriteParameters(parameters) => {
+                    if let Err(e) = self.write_parameters(&parameters).await {
+                        report(Subsystem::Flash, e, "writing parameters");
+                    }
+                },
+                #[cfg(not(feature = "gcs"))]
+                FlashRequest::PrintWear => self.print_wear().await,
             }
+
+            self.save_wear_table().await;
         }
     }
 }
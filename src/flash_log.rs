@@ -0,0 +1,376 @@
+//! Format of the flash log, shared by the firmware writing it (see `flash.rs`) and host software
+//! reading it, so both always agree on the record definitions.
+//!
+//! The log is written in pages of `PAGE_SIZE` bytes, each starting with a zero byte and ending
+//! with a CRC16 over the data in between. The page data forms a continuous stream of records,
+//! which may span page boundaries. Records are postcard-serialized and COBS-encoded, with a zero
+//! byte as delimiter. Most records are downlink messages. Operator notes, radio diagnostics and
+//! time references are prefixed by `LOG_NOTE_TAG`, `RADIO_DIAGNOSTICS_TAG` and
+//! `TIME_REFERENCE_TAG` to tell them apart.
+//!
+//! The downlink messages only carry the vehicle time, which starts at zero on every boot. Once
+//! the clock has been synchronized to GPS time, a time reference is logged, which relates the
+//! vehicle time to UTC for the rest of the boot.
+//!
+//! Notes can also be entered on the GCS, which uplinks them in chunks, see `note_chunks`.
+//!
+//! With the `std` feature, `LogDecoder` turns a flash dump back into records, which can be
+//! converted to JSON or CSV for analysis.
+
+use crc::{Crc, CRC_16_IBM_SDLC};
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+
+use shared_types::DownlinkMessage;
+
+use crate::radio_diagnostics::RadioDiagnostics;
+
+pub const PAGE_SIZE: usize = 256;
+/// Data bytes per page, without the leading zero byte and the checksum
+pub const PAGE_DATA_SIZE: usize = PAGE_SIZE - 3;
+
+const X25: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+
+/// First byte of serialized operator notes in the log. Never valid as the start of a serialized
+/// downlink message.
+pub const LOG_NOTE_TAG: u8 = 0xfb;
+/// First byte of serialized radio diagnostics in the log, see `LOG_NOTE_TAG` and
+/// `radio_diagnostics.rs`. Followed by the vehicle time (ms) they were read at.
+pub const RADIO_DIAGNOSTICS_TAG: u8 = 0xfa;
+/// First byte of serialized time references in the log, see `LOG_NOTE_TAG` and `TimeReference`.
+pub const TIME_REFERENCE_TAG: u8 = 0xf9;
+/// Maximum length of an operator note, longer ones are truncated
+pub const LOG_NOTE_LENGTH: usize = 64;
+/// Bytes of note text per uplink packet
+pub const NOTE_CHUNK_LENGTH: usize = 4;
+/// Maximum number of chunks per note, including the terminating one
+pub const NOTE_CHUNKS: usize = LOG_NOTE_LENGTH / NOTE_CHUNK_LENGTH + 1;
+
+/// Short operator note stored in the log, e.g. "second attempt", to document the circumstances
+/// of a flight together with its data.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LogNote {
+    /// Vehicle time (ms) at which the note was entered
+    pub time: u32,
+    pub text: String<LOG_NOTE_LENGTH>,
+}
+
+impl LogNote {
+    pub fn new(time: u32, text: &str) -> Self {
+        let mut truncated = String::new();
+        for c in text.chars() {
+            if truncated.push(c).is_err() {
+                break;
+            }
+        }
+
+        Self { time, text: truncated }
+    }
+}
+
+/// Vehicle time and the corresponding UTC time, logged and downlinked once the clock has been
+/// synchronized to GPS time, so flight data can be correlated with external sources such as video.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeReference {
+    /// Vehicle time (ms)
+    pub time: u32,
+    /// UTC time at `time` (ms since the unix epoch)
+    pub utc: u64,
+}
+
+impl TimeReference {
+    /// UTC time (ms since the unix epoch) at the given vehicle time (ms) of the same boot.
+    pub fn utc_at(&self, time: u32) -> u64 {
+        (self.utc as i64 + time as i64 - self.time as i64) as u64
+    }
+}
+
+/// Splits note text into chunks small enough for uplink packets, numbered from zero. The text is
+/// padded with zeros, the last chunk being the first to contain one.
+pub fn note_chunks(text: &str) -> Vec<(u8, [u8; NOTE_CHUNK_LENGTH]), NOTE_CHUNKS> {
+    let text = LogNote::new(0, text).text;
+    let bytes = text.as_bytes();
+
+    (0..=bytes.len() / NOTE_CHUNK_LENGTH)
+        .map(|i| {
+            let start = i * NOTE_CHUNK_LENGTH;
+            let end = usize::min(start + NOTE_CHUNK_LENGTH, bytes.len());
+            let mut chunk = [0; NOTE_CHUNK_LENGTH];
+            chunk[..end - start].copy_from_slice(&bytes[start..end]);
+            (i as u8, chunk)
+        })
+        .collect()
+}
+
+/// Reassembles note text uplinked in chunks, see `note_chunks`. Uplink packets aren't
+/// acknowledged, so notes with missing chunks are discarded.
+#[derive(Default)]
+pub struct NoteAssembler {
+    text: Vec<u8, LOG_NOTE_LENGTH>,
+    /// Index of the chunk expected next, if a note is being received
+    next: Option<u8>,
+}
+
+impl NoteAssembler {
+    /// Adds a chunk, returning the note text once it is complete.
+    pub fn push(&mut self, index: u8, chunk: [u8; NOTE_CHUNK_LENGTH]) -> Option<String<LOG_NOTE_LENGTH>> {
+        if index == 0 {
+            self.text.clear();
+            self.next = Some(0);
+        }
+
+        if self.next.take() != Some(index) {
+            return None;
+        }
+
+        let end = chunk.iter().position(|b| *b == 0);
+        self.text.extend_from_slice(&chunk[..end.unwrap_or(NOTE_CHUNK_LENGTH)]).ok()?;
+        if end.is_none() {
+            self.next = index.checked_add(1);
+            return None;
+        }
+
+        core::str::from_utf8(&self.text).ok().and_then(|text| String::try_from(text).ok())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogError {
+    /// Page checksum mismatch, the records in it are lost
+    Crc,
+    Encoding,
+    Deserialization,
+}
+
+/// A single entry in the log.
+#[derive(Clone, Debug)]
+pub enum LogRecord {
+    Message(DownlinkMessage),
+    Note(LogNote),
+    /// Vehicle time (ms) and diagnostics of the LoRa transceiver
+    RadioDiagnostics(u32, RadioDiagnostics),
+    TimeReference(TimeReference),
+}
+
+impl LogRecord {
+    /// Decodes a single COBS-encoded record without its delimiter. The frame is decoded in place.
+    pub fn decode(frame: &mut [u8]) -> Result<Self, LogError> {
+        let len = cobs::decode_in_place(frame).map_err(|_| LogError::Encoding)?;
+        let data = &frame[..len];
+
+        if data.first() == Some(&LOG_NOTE_TAG) {
+            let (_tag, note): (u8, LogNote) = postcard::from_bytes(data).map_err(|_| LogError::Deserialization)?;
+            Ok(Self::Note(note))
+        } else if data.first() == Some(&RADIO_DIAGNOSTICS_TAG) {
+            let (_tag, time, diagnostics): (u8, u32, RadioDiagnostics) = postcard::from_bytes(data).map_err(|_| LogError::Deserialization)?;
+            Ok(Self::RadioDiagnostics(time, diagnostics))
+        } else if data.first() == Some(&TIME_REFERENCE_TAG) {
+            let (_tag, reference): (u8, TimeReference) = postcard::from_bytes(data).map_err(|_| LogError::Deserialization)?;
+            Ok(Self::TimeReference(reference))
+        } else {
+            postcard::from_bytes(data).map(Self::Message).map_err(|_| LogError::Deserialization)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub use host::*;
+
+#[cfg(feature = "std")]
+mod host {
+    use std::io::Write;
+
+    use serde_json::Value;
+
+    use super::{LogError, LogRecord, PAGE_DATA_SIZE, PAGE_SIZE, X25};
+
+    /// Iterator over the records in a flash log.
+    pub struct LogDecoder {
+        stream: Vec<u8>,
+        position: usize,
+    }
+
+    impl LogDecoder {
+        /// Takes the contents of the flash log region, i.e. starting at `FLASH_HEADER_SIZE`.
+        /// Decoding stops at the first unwritten page. Pages with a checksum mismatch are skipped
+        /// and show up as a single `LogError::Crc`.
+        pub fn from_pages(data: &[u8]) -> Self {
+            let mut stream = Vec::with_capacity(data.len());
+            for page in data.chunks_exact(PAGE_SIZE) {
+                if page.iter().all(|b| *b == 0xff) {
+                    break;
+                }
+
+                let content = &page[1..(1 + PAGE_DATA_SIZE)];
+                let crc = u16::from_be_bytes([page[PAGE_SIZE - 2], page[PAGE_SIZE - 1]]);
+                if crc == X25.checksum(content) {
+                    stream.extend_from_slice(content);
+                } else {
+                    // A lone 0xff (never valid COBS) marks the corrupted page, and the delimiters
+                    // around it discard the records it cut off.
+                    stream.extend_from_slice(&[0x00, 0xff, 0x00]);
+                }
+            }
+
+            Self { stream, position: 0 }
+        }
+
+        /// Takes a plain stream of records without page framing, e.g. from the simulation.
+        pub fn from_stream(data: &[u8]) -> Self {
+            Self { stream: data.to_vec(), position: 0 }
+        }
+    }
+
+    impl Iterator for LogDecoder {
+        type Item = Result<LogRecord, LogError>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                let remaining = &self.stream[self.position..];
+                let len = remaining.iter().position(|b| *b == 0x00)?;
+                let mut frame = remaining[..len].to_vec();
+                self.position += len + 1;
+
+                match frame.as_slice() {
+                    [] => continue,
+                    [0xff] => return Some(Err(LogError::Crc)),
+                    _ => return Some(LogRecord::decode(&mut frame)),
+                }
+            }
+        }
+    }
+
+    impl LogRecord {
+        /// Name of the message type, `Note`, `RadioDiagnostics` or `TimeReference`, as used for
+        /// selecting CSV output
+        pub fn kind(&self) -> String {
+            match self.to_json() {
+                Value::Object(map) => map.keys().next().cloned().unwrap_or_default(),
+                Value::String(name) => name,
+                _ => String::new(),
+            }
+        }
+
+        /// The record as JSON, with the message type as the only key.
+        pub fn to_json(&self) -> Value {
+            match self {
+                Self::Message(msg) => serde_json::to_value(msg).unwrap_or_default(),
+                Self::Note(note) => serde_json::json!({ "Note": note }),
+                Self::RadioDiagnostics(time, diagnostics) => {
+                    serde_json::json!({ "RadioDiagnostics": { "time": time, "diagnostics": diagnostics } })
+                },
+                Self::TimeReference(reference) => serde_json::json!({ "TimeReference": reference }),
+            }
+        }
+    }
+
+    fn flatten(prefix: &str, value: &Value, columns: &mut Vec<(String, String)>) {
+        let key = |k: &dyn std::fmt::Display| if prefix.is_empty() { k.to_string() } else { format!("{}.{}", prefix, k) };
+        match value {
+            Value::Object(map) => map.iter().for_each(|(k, v)| flatten(&key(k), v, columns)),
+            Value::Array(values) => values.iter().enumerate().for_each(|(i, v)| flatten(&key(&i), v, columns)),
+            Value::Null => columns.push((prefix.to_string(), String::new())),
+            Value::String(s) => columns.push((prefix.to_string(), s.clone())),
+            v => columns.push((prefix.to_string(), v.to_string())),
+        }
+    }
+
+    fn csv_field(s: &str) -> String {
+        if s.contains([',', '"', '\n']) {
+            format!("\"{}\"", s.replace('"', "\"\""))
+        } else {
+            s.to_string()
+        }
+    }
+
+    /// Writes all records of one kind (see `LogRecord::kind`) as CSV, with one column per field.
+    /// Nested fields are flattened, e.g. `acceleration.0`. Since optional fields only show up when
+    /// present, the columns are the union of all fields seen.
+    pub fn write_csv<W: Write>(records: &[LogRecord], kind: &str, mut writer: W) -> std::io::Result<()> {
+        let rows: Vec<Vec<(String, String)>> = records
+            .iter()
+            .filter(|r| r.kind() == kind)
+            .map(|r| {
+                let mut columns = Vec::new();
+                if let Value::Object(map) = r.to_json() {
+                    map.values().for_each(|v| flatten("", v, &mut columns));
+                }
+                columns
+            })
+            .collect();
+
+        let mut header: Vec<String> = Vec::new();
+        for (name, _) in rows.iter().flatten() {
+            if !header.contains(name) {
+                header.push(name.clone());
+            }
+        }
+
+        writeln!(writer, "{}", header.iter().map(|h| csv_field(h)).collect::<Vec<_>>().join(","))?;
+        for row in rows {
+            let fields: Vec<String> = header
+                .iter()
+                .map(|h| row.iter().find(|(name, _)| name == h).map(|(_, v)| csv_field(v)).unwrap_or_default())
+                .collect();
+            writeln!(writer, "{}", fields.join(","))?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes all records as JSON lines, one object per record.
+    pub fn write_json<W: Write>(records: &[LogRecord], mut writer: W) -> std::io::Result<()> {
+        for record in records {
+            writeln!(writer, "{}", record.to_json())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(chunks: impl IntoIterator<Item = (u8, [u8; NOTE_CHUNK_LENGTH])>) -> std::vec::Vec<String<LOG_NOTE_LENGTH>> {
+        let mut assembler = NoteAssembler::default();
+        chunks.into_iter().filter_map(|(i, chunk)| assembler.push(i, chunk)).collect()
+    }
+
+    #[test]
+    fn note_roundtrip() {
+        for text in ["", "abc", "abcd", "second attempt, wind 5m/s", "ümlaut"] {
+            assert_eq!(assemble(note_chunks(text)), [text]);
+        }
+
+        let long = "x".repeat(100);
+        assert_eq!(assemble(note_chunks(&long)), [&long[..LOG_NOTE_LENGTH]]);
+    }
+
+    #[test]
+    fn time_reference_record() {
+        let reference = TimeReference { time: 12_345, utc: 1_760_000_000_000 };
+        let mut buffer = [0u8; 32];
+        let frame = postcard::to_slice_cobs(&(TIME_REFERENCE_TAG, reference), &mut buffer).unwrap();
+        let len = frame.len() - 1;
+
+        match LogRecord::decode(&mut frame[..len]) {
+            Ok(LogRecord::TimeReference(decoded)) => assert_eq!(decoded, reference),
+            other => panic!("{:?}", other),
+        }
+
+        assert_eq!(reference.utc_at(12_345), 1_760_000_000_000);
+        assert_eq!(reference.utc_at(13_345), 1_760_000_001_000);
+        assert_eq!(reference.utc_at(2_345), 1_759_999_990_000);
+    }
+
+    #[test]
+    fn note_with_missing_chunk() {
+        let chunks = note_chunks("first note");
+        assert!(assemble(chunks.iter().copied().filter(|(i, _)| *i != 1)).is_empty());
+
+        // The next note still gets through.
+        let second = note_chunks("second note");
+        assert_eq!(assemble(chunks.into_iter().skip(1).chain(second)), ["second note"]);
+    }
+}
@@ -0,0 +1,110 @@
+//! Wear and defect tracking for the flash (see `flash.rs`).
+//!
+//! Records that are rewritten in place, such as the settings and the calibration, each live in a
+//! region of one sector. Instead of erasing the sector for every write, each write goes into the
+//! next free slot of it, and the newest slot with a valid checksum is read back. The sector is
+//! only erased once all of its slots are used, which cuts the number of erases by the number of
+//! slots. The log sectors are all erased together, so a single count covers them.
+//!
+//! Log pages that still don't read back correctly after several attempts mark their sector as
+//! bad. The rest of such a sector is skipped, and it is skipped again after reboots and erases.
+//! The counts and the bad sectors are kept in a wear table in a region of its own.
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use defmt::Format;
+
+use shared_types::FLASH_SETTINGS_SIZE;
+
+use crate::flash_log::PAGE_SIZE;
+
+/// Bad sectors remembered at most. A flash with this many is worn out anyway.
+pub const MAX_BAD_SECTORS: usize = 32;
+/// Schema version of the stored wear table, to be changed whenever `WearTable` changes
+pub const WEAR_TABLE_VERSION: u8 = 2;
+
+/// Regions of the flash holding a single record that is rewritten in place
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Region {
+    Settings,
+    FlightSummary,
+    Calibration,
+    LinkConfig,
+    Wear,
+    Parameters,
+}
+
+impl Region {
+    pub const ALL: [Self; 6] = [
+        Self::Settings,
+        Self::FlightSummary,
+        Self::Calibration,
+        Self::LinkConfig,
+        Self::Wear,
+        Self::Parameters,
+    ];
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Settings => "settings",
+            Self::FlightSummary => "flight summary",
+            Self::Calibration => "calibration",
+            Self::LinkConfig => "link config",
+            Self::Wear => "wear table",
+            Self::Parameters => "parameters",
+        }
+    }
+
+    /// Size (bytes) of a single copy of the record, including its checksum
+    pub fn slot_size(&self) -> u32 {
+        match self {
+            Self::Settings => FLASH_SETTINGS_SIZE,
+            _ => PAGE_SIZE as u32,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WearTable {
+    /// Number of times the sector of each region has been erased, see `Region::index`
+    pub region_erases: [u32; Region::ALL.len()],
+    /// Number of times the log has been erased, and thereby each of its sectors
+    pub log_erases: u32,
+    /// Number of log pages that failed verification
+    pub write_failures: u32,
+    /// Indices of the log sectors that are skipped
+    pub bad_sectors: Vec<u32, MAX_BAD_SECTORS>,
+}
+
+impl WearTable {
+    /// Deserializes a wear table stored with the given schema version, converting it to the
+    /// current one if necessary. Returns `None` for unknown versions.
+    pub fn decode(version: u8, data: &[u8]) -> Option<Result<Self, postcard::Error>> {
+        match version {
+            // Before the parameters region existed
+            1 => Some(postcard::from_bytes::<([u32; 5], u32, u32, Vec<u32, MAX_BAD_SECTORS>)>(data).map(
+                |(erases, log_erases, write_failures, bad_sectors)| {
+                    let mut region_erases = [0; Region::ALL.len()];
+                    region_erases[..erases.len()].copy_from_slice(&erases);
+                    Self { region_erases, log_erases, write_failures, bad_sectors }
+                },
+            )),
+            WEAR_TABLE_VERSION => Some(postcard::from_bytes(data)),
+            _ => None,
+        }
+    }
+
+    pub fn is_bad(&self, sector: u32) -> bool {
+        self.bad_sectors.contains(&sector)
+    }
+
+    /// Returns false if the table is full, in which case the sector won't be skipped in future.
+    pub fn mark_bad(&mut self, sector: u32) -> bool {
+        self.is_bad(sector) || self.bad_sectors.push(sector).is_ok()
+    }
+}
@@ -0,0 +1,184 @@
+//! Summary of the flight's key numbers, computed on board so they are available right after
+//! recovery, without downloading and processing the full log. The summary is completed on
+//! landing and stored in a reserved sector at the end of the flash (see `flash.rs`).
+//!
+//! While landed, the vehicle also downlinks a condensed `FlightSummaryReport`, so the numbers
+//! reach the ground station before recovery, see `Radio::send_flight_summary`.
+
+use core::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use defmt::Format;
+
+use shared_types::FlightMode;
+
+use crate::clock::Instant;
+
+/// Time (ms) after each deployment before the descent rate is averaged, to skip the deceleration
+const DESCENT_SETTLE_TIME: u32 = 2_000;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FlightSummary {
+    /// Maximum altitude (m AGL)
+    pub apogee: f32,
+    /// Maximum vertical speed (m/s)
+    pub max_vertical_speed: f32,
+    /// Maximum vertical acceleration (m/s², without gravity)
+    pub max_acceleration: f32,
+    /// Duration (ms) of the burn phase
+    pub burn_time: u32,
+    /// Average descent rate (m/s) under drogue, if deployed long enough to measure
+    pub drogue_descent_rate: Option<f32>,
+    /// Average descent rate (m/s) under main, if deployed long enough to measure
+    pub main_descent_rate: Option<f32>,
+    /// Time (ms) from launch to landing
+    pub flight_duration: u32,
+    /// Launch rail azimuth and elevation (deg) captured at arming, see `launch_rail.rs`
+    pub rail: Option<(f32, f32)>,
+}
+
+/// Flight summary in fixed-point, small enough for a single downlink packet even in the worst
+/// case (7 varints of up to 3 bytes). The launch rail orientation is left out, it is only stored
+/// in the flash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub struct FlightSummaryReport {
+    /// Maximum altitude (m AGL)
+    pub apogee: u16,
+    /// Maximum vertical speed (0.1 m/s)
+    pub max_vertical_speed: u16,
+    /// Maximum vertical acceleration (0.1 m/s²)
+    pub max_acceleration: u16,
+    /// Duration (10 ms) of the burn phase
+    pub burn_time: u16,
+    /// Average descent rate (0.1 m/s) under drogue, 0 if not measured
+    pub drogue_descent_rate: u16,
+    /// Average descent rate (0.1 m/s) under main, 0 if not measured
+    pub main_descent_rate: u16,
+    /// Time (s) from launch to landing
+    pub flight_duration: u16,
+}
+
+impl From<&FlightSummary> for FlightSummaryReport {
+    fn from(summary: &FlightSummary) -> Self {
+        // Float to int casts saturate, negative values end up as 0.
+        Self {
+            apogee: summary.apogee as u16,
+            max_vertical_speed: (summary.max_vertical_speed * 10.0) as u16,
+            max_acceleration: (summary.max_acceleration * 10.0) as u16,
+            burn_time: (summary.burn_time / 10).min(u16::MAX as u32) as u16,
+            drogue_descent_rate: summary.drogue_descent_rate.map(|r| (r * 10.0) as u16).unwrap_or(0),
+            main_descent_rate: summary.main_descent_rate.map(|r| (r * 10.0) as u16).unwrap_or(0),
+            flight_duration: (summary.flight_duration / 1000).min(u16::MAX as u32) as u16,
+        }
+    }
+}
+
+impl fmt::Display for FlightSummaryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "apogee {}m, max. speed {:.1}m/s, max. acceleration {:.1}m/s², burn {:.2}s, ",
+            self.apogee,
+            self.max_vertical_speed as f32 / 10.0,
+            self.max_acceleration as f32 / 10.0,
+            self.burn_time as f32 / 100.0,
+        )?;
+        for (name, rate) in [("drogue", self.drogue_descent_rate), ("main", self.main_descent_rate)] {
+            match rate {
+                0 => write!(f, "{} descent n/a, ", name)?,
+                rate => write!(f, "{} descent {:.1}m/s, ", name, rate as f32 / 10.0)?,
+            }
+        }
+        write!(f, "flight {}s", self.flight_duration)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Average {
+    sum: f32,
+    count: u32,
+}
+
+impl Average {
+    fn add(&mut self, value: f32) {
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn value(&self) -> Option<f32> {
+        (self.count > 0).then(|| self.sum / self.count as f32)
+    }
+}
+
+pub struct FlightSummaryRecorder {
+    launch_time: Option<Instant>,
+    /// Current mode and the time it was entered
+    mode: (FlightMode, Instant),
+    summary: FlightSummary,
+    drogue_descent: Average,
+    main_descent: Average,
+}
+
+impl FlightSummaryRecorder {
+    pub fn new() -> Self {
+        Self {
+            launch_time: None,
+            mode: (FlightMode::Idle, Instant::ZERO),
+            summary: FlightSummary::default(),
+            drogue_descent: Average::default(),
+            main_descent: Average::default(),
+        }
+    }
+
+    /// Returns the completed summary once, upon landing.
+    pub fn tick(
+        &mut self,
+        time: Instant,
+        mode: FlightMode,
+        altitude_agl: f32,
+        vertical_speed: f32,
+        vertical_acceleration: f32,
+    ) -> Option<FlightSummary> {
+        let (previous_mode, mode_since) = self.mode;
+        if mode != previous_mode {
+            self.mode = (mode, time);
+        }
+
+        if mode < FlightMode::Burn {
+            *self = Self { mode: self.mode, ..Self::new() };
+            return None;
+        }
+
+        let launch_time = *self.launch_time.get_or_insert(time);
+        let since_launch = time.millis_since(launch_time);
+
+        if mode == FlightMode::Landed {
+            if previous_mode == FlightMode::Landed {
+                return None;
+            }
+
+            self.summary.flight_duration = since_launch;
+            self.summary.drogue_descent_rate = self.drogue_descent.value();
+            self.summary.main_descent_rate = self.main_descent.value();
+            return Some(self.summary.clone());
+        }
+
+        if mode != previous_mode && previous_mode == FlightMode::Burn {
+            self.summary.burn_time = since_launch;
+        }
+
+        self.summary.apogee = f32::max(self.summary.apogee, altitude_agl);
+        self.summary.max_vertical_speed = f32::max(self.summary.max_vertical_speed, vertical_speed);
+        self.summary.max_acceleration = f32::max(self.summary.max_acceleration, vertical_acceleration);
+
+        let settled = mode == previous_mode && time.millis_since(mode_since) >= DESCENT_SETTLE_TIME;
+        match mode {
+            FlightMode::RecoveryDrogue if settled => self.drogue_descent.add(-vertical_speed),
+            FlightMode::RecoveryMain if settled => self.main_descent.add(-vertical_speed),
+            _ => {},
+        }
+
+        None
+    }
+}
@@ -0,0 +1,284 @@
+//! Framing for the USB serial link. Messages are serialized using postcard, prefixed with the
+//! protocol version, followed by a CRC16 checksum, and COBS-encoded with a zero byte as frame
+//! delimiter. A receiver that lost bytes or received garbage simply discards everything up to the
+//! next delimiter.
+
+use heapless::Vec;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crc::{Crc, CRC_16_IBM_SDLC};
+
+use defmt::Format;
+
+const X25: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+
+/// Version of the telemetry protocol, i.e. the message definitions in `shared_types`, the framing
+/// here and the LoRa packet format in `lora_packet.rs`. Has to be incremented for incompatible
+/// changes, so mismatched firmware is reported as such instead of failing to deserialize.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+pub const MAX_PAYLOAD_SIZE: usize = 512;
+/// Version, payload and checksum, plus COBS overhead of one byte per 254 bytes, plus delimiter.
+pub const MAX_FRAME_SIZE: usize = 1 + MAX_PAYLOAD_SIZE + 2 + (1 + MAX_PAYLOAD_SIZE + 2) / 254 + 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum FrameError {
+    Overflow,
+    Encoding,
+    Checksum,
+    Serialization,
+    /// Frame from a different protocol version
+    Version(u8),
+}
+
+/// Serializes a message into a single delimited frame.
+pub fn encode_frame<T: Serialize>(msg: &T) -> Result<Vec<u8, MAX_FRAME_SIZE>, FrameError> {
+    let mut buffer = [0u8; 1 + MAX_PAYLOAD_SIZE + 2];
+    buffer[0] = PROTOCOL_VERSION;
+    let len = 1 + postcard::to_slice(msg, &mut buffer[1..(1 + MAX_PAYLOAD_SIZE)])
+        .map_err(|_| FrameError::Serialization)?
+        .len();
+
+    let crc = X25.checksum(&buffer[..len]);
+    buffer[len..(len + 2)].copy_from_slice(&crc.to_be_bytes());
+
+    let mut frame = Vec::new();
+    let _ = frame.resize(MAX_FRAME_SIZE, 0);
+    let encoded_len = cobs::encode(&buffer[..(len + 2)], &mut frame);
+    frame.truncate(encoded_len);
+    let _ = frame.push(0);
+
+    Ok(frame)
+}
+
+/// Incremental frame decoder. Bytes are fed in one at a time, so every byte is only looked at
+/// once, no matter how the frames are split across USB packets.
+pub struct FrameDecoder {
+    buffer: Vec<u8, MAX_FRAME_SIZE>,
+    overflowed: bool,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            overflowed: false,
+        }
+    }
+
+    /// Bytes received since the last delimiter.
+    pub fn pending(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn reset(&mut self) {
+        self.buffer.truncate(0);
+        self.overflowed = false;
+    }
+
+    /// Drops bytes matching `skip` preceding the pending frame, such as line endings sent by a
+    /// terminal. Since these may just as well be COBS code bytes, they are only dropped while the
+    /// pending bytes don't look like a complete frame.
+    pub fn discard_prefix(&mut self, skip: impl Fn(u8) -> bool) {
+        if self.overflowed {
+            return;
+        }
+
+        let mut start = 0;
+        while start < self.buffer.len() && skip(self.buffer[start]) && !is_plausible_frame(&self.buffer[start..]) {
+            start += 1;
+        }
+
+        let len = self.buffer.len();
+        self.buffer.rotate_left(start);
+        self.buffer.truncate(len - start);
+    }
+
+    /// Feeds a single received byte to the decoder. Returns the decoded message, or the reason
+    /// it was discarded, once a delimiter is received.
+    pub fn push<T: DeserializeOwned>(&mut self, byte: u8) -> Option<Result<T, FrameError>> {
+        self.push_with(byte, |payload| postcard::from_bytes(payload).map_err(|_| FrameError::Serialization))
+    }
+
+    /// Like `push`, but hands the checked payload to the given function instead of deserializing
+    /// it directly, e.g. to support different message types.
+    pub fn push_with<R>(&mut self, byte: u8, f: impl FnOnce(&[u8]) -> Result<R, FrameError>) -> Option<Result<R, FrameError>> {
+        if byte != 0 {
+            if self.buffer.push(byte).is_err() {
+                self.overflowed = true;
+            }
+            return None;
+        }
+
+        // Consecutive delimiters, nothing to decode
+        if self.buffer.is_empty() && !self.overflowed {
+            return None;
+        }
+
+        let result = self.decode().and_then(f);
+        self.reset();
+        Some(result)
+    }
+
+    fn decode(&mut self) -> Result<&[u8], FrameError> {
+        if self.overflowed {
+            return Err(FrameError::Overflow);
+        }
+
+        let len = cobs::decode_in_place(&mut self.buffer).map_err(|_| FrameError::Encoding)?;
+        if len < 3 {
+            return Err(FrameError::Encoding);
+        }
+
+        let (payload, crc) = self.buffer[..len].split_at(len - 2);
+        if u16::from_be_bytes([crc[0], crc[1]]) != X25.checksum(payload) {
+            return Err(FrameError::Checksum);
+        }
+
+        if payload[0] != PROTOCOL_VERSION {
+            return Err(FrameError::Version(payload[0]));
+        }
+
+        Ok(&payload[1..])
+    }
+}
+
+/// Whether the given bytes start with the protocol version as first decoded byte and the chain
+/// of COBS code bytes ends exactly at their end.
+fn is_plausible_frame(bytes: &[u8]) -> bool {
+    if bytes.len() < 2 || bytes[0] < 2 || bytes[1] != PROTOCOL_VERSION {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < bytes.len() {
+        i += bytes[i] as usize;
+    }
+
+    i == bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(decoder: &mut FrameDecoder, bytes: &[u8]) -> std::vec::Vec<Result<(u8, u32), FrameError>> {
+        bytes.iter().filter_map(|byte| decoder.push(*byte)).collect()
+    }
+
+    fn message() -> std::vec::Vec<u8> {
+        let mut buffer = [0u8; 16];
+        postcard::to_slice(&(0xfeu8, 123_456u32), &mut buffer).unwrap().to_vec()
+    }
+
+    /// Builds a frame by hand, the way a host implementing the format from the README would.
+    fn raw_frame(version: u8, message: &[u8]) -> std::vec::Vec<u8> {
+        let mut payload = std::vec![version];
+        payload.extend_from_slice(message);
+        payload.extend_from_slice(&X25.checksum(&payload).to_be_bytes());
+
+        let mut frame = std::vec![0; payload.len() + payload.len() / 254 + 2];
+        let len = cobs::encode(&payload, &mut frame);
+        frame.truncate(len);
+        frame.push(0);
+        frame
+    }
+
+    #[test]
+    fn round_trip() {
+        let frame = encode_frame(&(0xfeu8, 123_456u32)).unwrap();
+        assert_eq!(frame.iter().filter(|b| **b == 0).count(), 1);
+        assert_eq!(frame.last(), Some(&0));
+
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(decode_all(&mut decoder, &frame), [Ok((0xfe, 123_456))]);
+        assert!(decoder.pending().is_empty());
+    }
+
+    #[test]
+    fn matches_documented_format() {
+        let message = message();
+        let frame = encode_frame(&(0xfeu8, 123_456u32)).unwrap();
+        assert_eq!(&frame[..], &raw_frame(PROTOCOL_VERSION, &message)[..]);
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut frame = encode_frame(&(0xfeu8, 123_456u32)).unwrap();
+        frame[3] ^= 0x01;
+
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(decode_all(&mut decoder, &frame), [Err(FrameError::Checksum)]);
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let message = message();
+        let frame = raw_frame(PROTOCOL_VERSION + 1, &message);
+
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(decode_all(&mut decoder, &frame), [Err(FrameError::Version(PROTOCOL_VERSION + 1))]);
+    }
+
+    #[test]
+    fn unversioned_frame_rejected() {
+        // Version 1 frames carried the COBS-encoded message without version or checksum.
+        let message = message();
+        let mut frame = std::vec![0; message.len() + 2];
+        let len = cobs::encode(&message, &mut frame);
+        frame.truncate(len);
+        frame.push(0);
+
+        let mut decoder = FrameDecoder::new();
+        assert!(decode_all(&mut decoder, &frame)[0].is_err());
+    }
+
+    #[test]
+    fn resynchronizes_after_garbage() {
+        let frame = encode_frame(&(0xfeu8, 42u32)).unwrap();
+        let mut bytes = std::vec![0x13, 0x37, 0x42];
+        bytes.extend_from_slice(&frame[2..]);
+        bytes.extend_from_slice(&frame);
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&frame);
+
+        let mut decoder = FrameDecoder::new();
+        let results = decode_all(&mut decoder, &bytes);
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_err());
+        assert_eq!(results[1..], [Ok((0xfe, 42)), Ok((0xfe, 42))]);
+    }
+
+    #[test]
+    fn discards_line_endings_before_frame() {
+        let is_line_end = |byte| byte == b'\r' || byte == b'\n';
+
+        // The first COBS code byte is a line ending here, which has to be kept.
+        let frame = encode_frame(&(0xfeu8, [1u8; 5])).unwrap();
+        assert_eq!(frame[0], b'\n');
+
+        for prefix in [&b""[..], b"\r", b"\n", b"\r\n"] {
+            let mut decoder = FrameDecoder::new();
+            let mut bytes = prefix.to_vec();
+            bytes.extend_from_slice(&frame);
+            let (last, rest) = bytes.split_last().unwrap();
+            for byte in rest {
+                assert!(decoder.push::<(u8, [u8; 5])>(*byte).is_none());
+            }
+
+            decoder.discard_prefix(is_line_end);
+            assert_eq!(decoder.push(*last), Some(Ok((0xfeu8, [1u8; 5]))));
+        }
+    }
+
+    #[test]
+    fn overflow() {
+        let mut decoder = FrameDecoder::new();
+        let garbage = std::vec![0x55; MAX_FRAME_SIZE + 10];
+        assert!(decode_all(&mut decoder, &garbage).is_empty());
+        assert_eq!(decoder.push::<(u8, u32)>(0), Some(Err(FrameError::Overflow)));
+
+        let frame = encode_frame(&(0xfeu8, 42u32)).unwrap();
+        assert_eq!(decode_all(&mut decoder, &frame), [Ok((0xfe, 42))]);
+    }
+}
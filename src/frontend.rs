@@ -0,0 +1,216 @@
+//! External RF frontend on the ground station. GCS hardware can be fitted with a low-noise
+//! amplifier and a power amplifier in front of the transceiver, each enabled via a GPIO (see
+//! `FrontendResources` in `board.rs`). The LNA is only enabled while receiving, and the PA is only
+//! keyed while transmitting, which the ground station only does in the uplink windows (or, on
+//! relays, when retransmitting a packet).
+//!
+//! In automatic mode, both follow the SNR of the received downlink. Close to the vehicle, the LNA
+//! is bypassed so the transceiver isn't overloaded, and the PA stays off, sparing the vehicle's
+//! receiver as well. As the link gets weaker, the LNA and then the PA are switched on. Without
+//! contact, both are on, to find the vehicle at any range.
+//!
+//! The configuration is changed via the console (`frontend ...`) and not persisted.
+
+use embassy_stm32::gpio::{AnyPin, Level, Output, Speed};
+
+pub const FRONTEND_HELP_TEXT: &[&str] = &[
+    "frontend                show external LNA/PA configuration and state",
+    "frontend <lna|pa> <on|off|auto>  switch the external LNA or PA",
+];
+
+/// Averaged downlink SNR (dB) above which the LNA is bypassed in automatic mode
+const LNA_BYPASS_SNR: f32 = 8.0;
+/// Averaged downlink SNR (dB) below which the PA is keyed in automatic mode
+const PA_ENABLE_SNR: f32 = 0.0;
+/// Distance (dB) between switching on and off, so the frontend doesn't toggle with every packet
+const SNR_HYSTERESIS: f32 = 3.0;
+/// Weight of each received packet in the SNR average
+const SNR_SMOOTHING: f32 = 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrontendMode {
+    Off,
+    On,
+    /// Switched depending on the downlink SNR
+    Auto,
+}
+
+impl FrontendMode {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Self::Off),
+            "on" => Some(Self::On),
+            "auto" => Some(Self::Auto),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::On => "on",
+            Self::Auto => "auto",
+        }
+    }
+
+    fn enabled(&self, auto: bool) -> bool {
+        match self {
+            Self::Off => false,
+            Self::On => true,
+            Self::Auto => auto,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrontendConfig {
+    pub lna: FrontendMode,
+    pub pa: FrontendMode,
+}
+
+impl Default for FrontendConfig {
+    fn default() -> Self {
+        Self {
+            lna: FrontendMode::Auto,
+            pa: FrontendMode::Auto,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrontendCommand {
+    Show,
+    Lna(FrontendMode),
+    Pa(FrontendMode),
+}
+
+impl FrontendCommand {
+    /// Parses the arguments following `frontend` on the console.
+    pub fn parse<'a>(part: Option<&str>, mut args: impl Iterator<Item = &'a str>) -> Option<Self> {
+        match part {
+            None => Some(Self::Show),
+            Some("lna") => args.next().and_then(FrontendMode::parse).map(Self::Lna),
+            Some("pa") => args.next().and_then(FrontendMode::parse).map(Self::Pa),
+            _ => None,
+        }
+    }
+}
+
+pub struct Frontend {
+    lna: Option<Output<'static, AnyPin>>,
+    pa: Option<Output<'static, AnyPin>>,
+    config: FrontendConfig,
+    /// Averaged SNR (dB) of the received downlink, or `None` without contact
+    snr: Option<f32>,
+    /// Whether the LNA and PA are wanted in automatic mode
+    lna_auto: bool,
+    pa_auto: bool,
+    transmitting: bool,
+}
+
+impl Frontend {
+    /// Frontend with the given enable pins, which are initialized low. Without any, there is no
+    /// external frontend and all switching is ignored.
+    pub fn new(lna: Option<AnyPin>, pa: Option<AnyPin>) -> Self {
+        let mut frontend = Self {
+            lna: lna.map(|pin| Output::new(pin, Level::Low, Speed::Low)),
+            pa: pa.map(|pin| Output::new(pin, Level::Low, Speed::Low)),
+            config: FrontendConfig::default(),
+            snr: None,
+            lna_auto: true,
+            pa_auto: true,
+            transmitting: false,
+        };
+        frontend.update_pins();
+        frontend
+    }
+
+    pub fn is_fitted(&self) -> bool {
+        self.lna.is_some() || self.pa.is_some()
+    }
+
+    pub fn config(&self) -> FrontendConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: FrontendConfig) {
+        self.config = config;
+        self.update_pins();
+    }
+
+    /// Averaged downlink SNR (dB) the automatic mode is based on, if in contact.
+    pub fn snr(&self) -> Option<f32> {
+        self.snr
+    }
+
+    /// Whether the LNA is enabled while receiving and the PA keyed while transmitting, or `None`
+    /// if not fitted.
+    pub fn state(&self) -> (Option<bool>, Option<bool>) {
+        (
+            self.lna.as_ref().map(|_| self.config.lna.enabled(self.lna_auto)),
+            self.pa.as_ref().map(|_| self.config.pa.enabled(self.pa_auto)),
+        )
+    }
+
+    /// Updates the automatic mode with the SNR of a received downlink packet, as reported by the
+    /// transceiver (0.25dB steps).
+    pub fn packet_received(&mut self, snr: i8) {
+        let snr = snr as f32 / 4.0;
+        let average = match self.snr {
+            Some(average) => average + (snr - average) * SNR_SMOOTHING,
+            None => snr,
+        };
+        self.snr = Some(average);
+
+        if average > LNA_BYPASS_SNR {
+            self.lna_auto = false;
+        } else if average < LNA_BYPASS_SNR - SNR_HYSTERESIS {
+            self.lna_auto = true;
+        }
+
+        if average < PA_ENABLE_SNR {
+            self.pa_auto = true;
+        } else if average > PA_ENABLE_SNR + SNR_HYSTERESIS {
+            self.pa_auto = false;
+        }
+
+        self.update_pins();
+    }
+
+    /// Switches the automatic mode to full range after losing contact.
+    pub fn contact_lost(&mut self) {
+        self.snr = None;
+        self.lna_auto = true;
+        self.pa_auto = true;
+        self.update_pins();
+    }
+
+    /// To be called right before the transceiver starts transmitting.
+    pub fn transmit(&mut self) {
+        self.transmitting = true;
+        self.update_pins();
+    }
+
+    /// To be called once the transceiver is done transmitting.
+    pub fn receive(&mut self) {
+        self.transmitting = false;
+        self.update_pins();
+    }
+
+    fn update_pins(&mut self) {
+        let lna = !self.transmitting && self.config.lna.enabled(self.lna_auto);
+        let pa = self.transmitting && self.config.pa.enabled(self.pa_auto);
+
+        // Never have both enabled at the same time, the PA would feed straight into the LNA.
+        let (first, second) = if self.transmitting {
+            ((&mut self.lna, lna), (&mut self.pa, pa))
+        } else {
+            ((&mut self.pa, pa), (&mut self.lna, lna))
+        };
+        for (pin, high) in [first, second] {
+            if let Some(pin) = pin.as_mut() {
+                pin.set_level(high.into());
+            }
+        }
+    }
+}
@@ -2,40 +2,87 @@ use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
 use embassy_executor::Spawner;
 use embassy_stm32::gpio::{Output, Input};
 use embassy_stm32::peripherals::*;
-use embassy_stm32::spi::Spi;
 use embassy_stm32::time::Hertz;
 use embassy_stm32::wdg::IndependentWatchdog;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_time::Instant;
 use embassy_time::{Ticker, Duration};
 
 use defmt::*;
 
 use shared_types::*;
 
-use crate::buzzer::Buzzer as BuzzerDriver;
+use crate::board::{BuzzerTimer, SensorSpi};
+use crate::bootloader::reboot_to_bootloader;
+use crate::capture::CAPTURE_HELP_TEXT;
+use crate::clock::Instant;
+use crate::countdown::CountdownStatus;
+use crate::drivers::sensors::GPSTime;
+use crate::buzzer::{Buzzer as BuzzerDriver, PwmToneOutput};
+use crate::downlink_loss::{Gap, LossMonitor};
+use crate::errors::{report, ErrorMonitor, Subsystem, SUBSYSTEMS};
+use crate::events::EventMonitor;
+use crate::flash::LoggingStatus;
+use crate::flash_log::TimeReference;
+use crate::flight_summary::FlightSummaryReport;
+use crate::frontend::{FrontendCommand, FrontendConfig, FRONTEND_HELP_TEXT};
+use crate::geofence::GeofenceViolation;
+use crate::leds::Leds;
 use crate::lora::*;
+use crate::lora_packet::DownlinkKind;
+use crate::redundancy::{PartnerState, RedundancyStatus};
+use crate::retransmission::{RetransmitRequest, RETRANSMIT_HELP_TEXT};
+use crate::sequence::*;
 use crate::usb::*;
+use crate::usb_console::*;
+use crate::version::{fingerprint, Heartbeat, FIRMWARE_VERSION, GIT_HASH};
 
 // TODO
-type RadioHandle = Radio<SpiDevice<'static, CriticalSectionRawMutex, Spi<'static, SPI1, DMA2_CH3, DMA2_CH2>, Output<'static, PA1>>, Input<'static, PC0>,Input<'static, PC1>>;
+type RadioHandle = Radio<SpiDevice<'static, CriticalSectionRawMutex, SensorSpi, Output<'static, PA1>>, Input<'static, PC0>,Input<'static, PC1>>;
 
-type LEDs = (Output<'static, PC13>, Output<'static, PC14>, Output<'static, PC15>);
-// TODO
-#[cfg(feature="rev1")]
-type Buzzer = BuzzerDriver<TIM4>;
-#[cfg(not(feature="rev1"))]
-type Buzzer = BuzzerDriver<TIM3>;
+type Buzzer = BuzzerDriver<PwmToneOutput<BuzzerTimer>>;
 
 const MAIN_LOOP_FREQUENCY: Hertz = Hertz::hz(1000);
+/// Free flash sectors on the vehicle below which the operator is warned, matching the reserve the
+/// vehicle itself keeps (512 KiB of 4 KiB sectors)
+const LOW_FLASH_SECTORS: u16 = 128;
 
 pub struct GroundControlStation {
-    pub time: core::num::Wrapping<u32>,
+    pub time: Instant,
     usb: UsbHandle,
     radio: RadioHandle,
-    leds: LEDs,
+    leds: Leds,
     buzzer: Buzzer,
-    last_msg_received: core::num::Wrapping<u32>,
+    errors: ErrorMonitor,
+    events: EventMonitor,
+    downlink_loss: LossMonitor,
+    /// Last downlink gap, for retransmission requests
+    last_gap: Option<Gap>,
+    /// Number of retransmitted messages received
+    retransmitted: u32,
+    /// Whether the GCS's own radio diagnostics were requested via the console
+    diagnostics_requested: bool,
+    sequence: Sequence,
+    last_msg_received: Instant,
+    /// Flight mode last reported by the vehicle
+    vehicle_mode: Option<FlightMode>,
+    /// Firmware and configuration last reported by the vehicle
+    vehicle_heartbeat: Option<Heartbeat>,
+    /// State of the vehicle's flash log last reported
+    vehicle_logging: Option<LoggingStatus>,
+    /// Summary of the vehicle's last flight, downlinked after landing
+    vehicle_summary: Option<FlightSummaryReport>,
+    /// Redundancy state last reported by each of the vehicle's FCs, see `redundancy.rs`
+    vehicle_redundancy: [Option<RedundancyStatus>; 2],
+    /// Time reference last reported by the vehicle, relating its time to UTC
+    vehicle_time_reference: Option<TimeReference>,
+}
+
+fn downlink_mode(msg: &DownlinkMessage) -> Option<FlightMode> {
+    match msg {
+        DownlinkMessage::TelemetryMain(m) => Some(m.mode),
+        DownlinkMessage::TelemetryFastCompressed(m) => Some(m.mode),
+        _ => None,
+    }
 }
 
 // TODO: move to main?
@@ -53,21 +100,35 @@ impl GroundControlStation {
     pub fn init(
         usb: UsbHandle,
         radio: RadioHandle,
-        leds: LEDs,
+        leds: Leds,
         buzzer: Buzzer,
     ) -> Self {
+        info!("Firmware {} ({})", FIRMWARE_VERSION, GIT_HASH);
         Self {
-            time: core::num::Wrapping(0),
+            time: Instant::ZERO,
             usb,
             radio,
             leds,
             buzzer,
-            last_msg_received: core::num::Wrapping(0),
+            errors: ErrorMonitor::new(),
+            events: EventMonitor::new(),
+            downlink_loss: LossMonitor::new(),
+            last_gap: None,
+            retransmitted: 0,
+            diagnostics_requested: false,
+            sequence: Sequence::new(),
+            last_msg_received: Instant::ZERO,
+            vehicle_mode: None,
+            vehicle_heartbeat: None,
+            vehicle_logging: None,
+            vehicle_summary: None,
+            vehicle_redundancy: [None; 2],
+            vehicle_time_reference: None,
         }
     }
 
     pub async fn tick(&mut self) {
-        let downlink_msg = self.radio.tick(self.time.0).await;
+        let downlink_msg = self.radio.tick(self.time.wire()).await;
         let uplink_msg = self.usb.next_uplink_message().and_then(|msg| {
             match msg {
                 UplinkMessage::Heartbeat => None,
@@ -84,18 +145,197 @@ impl GroundControlStation {
             }
         });
 
-        let rssi_led = (self.time - self.last_msg_received).0 < 50;
-        self.leds.0.set_level((!(self.radio.transmit_power >= TransmitPower::P20dBm)).into());
-        self.leds.1.set_level((!rssi_led).into());
-        self.leds.2.set_level((!true).into());
-        self.buzzer.tick(self.time.0, None);
+        let rssi_led = self.time.millis_since(self.last_msg_received) < 50;
+        self.leds.set(self.radio.transmit_power >= TransmitPower::P20dBm, rssi_led, true);
+        self.buzzer.tick(self.time, None);
 
         if let Some(msg) = uplink_msg {
             self.radio.queue_uplink_message(msg);
         }
 
+        if let Some(cmd) = self.usb.next_console_command() {
+            self.handle_console_command(cmd);
+        }
+        self.tick_sequence();
+
+        if let Some(rejection) = self.radio.take_mode_rejection() {
+            warn!("Vehicle rejected mode change to {:?}: {}", Debug2Format(&rejection.to), rejection.reason.name());
+            self.usb.console_print(format_args!(
+                "vehicle rejected {:?} -> {:?}: {}",
+                rejection.from,
+                rejection.to,
+                rejection.reason.name()
+            ));
+            self.events.mode_rejected(rejection);
+
+            // The rest of the sequence most likely relies on the mode change.
+            if self.sequence.is_running() {
+                self.sequence.abort();
+                self.usb.console_print(format_args!("seq: aborted"));
+            }
+        }
+
+        if let Some(status) = self.radio.take_countdown_status() {
+            match status {
+                CountdownStatus::Running(remaining) => self.usb.console_print(format_args!("countdown: {}s", remaining.div_ceil(1000))),
+                CountdownStatus::Complete => self.usb.console_print(format_args!("countdown: complete")),
+                CountdownStatus::Aborted(check) => {
+                    warn!("Vehicle aborted countdown, {} check failed.", check.name());
+                    self.usb.console_print(format_args!("countdown: aborted, {} check failed", check.name()));
+                },
+            }
+            self.events.countdown(status);
+        }
+
+        // Only reported when something changed, the heartbeat itself is repeated regularly.
+        if let Some(heartbeat) = self.radio.take_heartbeat() {
+            if self.vehicle_heartbeat != Some(heartbeat) {
+                self.vehicle_heartbeat = Some(heartbeat);
+                let link = fingerprint(&self.radio.link_config());
+                let mismatch = !heartbeat.same_firmware(&Heartbeat::new(0, 0, link)) || heartbeat.link != link;
+                if mismatch {
+                    warn!("Vehicle firmware or link configuration differs: {:?}", heartbeat);
+                }
+                self.usb.console_print(format_args!("vehicle: {}{}", heartbeat, if mismatch { ", MISMATCH" } else { "" }));
+                self.events.heartbeat(heartbeat, mismatch);
+            }
+        }
+
+        if let Some(status) = self.radio.take_logging_status() {
+            let new_errors = self.vehicle_logging.map(|s| status.write_errors > s.write_errors).unwrap_or(status.write_errors > 0);
+            let stalled = status.rate == 0 && self.vehicle_mode.map(|m| m >= FlightMode::ArmedLaunchImminent).unwrap_or(false);
+            let full = status.sectors_free < LOW_FLASH_SECTORS;
+            if new_errors || stalled || full {
+                warn!("Vehicle logging problem: {:?}", status);
+                self.usb.console_print(format_args!(
+                    "LOGGING:{}{}{} {} sectors free, {} bytes/s, {} errors",
+                    if new_errors { " write errors," } else { "" },
+                    if stalled { " stalled," } else { "" },
+                    if full { " flash almost full," } else { "" },
+                    status.sectors_free,
+                    status.rate,
+                    status.write_errors,
+                ));
+            }
+            self.vehicle_logging = Some(status);
+            self.events.logging_status(status, new_errors || stalled || full);
+        }
+
+        // Repeated regularly, only reported when something changed.
+        if let Some(status) = self.radio.take_redundancy_status() {
+            let slot = &mut self.vehicle_redundancy[(status.fc_id & 1) as usize];
+            if *slot != Some(status) {
+                *slot = Some(status);
+                if matches!(status.partner, PartnerState::Disagreeing | PartnerState::Lost) {
+                    warn!("FC {} reports partner {:?}", status.fc_id, status.partner);
+                }
+                self.usb.console_print(format_args!(
+                    "fc {}: partner {:?} (mode {:?}){}",
+                    status.fc_id,
+                    status.partner,
+                    status.partner_mode,
+                    if status.in_command { ", in command" } else { "" }
+                ));
+                self.events.redundancy(status);
+            }
+        }
+
+        // Repeated while the vehicle is landed, only reported once.
+        if let Some(summary) = self.radio.take_flight_summary() {
+            if self.vehicle_summary != Some(summary) {
+                info!("Flight summary received: {:?}", summary);
+                self.usb.console_print(format_args!("flight summary: {}", summary));
+                self.vehicle_summary = Some(summary);
+                self.events.flight_summary(summary);
+            }
+        }
+
+        // Repeated periodically, only printed if it doesn't match the last one, e.g. after a
+        // reboot.
+        if let Some(reference) = self.radio.take_time_reference() {
+            let consistent = self.vehicle_time_reference
+                .is_some_and(|r| r.utc_at(reference.time).abs_diff(reference.utc) < 1_000);
+            if !consistent {
+                info!("Time reference received: {}ms at {}ms UTC", reference.time, reference.utc);
+                self.print_time_reference(reference);
+            }
+            self.vehicle_time_reference = Some(reference);
+            self.events.time_reference(reference);
+        }
+
+        if let Some((violation, action)) = self.radio.take_geofence_violation() {
+            error!("Vehicle left the geofence: {:?}, {}", violation, action.name());
+            match violation {
+                GeofenceViolation::Altitude(altitude) => self.usb.console_print(format_args!("GEOFENCE: altitude {:.0}m AGL, {}", altitude, action.name())),
+                GeofenceViolation::Distance(distance) => self.usb.console_print(format_args!("GEOFENCE: {:.0}m from pad, {}", distance, action.name())),
+            }
+            self.events.geofence(violation, action);
+        }
+
+        // Retransmitted messages are only passed on, they are old news for everything else.
+        if let Some(msg) = self.radio.take_retransmission() {
+            self.retransmitted += 1;
+            self.usb.send_message(msg);
+        }
+
+        if core::mem::take(&mut self.diagnostics_requested) {
+            match self.radio.trx.diagnostics().await {
+                Ok(diagnostics) => {
+                    let usb = &mut self.usb;
+                    diagnostics.print("gcs radio", |args| usb.console_print(args));
+                    self.radio.tx_stats().print("gcs radio", |args| usb.console_print(args));
+                },
+                Err(e) => {
+                    report(Subsystem::Radio, e, "reading diagnostics");
+                    self.usb.console_print(format_args!("gcs radio: reading diagnostics failed"));
+                }
+            }
+        }
+
+        if let Some(diagnostics) = self.radio.take_diagnostics() {
+            let usb = &mut self.usb;
+            diagnostics.print("vehicle radio", |args| usb.console_print(args));
+        }
+
+        if let Some(counts) = self.radio.take_error_counts() {
+            let total: u32 = counts.0.iter().map(|c| *c as u32).sum();
+            self.usb.console_print(format_args!("vehicle errors: {}", total));
+            for (subsystem, count) in SUBSYSTEMS.iter().zip(counts.0).filter(|(_, c)| *c > 0) {
+                self.usb.console_print(format_args!("  {:?}: {}", subsystem, count));
+            }
+            self.events.error_counts(counts);
+        }
+
+        if let Some(result) = self.radio.take_self_test_result() {
+            if !result.passed() {
+                warn!("Vehicle self test failed: {:?}", result);
+            }
+            self.usb.console_print(format_args!("vehicle self test: {}", result));
+            self.events.self_test(result);
+        }
+
+        if let Some(result) = self.radio.take_scan_result() {
+            for channel in result {
+                self.usb.console_print(format_args!(
+                    "{}kHz: average {:.1}dBm, peak {:.1}dBm",
+                    channel.frequency / 1_000,
+                    channel.average,
+                    channel.peak
+                ));
+            }
+        }
+
         if let Some(msg) = downlink_msg {
             self.last_msg_received = self.time;
+            if let Some(mode) = downlink_mode(&msg) {
+                self.vehicle_mode = Some(mode);
+            }
+            let kind = DownlinkKind::of(&msg);
+            if let Some(gap) = self.downlink_loss.tick(kind, self.radio.sequence_number(), msg.time()) {
+                self.events.gap(gap);
+                self.last_gap = Some(gap);
+            }
+            self.events.tick(&msg, self.radio.trx.rssi, self.radio.trx.snr);
             let gcs_message = DownlinkMessage::TelemetryGCS(TelemetryGCS {
                 time: msg.time(),
                 lora_rssi: self.radio.trx.rssi,
@@ -107,6 +347,263 @@ impl GroundControlStation {
             self.usb.send_message(gcs_message);
         }
 
-        self.time += 1_000 / MAIN_LOOP_FREQUENCY.0;
+        self.errors.tick(self.time);
+
+        self.time = self.time.plus_millis(1_000 / MAIN_LOOP_FREQUENCY.0);
+    }
+
+    fn tick_sequence(&mut self) {
+        match self.sequence.tick(self.time, self.vehicle_mode, self.radio.uplink_pending()) {
+            Some(SequenceEvent::Send(cmd)) => {
+                self.usb.console_print(format_args!("seq: sending {:?}", cmd));
+                self.radio.queue_uplink_message(UplinkMessage::Command(cmd));
+            },
+            Some(SequenceEvent::Finished) => self.usb.console_print(format_args!("seq: finished")),
+            Some(SequenceEvent::TimedOut(mode)) => {
+                warn!("Command sequence aborted, vehicle did not reach {:?}.", Debug2Format(&mode));
+                self.usb.console_print(format_args!("seq: vehicle did not reach {:?}, aborted", mode));
+            },
+            None => {},
+        }
+    }
+
+    fn print_frontend(&mut self) {
+        let frontend = self.radio.frontend();
+        if !frontend.is_fitted() {
+            self.usb.console_print(format_args!("frontend: not fitted"));
+            return;
+        }
+
+        let config = frontend.config();
+        let (lna, pa) = frontend.state();
+        let snr = frontend.snr();
+        let state = |enabled: Option<bool>| match enabled {
+            Some(true) => "enabled",
+            Some(false) => "disabled",
+            None => "not fitted",
+        };
+        self.usb.console_print(format_args!("frontend lna: {} ({})", config.lna.name(), state(lna)));
+        self.usb.console_print(format_args!("frontend pa: {} ({})", config.pa.name(), state(pa)));
+        match snr {
+            Some(snr) => self.usb.console_print(format_args!("frontend snr: {:.1}dB", snr)),
+            None => self.usb.console_print(format_args!("frontend snr: no contact")),
+        }
+    }
+
+    fn print_time_reference(&mut self, reference: TimeReference) {
+        let utc = GPSTime::from_unix_millis(reference.utc);
+        self.usb.console_print(format_args!(
+            "vehicle time: {}ms at {:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03} UTC",
+            reference.time,
+            utc.year,
+            utc.month,
+            utc.day,
+            utc.hour,
+            utc.minute,
+            utc.second,
+            utc.millisecond
+        ));
+    }
+
+    fn handle_console_command(&mut self, cmd: ConsoleCommand) {
+        info!("Received console command: {:?}", Debug2Format(&cmd));
+        match cmd {
+            ConsoleCommand::Help => {
+                for line in SEQUENCE_HELP_TEXT {
+                    self.usb.console_print(format_args!("{}", line));
+                }
+                for line in CAPTURE_HELP_TEXT.iter().chain(RETRANSMIT_HELP_TEXT).chain(RADIO_HELP_TEXT).chain(ENGINE_HELP_TEXT).chain(FRONTEND_HELP_TEXT) {
+                    self.usb.console_print(format_args!("{}", line));
+                }
+                #[cfg(not(feature = "relay"))]
+                for line in RELAY_HELP_TEXT {
+                    self.usb.console_print(format_args!("{}", line));
+                }
+            },
+            ConsoleCommand::Status => {
+                self.usb.console_print(format_args!("firmware: {} ({})", FIRMWARE_VERSION, GIT_HASH));
+                match self.vehicle_heartbeat {
+                    Some(heartbeat) => self.usb.console_print(format_args!("vehicle firmware: {}", heartbeat)),
+                    None => self.usb.console_print(format_args!("vehicle firmware: unknown")),
+                }
+                self.usb.console_print(format_args!("vehicle mode: {:?}", self.vehicle_mode));
+                match self.vehicle_logging {
+                    Some(s) => self.usb.console_print(format_args!(
+                        "vehicle logging: {} bytes, {} sectors used, {} free, {} bytes/s, {} errors",
+                        s.bytes_written, s.sectors_used, s.sectors_free, s.rate, s.write_errors
+                    )),
+                    None => self.usb.console_print(format_args!("vehicle logging: unknown")),
+                }
+                for status in self.vehicle_redundancy.iter().flatten() {
+                    self.usb.console_print(format_args!(
+                        "fc {}: partner {:?} (mode {:?}), in command: {}",
+                        status.fc_id,
+                        status.partner,
+                        status.partner_mode,
+                        status.in_command
+                    ));
+                }
+                if let Some(summary) = self.vehicle_summary {
+                    self.usb.console_print(format_args!("flight summary: {}", summary));
+                }
+                if let Some(reference) = self.vehicle_time_reference {
+                    self.print_time_reference(reference);
+                }
+                self.usb.console_print(format_args!("packet capture: {}", crate::capture::enabled()));
+                self.usb.console_print(format_args!("downlink recording: {}", crate::capture::recording()));
+                let heap = crate::heap::stats();
+                self.usb.console_print(format_args!(
+                    "heap: {}/{} bytes used, peak {}, {} failed allocations ({} fragmented)",
+                    heap.used,
+                    heap.size,
+                    heap.peak,
+                    heap.failed_allocations,
+                    heap.fragmented_allocations
+                ));
+                for kind in DownlinkKind::ALL {
+                    let stats = self.downlink_loss.stats(kind);
+                    if stats.received > 0 {
+                        self.usb.console_print(format_args!(
+                            "downlink {}: {} received, {} lost ({:.1}%)",
+                            kind.name(),
+                            stats.received,
+                            stats.lost,
+                            stats.loss() * 100.0
+                        ));
+                    }
+                }
+                self.usb.console_print(format_args!("retransmitted: {} messages", self.retransmitted));
+                self.usb.console_print(format_args!("sync word: {:#04x}", self.radio.trx.sync_word()));
+                #[cfg(feature = "relay")]
+                self.usb.console_print(format_args!("relaying downlink"));
+                #[cfg(not(feature = "relay"))]
+                self.usb.console_print(format_args!("via relay: {}", self.radio.via_relay()));
+            },
+            #[cfg(not(feature = "relay"))]
+            ConsoleCommand::Relay(enabled) => {
+                self.radio.set_via_relay(enabled);
+                self.usb.console_print(format_args!("relay: {}", if enabled { "on" } else { "off" }));
+            },
+            ConsoleCommand::Frontend(cmd) => {
+                let config = self.radio.frontend().config();
+                let config = match cmd {
+                    FrontendCommand::Show => config,
+                    FrontendCommand::Lna(lna) => FrontendConfig { lna, ..config },
+                    FrontendCommand::Pa(pa) => FrontendConfig { pa, ..config },
+                };
+                self.radio.frontend_mut().set_config(config);
+                self.print_frontend();
+            },
+            // The vehicle's diagnostics are printed once they are downlinked.
+            ConsoleCommand::Radio => {
+                self.diagnostics_requested = true;
+                self.radio.request_diagnostics();
+            },
+            ConsoleCommand::FindMe(enabled) => {
+                self.radio.queue_find_me(enabled);
+                self.usb.console_print(format_args!("findme: sending {}", if enabled { "on" } else { "off" }));
+            },
+            ConsoleCommand::Downlink(profile) => {
+                self.radio.queue_downlink_profile(profile);
+                self.usb.console_print(format_args!("downlink profile: sending {}", profile.name()));
+            },
+            ConsoleCommand::Engine(Some(cmd)) => {
+                self.radio.queue_engine_command(cmd);
+                self.usb.console_print(format_args!("engine: sending {:?}", cmd));
+            },
+            // The result is printed once it is downlinked.
+            ConsoleCommand::SelfTest => {
+                self.radio.request_self_test();
+                self.usb.console_print(format_args!("selftest: requested"));
+            },
+            ConsoleCommand::Scan => {
+                self.radio.start_scan();
+                self.usb.console_print(format_args!("scan: started"));
+            },
+            ConsoleCommand::Link(None) | ConsoleCommand::Blacklist(None) => {
+                self.usb.console_print(format_args!("link: {}", self.radio.link_config()));
+            },
+            // Not persisted, the FC's announcement takes precedence anyway.
+            ConsoleCommand::Link(Some(link)) => {
+                let link = LinkConfig { blacklist: self.radio.link_config().blacklist, ..link };
+                if link.is_valid() {
+                    self.radio.set_link_config(link);
+                    self.usb.console_print(format_args!("link: {}", link));
+                } else {
+                    self.usb.console_print(format_args!("link: invalid configuration"));
+                }
+            },
+            // The GCS switches once the blacklist is sent, see `LinkConfig`.
+            ConsoleCommand::Blacklist(Some(blacklist)) => {
+                if LinkConfig { blacklist, ..self.radio.link_config() }.is_valid() {
+                    self.radio.queue_blacklist(blacklist);
+                    self.usb.console_print(format_args!("blacklist: sending"));
+                } else {
+                    self.usb.console_print(format_args!("blacklist: no channels left"));
+                }
+            },
+            ConsoleCommand::Hops => match self.radio.hop_table() {
+                Some(table) => for (i, (channel, frequency)) in table.iter().enumerate() {
+                    self.usb.console_print(format_args!("hop {}: channel {}, {}kHz", i, channel, frequency / 1_000));
+                },
+                None => self.usb.console_print(format_args!("hops: no channels enabled")),
+            },
+            ConsoleCommand::Capture(enabled) => {
+                crate::capture::set_enabled(enabled);
+                self.usb.console_print(format_args!("capture: {}", if enabled { "on" } else { "off" }));
+            },
+            ConsoleCommand::Record(enabled) => {
+                crate::capture::set_recording(enabled);
+                self.usb.console_print(format_args!("record: {}", if enabled { "on" } else { "off" }));
+            },
+            ConsoleCommand::Retransmit(range) => {
+                match range.or(self.last_gap.map(|gap| (gap.since, gap.until))) {
+                    Some((from, to)) => {
+                        let request = RetransmitRequest::new(from, to);
+                        self.radio.request_retransmission(request);
+                        self.usb.console_print(format_args!(
+                            "retransmit: requesting {}s from {}s",
+                            request.duration,
+                            request.start
+                        ));
+                    },
+                    None => self.usb.console_print(format_args!("retransmit: no downlink gap yet")),
+                }
+            },
+            ConsoleCommand::Sequence(SequenceCommand::Add(step)) => if self.sequence.push(step).is_err() {
+                self.usb.console_print(format_args!("seq: at most {} steps", MAX_STEPS));
+            },
+            ConsoleCommand::Sequence(SequenceCommand::Start) => {
+                self.sequence.start();
+                self.usb.console_print(format_args!("seq: started"));
+            },
+            ConsoleCommand::Sequence(SequenceCommand::Abort) => {
+                self.sequence.abort();
+                self.usb.console_print(format_args!("seq: aborted"));
+            },
+            ConsoleCommand::Sequence(SequenceCommand::Show) => {
+                for (i, step) in self.sequence.steps().enumerate() {
+                    self.usb.console_print(format_args!("{}: {:?}", i, step));
+                }
+                self.usb.console_print(format_args!("running: {}, vehicle mode: {:?}", self.sequence.is_running(), self.vehicle_mode));
+            },
+            // Written to the flash log by the FC, see `flash_log::note_chunks`.
+            ConsoleCommand::Note(text) => {
+                self.radio.queue_note(&text);
+                self.usb.console_print(format_args!("note: sending"));
+            },
+            ConsoleCommand::Abort => {
+                self.radio.queue_abort();
+                self.sequence.abort();
+                self.usb.console_print(format_args!("abort: sending"));
+            },
+            ConsoleCommand::Reboot => cortex_m::peripheral::SCB::sys_reset(),
+            ConsoleCommand::Bootloader => reboot_to_bootloader(),
+            ConsoleCommand::Exit => {},
+            ConsoleCommand::Invalid(cmd) => {
+                self.usb.console_print(format_args!("Unknown command '{}', try 'help'.", cmd));
+            },
+            _ => self.usb.console_print(format_args!("Not available on the ground station.")),
+        }
     }
 }
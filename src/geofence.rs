@@ -0,0 +1,224 @@
+//! Geofence, limiting altitude above ground and horizontal distance from the pad. Both are
+//! evaluated against the state estimator's output from launch until apogee. A violation is logged,
+//! shown on the console and downlinked (see `lora.rs`), and can optionally trigger an action, such
+//! as deploying the drogue early to limit drift.
+//!
+//! The pad position is tracked until launch is imminent, and frozen from then on. With a distance
+//! limit and an action other than a warning configured, the vehicle can't be armed before the pad
+//! position is known. A warning only isn't worth holding up the launch for.
+
+#[cfg(not(feature = "gcs"))]
+use num_traits::Float;
+use serde::{Deserialize, Serialize};
+
+use defmt::*;
+
+#[cfg(not(feature = "gcs"))]
+use shared_types::FlightMode;
+
+#[cfg(not(feature = "gcs"))]
+use crate::mode_guard::{ModeRejection, RejectionReason};
+
+/// Mean earth radius (m)
+#[cfg(not(feature = "gcs"))]
+const EARTH_RADIUS: f32 = 6_371_000.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub enum GeofenceAction {
+    /// Only report the violation
+    Warn,
+    /// Switch to drogue descent
+    Drogue,
+}
+
+impl GeofenceAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Warn => "warn",
+            Self::Drogue => "drogue deployed",
+        }
+    }
+}
+
+/// Limits of the geofence, to be adjusted to the launch site's waiver before flight (see
+/// `parameters.rs`)
+#[cfg(not(feature = "gcs"))]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GeofenceConfig {
+    /// Maximum altitude above ground (m)
+    pub max_altitude: Option<f32>,
+    /// Maximum horizontal distance from the pad (m)
+    pub max_distance: Option<f32>,
+    pub action: GeofenceAction,
+}
+
+#[cfg(not(feature = "gcs"))]
+impl Default for GeofenceConfig {
+    fn default() -> Self {
+        Self {
+            max_altitude: Some(12_000.0),
+            max_distance: Some(5_000.0),
+            action: GeofenceAction::Warn,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Format)]
+pub enum GeofenceViolation {
+    /// Altitude above ground (m)
+    Altitude(f32),
+    /// Horizontal distance from the pad (m)
+    Distance(f32),
+}
+
+#[cfg(not(feature = "gcs"))]
+pub struct Geofence {
+    config: GeofenceConfig,
+    /// Latitude and longitude (deg) of the pad
+    pad: Option<(f32, f32)>,
+    violation: Option<GeofenceViolation>,
+}
+
+/// Approximate distance (m) between two nearby positions, in degrees.
+#[cfg(not(feature = "gcs"))]
+fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    let north = (b.0 - a.0).to_radians() * EARTH_RADIUS;
+    let east = (b.1 - a.1).to_radians() * EARTH_RADIUS * a.0.to_radians().cos();
+    (north * north + east * east).sqrt()
+}
+
+#[cfg(not(feature = "gcs"))]
+impl Geofence {
+    pub fn new(config: GeofenceConfig) -> Self {
+        Self {
+            config,
+            pad: None,
+            violation: None,
+        }
+    }
+
+    /// Checks the current altitude (m above ground) and position (deg), returning the violation
+    /// and the configured action once when the geofence is first violated.
+    pub fn tick(&mut self, mode: FlightMode, altitude: f32, position: Option<(f32, f32)>) -> Option<(GeofenceViolation, GeofenceAction)> {
+        if mode < FlightMode::ArmedLaunchImminent {
+            self.pad = position.or(self.pad);
+            self.violation = None;
+            return None;
+        }
+
+        // Only check while ascending, after that there's nothing left to do. On the pad, the
+        // estimator's altitude and position are too noisy to act on.
+        if mode < FlightMode::Burn || mode >= FlightMode::RecoveryDrogue || self.violation.is_some() {
+            return None;
+        }
+
+        let distance = self.pad.zip(position).map(|(pad, pos)| distance(pad, pos));
+        self.violation = match (self.config.max_altitude, self.config.max_distance, distance) {
+            (Some(max), _, _) if altitude > max => Some(GeofenceViolation::Altitude(altitude)),
+            (_, Some(max), Some(d)) if d > max => Some(GeofenceViolation::Distance(d)),
+            _ => None,
+        };
+
+        let violation = self.violation?;
+        error!("Geofence violated: {:?}, action: {:?}", violation, self.config.action);
+        Some((violation, self.config.action))
+    }
+
+    /// Checks whether a commanded mode change arms the vehicle without a pad position, which
+    /// the distance limit is relative to. Only relevant if a violation triggers an action.
+    pub fn check_arming(&self, from: FlightMode, to: FlightMode) -> Result<(), ModeRejection> {
+        let needs_pad = self.config.max_distance.is_some() && self.config.action != GeofenceAction::Warn;
+        if from < FlightMode::Armed && to >= FlightMode::Armed && needs_pad && self.pad.is_none() {
+            return Err(ModeRejection { from, to, reason: RejectionReason::NoPadPosition });
+        }
+
+        Ok(())
+    }
+
+    pub fn violation(&self) -> Option<GeofenceViolation> {
+        self.violation
+    }
+
+    pub fn pad(&self) -> Option<(f32, f32)> {
+        self.pad
+    }
+}
+
+#[cfg(all(test, not(feature = "gcs")))]
+mod tests {
+    use super::{FlightMode, Geofence, GeofenceAction, GeofenceConfig, GeofenceViolation, ModeRejection, RejectionReason};
+
+    const PAD: (f32, f32) = (50.0, 8.0);
+    /// About 5.6km north of the pad
+    const FAR: (f32, f32) = (50.05, 8.0);
+
+    #[test]
+    fn violations() {
+        // Altitude (m) and position during the ascent, and the expected violation
+        let cases = [
+            (1_000.0, Some(PAD), None),
+            (13_000.0, Some(PAD), Some(GeofenceViolation::Altitude(13_000.0))),
+            (1_000.0, Some(FAR), Some(GeofenceViolation::Distance(5_560.0))),
+            (1_000.0, None, None),
+        ];
+
+        for (altitude, position, expected) in cases {
+            let mut geofence = Geofence::new(GeofenceConfig::default());
+            geofence.tick(FlightMode::Armed, 0.0, Some(PAD));
+            assert_eq!(geofence.tick(FlightMode::ArmedLaunchImminent, altitude, position), None);
+
+            let result = geofence.tick(FlightMode::Coast, altitude, position);
+            match (result, expected) {
+                (Some((GeofenceViolation::Distance(d), _)), Some(GeofenceViolation::Distance(e))) => {
+                    assert!((d - e).abs() < 10.0, "distance {}", d)
+                }
+                (result, expected) => assert_eq!(result.map(|(v, _)| v), expected),
+            }
+
+            // Reported only once
+            assert_eq!(geofence.tick(FlightMode::Coast, altitude, position), None);
+            assert_eq!(geofence.violation().is_some(), expected.is_some());
+        }
+    }
+
+    #[test]
+    fn pad_frozen_when_launch_imminent() {
+        let mut geofence = Geofence::new(GeofenceConfig::default());
+        geofence.tick(FlightMode::Armed, 0.0, Some(PAD));
+        geofence.tick(FlightMode::Armed, 0.0, None);
+        assert_eq!(geofence.pad(), Some(PAD));
+
+        geofence.tick(FlightMode::ArmedLaunchImminent, 0.0, Some(FAR));
+        assert_eq!(geofence.pad(), Some(PAD));
+    }
+
+    #[test]
+    fn not_checked_after_apogee() {
+        let mut geofence = Geofence::new(GeofenceConfig::default());
+        geofence.tick(FlightMode::Armed, 0.0, Some(PAD));
+        assert_eq!(geofence.tick(FlightMode::RecoveryDrogue, 13_000.0, Some(FAR)), None);
+    }
+
+    #[test]
+    fn arming_without_pad_position() {
+        let warn = GeofenceConfig::default();
+        let drogue = GeofenceConfig { action: GeofenceAction::Drogue, ..warn };
+        let no_distance = GeofenceConfig { max_distance: None, ..drogue };
+        let rejected = Err(ModeRejection { from: FlightMode::Idle, to: FlightMode::Armed, reason: RejectionReason::NoPadPosition });
+        // Config, whether the pad position is known, and the expected result of arming from Idle
+        let cases = [
+            (drogue, false, rejected),
+            (drogue, true, Ok(())),
+            (no_distance, false, Ok(())),
+            (warn, false, Ok(())),
+        ];
+
+        for (config, pad_known, expected) in cases {
+            let mut geofence = Geofence::new(config);
+            geofence.tick(FlightMode::Idle, 0.0, pad_known.then_some(PAD));
+            assert_eq!(geofence.check_arming(FlightMode::Idle, FlightMode::Armed), expected, "{:?}, pad {}", config, pad_known);
+            // Only arming is checked
+            assert_eq!(geofence.check_arming(FlightMode::Armed, FlightMode::ArmedLaunchImminent), Ok(()));
+        }
+    }
+}
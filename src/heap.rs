@@ -0,0 +1,92 @@
+//! Heap allocator with usage statistics. The firmware itself doesn't allocate, but some
+//! dependencies still do, e.g. when serializing messages, so a small heap is provided. The
+//! allocator keeps track of the peak usage and of failed allocations, including those that
+//! failed despite enough free memory in total, i.e. due to fragmentation.
+//!
+//! A failed allocation can't be recovered from, so code that might allocate should check for
+//! `headroom` first, and e.g. drop a telemetry message instead of risking a panic.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use alloc_cortex_m::CortexMHeap;
+
+use crate::errors::{report, ErrorKind, Subsystem};
+
+const HEAP_SIZE: usize = 1024;
+/// Free heap (bytes) below which allocating operations should be skipped
+const HEAP_RESERVE: usize = 256;
+
+static mut HEAP: [core::mem::MaybeUninit<u8>; HEAP_SIZE] = [core::mem::MaybeUninit::uninit(); HEAP_SIZE];
+
+#[global_allocator]
+static ALLOCATOR: TrackingHeap = TrackingHeap::empty();
+
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicU32 = AtomicU32::new(0);
+static FRAGMENTED: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    /// Bytes currently allocated
+    pub used: usize,
+    /// Highest number of bytes allocated at once since startup
+    pub peak: usize,
+    pub size: usize,
+    pub failed_allocations: u32,
+    /// Failed allocations for which enough memory would have been free in total
+    pub fragmented_allocations: u32,
+}
+
+struct TrackingHeap {
+    heap: CortexMHeap,
+}
+
+impl TrackingHeap {
+    const fn empty() -> Self {
+        Self { heap: CortexMHeap::empty() }
+    }
+}
+
+unsafe impl GlobalAlloc for TrackingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.heap.alloc(layout);
+        if ptr.is_null() {
+            FAILED.fetch_add(1, Ordering::Relaxed);
+            if self.heap.free() >= layout.size() {
+                FRAGMENTED.fetch_add(1, Ordering::Relaxed);
+            }
+            report(Subsystem::Memory, ErrorKind::OutOfMemory, "allocating");
+        } else {
+            PEAK.fetch_max(self.heap.used(), Ordering::Relaxed);
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.dealloc(ptr, layout)
+    }
+}
+
+/// Hands the heap memory to the allocator. Has to be called once, before anything allocates.
+pub fn init() {
+    // Safety: called once at startup, before the heap is used.
+    unsafe { ALLOCATOR.heap.init(core::ptr::addr_of_mut!(HEAP) as usize, HEAP_SIZE) }
+}
+
+/// Whether enough heap is free for allocating operations, such as serializing a message.
+#[cfg_attr(feature = "gcs", allow(dead_code))]
+pub fn headroom() -> bool {
+    ALLOCATOR.heap.free() >= HEAP_RESERVE
+}
+
+pub fn stats() -> HeapStats {
+    HeapStats {
+        used: ALLOCATOR.heap.used(),
+        peak: PEAK.load(Ordering::Relaxed),
+        size: HEAP_SIZE,
+        failed_allocations: FAILED.load(Ordering::Relaxed),
+        fragmented_allocations: FRAGMENTED.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,93 @@
+//! Hardware-in-the-loop testing. A host simulator streams sensor frames over USB, which replace
+//! the readings of the real sensors. Everything else, including radio, flash and recovery
+//! outputs, runs as usual, so the flight logic can be exercised on the bench against synthetic
+//! flights.
+//!
+//! Only built with the `hil` feature, so flight builds can't have their sensors overridden. Frames
+//! use the regular USB framing (see `framing.rs`), with the payload prefixed by `HIL_FRAME_TAG` to
+//! tell them apart from uplink messages. The host should send one frame per main loop iteration
+//! (1kHz); the USB link applies backpressure if frames arrive faster than they are consumed. Once
+//! frames stop arriving, the real sensors are used again.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+use defmt::*;
+
+use crate::clock::Instant;
+
+/// First payload byte of HIL frames. Never valid as the start of a serialized uplink message.
+pub const HIL_FRAME_TAG: u8 = 0xff;
+
+/// Time (ms) without frames after which HIL mode is left.
+const HIL_TIMEOUT: u32 = 100;
+
+/// Frames received via USB, waiting to be consumed by the main loop.
+pub static HIL_CHANNEL: Channel<CriticalSectionRawMutex, HilFrame, 8> = Channel::new();
+
+/// Sensor readings injected by the host. Missing values are treated like failed sensors.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HilFrame {
+    /// Simulation time (ms), only used to detect dropped frames
+    pub time: u32,
+    pub gyroscope: Option<[f32; 3]>,
+    pub accelerometer1: Option<[f32; 3]>,
+    pub accelerometer2: Option<[f32; 3]>,
+    pub magnetometer: Option<[f32; 3]>,
+    pub pressure_baro: Option<f32>,
+    pub altitude_baro: Option<f32>,
+    pub arm_voltage: Option<u16>,
+}
+
+impl HilFrame {
+    pub fn gyroscope(&self) -> Option<Vector3<f32>> {
+        self.gyroscope.map(Vector3::from)
+    }
+
+    pub fn accelerometer1(&self) -> Option<Vector3<f32>> {
+        self.accelerometer1.map(Vector3::from)
+    }
+
+    pub fn accelerometer2(&self) -> Option<Vector3<f32>> {
+        self.accelerometer2.map(Vector3::from)
+    }
+
+    pub fn magnetometer(&self) -> Option<Vector3<f32>> {
+        self.magnetometer.map(Vector3::from)
+    }
+}
+
+pub struct Hil {
+    /// Latest frame and the time it was received at
+    frame: Option<(Instant, HilFrame)>,
+}
+
+impl Hil {
+    pub fn new() -> Self {
+        Self { frame: None }
+    }
+
+    pub fn tick(&mut self, time: Instant) {
+        if let Ok(frame) = HIL_CHANNEL.try_receive() {
+            match &self.frame {
+                None => info!("HIL frames received, replacing sensor data."),
+                Some((_, last)) if frame.time.wrapping_sub(last.time) > 1 => {
+                    warn!("HIL frames dropped: {} -> {}", last.time, frame.time);
+                }
+                _ => {}
+            }
+
+            self.frame = Some((time, frame));
+        } else if self.frame.as_ref().map(|(t, _)| time.millis_since(*t) > HIL_TIMEOUT).unwrap_or(false) {
+            warn!("HIL frames stopped, using real sensors.");
+            self.frame = None;
+        }
+    }
+
+    /// Current injected sensor data, if HIL mode is active.
+    pub fn frame(&self) -> Option<&HilFrame> {
+        self.frame.as_ref().map(|(_, frame)| frame)
+    }
+}
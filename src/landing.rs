@@ -0,0 +1,107 @@
+//! Landing point prediction for recovery crews. During descent, the horizontal drift is estimated
+//! from the change in position, which under parachute is mostly wind. Extrapolating this drift
+//! over the remaining time to the ground gives the predicted landing point.
+//!
+//! After landing, the last known position is kept as the resting position, so it can still be
+//! reported (e.g. in APRS beacons) if the GPS loses its fix lying on the ground.
+
+use num_traits::Float;
+
+use shared_types::FlightMode;
+
+use crate::clock::Instant;
+
+/// Mean earth radius (m)
+const EARTH_RADIUS: f32 = 6_371_000.0;
+/// Interval (ms) over which drift is measured
+const DRIFT_INTERVAL: u32 = 1000;
+/// Weight of each new drift measurement in the (exponentially) averaged drift
+const DRIFT_SMOOTHING: f32 = 0.3;
+/// Minimum descent rate (m/s) for a prediction, below which the time to ground is meaningless
+const MIN_DESCENT_RATE: f32 = 1.0;
+
+pub struct LandingPredictor {
+    /// Position (deg) and time at the start of the current drift interval
+    reference: Option<(Instant, (f32, f32))>,
+    /// Horizontal drift north and east (m/s)
+    drift: Option<(f32, f32)>,
+    prediction: Option<(f32, f32)>,
+    /// Last known position after landing
+    resting_position: Option<(f32, f32)>,
+}
+
+/// Moves a position (deg) by the given distances north and east (m).
+fn offset(position: (f32, f32), north: f32, east: f32) -> (f32, f32) {
+    let latitude = position.0 + (north / EARTH_RADIUS).to_degrees();
+    let longitude = position.1 + (east / (EARTH_RADIUS * position.0.to_radians().cos())).to_degrees();
+    (latitude, longitude)
+}
+
+impl LandingPredictor {
+    pub fn new() -> Self {
+        Self {
+            reference: None,
+            drift: None,
+            prediction: None,
+            resting_position: None,
+        }
+    }
+
+    /// Updates the prediction given altitude above ground (m), vertical speed (m/s) and position
+    /// (deg).
+    pub fn tick(&mut self, time: Instant, mode: FlightMode, altitude: f32, vertical_speed: f32, position: Option<(f32, f32)>) {
+        if mode == FlightMode::Landed {
+            self.resting_position = position.or(self.resting_position);
+            self.prediction = None;
+            return;
+        }
+
+        self.resting_position = None;
+        let descending = matches!(mode, FlightMode::RecoveryDrogue | FlightMode::RecoveryMain);
+        let Some(position) = position.filter(|_| descending) else {
+            self.reference = None;
+            self.drift = None;
+            self.prediction = None;
+            return;
+        };
+
+        match self.reference {
+            Some((t, reference)) if time.millis_since(t) >= DRIFT_INTERVAL => {
+                let dt = time.millis_since(t) as f32 / 1000.0;
+                let north = (position.0 - reference.0).to_radians() * EARTH_RADIUS / dt;
+                let east = (position.1 - reference.1).to_radians() * EARTH_RADIUS * reference.0.to_radians().cos() / dt;
+                self.drift = Some(match self.drift {
+                    Some((n, e)) => (n + (north - n) * DRIFT_SMOOTHING, e + (east - e) * DRIFT_SMOOTHING),
+                    None => (north, east),
+                });
+                self.reference = Some((time, position));
+            },
+            Some(_) => {},
+            None => self.reference = Some((time, position)),
+        }
+
+        let descent_rate = -vertical_speed;
+        self.prediction = match self.drift {
+            Some((north, east)) if descent_rate > MIN_DESCENT_RATE => {
+                let time_to_ground = f32::max(altitude, 0.0) / descent_rate;
+                Some(offset(position, north * time_to_ground, east * time_to_ground))
+            },
+            _ => None,
+        };
+    }
+
+    /// Predicted landing point (deg) during descent
+    pub fn prediction(&self) -> Option<(f32, f32)> {
+        self.prediction
+    }
+
+    /// Last known position (deg) after landing
+    pub fn resting_position(&self) -> Option<(f32, f32)> {
+        self.resting_position
+    }
+
+    /// Horizontal drift north and east (m/s) during descent
+    pub fn drift(&self) -> Option<(f32, f32)> {
+        self.drift
+    }
+}
@@ -0,0 +1,92 @@
+//! Capture of the launch rail's orientation at arming. The vehicle's longitudinal axis is averaged
+//! over the first second in Armed mode, using the state estimator's attitude, which relies on the
+//! magnetometer for the heading. The resulting azimuth and elevation are logged, shown on the
+//! console and stored with the flight summary, so the rail setup can be checked against the flight
+//! card, and post-flight analysis can account for the initial tilt.
+//!
+//! The estimator's world frame is assumed to be east-north-up, with north being magnetic north.
+
+use nalgebra::{UnitQuaternion, Vector3};
+use num_traits::Float;
+
+use defmt::*;
+
+use shared_types::FlightMode;
+
+use crate::clock::Instant;
+
+/// Duration (ms) over which the orientation is averaged after arming
+const CAPTURE_TIME: u32 = 1_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Format)]
+pub struct RailOrientation {
+    /// Direction (deg from true north, clockwise) the rail is tilted towards
+    pub azimuth: f32,
+    /// Angle (deg) above the horizon, 90 for a vertical rail
+    pub elevation: f32,
+}
+
+impl RailOrientation {
+    /// Orientation of the given axis, with the magnetic declination (deg, east positive) added to
+    /// obtain a true azimuth.
+    fn from_axis(axis: Vector3<f32>, declination: f32) -> Self {
+        let azimuth = axis.x.atan2(axis.y).to_degrees() + declination;
+        Self {
+            azimuth: (azimuth + 360.0) % 360.0,
+            elevation: axis.z.clamp(-1.0, 1.0).asin().to_degrees(),
+        }
+    }
+}
+
+pub struct LaunchRail {
+    /// Magnetic declination (deg, east positive) at the launch site
+    declination: f32,
+    /// Sum of the longitudinal axis in the world frame since arming, and the number of samples
+    axis_sum: Vector3<f32>,
+    samples: u32,
+    armed_since: Option<Instant>,
+    captured: Option<RailOrientation>,
+}
+
+impl LaunchRail {
+    pub fn new(declination: f32) -> Self {
+        Self {
+            declination,
+            axis_sum: Vector3::zeros(),
+            samples: 0,
+            armed_since: None,
+            captured: None,
+        }
+    }
+
+    pub fn tick(&mut self, time: Instant, mode: FlightMode, orientation: Option<UnitQuaternion<f32>>) {
+        if mode < FlightMode::Armed {
+            *self = Self::new(self.declination);
+            return;
+        }
+
+        // Only captured on the pad, if launch happens first we don't get a reading.
+        if self.captured.is_some() || mode > FlightMode::ArmedLaunchImminent {
+            return;
+        }
+
+        let armed_since = *self.armed_since.get_or_insert(time);
+        if let Some(q) = orientation {
+            self.axis_sum += q * Vector3::z();
+            self.samples += 1;
+        }
+
+        if time.millis_since(armed_since) < CAPTURE_TIME || self.samples == 0 {
+            return;
+        }
+
+        let rail = RailOrientation::from_axis(self.axis_sum.normalize(), self.declination);
+        info!("Launch rail: azimuth {}deg, elevation {}deg", rail.azimuth, rail.elevation);
+        self.captured = Some(rail);
+    }
+
+    /// Rail orientation captured at arming
+    pub fn orientation(&self) -> Option<RailOrientation> {
+        self.captured
+    }
+}
@@ -0,0 +1,119 @@
+//! Driver for the status LEDs and an optional high-power strobe. The status LEDs show the flight
+//! mode (see `FlightMode::led_state`), while the strobe flashes during descent and after landing
+//! to make the vehicle easier to spot during recovery. While the find-me siren is active (see
+//! `Buzzer::find_me`), the strobe flashes rapidly instead.
+//!
+//! The strobe is only available with the `strobe` feature, in which case it is driven via the pin
+//! the board maps it to (see `outputs.rs`).
+
+use embassy_stm32::gpio::{AnyPin, Output};
+use embassy_stm32::peripherals::*;
+
+use shared_types::FlightMode;
+
+use crate::clock::Instant;
+
+/// Strobe flash pattern: a number of short flashes, repeated periodically.
+#[derive(Clone, Copy)]
+pub struct StrobePattern {
+    /// Time (ms) after which the pattern repeats
+    period: u32,
+    flashes: u32,
+    /// Duration (ms) of each flash, as well as the pause between them
+    on_time: u32,
+}
+
+impl StrobePattern {
+    const fn new(period: u32, flashes: u32, on_time: u32) -> Self {
+        Self { period, flashes, on_time }
+    }
+
+    fn is_on(&self, time: Instant) -> bool {
+        let t = (time.as_millis() % self.period as u64) as u32;
+        t / (2 * self.on_time) < self.flashes && t % (2 * self.on_time) < self.on_time
+    }
+}
+
+/// Strobe pattern for each flight mode, `None` keeps the strobe off.
+fn strobe_pattern(mode: FlightMode) -> Option<StrobePattern> {
+    match mode {
+        FlightMode::RecoveryDrogue | FlightMode::RecoveryMain => Some(StrobePattern::new(1000, 2, 50)),
+        FlightMode::Landed => Some(StrobePattern::new(2000, 1, 50)),
+        _ => None,
+    }
+}
+
+/// Strobe pattern accompanying the find-me siren
+const FIND_ME_PATTERN: StrobePattern = StrobePattern::new(500, 3, 50);
+
+/// Time (ms) each LED is lit for during the self test pattern
+const TEST_STEP: u32 = 300;
+
+pub struct Leds {
+    red: Output<'static, PC13>,
+    yellow: Output<'static, PC14>,
+    green: Output<'static, PC15>,
+    strobe: Option<Output<'static, AnyPin>>,
+    find_me: bool,
+    /// Start of the self test pattern, if running
+    test_started: Option<Instant>,
+}
+
+impl Leds {
+    pub fn new(
+        red: Output<'static, PC13>,
+        yellow: Output<'static, PC14>,
+        green: Output<'static, PC15>,
+        strobe: Option<Output<'static, AnyPin>>,
+    ) -> Self {
+        Self { red, yellow, green, strobe, find_me: false, test_started: None }
+    }
+
+    /// Sets the status LEDs, which are active-low.
+    pub fn set(&mut self, red: bool, yellow: bool, green: bool) {
+        self.red.set_level((!red).into());
+        self.yellow.set_level((!yellow).into());
+        self.green.set_level((!green).into());
+    }
+
+    /// Updates status LEDs and strobe according to the flight mode, or the self test pattern.
+    pub fn tick(&mut self, time: Instant, mode: FlightMode) {
+        if let Some(started) = self.test_started {
+            let step = time.millis_since(started) / TEST_STEP;
+            if step < 4 {
+                self.set(step == 0, step == 1, step == 2);
+                if let Some(strobe) = self.strobe.as_mut() {
+                    strobe.set_level((step == 3).into());
+                }
+                return;
+            }
+            self.test_started = None;
+        }
+
+        let (r, y, g) = mode.led_state(time.wire());
+        self.set(r, y, g);
+
+        if let Some(strobe) = self.strobe.as_mut() {
+            let pattern = if self.find_me { Some(FIND_ME_PATTERN) } else { strobe_pattern(mode) };
+            let on = pattern.map(|p| p.is_on(time)).unwrap_or(false);
+            strobe.set_level(on.into());
+        }
+    }
+
+    /// Starts the self test pattern, lighting red, yellow, green and the strobe one after another
+    /// so each can be checked by eye. Returns whether it started, which it doesn't while the
+    /// find-me strobe is active.
+    pub fn start_test(&mut self, time: Instant) -> bool {
+        if self.find_me {
+            return false;
+        }
+
+        self.test_started = Some(time);
+        true
+    }
+
+    #[cfg_attr(feature = "gcs", allow(dead_code))]
+    pub fn set_find_me(&mut self, find_me: bool) {
+        self.find_me = find_me;
+    }
+}
@@ -1,11 +1,50 @@
 //! Export of selected modules for use in external programs, such as ground station.
 
 #![cfg_attr(target_os="none", no_std)]
-#![no_main]
+#![cfg_attr(target_os="none", no_main)]
+
+#[cfg(not(feature = "gcs"))]
+pub mod arm;
+#[cfg(not(feature = "gcs"))]
+pub mod backup_apogee;
+pub mod clock;
+pub mod countdown;
+pub mod filters;
+pub mod flash_log;
+pub mod framing;
+pub mod geofence;
+pub mod lora_packet;
+pub mod mode_guard;
+pub mod quaternion;
+pub mod radio_diagnostics;
+pub mod schedule;
+pub mod telemetry;
+pub mod traits;
+#[cfg(feature = "std")]
+pub mod mocks;
+#[cfg(feature = "std")]
+pub mod sim;
 
 #[cfg(target_os = "none")]
 use defmt_rtt as _; // global logger (TODO)
 
+/// Some of the exported modules log via defmt, which needs a global logger to link. On the host,
+/// their messages are discarded.
+#[cfg(not(target_os = "none"))]
+#[defmt::global_logger]
+struct HostLogger;
+
+#[cfg(not(target_os = "none"))]
+unsafe impl defmt::Logger for HostLogger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+#[cfg(not(target_os = "none"))]
+defmt::timestamp!("");
+
 #[cfg(target_os = "none")]
 use panic_probe as _;
 
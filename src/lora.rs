@@ -1,12 +1,13 @@
 use core::hash::Hasher;
 
-use heapless::{String, Vec};
+use heapless::{Deque, String, Vec};
 
 use embedded_hal::digital::InputPin;
 use embedded_hal_async::spi::SpiDevice;
 
 use rand::prelude::*;
 use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
 use siphasher::sip::SipHasher;
 
@@ -14,7 +15,30 @@ use defmt::*;
 
 use shared_types::*;
 
+#[cfg(feature = "gcs")]
+use crate::capture::{PacketStatus, RawPacket, Recording};
+use crate::countdown::CountdownStatus;
 use crate::drivers::lora::*;
+use crate::errors::{report, ErrorCounts, Subsystem};
+use crate::flash::LoggingStatus;
+use crate::flight_summary::FlightSummaryReport;
+#[cfg(not(feature = "gcs"))]
+use crate::flash_log::{NoteAssembler, LOG_NOTE_LENGTH};
+use crate::flash_log::{TimeReference, NOTE_CHUNKS, NOTE_CHUNK_LENGTH};
+#[cfg(feature = "gcs")]
+use crate::frontend::Frontend;
+use crate::geofence::{GeofenceAction, GeofenceViolation};
+use crate::lora_packet::{self, DownlinkKind};
+use crate::mode_guard::ModeRejection;
+use crate::radio_diagnostics::{RadioDiagnostics, TxStats};
+use crate::redundancy::RedundancyStatus;
+use crate::retransmission::RetransmitRequest;
+use crate::self_test::SelfTestResult;
+use crate::telemetry::DownlinkProfile;
+use crate::usb_console::EngineCommand;
+use crate::version::Heartbeat;
+#[cfg(not(feature = "gcs"))]
+use crate::traits::TelemetryRadio;
 
 // The available channels for telemetry, assuming a 500kHz band width.
 const CHANNELS: [u32; 14] = [
@@ -34,15 +58,247 @@ const CHANNELS: [u32; 14] = [
     869_750_000,
 ];
 
-#[cfg(feature = "gcs")]
-type TxHmac = u64;
-#[cfg(not(feature = "gcs"))]
-type TxHmac = u16;
+/// Limit for the correction of the FC's frequency offset (Hz). Crystal tolerance and drift on
+/// both sides should stay well below this.
+#[cfg(feature="gcs")]
+const MAX_FREQUENCY_CORRECTION: i32 = 20_000;
+/// Only a fraction of each measured frequency error is corrected, to average out noise.
+#[cfg(feature="gcs")]
+const FREQUENCY_CORRECTION_DIVIDER: i32 = 16;
+
+/// Relays retransmit downlink packets on the channel this many places further in `CHANNELS`,
+/// so relayed packets never collide with the FC's.
+#[cfg(feature="gcs")]
+const RELAY_CHANNEL_OFFSET: usize = CHANNELS.len() / 2;
+/// Additional delay (ms) per hop of relayed packets, i.e. the time a relay takes between
+/// receiving a packet and starting its retransmission. The GCS's estimate of FC time is kept
+/// behind by the retransmission time, so it is tuned to the relay channel for the interval a
+/// packet was originally sent in while the relayed copy arrives.
+#[cfg(feature="gcs")]
+const RELAY_DELAY_MS: i64 = 2;
+/// Number of recently received packets remembered to discard duplicates
+#[cfg(feature="gcs")]
+const DEDUP_HISTORY: usize = 8;
+
+/// Number of packets that can wait for the transceiver to finish the previous transmission
+const TX_QUEUE_LENGTH: usize = 2;
+
+/// Time (ms) to wait after switching channels during a spectrum scan before measuring
+const SCAN_SETTLE_TIME: u32 = 2;
+/// Number of RSSI samples taken per channel during a spectrum scan, one per tick
+const SCAN_SAMPLES: u32 = 50;
+
+/// First byte of serialized link announcements. Never valid as the start of a serialized
+/// downlink message.
+const LINK_ANNOUNCEMENT_TAG: u8 = 0xfa;
+/// First byte of serialized mode rejections, see `LINK_ANNOUNCEMENT_TAG` and `mode_guard.rs`.
+const MODE_REJECTION_TAG: u8 = 0xf9;
+/// First byte of serialized countdown progress, see `LINK_ANNOUNCEMENT_TAG` and `countdown.rs`.
+const COUNTDOWN_TAG: u8 = 0xf8;
+/// Uplink packets consisting of only this byte abort the launch, see `Vehicle::abort`. Sent in
+/// place of the regular uplink message, repeatedly since uplink packets aren't acknowledged.
+const ABORT_TAG: u8 = 0xf7;
+#[cfg(feature="gcs")]
+const ABORT_REPEATS: u8 = 3;
+/// First byte of serialized retransmission requests, sent in place of the regular uplink message,
+/// see `retransmission.rs`.
+const RETRANSMIT_REQUEST_TAG: u8 = 0xf6;
+/// First byte of serialized retransmitted downlink messages, see `LINK_ANNOUNCEMENT_TAG`.
+const RETRANSMISSION_TAG: u8 = 0xf5;
+/// First byte of serialized channel blacklists, sent in place of the regular uplink message, see
+/// `LinkConfig::blacklist`.
+const BLACKLIST_TAG: u8 = 0xf4;
+/// Uplink packets consisting of only this byte request the FC's radio diagnostics, sent in place
+/// of the regular uplink message, see `radio_diagnostics.rs`.
+const DIAGNOSTICS_REQUEST_TAG: u8 = 0xf3;
+/// First byte of serialized radio diagnostics, see `LINK_ANNOUNCEMENT_TAG`.
+const DIAGNOSTICS_TAG: u8 = 0xf2;
+/// First byte of serialized geofence violations, see `LINK_ANNOUNCEMENT_TAG` and `geofence.rs`.
+const GEOFENCE_TAG: u8 = 0xf1;
+/// First byte of serialized chunks of operator notes for the flash log, sent in place of the
+/// regular uplink message, see `flash_log::note_chunks`.
+const NOTE_TAG: u8 = 0xf0;
+/// First byte of serialized heartbeats, see `LINK_ANNOUNCEMENT_TAG` and `version.rs`.
+const HEARTBEAT_TAG: u8 = 0xef;
+/// First byte of serialized logging status, see `LINK_ANNOUNCEMENT_TAG` and `flash.rs`.
+const LOGGING_STATUS_TAG: u8 = 0xee;
+/// First byte of serialized flight summaries, see `LINK_ANNOUNCEMENT_TAG` and `flight_summary.rs`.
+const FLIGHT_SUMMARY_TAG: u8 = 0xed;
+/// Uplink packets consisting of only this byte start the FC's self test, sent in place of the
+/// regular uplink message, see `self_test.rs`.
+const SELF_TEST_REQUEST_TAG: u8 = 0xec;
+/// First byte of serialized self test results, see `LINK_ANNOUNCEMENT_TAG` and `self_test.rs`.
+const SELF_TEST_TAG: u8 = 0xeb;
+/// First byte of serialized engine controller commands, sent in place of the regular uplink
+/// message, see `engine.rs`.
+const ENGINE_COMMAND_TAG: u8 = 0xea;
+/// First byte of serialized downlink profiles, sent in place of the regular uplink message, see
+/// `telemetry.rs`.
+const DOWNLINK_PROFILE_TAG: u8 = 0xe9;
+/// First byte of serialized find-me siren commands (on/off), sent in place of the regular uplink
+/// message, see `Buzzer::find_me`.
+const FIND_ME_TAG: u8 = 0xe8;
+/// First byte of serialized redundancy states, see `LINK_ANNOUNCEMENT_TAG` and `redundancy.rs`.
+const REDUNDANCY_TAG: u8 = 0xe7;
+/// First byte of serialized time references, see `LINK_ANNOUNCEMENT_TAG` and `flash_log.rs`.
+const TIME_REFERENCE_TAG: u8 = 0xe6;
+/// First byte of serialized error counts, see `LINK_ANNOUNCEMENT_TAG` and `errors.rs`.
+const ERROR_COUNTS_TAG: u8 = 0xe5;
+/// While not hearing from the GCS, the FC announces its link configuration this often (ms),
+/// replacing the next downlink message.
+#[cfg(not(feature="gcs"))]
+const LINK_ANNOUNCEMENT_INTERVAL: u32 = 2000;
+/// Time (ms) without uplink messages after which the GCS is considered disconnected
+#[cfg(not(feature="gcs"))]
+const UPLINK_TIMEOUT: u32 = 5000;
+
+/// Timing of the link, which FC and GCS have to agree on. The FC stores its configuration in
+/// flash (see `flash.rs`) and announces it whenever it doesn't hear from the GCS, which takes
+/// over any announced configuration. Telemetry rates can thus be changed on the FC alone.
+///
+/// Channels occupied by someone else can be blacklisted, on top of the ones disabled in the
+/// settings. Both sides regenerate the hop sequence from the remaining channels, see
+/// `Radio::generate_sequence`. The GCS can uplink a blacklist, switching over right after sending
+/// it. Should the FC not receive or reject it, the GCS loses contact until it hears the FC's
+/// announcement.
+///
+/// The telemetry schedules (see `telemetry.rs`) have to leave the uplink windows free.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub struct LinkConfig {
+    /// Length (ms) of each message interval, at the start of which the channel is changed
+    pub message_interval: u32,
+    /// Uplink windows (one message interval long) repeat every `uplink_interval` ms, starting
+    /// `uplink_offset` ms into it
+    pub uplink_interval: u32,
+    pub uplink_offset: u32,
+    /// Channels excluded from hopping, one bit per entry in `CHANNELS`
+    pub blacklist: u16,
+}
 
-#[cfg(feature = "gcs")]
-type RxHmac = u16;
-#[cfg(not(feature = "gcs"))]
-type RxHmac = u64;
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            message_interval: LORA_MESSAGE_INTERVAL,
+            uplink_interval: LORA_UPLINK_INTERVAL,
+            uplink_offset: LORA_UPLINK_MODULO,
+            blacklist: 0,
+        }
+    }
+}
+
+impl LinkConfig {
+    /// All intervals have to fit evenly into a second, uplink windows have to line up with
+    /// message intervals, and at least one channel has to be left.
+    pub fn is_valid(&self) -> bool {
+        self.message_interval > TRANSMISSION_TIMEOUT_MS + 2
+            && 1000 % self.message_interval == 0
+            && self.uplink_interval % self.message_interval == 0
+            && 1000 % self.uplink_interval == 0
+            && self.uplink_offset < self.uplink_interval
+            && self.uplink_offset % self.message_interval == 0
+            && self.blacklist >> CHANNELS.len() == 0
+            && self.blacklist != (1 << CHANNELS.len()) - 1
+    }
+
+    fn is_blacklisted(&self, channel: usize) -> bool {
+        self.blacklist & (1 << channel) != 0
+    }
+}
+
+impl core::fmt::Display for LinkConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::write!(f, "message interval {}ms, uplink every {}ms at {}ms", self.message_interval, self.uplink_interval, self.uplink_offset)?;
+        if self.blacklist != 0 {
+            core::write!(f, ", blacklisted channels")?;
+            for channel in (0..CHANNELS.len()).filter(|c| self.is_blacklisted(*c)) {
+                core::write!(f, " {}", channel)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Contents of a received packet
+enum Payload<M> {
+    Message(M),
+    LinkAnnouncement(LinkConfig),
+    ModeRejection(ModeRejection),
+    Countdown(CountdownStatus),
+    Abort,
+    RetransmitRequest(RetransmitRequest),
+    Retransmission(DownlinkMessage),
+    Blacklist(u16),
+    DiagnosticsRequest,
+    Diagnostics(RadioDiagnostics),
+    Geofence(GeofenceViolation, GeofenceAction),
+    Note(u8, [u8; NOTE_CHUNK_LENGTH]),
+    Heartbeat(Heartbeat),
+    LoggingStatus(LoggingStatus),
+    FlightSummary(FlightSummaryReport),
+    SelfTestRequest,
+    SelfTest(SelfTestResult),
+    EngineCommand(EngineCommand),
+    DownlinkProfile(DownlinkProfile),
+    FindMe(bool),
+    Redundancy(RedundancyStatus),
+    TimeReference(TimeReference),
+    ErrorCounts(ErrorCounts),
+}
+
+impl<M: DeserializeOwned> Payload<M> {
+    fn decode(serialized: &[u8]) -> Result<Self, lora_packet::PacketError> {
+        let payload = match serialized.first() {
+            Some(&LINK_ANNOUNCEMENT_TAG) => postcard::from_bytes(serialized).map(|(_tag, link): (u8, LinkConfig)| Self::LinkAnnouncement(link)),
+            Some(&MODE_REJECTION_TAG) => postcard::from_bytes(serialized).map(|(_tag, rejection): (u8, ModeRejection)| Self::ModeRejection(rejection)),
+            Some(&COUNTDOWN_TAG) => postcard::from_bytes(serialized).map(|(_tag, status): (u8, CountdownStatus)| Self::Countdown(status)),
+            Some(&ABORT_TAG) => postcard::from_bytes(serialized).map(|_tag: u8| Self::Abort),
+            Some(&RETRANSMIT_REQUEST_TAG) => postcard::from_bytes(serialized).map(|(_tag, request): (u8, RetransmitRequest)| Self::RetransmitRequest(request)),
+            Some(&RETRANSMISSION_TAG) => postcard::from_bytes(serialized).map(|(_tag, msg): (u8, DownlinkMessage)| Self::Retransmission(msg)),
+            Some(&BLACKLIST_TAG) => postcard::from_bytes(serialized).map(|(_tag, blacklist): (u8, u16)| Self::Blacklist(blacklist)),
+            Some(&DIAGNOSTICS_REQUEST_TAG) => postcard::from_bytes(serialized).map(|_tag: u8| Self::DiagnosticsRequest),
+            Some(&DIAGNOSTICS_TAG) => postcard::from_bytes(serialized).map(|(_tag, diagnostics): (u8, RadioDiagnostics)| Self::Diagnostics(diagnostics)),
+            Some(&GEOFENCE_TAG) => postcard::from_bytes(serialized).map(|(_tag, violation, action): (u8, GeofenceViolation, GeofenceAction)| Self::Geofence(violation, action)),
+            Some(&NOTE_TAG) => postcard::from_bytes(serialized).map(|(_tag, index, chunk): (u8, u8, [u8; NOTE_CHUNK_LENGTH])| Self::Note(index, chunk)),
+            Some(&HEARTBEAT_TAG) => postcard::from_bytes(serialized).map(|(_tag, heartbeat): (u8, Heartbeat)| Self::Heartbeat(heartbeat)),
+            Some(&LOGGING_STATUS_TAG) => postcard::from_bytes(serialized).map(|(_tag, status): (u8, LoggingStatus)| Self::LoggingStatus(status)),
+            Some(&FLIGHT_SUMMARY_TAG) => postcard::from_bytes(serialized).map(|(_tag, summary): (u8, FlightSummaryReport)| Self::FlightSummary(summary)),
+            Some(&SELF_TEST_REQUEST_TAG) => postcard::from_bytes(serialized).map(|_tag: u8| Self::SelfTestRequest),
+            Some(&SELF_TEST_TAG) => postcard::from_bytes(serialized).map(|(_tag, result): (u8, SelfTestResult)| Self::SelfTest(result)),
+            Some(&ENGINE_COMMAND_TAG) => postcard::from_bytes(serialized).map(|(_tag, cmd): (u8, EngineCommand)| Self::EngineCommand(cmd)),
+            Some(&DOWNLINK_PROFILE_TAG) => postcard::from_bytes(serialized).map(|(_tag, profile): (u8, DownlinkProfile)| Self::DownlinkProfile(profile)),
+            Some(&FIND_ME_TAG) => postcard::from_bytes(serialized).map(|(_tag, enabled): (u8, bool)| Self::FindMe(enabled)),
+            Some(&REDUNDANCY_TAG) => postcard::from_bytes(serialized).map(|(_tag, status): (u8, RedundancyStatus)| Self::Redundancy(status)),
+            Some(&TIME_REFERENCE_TAG) => postcard::from_bytes(serialized).map(|(_tag, reference): (u8, TimeReference)| Self::TimeReference(reference)),
+            Some(&ERROR_COUNTS_TAG) => postcard::from_bytes(serialized).map(|(_tag, counts): (u8, ErrorCounts)| Self::ErrorCounts(counts)),
+            _ => postcard::from_bytes(serialized).map(Self::Message),
+        };
+
+        payload.map_err(|_| lora_packet::PacketError::Deserialization)
+    }
+}
+
+/// Noise measured on a single channel during a spectrum scan
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChannelNoise {
+    /// Nominal channel frequency (Hz)
+    pub frequency: u32,
+    /// Average signal strength (dBm)
+    pub average: f32,
+    /// Strongest signal seen (dBm)
+    pub peak: f32,
+}
+
+/// A running spectrum scan, which measures the instantaneous RSSI on every channel in turn.
+#[derive(Clone, Copy)]
+struct SpectrumScan {
+    channel: usize,
+    /// Ticks spent on the current channel
+    ticks: u32,
+    /// Sum and minimum of the raw RSSI samples (-dBm * 2) on the current channel
+    sum: u32,
+    min: u8,
+    results: [ChannelNoise; CHANNELS.len()],
+}
 
 #[derive(Debug, PartialEq, Eq)]
 enum RadioState {
@@ -56,6 +312,7 @@ pub enum RadioError<E> {
     Crc,
     Busy,
     Timeout,
+    Overflow,
 }
 
 impl<E> From<E> for RadioError<E> {
@@ -71,15 +328,138 @@ pub struct Radio<SPI, IRQ, BUSY> {
     state_time: u32,
     pub transmit_power: TransmitPower,
     transmit_power_setpoint: TransmitPower,
+    /// Sync word and IQ inversion derived from the binding phrase, applied once the transceiver
+    /// is idle
+    sync_word_setpoint: u8,
+    iq_inversion_setpoint: bool,
+    /// Encoded packets waiting for the transceiver, with the start of the message interval they
+    /// were queued in, see `queue_packet`
+    tx_queue: Deque<(u32, Vec<u8, 64>), TX_QUEUE_LENGTH>,
     #[cfg(feature="gcs")]
     uplink_message: Option<UplinkMessage>,
+    /// Remaining number of abort packets to send on the GCS, or whether one was received on the FC
+    #[cfg(feature="gcs")]
+    aborts_pending: u8,
+    #[cfg(not(feature="gcs"))]
+    abort_received: bool,
     last_message_received: u32,
     #[cfg(feature="gcs")]
     fc_time_offset: i64,
+    /// Offset applied to all channel frequencies to match the FC's (Hz)
+    #[cfg(feature="gcs")]
+    frequency_correction: i32,
+    /// External LNA/PA, if fitted, see `frontend.rs`
+    #[cfg(feature="gcs")]
+    frontend: Frontend,
     authentication_key: [u8; 16],
+    link: LinkConfig,
+    /// Time the link configuration was last announced
+    #[cfg(not(feature="gcs"))]
+    last_announcement: u32,
+    /// Rejected mode change waiting to be downlinked on the FC, or last one received on the GCS
+    mode_rejection: Option<ModeRejection>,
+    /// Countdown progress waiting to be downlinked on the FC, or last received on the GCS
+    countdown_status: Option<CountdownStatus>,
+    /// Retransmission request waiting to be uplinked on the GCS, or last received on the FC
+    retransmit_request: Option<RetransmitRequest>,
+    /// Last retransmitted message received
+    #[cfg(feature="gcs")]
+    retransmission: Option<DownlinkMessage>,
+    /// Channel blacklist waiting to be uplinked on the GCS, or last received on the FC
+    blacklist: Option<u16>,
+    /// Whether a diagnostics request is waiting to be uplinked on the GCS, or was received on the
+    /// FC
+    diagnostics_requested: bool,
+    /// Radio diagnostics waiting to be downlinked on the FC, or last received on the GCS
+    diagnostics: Option<RadioDiagnostics>,
+    /// Error counts waiting to be downlinked on the FC, or last received on the GCS
+    error_counts: Option<ErrorCounts>,
+    /// Geofence violation waiting to be downlinked on the FC, or last received on the GCS
+    geofence_violation: Option<(GeofenceViolation, GeofenceAction)>,
+    /// Chunks of an operator note waiting to be uplinked on the GCS, or the note being received
+    /// and the last complete one on the FC
+    #[cfg(feature="gcs")]
+    note_chunks: Deque<(u8, [u8; NOTE_CHUNK_LENGTH]), NOTE_CHUNKS>,
+    #[cfg(not(feature="gcs"))]
+    note_assembler: NoteAssembler,
+    #[cfg(not(feature="gcs"))]
+    note: Option<String<LOG_NOTE_LENGTH>>,
+    /// Heartbeat waiting to be downlinked on the FC, or last received on the GCS
+    heartbeat: Option<Heartbeat>,
+    /// Logging status waiting to be downlinked on the FC, or last received on the GCS
+    logging_status: Option<LoggingStatus>,
+    /// Flight summary waiting to be downlinked on the FC, or last received on the GCS
+    flight_summary: Option<FlightSummaryReport>,
+    /// Whether a self test was requested, to be uplinked on the GCS or received on the FC
+    self_test_requested: bool,
+    /// Self test result waiting to be downlinked on the FC, or last received on the GCS
+    self_test: Option<SelfTestResult>,
+    /// Engine controller command waiting to be uplinked on the GCS, or last received on the FC
+    engine_command: Option<EngineCommand>,
+    /// Remaining repeats of an engine abort on the GCS, see `ABORT_REPEATS`
+    #[cfg(feature="gcs")]
+    engine_aborts_pending: u8,
+    /// Downlink profile waiting to be uplinked on the GCS, or last received on the FC
+    downlink_profile: Option<DownlinkProfile>,
+    /// Find-me siren command waiting to be uplinked on the GCS, or last received on the FC
+    find_me: Option<bool>,
+    /// Redundancy state waiting to be downlinked on the FC, or last received on the GCS
+    redundancy: Option<RedundancyStatus>,
+    /// Time reference waiting to be downlinked on the FC, or last received on the GCS
+    time_reference: Option<TimeReference>,
+    tx_stats: TxStats,
+    /// Sequence numbers of the next downlink message of each kind, see `lora_packet.rs`
+    #[cfg(not(feature="gcs"))]
+    downlink_sequence_numbers: [u8; DownlinkKind::ALL.len()],
+    /// Sequence number of the last received downlink message
+    #[cfg(feature="gcs")]
+    sequence_number: u8,
     channels: [bool; CHANNELS.len()],
     binding_phrase: String<64>,
     sequence: Option<[usize; CHANNELS.len()]>,
+    scan: Option<SpectrumScan>,
+    scan_result: Option<[ChannelNoise; CHANNELS.len()]>,
+    /// Whether the GCS listens to a relay instead of the FC directly
+    #[cfg(feature="gcs")]
+    via_relay: bool,
+    /// Hop count of the last received packet
+    #[cfg(feature="gcs")]
+    hops: u8,
+    /// Keys of recently received packets, see `lora_packet::dedup_key`
+    #[cfg(feature="gcs")]
+    recent_packets: Deque<lora_packet::RxHmac, DEDUP_HISTORY>,
+    /// Last received packet, waiting for its decoded message to be recorded, see `capture.rs`
+    #[cfg(feature="gcs")]
+    recording: Option<Recording>,
+    /// Last packet received from the FC, waiting to be relayed
+    #[cfg(feature="relay")]
+    relay_packet: Option<Vec<u8, 64>>,
+}
+
+/// Sync word and IQ inversion for the given binding phrase, so stations with a different binding
+/// phrase, and other LoRa networks, are already filtered out by the transceiver. The LoRaWAN and
+/// default sync words are avoided, and each nibble is kept within 1-7, since not all transceivers
+/// reliably detect other values.
+fn private_network(binding_phrase: &String<64>) -> (u8, bool) {
+    // Keyed differently from the hop sequence seed, see `generate_sequence`.
+    let mut siphasher = SipHasher::new_with_key(&[0x5a; 16]);
+    siphasher.write(binding_phrase.as_bytes());
+    let hash = siphasher.finish();
+
+    let high = (hash % 7) as u8 + 1;
+    let low = ((hash >> 8) % 7) as u8 + 1;
+    let sync_word = (high << 4) | low;
+    let sync_word = match sync_word {
+        DEFAULT_SYNC_WORD | LORAWAN_SYNC_WORD => sync_word + 1,
+        _ => sync_word,
+    };
+    (sync_word, (hash >> 16) & 1 == 1)
+}
+
+/// Channel relays retransmit packets received on the given channel on.
+#[cfg(feature="gcs")]
+fn relay_channel(channel: usize) -> usize {
+    (channel + RELAY_CHANNEL_OFFSET) % CHANNELS.len()
 }
 
 impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> Radio<SPI, IRQ, BUSY> {
@@ -93,18 +473,93 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> Radio<SPI, IRQ, BUSY> {
             state_time: 0,
             transmit_power: TransmitPower::P14dBm,
             transmit_power_setpoint: TransmitPower::P14dBm,
+            sync_word_setpoint: DEFAULT_SYNC_WORD,
+            iq_inversion_setpoint: false,
+            tx_queue: Deque::new(),
             #[cfg(feature="gcs")]
             uplink_message: None,
+            #[cfg(feature="gcs")]
+            aborts_pending: 0,
+            #[cfg(not(feature="gcs"))]
+            abort_received: false,
             last_message_received: 0,
             #[cfg(feature="gcs")]
             fc_time_offset: 0,
+            #[cfg(feature="gcs")]
+            frequency_correction: 0,
+            #[cfg(feature="gcs")]
+            frontend: Frontend::new(None, None),
             authentication_key: [0x00; 16],
+            link: LinkConfig::default(),
+            #[cfg(not(feature="gcs"))]
+            last_announcement: 0,
+            mode_rejection: None,
+            countdown_status: None,
+            retransmit_request: None,
+            #[cfg(feature="gcs")]
+            retransmission: None,
+            blacklist: None,
+            diagnostics_requested: false,
+            diagnostics: None,
+            error_counts: None,
+            geofence_violation: None,
+            #[cfg(feature="gcs")]
+            note_chunks: Deque::new(),
+            #[cfg(not(feature="gcs"))]
+            note_assembler: NoteAssembler::default(),
+            #[cfg(not(feature="gcs"))]
+            note: None,
+            heartbeat: None,
+            logging_status: None,
+            flight_summary: None,
+            self_test_requested: false,
+            self_test: None,
+            engine_command: None,
+            #[cfg(feature="gcs")]
+            engine_aborts_pending: 0,
+            downlink_profile: None,
+            find_me: None,
+            redundancy: None,
+            time_reference: None,
+            tx_stats: TxStats::default(),
+            #[cfg(not(feature="gcs"))]
+            downlink_sequence_numbers: [0; DownlinkKind::ALL.len()],
+            #[cfg(feature="gcs")]
+            sequence_number: 0,
             channels: [true; CHANNELS.len()],
             binding_phrase: String::new(),
             sequence: None,
+            scan: None,
+            scan_result: None,
+            #[cfg(feature="gcs")]
+            via_relay: false,
+            #[cfg(feature="gcs")]
+            hops: 0,
+            #[cfg(feature="gcs")]
+            recent_packets: Deque::new(),
+            #[cfg(feature="gcs")]
+            recording: None,
+            #[cfg(feature="relay")]
+            relay_packet: None,
         })
     }
 
+    /// Adds an external LNA/PA, switched along with the transceiver.
+    #[cfg(feature="gcs")]
+    pub fn with_frontend(self, frontend: Frontend) -> Self {
+        Self { frontend, ..self }
+    }
+
+    #[cfg(feature="gcs")]
+    pub fn frontend(&self) -> &Frontend {
+        &self.frontend
+    }
+
+    #[cfg(feature="gcs")]
+    pub fn frontend_mut(&mut self) -> &mut Frontend {
+        &mut self.frontend
+    }
+
     pub fn set_transmit_power(&mut self, tx_power: TransmitPower) {
         self.transmit_power_setpoint = tx_power;
     }
@@ -153,10 +608,63 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> Radio<SPI, IRQ, BUSY> {
 
         self.channels = settings.channels;
         self.binding_phrase = settings.binding_phrase.clone();
-        self.sequence = self.generate_sequence(settings.channels, &settings.binding_phrase);
+        (self.sync_word_setpoint, self.iq_inversion_setpoint) = private_network(&self.binding_phrase);
+        self.update_sequence();
         //info!("Generated sequence {:?} using phrase {:?}", self.sequence, Debug2Format(&self.binding_phrase));
     }
 
+    /// Regenerates the hop sequence from the enabled channels that aren't blacklisted.
+    fn update_sequence(&mut self) {
+        let mut channels = self.channels;
+        for (i, enabled) in channels.iter_mut().enumerate() {
+            *enabled &= !self.link.is_blacklisted(i);
+        }
+        let binding_phrase = self.binding_phrase.clone();
+        self.sequence = self.generate_sequence(channels, &binding_phrase);
+    }
+
+    /// Channel index and nominal frequency (Hz) for each hop, once a sequence was generated.
+    pub fn hop_table(&self) -> Option<[(usize, u32); CHANNELS.len()]> {
+        self.sequence.map(|sequence| sequence.map(|channel| (channel, CHANNELS[channel])))
+    }
+
+    pub fn link_config(&self) -> LinkConfig {
+        self.link
+    }
+
+    /// Changes the link timing and blacklist, which take effect with the next message interval.
+    pub fn set_link_config(&mut self, link: LinkConfig) {
+        let blacklist_changed = link.blacklist != self.link.blacklist;
+        self.link = link;
+        if blacklist_changed {
+            self.update_sequence();
+        }
+    }
+
+    /// Takes over the link configuration announced by the FC.
+    #[cfg(feature="gcs")]
+    fn apply_link_announcement(&mut self, link: LinkConfig) {
+        if link == self.link {
+            return;
+        }
+
+        if link.is_valid() {
+            info!("FC announced link configuration {}, switching.", link);
+            self.set_link_config(link);
+        } else {
+            warn!("FC announced invalid link configuration {}, ignoring.", link);
+        }
+    }
+
+    /// The FC announces its link configuration while it doesn't hear from the GCS, in case the
+    /// GCS expects a different one.
+    #[cfg(not(feature="gcs"))]
+    fn link_announcement_due(&self) -> bool {
+        let gcs_silent = self.last_message_received == 0
+            || self.time.wrapping_sub(self.last_message_received) >= UPLINK_TIMEOUT;
+        gcs_silent && self.time.wrapping_sub(self.last_announcement) >= LINK_ANNOUNCEMENT_INTERVAL
+    }
+
     async fn switch_to_next_frequency(&mut self) -> Result<(), RadioError<SPI::Error>> {
         // Switch to the correct frequency for the current message interval.
         // On the FC, this is pretty straight forward.
@@ -166,8 +674,93 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> Radio<SPI, IRQ, BUSY> {
         #[cfg(feature="gcs")]
         let t = (self.time as i64).wrapping_add(self.fc_time_offset) as u32;
 
-        let message_i = (t / LORA_MESSAGE_INTERVAL) as usize % CHANNELS.len();
-        self.trx.set_frequency(CHANNELS[self.sequence.map(|s| s[message_i]).unwrap_or(0)]).await
+        let message_i = (t / self.link.message_interval) as usize % CHANNELS.len();
+        let channel = self.sequence.map(|s| s[message_i]).unwrap_or(0);
+
+        #[cfg(feature="gcs")]
+        let channel = if self.via_relay { relay_channel(channel) } else { channel };
+
+        let frequency = self.channel_frequency(channel);
+        self.trx.set_frequency(frequency).await
+    }
+
+    /// Selects whether the GCS receives downlink packets via a relay, on the relay channels, or
+    /// directly from the FC. Uplink messages are not relayed.
+    #[cfg(all(feature="gcs", not(feature="relay")))]
+    pub fn set_via_relay(&mut self, via_relay: bool) {
+        self.via_relay = via_relay;
+        self.trx.set_rx_packet_size(if via_relay { RELAY_PACKET_SIZE } else { DOWNLINK_PACKET_SIZE });
+        self.last_message_received = 0;
+    }
+
+    #[cfg(all(feature="gcs", not(feature="relay")))]
+    pub fn via_relay(&self) -> bool {
+        self.via_relay
+    }
+
+    /// Sequence number of the last received downlink message, see `lora_packet.rs`
+    #[cfg(feature="gcs")]
+    pub fn sequence_number(&self) -> u8 {
+        self.sequence_number
+    }
+
+    /// Retransmits the last packet received from the FC on the relay channel for the interval it
+    /// was sent in. Since the transceiver can't receive while transmitting, the FC's next packet
+    /// is usually missed, so at most every other packet is relayed.
+    #[cfg(feature="relay")]
+    async fn relay(&mut self, fc_time: u32) -> Result<(), RadioError<SPI::Error>> {
+        let Some(packet) = self.relay_packet.take() else {
+            return Ok(());
+        };
+
+        let mut buffer = [0u8; TX_PACKET_SIZE as usize];
+        let len = match lora_packet::encode_relayed(packet.get(1..).unwrap_or_default(), 1, &mut buffer) {
+            Ok(len) => len,
+            Err(e) => {
+                report(Subsystem::Radio, e, "encoding relayed packet");
+                return Ok(());
+            }
+        };
+
+        let message_i = (fc_time / self.link.message_interval) as usize % CHANNELS.len();
+        let channel = relay_channel(self.sequence.map(|s| s[message_i]).unwrap_or(0));
+        self.trx.set_frequency(self.channel_frequency(channel)).await?;
+        self.send_packet(&buffer[..len]).await
+    }
+
+    /// Starts transmitting an encoded packet, keying the external PA on the GCS.
+    async fn send_packet(&mut self, packet: &[u8]) -> Result<(), RadioError<SPI::Error>> {
+        #[cfg(feature="gcs")]
+        self.frontend.transmit();
+
+        let result = self.trx.send(packet).await;
+        #[cfg(feature="gcs")]
+        if result.is_err() {
+            self.frontend.receive();
+        }
+        result?;
+
+        self.set_state(RadioState::Transmitting);
+        Ok(())
+    }
+
+    fn channel_frequency(&self, channel: usize) -> u32 {
+        #[cfg(not(feature="gcs"))]
+        let correction = 0;
+        #[cfg(feature="gcs")]
+        let correction = self.frequency_correction;
+
+        CHANNELS[channel].wrapping_add_signed(correction)
+    }
+
+    /// The GCS follows the FC's frequency, which may be off due to crystal tolerance and
+    /// temperature drift. The FC keeps its nominal frequencies, so only one side is adjusting.
+    #[cfg(feature="gcs")]
+    fn update_frequency_correction(&mut self) {
+        // The error is measured relative to the already corrected frequency.
+        let step = self.trx.frequency_error / FREQUENCY_CORRECTION_DIVIDER;
+        self.frequency_correction = (self.frequency_correction + step)
+            .clamp(-MAX_FREQUENCY_CORRECTION, MAX_FREQUENCY_CORRECTION);
     }
 
     fn start_of_current_interval(&self) -> u32 {
@@ -178,112 +771,933 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> Radio<SPI, IRQ, BUSY> {
         #[cfg(feature="gcs")]
         let t = (self.time as i64).wrapping_add(self.fc_time_offset) as u32;
 
-        t.wrapping_sub(t % LORA_MESSAGE_INTERVAL)
+        t.wrapping_sub(t % self.link.message_interval)
     }
 
-    pub async fn send<M: Transmit>(&mut self, msg: M) -> Result<(), RadioError<SPI::Error>> {
-        let serialized = msg.serialize().unwrap_or_default();
+    #[cfg(not(feature="gcs"))]
+    pub async fn send(&mut self, msg: DownlinkMessage) -> Result<(), RadioError<SPI::Error>> {
+        // Uplink windows are reserved for the GCS, which only matters if the telemetry
+        // schedule doesn't fit the link configuration.
+        if self.is_uplink_window(self.time, false) {
+            return Ok(());
+        }
 
-        if self.sequence.is_none() {
+        // Announcements don't count as messages of any kind, so they carry no meaningful
+        // sequence number, and the replaced message doesn't show up as lost.
+        if self.link_announcement_due() {
+            self.last_announcement = self.time;
+            return self.transmit(&(LINK_ANNOUNCEMENT_TAG, self.link), Some(0)).await.map(|_| ());
+        }
+
+        // Likewise for geofence violations and mode rejections, which are only retried if the
+        // packet wasn't sent at all.
+        if let Some((violation, action)) = self.geofence_violation {
+            if self.transmit(&(GEOFENCE_TAG, violation, action), Some(0)).await? {
+                self.geofence_violation = None;
+            }
+            return Ok(());
+        }
+
+        if let Some(rejection) = self.mode_rejection {
+            if self.transmit(&(MODE_REJECTION_TAG, rejection), Some(0)).await? {
+                self.mode_rejection = None;
+            }
             return Ok(());
         }
 
-        if self.state != RadioState::Idle {
-            error!("skipping");
-            return Ok(()); // TODO
+        if let Some(status) = self.countdown_status {
+            if self.transmit(&(COUNTDOWN_TAG, status), Some(0)).await? {
+                self.countdown_status = None;
+            }
+            return Ok(());
         }
 
-        // Prepend message authentication
-        let mut siphasher = SipHasher::new_with_key(&self.authentication_key);
-        #[cfg(feature="gcs")] // only include time for uplink messages, prevents replay attacks
-        siphasher.write(&self.start_of_current_interval().to_be_bytes());
-        siphasher.write(&serialized);
-        let hash = (siphasher.finish() as TxHmac).to_be_bytes();
-        let msg = [&hash, serialized.as_slice()].concat();
+        if let Some(diagnostics) = self.diagnostics {
+            if self.transmit(&(DIAGNOSTICS_TAG, diagnostics), Some(0)).await? {
+                self.diagnostics = None;
+            }
+            return Ok(());
+        }
+
+        if let Some(counts) = self.error_counts {
+            if self.transmit(&(ERROR_COUNTS_TAG, counts), Some(0)).await? {
+                self.error_counts = None;
+            }
+            return Ok(());
+        }
+
+        if let Some(heartbeat) = self.heartbeat {
+            if self.transmit(&(HEARTBEAT_TAG, heartbeat), Some(0)).await? {
+                self.heartbeat = None;
+            }
+            return Ok(());
+        }
+
+        if let Some(status) = self.logging_status {
+            if self.transmit(&(LOGGING_STATUS_TAG, status), Some(0)).await? {
+                self.logging_status = None;
+            }
+            return Ok(());
+        }
+
+        if let Some(summary) = self.flight_summary {
+            if self.transmit(&(FLIGHT_SUMMARY_TAG, summary), Some(0)).await? {
+                self.flight_summary = None;
+            }
+            return Ok(());
+        }
+
+        if let Some(result) = self.self_test {
+            if self.transmit(&(SELF_TEST_TAG, result), Some(0)).await? {
+                self.self_test = None;
+            }
+            return Ok(());
+        }
+
+        if let Some(status) = self.redundancy {
+            if self.transmit(&(REDUNDANCY_TAG, status), Some(0)).await? {
+                self.redundancy = None;
+            }
+            return Ok(());
+        }
+
+        if let Some(reference) = self.time_reference {
+            if self.transmit(&(TIME_REFERENCE_TAG, reference), Some(0)).await? {
+                self.time_reference = None;
+            }
+            return Ok(());
+        }
+
+        let i = DownlinkKind::of(&msg).index();
+        let sequence_number = self.downlink_sequence_numbers[i];
+        if self.transmit(&msg, Some(sequence_number)).await? {
+            self.downlink_sequence_numbers[i] = sequence_number.wrapping_add(1);
+        }
 
-        self.trx.send(&msg).await?;
-        self.set_state(RadioState::Transmitting);
         Ok(())
     }
 
+    #[cfg(feature="gcs")]
+    pub async fn send(&mut self, msg: UplinkMessage) -> Result<(), RadioError<SPI::Error>> {
+        self.transmit(&msg, None).await.map(|_| ())
+    }
+
+    /// Returns whether the packet was sent, or queued to be sent once the transceiver is done with
+    /// the previous one.
+    async fn transmit<M: Serialize>(&mut self, msg: &M, sequence_number: Option<u8>) -> Result<bool, RadioError<SPI::Error>> {
+        if self.sequence.is_none() {
+            return Ok(false);
+        }
+
+        // Prepend message authentication, only including time for uplink messages
+        #[cfg(feature="gcs")]
+        let interval_start = Some(self.start_of_current_interval());
+        #[cfg(not(feature="gcs"))]
+        let interval_start = None;
+        let mut buffer = [0u8; TX_PACKET_SIZE as usize];
+        let len = match lora_packet::encode(msg, &self.authentication_key, interval_start, sequence_number, &mut buffer) {
+            Ok(len) => len,
+            Err(e) => {
+                report(Subsystem::Radio, e, "encoding packet");
+                return Ok(false);
+            }
+        };
+
+        if self.state == RadioState::Idle {
+            match self.send_packet(&buffer[..len]).await {
+                Err(RadioError::Busy) => {},
+                result => return result.map(|_| true),
+            }
+        }
+
+        Ok(self.queue_packet(&buffer[..len]))
+    }
+
+    /// Queues a packet while the transceiver is still transmitting or busy. Queued packets are
+    /// sent later on in the same message interval, but dropped once it has passed, since they
+    /// would be sent on the wrong channel. Returns false if the queue is full.
+    fn queue_packet(&mut self, packet: &[u8]) -> bool {
+        let interval_start = self.start_of_current_interval();
+        let queued = Vec::from_slice(packet)
+            .map_err(|_| ())
+            .and_then(|packet| self.tx_queue.push_back((interval_start, packet)).map_err(|_| ()));
+
+        if queued.is_err() {
+            warn!("Transmit queue full, dropping packet.");
+        }
+        queued.is_ok()
+    }
+
+    /// Sends the next queued packet that is still due, see `queue_packet`.
+    async fn send_queued_packet(&mut self) {
+        let interval_start = self.start_of_current_interval();
+        while let Some((queued_in, packet)) = self.tx_queue.pop_front() {
+            if queued_in != interval_start {
+                continue;
+            }
+
+            match self.send_packet(&packet).await {
+                Ok(()) => {},
+                Err(RadioError::Busy) => {
+                    let _ = self.tx_queue.push_front((queued_in, packet));
+                },
+                Err(e) => report(Subsystem::Radio, e, "sending queued packet"),
+            }
+            return;
+        }
+    }
+
+    /// Downlinks a rejected mode change in place of the next message, see `mode_guard.rs`.
+    #[cfg(not(feature="gcs"))]
+    pub fn send_mode_rejection(&mut self, rejection: ModeRejection) {
+        self.mode_rejection = Some(rejection);
+    }
+
+    /// Downlinks a geofence violation in place of the next message, see `geofence.rs`.
+    #[cfg(not(feature="gcs"))]
+    pub fn send_geofence_violation(&mut self, violation: GeofenceViolation, action: GeofenceAction) {
+        self.geofence_violation = Some((violation, action));
+    }
+
+    /// Returns the geofence violation last reported by the FC, if any.
+    #[cfg(feature="gcs")]
+    pub fn take_geofence_violation(&mut self) -> Option<(GeofenceViolation, GeofenceAction)> {
+        self.geofence_violation.take()
+    }
+
+    /// Downlinks a heartbeat in place of the next message, see `version.rs`.
+    #[cfg(not(feature="gcs"))]
+    pub fn send_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.heartbeat = Some(heartbeat);
+    }
+
+    /// Returns the heartbeat received since the last call, if any.
+    #[cfg(feature="gcs")]
+    pub fn take_heartbeat(&mut self) -> Option<Heartbeat> {
+        self.heartbeat.take()
+    }
+
+    /// Downlinks the state of the flash log in place of the next message, see `flash.rs`.
+    #[cfg(not(feature="gcs"))]
+    pub fn send_logging_status(&mut self, status: LoggingStatus) {
+        self.logging_status = Some(status);
+    }
+
+    /// Returns the logging status received since the last call, if any.
+    #[cfg(feature="gcs")]
+    pub fn take_logging_status(&mut self) -> Option<LoggingStatus> {
+        self.logging_status.take()
+    }
+
+    /// Downlinks the flight summary in place of the next message, see `flight_summary.rs`.
+    #[cfg(not(feature="gcs"))]
+    pub fn send_flight_summary(&mut self, summary: FlightSummaryReport) {
+        self.flight_summary = Some(summary);
+    }
+
+    /// Returns the flight summary received since the last call, if any.
+    #[cfg(feature="gcs")]
+    pub fn take_flight_summary(&mut self) -> Option<FlightSummaryReport> {
+        self.flight_summary.take()
+    }
+
+    /// Returns whether a self test was requested since the last call.
+    #[cfg(not(feature="gcs"))]
+    pub fn take_self_test_request(&mut self) -> bool {
+        core::mem::take(&mut self.self_test_requested)
+    }
+
+    /// Downlinks a self test result in place of the next message, see `self_test.rs`.
+    #[cfg(not(feature="gcs"))]
+    pub fn send_self_test_result(&mut self, result: SelfTestResult) {
+        self.self_test = Some(result);
+    }
+
+    /// Starts the FC's self test in the next uplink window, after any pending abort.
+    #[cfg(feature="gcs")]
+    pub fn request_self_test(&mut self) {
+        self.self_test_requested = true;
+    }
+
+    /// Returns the self test result received since the last call, if any.
+    #[cfg(feature="gcs")]
+    pub fn take_self_test_result(&mut self) -> Option<SelfTestResult> {
+        self.self_test.take()
+    }
+
+    /// Returns the engine controller command received since the last call, if any.
+    #[cfg(not(feature="gcs"))]
+    pub fn take_engine_command(&mut self) -> Option<EngineCommand> {
+        self.engine_command.take()
+    }
+
+    /// Uplinks an engine controller command in the next uplink window, after any pending abort.
+    /// Replaces a command not sent yet, except for engine aborts, which are repeated like vehicle
+    /// aborts and take precedence.
+    #[cfg(feature="gcs")]
+    pub fn queue_engine_command(&mut self, cmd: EngineCommand) {
+        if cmd == EngineCommand::Abort {
+            self.engine_aborts_pending = ABORT_REPEATS;
+            self.engine_command = None;
+        } else {
+            self.engine_command = Some(cmd);
+        }
+    }
+
+    /// Returns the downlink profile received since the last call, if any.
+    #[cfg(not(feature="gcs"))]
+    pub fn take_downlink_profile(&mut self) -> Option<DownlinkProfile> {
+        self.downlink_profile.take()
+    }
+
+    /// Uplinks a downlink profile for the FC to switch to in the next uplink window, after any
+    /// pending abort or engine command.
+    #[cfg(feature="gcs")]
+    pub fn queue_downlink_profile(&mut self, profile: DownlinkProfile) {
+        self.downlink_profile = Some(profile);
+    }
+
+    /// Returns the find-me siren command received since the last call, if any.
+    #[cfg(not(feature="gcs"))]
+    pub fn take_find_me(&mut self) -> Option<bool> {
+        self.find_me.take()
+    }
+
+    /// Starts or stops the FC's find-me siren in the next uplink window, after any pending abort
+    /// or engine command.
+    #[cfg(feature="gcs")]
+    pub fn queue_find_me(&mut self, enabled: bool) {
+        self.find_me = Some(enabled);
+    }
+
+    /// Downlinks the redundancy state in place of the next message, see `redundancy.rs`.
+    #[cfg(not(feature="gcs"))]
+    pub fn send_redundancy_status(&mut self, status: RedundancyStatus) {
+        self.redundancy = Some(status);
+    }
+
+    /// Returns the redundancy state received since the last call, if any.
+    #[cfg(feature="gcs")]
+    pub fn take_redundancy_status(&mut self) -> Option<RedundancyStatus> {
+        self.redundancy.take()
+    }
+
+    /// Downlinks the time reference in place of the next message, see `flash_log::TimeReference`.
+    #[cfg(not(feature="gcs"))]
+    pub fn send_time_reference(&mut self, reference: TimeReference) {
+        self.time_reference = Some(reference);
+    }
+
+    /// Returns the time reference received since the last call, if any.
+    #[cfg(feature="gcs")]
+    pub fn take_time_reference(&mut self) -> Option<TimeReference> {
+        self.time_reference.take()
+    }
+
+    /// Uplinks an operator note for the flash log, one chunk per uplink window after any other
+    /// pending requests. Replaces a note still being sent.
+    #[cfg(feature="gcs")]
+    pub fn queue_note(&mut self, text: &str) {
+        self.note_chunks.clear();
+        for chunk in crate::flash_log::note_chunks(text) {
+            let _ = self.note_chunks.push_back(chunk);
+        }
+    }
+
+    /// Returns the operator note received since the last call, if any.
+    #[cfg(not(feature="gcs"))]
+    pub fn take_note(&mut self) -> Option<String<LOG_NOTE_LENGTH>> {
+        self.note.take()
+    }
+
+    /// Sends an abort in the next uplink windows, ahead of any queued message.
+    #[cfg(feature="gcs")]
+    pub fn queue_abort(&mut self) {
+        self.aborts_pending = ABORT_REPEATS;
+    }
+
+    /// Returns whether an abort was received since the last call.
+    #[cfg(not(feature="gcs"))]
+    pub fn take_abort(&mut self) -> bool {
+        core::mem::take(&mut self.abort_received)
+    }
+
+    /// Whether a message sent now would take a downlink slot of its own, i.e. the time is aligned
+    /// to the message interval, outside the uplink windows, and the radio isn't busy.
+    #[cfg(not(feature="gcs"))]
+    pub fn downlink_slot_free(&self) -> bool {
+        self.state == RadioState::Idle
+            && self.time % self.link.message_interval == 0
+            && !self.is_uplink_window(self.time, false)
+    }
+
+    /// Sends a message again, see `retransmission.rs`. Retransmissions carry no meaningful
+    /// sequence number, like announcements.
+    #[cfg(not(feature="gcs"))]
+    pub async fn retransmit(&mut self, msg: &DownlinkMessage) -> Result<(), RadioError<SPI::Error>> {
+        self.transmit(&(RETRANSMISSION_TAG, msg), Some(0)).await.map(|_| ())
+    }
+
+    /// Returns the channel blacklist received since the last call, if any. It is up to the
+    /// vehicle to apply it, see `set_link_config`.
+    #[cfg(not(feature="gcs"))]
+    pub fn take_blacklist(&mut self) -> Option<u16> {
+        self.blacklist.take()
+    }
+
+    /// Sends a channel blacklist in the next uplink window, after any pending abort, and switches
+    /// to it afterwards.
+    #[cfg(feature="gcs")]
+    pub fn queue_blacklist(&mut self, blacklist: u16) {
+        self.blacklist = Some(blacklist);
+    }
+
+    /// Returns whether radio diagnostics were requested since the last call.
+    #[cfg(not(feature="gcs"))]
+    pub fn take_diagnostics_request(&mut self) -> bool {
+        core::mem::take(&mut self.diagnostics_requested)
+    }
+
+    /// Downlinks radio diagnostics in place of the next message, see `radio_diagnostics.rs`.
+    #[cfg(not(feature="gcs"))]
+    pub fn send_diagnostics(&mut self, diagnostics: RadioDiagnostics) {
+        self.diagnostics = Some(diagnostics);
+    }
+
+    /// Requests the FC's radio diagnostics in the next uplink window, after any pending abort.
+    #[cfg(feature="gcs")]
+    pub fn request_diagnostics(&mut self) {
+        self.diagnostics_requested = true;
+    }
+
+    /// Returns the radio diagnostics last reported by the FC, if any.
+    #[cfg(feature="gcs")]
+    pub fn take_diagnostics(&mut self) -> Option<RadioDiagnostics> {
+        self.diagnostics.take()
+    }
+
+    /// Downlinks error counts in place of the next message, see `errors.rs`.
+    #[cfg(not(feature="gcs"))]
+    pub fn send_error_counts(&mut self, counts: ErrorCounts) {
+        self.error_counts = Some(counts);
+    }
+
+    /// Returns the error counts last reported by the FC, if any.
+    #[cfg(feature="gcs")]
+    pub fn take_error_counts(&mut self) -> Option<ErrorCounts> {
+        self.error_counts.take()
+    }
+
+    /// Statistics of the local transmissions since startup.
+    pub fn tx_stats(&self) -> TxStats {
+        TxStats { busy_timeouts: self.trx.busy_timeouts, ..self.tx_stats }
+    }
+
+    /// Returns the retransmission request received since the last call, if any.
+    #[cfg(not(feature="gcs"))]
+    pub fn take_retransmit_request(&mut self) -> Option<RetransmitRequest> {
+        self.retransmit_request.take()
+    }
+
+    /// Sends a retransmission request in the next uplink window, after any pending abort.
+    #[cfg(feature="gcs")]
+    pub fn request_retransmission(&mut self, request: RetransmitRequest) {
+        self.retransmit_request = Some(request);
+    }
+
+    /// Returns the last retransmitted message received, if any.
+    #[cfg(feature="gcs")]
+    pub fn take_retransmission(&mut self) -> Option<DownlinkMessage> {
+        self.retransmission.take()
+    }
+
+    /// Downlinks countdown progress in place of the next message, see `countdown.rs`.
+    #[cfg(not(feature="gcs"))]
+    pub fn send_countdown_status(&mut self, status: CountdownStatus) {
+        self.countdown_status = Some(status);
+    }
+
+    /// Returns the countdown progress last reported by the FC, if any.
+    #[cfg(feature="gcs")]
+    pub fn take_countdown_status(&mut self) -> Option<CountdownStatus> {
+        self.countdown_status.take()
+    }
+
+    /// Returns the mode change the FC last reported as rejected, if any.
+    #[cfg(feature="gcs")]
+    pub fn take_mode_rejection(&mut self) -> Option<ModeRejection> {
+        self.mode_rejection.take()
+    }
+
     #[cfg(feature="gcs")]
     pub fn queue_uplink_message(&mut self, msg: UplinkMessage) {
         self.uplink_message = Some(msg);
     }
 
+    /// Whether a queued uplink message is still waiting for an uplink window.
+    #[cfg(feature="gcs")]
+    pub fn uplink_pending(&self) -> bool {
+        self.uplink_message.is_some()
+    }
+
+    /// Received packet (including the status byte) with its reception metadata, see `capture.rs`.
+    #[cfg(feature="gcs")]
+    fn raw_packet(&self, buffer: &[u8], status: PacketStatus) -> RawPacket {
+        RawPacket {
+            time: self.time,
+            frequency: self.trx.frequency(),
+            rssi: self.trx.rssi,
+            rssi_signal: self.trx.rssi_signal,
+            snr: self.trx.snr,
+            status,
+            data: Vec::from_slice(buffer.get(1..).unwrap_or_default()).unwrap_or_default(),
+        }
+    }
+
     async fn receive<M: Transmit + DeserializeOwned>(&mut self) -> Result<Option<M>, RadioError<SPI::Error>> {
-        let mut buffer = match self.trx.receive().await? {
+        let result = self.trx.receive().await;
+
+        #[cfg(feature="gcs")]
+        if let Some(packet) = self.trx.corrupted_packet.take() {
+            let packet = self.raw_packet(&packet, PacketStatus::Crc);
+            crate::capture::capture(packet.clone());
+            crate::capture::record(Recording { packet, sequence_number: None, hops: 0, message: None });
+        }
+
+        let mut buffer = match result? {
             Some(buffer) => buffer,
             None => return Ok(None),
         };
 
-        let (hmac, serialized) = buffer[1..].split_at_mut(core::mem::size_of::<RxHmac>());
-        let serialized_end = serialized.iter()
-            .position(|b| *b == 0)
-            .map(|i| i + 1)
-            .unwrap_or(serialized.len());
+        // Decoding works in place, so keep a copy of the raw packet for capture.
+        #[cfg(feature="gcs")]
+        let raw = (crate::capture::enabled() || crate::capture::recording()).then(|| buffer.clone());
+
+        // only include time for uplink messages, prevents replay attacks
+        #[cfg(not(feature="gcs"))]
+        let interval_start = Some(self.start_of_current_interval());
+        #[cfg(feature="gcs")]
+        let interval_start = None;
 
-        let mut siphasher = SipHasher::new_with_key(&self.authentication_key);
-        #[cfg(not(feature="gcs"))] // only include time for uplink messages, prevents replay attacks
-        siphasher.write(&self.start_of_current_interval().to_be_bytes());
-        siphasher.write(&serialized[..serialized_end]);
-        let correct = (siphasher.finish() as RxHmac).to_be_bytes();
+        // The packet is retransmitted as is, so keep a copy for relaying.
+        #[cfg(feature="relay")]
+        let relay_packet = buffer.clone();
 
-        if correct != hmac {
-            warn!("HMAC mismatch.");
+        let Some(packet) = buffer.get_mut(1..) else {
             return Ok(None);
+        };
+
+        // Relayed packets are prefixed by the relay header, see `lora_packet.rs`.
+        #[cfg(feature="gcs")]
+        let packet = if self.via_relay {
+            match lora_packet::decode_relayed(packet) {
+                Ok((hops, original)) => {
+                    self.hops = hops;
+                    original
+                },
+                Err(e) => {
+                    report(Subsystem::Radio, e, "decoding relayed packet");
+                    return Ok(None);
+                }
+            }
+        } else {
+            self.hops = 0;
+            packet
+        };
+
+        #[cfg(feature="gcs")]
+        let dedup_key = lora_packet::dedup_key(packet);
+
+        let result = lora_packet::authenticate(packet, &self.authentication_key, interval_start)
+            .and_then(|(sequence_number, serialized)| Ok((sequence_number, Payload::decode(serialized)?)));
+
+        #[cfg(feature="gcs")]
+        if let Some(raw) = raw {
+            let packet = self.raw_packet(&raw, if result.is_ok() { PacketStatus::Valid } else { PacketStatus::Invalid });
+            crate::capture::capture(packet.clone());
+
+            // The decoded message is added once it has been checked, see `tick`.
+            self.recording = crate::capture::recording().then(|| Recording {
+                packet,
+                sequence_number: result.as_ref().ok().and_then(|(sequence_number, _)| *sequence_number),
+                hops: self.hops,
+                message: None,
+            });
+        }
+
+        // Only downlink packets carry a sequence number.
+        #[cfg_attr(not(feature="gcs"), allow(unused_variables))]
+        let (sequence_number, payload) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                report(Subsystem::Radio, e, "decoding packet");
+                return Ok(None);
+            }
+        };
+
+        // Discard copies of the same packet received via different paths.
+        #[cfg(feature="gcs")]
+        if let Some(key) = dedup_key {
+            if self.recent_packets.iter().any(|k| *k == key) {
+                if let Some(recording) = self.recording.as_mut() {
+                    recording.packet.status = PacketStatus::Duplicate;
+                }
+                return Ok(None);
+            }
+
+            if self.recent_packets.is_full() {
+                self.recent_packets.pop_front();
+            }
+            let _ = self.recent_packets.push_back(key);
         }
 
-        let deserialized = postcard::from_bytes_cobs(serialized).ok();
-        if deserialized.is_none() {
-            error!("Failed to decode message: {}", &buffer[1..]);
+        let msg = match payload {
+            #[cfg(feature="gcs")]
+            Payload::Message(msg) => {
+                self.sequence_number = sequence_number.unwrap_or_default();
+                msg
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::Message(msg) => msg,
+            #[cfg(feature="gcs")]
+            Payload::LinkAnnouncement(link) => {
+                self.apply_link_announcement(link);
+                return Ok(None);
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::LinkAnnouncement(_) => return Ok(None),
+            #[cfg(feature="gcs")]
+            Payload::ModeRejection(rejection) => {
+                self.mode_rejection = Some(rejection);
+                return Ok(None);
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::ModeRejection(_) => return Ok(None),
+            #[cfg(feature="gcs")]
+            Payload::Countdown(status) => {
+                self.countdown_status = Some(status);
+                return Ok(None);
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::Countdown(_) => return Ok(None),
+            #[cfg(not(feature="gcs"))]
+            Payload::Abort => {
+                self.last_message_received = self.time;
+                self.abort_received = true;
+                return Ok(None);
+            },
+            #[cfg(feature="gcs")]
+            Payload::Abort => return Ok(None),
+            #[cfg(not(feature="gcs"))]
+            Payload::RetransmitRequest(request) => {
+                self.last_message_received = self.time;
+                self.retransmit_request = Some(request);
+                return Ok(None);
+            },
+            #[cfg(feature="gcs")]
+            Payload::RetransmitRequest(_) => return Ok(None),
+            #[cfg(feature="gcs")]
+            Payload::Retransmission(msg) => {
+                self.retransmission = Some(msg);
+                return Ok(None);
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::Retransmission(_) => return Ok(None),
+            #[cfg(not(feature="gcs"))]
+            Payload::Blacklist(blacklist) => {
+                self.last_message_received = self.time;
+                self.blacklist = Some(blacklist);
+                return Ok(None);
+            },
+            #[cfg(feature="gcs")]
+            Payload::Blacklist(_) => return Ok(None),
+            #[cfg(not(feature="gcs"))]
+            Payload::DiagnosticsRequest => {
+                self.last_message_received = self.time;
+                self.diagnostics_requested = true;
+                return Ok(None);
+            },
+            #[cfg(feature="gcs")]
+            Payload::DiagnosticsRequest => return Ok(None),
+            #[cfg(feature="gcs")]
+            Payload::Diagnostics(diagnostics) => {
+                self.diagnostics = Some(diagnostics);
+                return Ok(None);
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::Diagnostics(_) => return Ok(None),
+            #[cfg(feature="gcs")]
+            Payload::Geofence(violation, action) => {
+                self.geofence_violation = Some((violation, action));
+                return Ok(None);
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::Geofence(..) => return Ok(None),
+            #[cfg(not(feature="gcs"))]
+            Payload::Note(index, chunk) => {
+                self.last_message_received = self.time;
+                if let Some(text) = self.note_assembler.push(index, chunk) {
+                    self.note = Some(text);
+                }
+                return Ok(None);
+            },
+            #[cfg(feature="gcs")]
+            Payload::Note(..) => return Ok(None),
+            #[cfg(feature="gcs")]
+            Payload::Heartbeat(heartbeat) => {
+                self.heartbeat = Some(heartbeat);
+                return Ok(None);
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::Heartbeat(_) => return Ok(None),
+            #[cfg(feature="gcs")]
+            Payload::LoggingStatus(status) => {
+                self.logging_status = Some(status);
+                return Ok(None);
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::LoggingStatus(_) => return Ok(None),
+            #[cfg(feature="gcs")]
+            Payload::FlightSummary(summary) => {
+                self.flight_summary = Some(summary);
+                return Ok(None);
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::FlightSummary(_) => return Ok(None),
+            #[cfg(not(feature="gcs"))]
+            Payload::SelfTestRequest => {
+                self.last_message_received = self.time;
+                self.self_test_requested = true;
+                return Ok(None);
+            },
+            #[cfg(feature="gcs")]
+            Payload::SelfTestRequest => return Ok(None),
+            #[cfg(feature="gcs")]
+            Payload::SelfTest(result) => {
+                self.self_test = Some(result);
+                return Ok(None);
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::SelfTest(_) => return Ok(None),
+            #[cfg(not(feature="gcs"))]
+            Payload::EngineCommand(cmd) => {
+                self.last_message_received = self.time;
+                self.engine_command = Some(cmd);
+                return Ok(None);
+            },
+            #[cfg(feature="gcs")]
+            Payload::EngineCommand(_) => return Ok(None),
+            #[cfg(not(feature="gcs"))]
+            Payload::DownlinkProfile(profile) => {
+                self.last_message_received = self.time;
+                self.downlink_profile = Some(profile);
+                return Ok(None);
+            },
+            #[cfg(feature="gcs")]
+            Payload::DownlinkProfile(_) => return Ok(None),
+            #[cfg(not(feature="gcs"))]
+            Payload::FindMe(enabled) => {
+                self.last_message_received = self.time;
+                self.find_me = Some(enabled);
+                return Ok(None);
+            },
+            #[cfg(feature="gcs")]
+            Payload::FindMe(_) => return Ok(None),
+            #[cfg(feature="gcs")]
+            Payload::Redundancy(status) => {
+                self.redundancy = Some(status);
+                return Ok(None);
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::Redundancy(_) => return Ok(None),
+            #[cfg(feature="gcs")]
+            Payload::TimeReference(reference) => {
+                self.time_reference = Some(reference);
+                return Ok(None);
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::TimeReference(_) => return Ok(None),
+            #[cfg(feature="gcs")]
+            Payload::ErrorCounts(counts) => {
+                self.error_counts = Some(counts);
+                return Ok(None);
+            },
+            #[cfg(not(feature="gcs"))]
+            Payload::ErrorCounts(_) => return Ok(None),
+        };
+
+        #[cfg(feature="relay")]
+        {
+            self.relay_packet = Some(relay_packet);
         }
 
-        Ok(deserialized)
+        Ok(Some(msg))
     }
 
     fn is_uplink_window(&self, time: u32, first_only: bool) -> bool {
         let mut t = time % 1000;
 
         if !first_only {
-            t -= t % LORA_MESSAGE_INTERVAL;
+            t -= t % self.link.message_interval;
         }
-        (t % LORA_UPLINK_INTERVAL) == LORA_UPLINK_MODULO
+        (t % self.link.uplink_interval) == self.link.uplink_offset
     }
 
     async fn tick_common(&mut self, time: u32) {
         self.time = time;
 
-        // Return to rx mode after transmission. A delay is necessary in order
-        // to allow the LLCC68 to actually finish the transmission
-        if self.state == RadioState::Transmitting && time >= self.state_time.wrapping_add(TRANSMISSION_TIMEOUT_MS + 2) {
-            if let Err(e) = self.trx.switch_to_rx().await {
-                error!("Failed to return to RX mode: {:?}", Debug2Format(&e));
-            } else {
-                self.set_state(RadioState::Idle);
+        // Return to rx mode as soon as the LLCC68 reports the transmission as done. If the
+        // interrupt is missed or the IRQ status can't be read, the transmission has timed out
+        // after a fixed delay at the latest.
+        if self.state == RadioState::Transmitting {
+            let timed_out = time.wrapping_sub(self.state_time) >= TRANSMISSION_TIMEOUT_MS + 2;
+            let done = match self.trx.transmission_done().await {
+                Ok(Some(time_on_air)) => {
+                    self.tx_stats.packet_sent(time_on_air);
+                    true
+                },
+                Err(e @ RadioError::Timeout) => {
+                    self.tx_stats.timeouts += 1;
+                    report(Subsystem::Radio, e, "transmitting");
+                    true
+                },
+                result => {
+                    if let Err(e) = result {
+                        report(Subsystem::Radio, e, "checking for TxDone");
+                    }
+
+                    if timed_out {
+                        self.tx_stats.timeouts += 1;
+                        if let Err(e) = self.trx.clear_interrupts().await {
+                            report(Subsystem::Radio, e, "clearing interrupts");
+                        }
+                    }
+
+                    timed_out
+                }
+            };
+
+            if done {
+                #[cfg(feature="gcs")]
+                self.frontend.receive();
+
+                if let Err(e) = self.trx.switch_to_rx().await {
+                    report(Subsystem::Radio, e, "returning to RX mode");
+                } else {
+                    self.set_state(RadioState::Idle);
+                }
+            }
+        }
+
+        let network = (self.trx.sync_word(), self.trx.iq_inversion());
+        if self.state == RadioState::Idle && network != (self.sync_word_setpoint, self.iq_inversion_setpoint) {
+            let result = match self.trx.set_sync_word(self.sync_word_setpoint).await {
+                Ok(()) => self.trx.set_iq_inversion(self.iq_inversion_setpoint).await,
+                Err(e) => Err(e),
+            };
+            let result = match result {
+                Ok(()) => self.trx.switch_to_rx().await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => info!("Switched to sync word 0x{=u8:02x}, IQ inverted: {}.", self.sync_word_setpoint, self.iq_inversion_setpoint),
+                Err(e) => report(Subsystem::Radio, e, "setting sync word"),
             }
         }
 
         if self.transmit_power != self.transmit_power_setpoint {
             if let Err(e) = self.trx.set_output_power(self.transmit_power_setpoint).await {
-                error!("Error setting power level: {:?}", Debug2Format(&e));
+                report(Subsystem::Radio, e, "setting power level");
             } else {
                 self.transmit_power = self.transmit_power_setpoint;
             }
         }
+
+        if self.state == RadioState::Idle && !self.tx_queue.is_empty() {
+            self.send_queued_packet().await;
+        }
+    }
+
+    /// Starts a spectrum scan, during which no messages are sent or received.
+    pub fn start_scan(&mut self) {
+        self.scan = Some(SpectrumScan {
+            channel: 0,
+            ticks: 0,
+            sum: 0,
+            min: u8::MAX,
+            results: [ChannelNoise::default(); CHANNELS.len()],
+        });
+    }
+
+    /// Returns the noise on each channel once a spectrum scan has finished.
+    pub fn take_scan_result(&mut self) -> Option<[ChannelNoise; CHANNELS.len()]> {
+        self.scan_result.take()
+    }
+
+    /// Advances a running spectrum scan, returning false if there is none. Regular operation
+    /// resumes on the next channel switch afterwards.
+    async fn tick_scan(&mut self) -> bool {
+        let Some(mut scan) = self.scan.take() else {
+            return false;
+        };
+
+        if scan.ticks == 0 {
+            let result = match self.trx.set_frequency(CHANNELS[scan.channel]).await {
+                Ok(()) => self.trx.switch_to_rx().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                report(Subsystem::Radio, e, "switching frequencies");
+            }
+        } else if scan.ticks >= SCAN_SETTLE_TIME {
+            match self.trx.instantaneous_rssi().await {
+                Ok(rssi) => {
+                    scan.sum += rssi as u32;
+                    scan.min = u8::min(scan.min, rssi);
+                },
+                Err(e) => report(Subsystem::Radio, e, "reading RSSI"),
+            }
+        }
+
+        scan.ticks += 1;
+        if scan.ticks < SCAN_SETTLE_TIME + SCAN_SAMPLES {
+            self.scan = Some(scan);
+            return true;
+        }
+
+        scan.results[scan.channel] = ChannelNoise {
+            frequency: CHANNELS[scan.channel],
+            average: -(scan.sum as f32 / SCAN_SAMPLES as f32) / 2.0,
+            peak: -(scan.min as f32) / 2.0,
+        };
+
+        if scan.channel + 1 < CHANNELS.len() {
+            self.scan = Some(SpectrumScan {
+                channel: scan.channel + 1,
+                ticks: 0,
+                sum: 0,
+                min: u8::MAX,
+                ..scan
+            });
+        } else {
+            info!("Spectrum scan finished.");
+            self.scan_result = Some(scan.results);
+        }
+
+        true
     }
 
     #[cfg(not(feature = "gcs"))]
     pub async fn tick(&mut self, time: u32) -> Option<Command> {
         self.tick_common(time).await;
 
-        if self.state != RadioState::Idle {
+        if self.state != RadioState::Idle || self.tick_scan().await {
             return None;
         }
 
-        if self.time % LORA_MESSAGE_INTERVAL == 0 {
+        if self.time % self.link.message_interval == 0 {
             if let Err(e) = self.switch_to_next_frequency().await {
-                error!("Failed to switch frequencies: {:?}", Debug2Format(&e));
+                report(Subsystem::Radio, e, "switching frequencies");
             }
         }
 
@@ -299,7 +1713,7 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> Radio<SPI, IRQ, BUSY> {
                 },
                 Ok(None) => None,
                 Err(e) => {
-                    error!("Error receiving message: {:?}", Debug2Format(&e));
+                    report(Subsystem::Radio, e, "receiving message");
                     None
                 }
             }
@@ -316,7 +1730,7 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> Radio<SPI, IRQ, BUSY> {
 
         self.tick_common(time).await;
 
-        if self.state != RadioState::Idle {
+        if self.state != RadioState::Idle || self.tick_scan().await {
             return None;
         }
 
@@ -325,54 +1739,180 @@ impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> Radio<SPI, IRQ, BUSY> {
 
         // When not in contact with the FC we do a slow sweep across channels.
         if !in_contact && self.time % 1000 == 0 {
+            self.frontend.contact_lost();
+
             let i = (self.time as usize / 1000) % CHANNELS.len();
-            info!("Sweeping, switching to {}kHz.", CHANNELS[i] / 1_000);
-            if let Err(e) = self.trx.set_frequency(CHANNELS[i]).await {
-                error!("Failed to switch frequencies: {:?}", Debug2Format(&e));
+            let frequency = self.channel_frequency(i);
+            info!("Sweeping, switching to {}kHz.", frequency / 1_000);
+            if let Err(e) = self.trx.set_frequency(frequency).await {
+                report(Subsystem::Radio, e, "switching frequencies");
             }
 
             if let Err(e) = self.trx.switch_to_rx().await {
-                error!("Failed to switch frequencies: {:?}", Debug2Format(&e));
+                report(Subsystem::Radio, e, "switching frequencies");
             }
         }
 
-        if in_contact && fc_time % LORA_MESSAGE_INTERVAL == 0 {
+        if in_contact && fc_time % self.link.message_interval == 0 {
             if let Err(e) = self.switch_to_next_frequency().await {
-                error!("Failed to switch frequencies: {:?}", Debug2Format(&e));
+                report(Subsystem::Radio, e, "switching frequencies");
             }
 
             if let Err(e) = self.trx.switch_to_rx().await {
-                error!("Failed to switch frequencies: {:?}", Debug2Format(&e));
+                report(Subsystem::Radio, e, "switching frequencies");
             }
         }
 
-        if in_contact && self.is_uplink_window(fc_time.wrapping_sub(2), true) {
+        // Relays only listen, uplink messages are sent by the GCS itself.
+        if in_contact && !cfg!(feature="relay") && self.is_uplink_window(fc_time.wrapping_sub(2), true) {
+            if self.aborts_pending > 0 {
+                self.aborts_pending -= 1;
+                if let Err(e) = self.transmit(&ABORT_TAG, None).await {
+                    report(Subsystem::Radio, e, "sending abort");
+                }
+                return None;
+            }
+
+            if self.engine_aborts_pending > 0 {
+                self.engine_aborts_pending -= 1;
+                if let Err(e) = self.transmit(&(ENGINE_COMMAND_TAG, EngineCommand::Abort), None).await {
+                    report(Subsystem::Radio, e, "sending engine abort");
+                }
+                return None;
+            }
+
+            if let Some(cmd) = self.engine_command.take() {
+                if let Err(e) = self.transmit(&(ENGINE_COMMAND_TAG, cmd), None).await {
+                    report(Subsystem::Radio, e, "sending engine command");
+                }
+                return None;
+            }
+
+            if let Some(enabled) = self.find_me.take() {
+                if let Err(e) = self.transmit(&(FIND_ME_TAG, enabled), None).await {
+                    report(Subsystem::Radio, e, "sending find-me command");
+                }
+                return None;
+            }
+
+            if let Some(profile) = self.downlink_profile.take() {
+                if let Err(e) = self.transmit(&(DOWNLINK_PROFILE_TAG, profile), None).await {
+                    report(Subsystem::Radio, e, "sending downlink profile");
+                }
+                return None;
+            }
+
+            // The GCS switches right away, see `LinkConfig`.
+            if let Some(blacklist) = self.blacklist.take() {
+                match self.transmit(&(BLACKLIST_TAG, blacklist), None).await {
+                    Ok(true) => self.set_link_config(LinkConfig { blacklist, ..self.link }),
+                    Ok(false) => self.blacklist = Some(blacklist),
+                    Err(e) => report(Subsystem::Radio, e, "sending channel blacklist"),
+                }
+                return None;
+            }
+
+            // The request isn't repeated, the GCS can simply request again.
+            if let Some(request) = self.retransmit_request.take() {
+                if let Err(e) = self.transmit(&(RETRANSMIT_REQUEST_TAG, request), None).await {
+                    report(Subsystem::Radio, e, "sending retransmission request");
+                }
+                return None;
+            }
+
+            if core::mem::take(&mut self.diagnostics_requested) {
+                if let Err(e) = self.transmit(&DIAGNOSTICS_REQUEST_TAG, None).await {
+                    report(Subsystem::Radio, e, "sending diagnostics request");
+                }
+                return None;
+            }
+
+            if core::mem::take(&mut self.self_test_requested) {
+                if let Err(e) = self.transmit(&SELF_TEST_REQUEST_TAG, None).await {
+                    report(Subsystem::Radio, e, "sending self test request");
+                }
+                return None;
+            }
+
+            // Notes are sent one chunk at a time, a missing chunk discards the note on the FC.
+            if let Some((index, chunk)) = self.note_chunks.pop_front() {
+                if let Err(e) = self.transmit(&(NOTE_TAG, index, chunk), None).await {
+                    report(Subsystem::Radio, e, "sending note");
+                }
+                return None;
+            }
+
             let msg = self.uplink_message.take().unwrap_or(UplinkMessage::Heartbeat);
             if let Err(e) = self.send(msg).await {
-                error!("Failed to send uplink message: {:?}", Debug2Format(&e));
+                report(Subsystem::Radio, e, "sending uplink message");
             }
 
             None
         } else {
             let result: Result<Option<DownlinkMessage>, _> = self.receive().await;
-            match &result {
+
+            if let Some(mut recording) = self.recording.take() {
+                recording.message = result.as_ref().ok().and_then(|msg| msg.clone());
+                crate::capture::record(recording);
+            }
+
+            match result {
                 Ok(Some(msg)) => {
                     self.last_message_received = self.time;
                     self.fc_time_offset = (msg.time() as i64)
                         .wrapping_sub(self.time as i64)
-                        .wrapping_add(FC_GCS_TIME_OFFSET_MS); // compensate for message delay
+                        .wrapping_add(FC_GCS_TIME_OFFSET_MS) // compensate for message delay
+                        .wrapping_add(self.hops as i64 * RELAY_DELAY_MS);
+
+                    self.update_frequency_correction();
+                    self.frontend.packet_received(self.trx.snr);
 
-                    if let DownlinkMessage::TelemetryDiagnostics(tm) = msg {
+                    #[cfg(feature="relay")]
+                    if let Err(e) = self.relay(msg.time()).await {
+                        report(Subsystem::Radio, e, "relaying packet");
+                    }
+
+                    if let DownlinkMessage::TelemetryDiagnostics(tm) = &msg {
                         self.transmit_power_setpoint = (tm.transmit_power_and_data_rate & 0x7f).into();
                     }
+
+                    Some(msg)
                 }
-                Ok(None) => {},
+                Ok(None) => None,
                 Err(e) => {
-                    error!("Error receiving message: {:?}", Debug2Format(&e));
+                    report(Subsystem::Radio, e, "receiving message");
+                    None
                 }
             }
-
-            result.unwrap_or(None)
         }
     }
 }
+
+#[cfg(not(feature = "gcs"))]
+impl<SPI: SpiDevice<u8>, IRQ: InputPin, BUSY: InputPin> TelemetryRadio for Radio<SPI, IRQ, BUSY> {
+    type Error = RadioError<SPI::Error>;
+
+    async fn send(&mut self, msg: DownlinkMessage) -> Result<(), Self::Error> {
+        Radio::send(self, msg).await
+    }
+
+    async fn tick(&mut self, time: u32) -> Option<Command> {
+        Radio::tick(self, time).await
+    }
+
+    fn set_transmit_power(&mut self, power: TransmitPower) {
+        Radio::set_transmit_power(self, power)
+    }
+
+    fn set_max_transmit_power(&mut self) {
+        Radio::set_max_transmit_power(self)
+    }
+
+    fn transmit_power(&self) -> TransmitPower {
+        self.transmit_power
+    }
+
+    fn rssi(&self) -> u8 {
+        self.trx.rssi
+    }
+}
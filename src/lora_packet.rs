@@ -0,0 +1,313 @@
+//! Encoding, authentication and decoding of LoRa packets. This is the part of the radio protocol
+//! (see `lora.rs`) that handles bytes from the outside world, kept separate from the transceiver
+//! so it can be fuzzed on the host (see `fuzz/`).
+//!
+//! Packets consist of a truncated SipHash MAC, followed by the COBS-encoded message. For uplink
+//! messages, the MAC also covers the start of the current time interval to prevent replay attacks.
+//! The MAC also covers the protocol version, which doesn't cost any payload bytes. To tell
+//! firmware mismatches apart from corrupted packets, a packet that fails authentication is checked
+//! against the neighboring protocol versions.
+//!
+//! Downlink packets carry a one-byte sequence number between the MAC and the message, covered by
+//! the MAC. It is counted separately for each `DownlinkKind`, so the GCS can tell lost packets
+//! apart from messages that simply weren't sent, e.g. after a change of the downlink profile.
+//!
+//! Downlink packets retransmitted by a relay are prefixed by a one-byte relay header holding the
+//! hop count, and are otherwise forwarded unchanged, so the MAC still authenticates the flight
+//! computer. Since the MAC differs between messages, it doubles as the key for recognizing copies
+//! of the same packet received via different paths.
+
+use core::hash::Hasher;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use siphasher::sip::SipHasher;
+
+use defmt::Format;
+
+use shared_types::DownlinkMessage;
+
+use crate::framing::PROTOCOL_VERSION;
+
+#[cfg(feature = "gcs")]
+pub type TxHmac = u64;
+#[cfg(not(feature = "gcs"))]
+pub type TxHmac = u16;
+
+#[cfg(feature = "gcs")]
+pub type RxHmac = u16;
+#[cfg(not(feature = "gcs"))]
+pub type RxHmac = u64;
+
+/// Whether received packets carry a sequence number, i.e. are downlink packets
+const RX_SEQUENCE_NUMBER: bool = cfg!(feature = "gcs");
+
+/// Message types with separate downlink sequence numbers, i.e. those sent by the LoRa telemetry
+/// schedules (see `telemetry.rs`). Everything else shares one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Format)]
+pub enum DownlinkKind {
+    Gps,
+    Diagnostics,
+    Pressures,
+    Kalman,
+    Bus,
+    FastCompressed,
+    Other,
+}
+
+impl DownlinkKind {
+    pub const ALL: [DownlinkKind; 7] = [
+        DownlinkKind::Gps,
+        DownlinkKind::Diagnostics,
+        DownlinkKind::Pressures,
+        DownlinkKind::Kalman,
+        DownlinkKind::Bus,
+        DownlinkKind::FastCompressed,
+        DownlinkKind::Other,
+    ];
+
+    pub fn of(msg: &DownlinkMessage) -> Self {
+        match msg {
+            DownlinkMessage::TelemetryGPS(_) => Self::Gps,
+            DownlinkMessage::TelemetryDiagnostics(_) => Self::Diagnostics,
+            DownlinkMessage::TelemetryPressures(_) => Self::Pressures,
+            DownlinkMessage::TelemetryKalman(_) => Self::Kalman,
+            DownlinkMessage::TelemetryBus(_) => Self::Bus,
+            DownlinkMessage::TelemetryFastCompressed(_) => Self::FastCompressed,
+            _ => Self::Other,
+        }
+    }
+
+    /// Position in `ALL`, for per-kind counters
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    #[cfg_attr(not(feature = "gcs"), allow(dead_code))]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gps => "gps",
+            Self::Diagnostics => "diagnostics",
+            Self::Pressures => "pressures",
+            Self::Kalman => "kalman",
+            Self::Bus => "bus",
+            Self::FastCompressed => "fast",
+            Self::Other => "other",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum PacketError {
+    TooShort,
+    Authentication,
+    Serialization,
+    Deserialization,
+    /// Authentic packet from a different protocol version
+    Version(u8),
+}
+
+/// Computes the MAC for a serialized message.
+pub fn mac(key: &[u8; 16], interval_start: Option<u32>, serialized: &[u8]) -> u64 {
+    mac_for_version(PROTOCOL_VERSION, key, interval_start, serialized)
+}
+
+fn mac_for_version(version: u8, key: &[u8; 16], interval_start: Option<u32>, serialized: &[u8]) -> u64 {
+    let mut siphasher = SipHasher::new_with_key(key);
+    siphasher.write_u8(version);
+    if let Some(t) = interval_start {
+        siphasher.write(&t.to_be_bytes());
+    }
+    siphasher.write(serialized);
+    siphasher.finish()
+}
+
+/// Serializes a message and prepends its MAC and sequence number (only for downlink packets),
+/// without allocating. Returns the length of the packet written to `buffer`.
+pub fn encode<M: Serialize>(
+    msg: &M,
+    key: &[u8; 16],
+    interval_start: Option<u32>,
+    sequence_number: Option<u8>,
+    buffer: &mut [u8],
+) -> Result<usize, PacketError> {
+    let header_len = core::mem::size_of::<TxHmac>() + sequence_number.map(|_| 1).unwrap_or(0);
+    if buffer.len() <= header_len {
+        return Err(PacketError::TooShort);
+    }
+
+    let (hmac, covered) = buffer.split_at_mut(core::mem::size_of::<TxHmac>());
+    let (sequence, serialized) = covered.split_at_mut(header_len - hmac.len());
+    if let Some(n) = sequence_number {
+        sequence[0] = n;
+    }
+    let len = sequence.len() + postcard::to_slice_cobs(msg, serialized).map_err(|_| PacketError::Serialization)?.len();
+    hmac.copy_from_slice(&(mac(key, interval_start, &covered[..len]) as TxHmac).to_be_bytes());
+
+    Ok(hmac.len() + len)
+}
+
+/// Checks the MAC of a received packet and deserializes the message. The packet is decoded in
+/// place.
+pub fn decode<M: DeserializeOwned>(packet: &mut [u8], key: &[u8; 16], interval_start: Option<u32>) -> Result<M, PacketError> {
+    let (_sequence_number, serialized) = authenticate(packet, key, interval_start)?;
+    postcard::from_bytes(serialized).map_err(|_| PacketError::Deserialization)
+}
+
+/// Checks the MAC of a received packet and returns the sequence number (only for downlink
+/// packets) and the serialized message, for payloads that need to be inspected before
+/// deserializing. The packet is decoded in place.
+pub fn authenticate<'a>(packet: &'a mut [u8], key: &[u8; 16], interval_start: Option<u32>) -> Result<(Option<u8>, &'a [u8]), PacketError> {
+    let sequence_len = if RX_SEQUENCE_NUMBER { 1 } else { 0 };
+    if packet.len() <= core::mem::size_of::<RxHmac>() + sequence_len {
+        return Err(PacketError::TooShort);
+    }
+
+    let (hmac, rest) = packet.split_at_mut(core::mem::size_of::<RxHmac>());
+
+    // The MAC covers the sequence number and the message up to and including the COBS delimiter
+    let covered_end = rest[sequence_len..].iter()
+        .position(|b| *b == 0)
+        .map(|i| sequence_len + i + 1)
+        .unwrap_or(rest.len());
+
+    let covered = &rest[..covered_end];
+    let authentic = |version| (mac_for_version(version, key, interval_start, covered) as RxHmac).to_be_bytes()[..] == hmac[..];
+    if !authentic(PROTOCOL_VERSION) {
+        let other_version = [PROTOCOL_VERSION.wrapping_sub(1), PROTOCOL_VERSION.wrapping_add(1)]
+            .into_iter()
+            .find(|v| authentic(*v));
+        return Err(other_version.map(PacketError::Version).unwrap_or(PacketError::Authentication));
+    }
+
+    let (sequence, serialized) = rest.split_at_mut(sequence_len);
+    let len = cobs::decode_in_place(serialized).map_err(|_| PacketError::Deserialization)?;
+    Ok((sequence.first().copied(), &serialized[..len]))
+}
+
+/// Relayed packets with more hops than this are discarded.
+#[cfg(feature = "gcs")]
+pub const MAX_HOPS: u8 = 3;
+
+/// Prepends the relay header to a received downlink packet. Returns the length of the packet
+/// written to `buffer`.
+#[cfg(feature = "relay")]
+pub fn encode_relayed(packet: &[u8], hops: u8, buffer: &mut [u8]) -> Result<usize, PacketError> {
+    if buffer.len() < packet.len() + 1 {
+        return Err(PacketError::TooShort);
+    }
+
+    buffer[0] = hops;
+    buffer[1..(packet.len() + 1)].copy_from_slice(packet);
+    Ok(packet.len() + 1)
+}
+
+/// Strips the relay header from a relayed packet, returning the hop count and the original
+/// packet.
+#[cfg(feature = "gcs")]
+pub fn decode_relayed(packet: &mut [u8]) -> Result<(u8, &mut [u8]), PacketError> {
+    match packet.split_first_mut() {
+        Some((&mut hops, _)) if hops == 0 || hops > MAX_HOPS => Err(PacketError::Deserialization),
+        Some((&mut hops, original)) => Ok((hops, original)),
+        None => Err(PacketError::TooShort),
+    }
+}
+
+/// Key for recognizing duplicates of the same packet, i.e. its MAC.
+#[cfg(feature = "gcs")]
+pub fn dedup_key(packet: &[u8]) -> Option<RxHmac> {
+    let mac = packet.get(..core::mem::size_of::<RxHmac>())?;
+    Some(RxHmac::from_be_bytes(mac.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    const KEY: [u8; 16] = *b"0123456789abcdef";
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        value: u32,
+        flags: [u8; 3],
+    }
+
+    // Contains zeros, so COBS has something to encode
+    const MSG: Message = Message { value: 0x0100_0000, flags: [0, 1, 0] };
+
+    /// Builds a packet the way the other end sends it, i.e. with an `RxHmac` and, for downlink
+    /// packets, a sequence number.
+    fn received(version: u8, key: &[u8; 16], interval_start: Option<u32>) -> ([u8; 32], usize) {
+        let mut packet = [0; 32];
+        let hmac_len = core::mem::size_of::<RxHmac>();
+        let header_len = hmac_len + if RX_SEQUENCE_NUMBER { 1 } else { 0 };
+        if RX_SEQUENCE_NUMBER {
+            packet[hmac_len] = 7;
+        }
+        let len = header_len + postcard::to_slice_cobs(&MSG, &mut packet[header_len..]).unwrap().len();
+        let hmac = (mac_for_version(version, key, interval_start, &packet[hmac_len..len]) as RxHmac).to_be_bytes();
+        packet[..hmac_len].copy_from_slice(&hmac);
+        (packet, len)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut packet, len) = received(PROTOCOL_VERSION, &KEY, Some(1000));
+        assert_eq!(decode::<Message>(&mut packet[..len], &KEY, Some(1000)), Ok(MSG));
+
+        let (mut packet, len) = received(PROTOCOL_VERSION, &KEY, None);
+        let (sequence_number, _) = authenticate(&mut packet[..len], &KEY, None).unwrap();
+        assert_eq!(sequence_number, RX_SEQUENCE_NUMBER.then_some(7));
+    }
+
+    #[test]
+    fn encoded_mac_covers_sequence_number_and_message() {
+        let mut buffer = [0; 32];
+        let len = encode(&MSG, &KEY, Some(1000), Some(3), &mut buffer).unwrap();
+
+        let (hmac, covered) = buffer[..len].split_at_mut(core::mem::size_of::<TxHmac>());
+        assert_eq!(hmac, &(mac(&KEY, Some(1000), covered) as TxHmac).to_be_bytes()[..]);
+        assert_eq!(covered[0], 3);
+        assert_eq!(postcard::from_bytes_cobs::<Message>(&mut covered[1..]), Ok(MSG));
+
+        assert_eq!(encode(&MSG, &KEY, None, Some(3), &mut buffer[..3]), Err(PacketError::TooShort));
+    }
+
+    #[test]
+    fn tampered_packets_are_rejected() {
+        let (original, len) = received(PROTOCOL_VERSION, &KEY, Some(1000));
+        for i in 0..len {
+            let mut packet = original;
+            packet[i] ^= 0x01;
+            assert_eq!(decode::<Message>(&mut packet[..len], &KEY, Some(1000)), Err(PacketError::Authentication), "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn wrong_key_or_interval_is_rejected() {
+        let (mut packet, len) = received(PROTOCOL_VERSION, b"fedcba9876543210", Some(1000));
+        assert_eq!(decode::<Message>(&mut packet[..len], &KEY, Some(1000)), Err(PacketError::Authentication));
+
+        // Replayed from the previous time interval
+        let (mut packet, len) = received(PROTOCOL_VERSION, &KEY, Some(1000));
+        assert_eq!(decode::<Message>(&mut packet[..len], &KEY, Some(1200)), Err(PacketError::Authentication));
+    }
+
+    #[test]
+    fn other_protocol_versions_are_recognized() {
+        let version = PROTOCOL_VERSION.wrapping_add(1);
+        let (mut packet, len) = received(version, &KEY, None);
+        assert_eq!(decode::<Message>(&mut packet[..len], &KEY, None), Err(PacketError::Version(version)));
+
+        let (mut packet, len) = received(PROTOCOL_VERSION.wrapping_add(2), &KEY, None);
+        assert_eq!(decode::<Message>(&mut packet[..len], &KEY, None), Err(PacketError::Authentication));
+    }
+
+    #[test]
+    fn short_packets_are_rejected() {
+        let mut packet = [0; core::mem::size_of::<RxHmac>()];
+        assert_eq!(decode::<Message>(&mut packet, &KEY, None), Err(PacketError::TooShort));
+    }
+}
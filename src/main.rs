@@ -10,15 +10,12 @@ extern crate alloc;
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
 use embassy_executor::{InterruptExecutor, Spawner};
 use embassy_stm32::adc::Adc;
-use embassy_stm32::gpio::{Level, Speed, Output, Pull, Input, OutputType};
+use embassy_stm32::gpio::{Level, Speed, Output, Pull, Input};
 use embassy_stm32::interrupt::{InterruptExt, Priority};
 use embassy_stm32::peripherals::*;
 use embassy_stm32::rcc::{AHBPrescaler, APBPrescaler, Pll, PllMul, PllPreDiv, PllPDiv, PllQDiv, PllSource, Sysclk};
 use embassy_stm32::spi::Spi;
 use embassy_stm32::time::Hertz;
-use embassy_stm32::timer::Channel;
-use embassy_stm32::gpio::low_level::Pin;
-use embassy_stm32::timer::simple_pwm::{PwmPin, SimplePwm};
 use embassy_stm32::{interrupt, Config};
 use embassy_stm32::wdg::IndependentWatchdog;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
@@ -29,12 +26,97 @@ use static_cell::StaticCell;
 
 use {defmt_rtt as _, panic_probe as _};
 
+#[cfg(all(feature="aprs", not(feature="gcs")))]
+mod aprs;
+#[cfg(not(feature="gcs"))]
+mod arm;
+#[cfg(not(feature="gcs"))]
+mod backup_apogee;
+#[cfg(not(feature="gcs"))]
+mod baro_lag;
+#[cfg(not(feature="gcs"))]
+mod baro_speed;
+mod board;
+mod bootloader;
 mod buzzer;
+#[cfg(not(feature="gcs"))]
+mod calibration;
 mod can;
+#[cfg(feature="gcs")]
+mod capture;
+#[allow(dead_code)] // also exported via lib.rs, not every conversion is used here
+mod clock;
+mod countdown;
+#[cfg(not(feature="gcs"))]
+mod critical_state;
+#[cfg(feature="gcs")]
+mod downlink_loss;
 mod drivers;
+#[cfg(all(feature="engine", not(feature="gcs")))]
+mod engine;
+mod errors;
+#[cfg(feature="gcs")]
+mod events;
+#[allow(dead_code)] // also exported via lib.rs, not every filter is used here
+mod filters;
 mod flash;
+#[allow(dead_code)] // also exported via lib.rs, the decoder side is only used on the host
+mod flash_log;
+mod flash_wear;
+#[allow(dead_code)] // the recorder is only used on the FC, the report on both sides
+mod flight_summary;
+mod framing;
+#[cfg(feature="gcs")]
+mod frontend;
+mod geofence;
+mod heap;
+#[cfg(all(feature="hil", not(feature="gcs")))]
+mod hil;
+#[cfg(not(feature="gcs"))]
+mod landing;
+#[cfg(not(feature="gcs"))]
+mod launch_rail;
+mod leds;
 mod lora;
+mod lora_packet;
+mod mode_guard;
+#[cfg(not(feature="gcs"))]
+mod outputs;
+#[cfg(not(feature="gcs"))]
+mod parameters;
+#[cfg(not(feature="gcs"))]
+mod profiling;
+mod radio_diagnostics;
+#[cfg(not(feature="gcs"))]
+mod recent_telemetry;
+#[allow(dead_code)] // the GCS only uses RedundancyStatus, to show the downlinked state
+mod redundancy;
+mod retransmission;
+#[cfg(not(feature="gcs"))]
+mod rtc;
+#[allow(dead_code)] // also exported via lib.rs, only needed by telemetry.rs on the GCS
+mod schedule;
+mod self_test;
+#[cfg(all(feature="tank_pressure", not(feature="gcs")))]
+mod tank_pressure;
+#[cfg(not(feature="gcs"))]
+mod sensor_stats;
+#[cfg(feature="gcs")]
+mod sequence;
+#[cfg(all(feature="servo", not(feature="gcs")))]
+mod servo;
+#[cfg(not(feature="gcs"))]
+mod shock;
+#[allow(dead_code)] // the GCS only uses DownlinkProfile, to uplink profile changes
+mod telemetry;
+#[cfg(not(feature="gcs"))]
+mod thermal;
+mod traits;
+#[cfg(all(feature="umbilical", not(feature="gcs")))]
+mod umbilical;
 mod usb;
+mod usb_console;
+mod version;
 
 #[cfg(not(feature="gcs"))]
 mod vehicle;
@@ -46,6 +128,8 @@ use can::*;
 use flash::*;
 use lora::*;
 use drivers::sensors::*;
+#[cfg(not(feature="gcs"))]
+use rtc::*;
 use usb::*;
 
 #[cfg(not(feature="gcs"))]
@@ -53,16 +137,10 @@ use vehicle::*;
 #[cfg(feature="gcs")]
 use gcs::*;
 
-const HEAP_SIZE: usize = 1024;
-static mut HEAP: [core::mem::MaybeUninit<u8>; HEAP_SIZE] = [core::mem::MaybeUninit::uninit(); HEAP_SIZE];
-
-#[global_allocator]
-static ALLOCATOR: alloc_cortex_m::CortexMHeap = alloc_cortex_m::CortexMHeap::empty();
-
 static EXECUTOR_HIGH: InterruptExecutor = InterruptExecutor::new();
 static EXECUTOR_MEDIUM: InterruptExecutor = InterruptExecutor::new();
 
-static SPI1_SHARED: StaticCell<Mutex<CriticalSectionRawMutex, Spi<SPI1, DMA2_CH3, DMA2_CH2>>> = StaticCell::new();
+static SPI1_SHARED: StaticCell<Mutex<CriticalSectionRawMutex, board::SensorSpi>> = StaticCell::new();
 static SPI2_SHARED: StaticCell<Mutex<CriticalSectionRawMutex, Spi<SPI2, DMA1_CH4, DMA1_CH3>>> = StaticCell::new();
 static SPI3_SHARED: StaticCell<Mutex<CriticalSectionRawMutex, Spi<SPI3, DMA1_CH7, DMA1_CH0>>> = StaticCell::new();
 
@@ -70,6 +148,9 @@ static SPI3_SHARED: StaticCell<Mutex<CriticalSectionRawMutex, Spi<SPI3, DMA1_CH7
 #[cfg_attr(feature="gcs", allow(unused_variables))]
 #[embassy_executor::main]
 async fn main(low_priority_spawner: Spawner) {
+    // If we were asked to reboot into the bootloader, do so before touching any hardware.
+    bootloader::jump_to_bootloader_if_requested();
+
     // Basic setup, including clocks
     // Divider values taken from STM32CubeMx
     let mut config = Config::default();
@@ -90,25 +171,32 @@ async fn main(low_priority_spawner: Spawner) {
     config.rcc.apb2_pre = APBPrescaler::DIV2;
     config.rcc.sys = Sysclk::PLL1_P;
     let p = embassy_stm32::init(config);
+    let board = board_resources!(p);
+
+    // Enable the DWT cycle counter, used for profiling the main loop.
+    let mut core_peripherals = cortex_m::Peripherals::take().unwrap();
+    core_peripherals.DCB.enable_trace();
+    core_peripherals.DWT.enable_cycle_counter();
 
     // Set up the independent watchdog. This reboots the processor
     // if it is not pet regularly, even if the main clock fails.
-    // TODO: check if the current boot is a watchdog reset and react
-    // appropriately
+    // After such a reset, the vehicle resumes from the state mirrored
+    // in the RTC's backup registers (see `critical_state.rs`).
+    #[cfg(not(feature="gcs"))]
+    let reset_cause = critical_state::ResetCause::read();
+    #[cfg(not(feature="gcs"))]
+    critical_state::program_brownout_level();
     let mut iwdg = IndependentWatchdog::new(p.IWDG, 512_000); // 512ms timeout
 
     // Initialize heap
-    unsafe { ALLOCATOR.init(HEAP.as_ptr() as usize, HEAP_SIZE) }
+    heap::init();
 
     let (usb, usb_flash) = UsbHandle::init(p.USB_OTG_FS, p.PA12, p.PA11).await;
 
     // Shared SPI1 bus
     let mut spi1_config = embassy_stm32::spi::Config::default();
     spi1_config.frequency = Hertz::mhz(10);
-    #[cfg(feature="rev1")]
-    let spi1 = Spi::new(p.SPI1, p.PA5, p.PA7, p.PB4, p.DMA2_CH3, p.DMA2_CH2, spi1_config);
-    #[cfg(not(feature="rev1"))]
-    let spi1 = Spi::new(p.SPI1, p.PA5, p.PA7, p.PA6, p.DMA2_CH3, p.DMA2_CH2, spi1_config);
+    let spi1 = board.sensor_spi.init(spi1_config);
     let spi1 = Mutex::<CriticalSectionRawMutex, _>::new(spi1);
     let spi1 = SPI1_SHARED.init(spi1);
 
@@ -121,7 +209,7 @@ async fn main(low_priority_spawner: Spawner) {
 
     let imu = LSM6::init(SpiDevice::new(spi1, spi1_cs_imu)).await.unwrap();
     let acc = H3LIS331DL::init(SpiDevice::new(spi1, spi1_cs_acc)).await.unwrap();
-    let mag = LIS3MDL::init(SpiDevice::new(spi1, spi1_cs_mag)).await.unwrap();
+    let mag = board::MagnetometerDriver::init(SpiDevice::new(spi1, spi1_cs_mag)).await.unwrap();
     let baro = MS5611::init(SpiDevice::new(spi1, spi1_cs_baro)).await.unwrap();
     let radio = Radio::init(
         SpiDevice::new(spi1, spi1_cs_radio),
@@ -129,6 +217,16 @@ async fn main(low_priority_spawner: Spawner) {
         Input::new(p.PC1, Pull::Down),
     ).await.unwrap();
 
+    #[cfg(all(feature="thermocouple", not(feature="gcs")))]
+    let thermocouple = {
+        use embassy_embedded_hal::shared_bus::asynch::spi::SpiDeviceWithConfig;
+
+        let mut config = embassy_stm32::spi::Config::default();
+        config.frequency = Hertz::mhz(5);
+        let spi1_cs_thermocouple = Output::new(p.PB8, Level::High, Speed::VeryHigh);
+        MAX31855::init(SpiDeviceWithConfig::new(spi1, spi1_cs_thermocouple, config)).await
+    };
+
     // SPI2, only used for CAN bus
     let mut spi2_config = embassy_stm32::spi::Config::default();
     spi2_config.frequency = Hertz::mhz(20);
@@ -150,7 +248,15 @@ async fn main(low_priority_spawner: Spawner) {
 
     let spi3_cs_flash = Output::new(p.PD2, Level::High, Speed::VeryHigh);
     #[cfg(not(feature="gcs"))]
-    let (flash, flash_handle, settings) = Flash::init(SpiDevice::new(spi3, spi3_cs_flash), usb_flash).await.map_err(|_e| ()).unwrap();
+    let flash_driver = drivers::flash::SpiNorFlash::init(SpiDevice::new(spi3, spi3_cs_flash)).await.map_err(|_e| ()).unwrap();
+    #[cfg(not(feature="gcs"))]
+    let (mut flash, flash_handle, settings) = Flash::init(flash_driver, usb_flash).await.map_err(|_e| ()).unwrap();
+    #[cfg(not(feature="gcs"))]
+    let calibration = flash.load_calibration(&settings).await;
+    #[cfg(not(feature="gcs"))]
+    let link_config = flash.load_link_config().await;
+    #[cfg(not(feature="gcs"))]
+    let parameters = flash.load_parameters().await;
 
     // Initialize GPS
     #[cfg(not(feature="gcs"))]
@@ -161,34 +267,57 @@ async fn main(low_priority_spawner: Spawner) {
     #[cfg(not(feature="gcs"))]
     let power = PowerMonitor::init(adc, p.PB0, p.PC5, p.PC4).await;
 
+    #[cfg(all(feature="loadcell", not(feature="gcs")))]
+    let hx711 = HX711::init(
+        Output::new(p.PB6, Level::Low, Speed::VeryHigh),
+        embassy_stm32::exti::ExtiInput::new(Input::new(p.PB7, Pull::None), p.EXTI7),
+    );
+
+    #[cfg(not(feature="gcs"))]
+    let rtc = RealTimeClock::init(p.RTC);
+
     let led_red = Output::new(p.PC13, Level::Low, Speed::Low);
     let led_yellow = Output::new(p.PC14, Level::Low, Speed::Low);
     let led_green = Output::new(p.PC15, Level::Low, Speed::Low);
-    let leds = (led_red, led_yellow, led_green);
-
     #[cfg(not(feature="gcs"))]
-    let gpio_drogue = Output::new(p.PC8, Level::Low, Speed::Low);
+    let mut outputs = board.outputs.init();
     #[cfg(not(feature="gcs"))]
-    let gpio_main = Output::new(p.PC9, Level::Low, Speed::Low);
-    #[cfg(not(feature="gcs"))]
-    let recovery = (gpio_drogue, gpio_main);
-
-    #[cfg(feature="rev1")]
-    let buzzer = {
-        let gpiob_block = p.PB9.block();
-        let pwm_pin = PwmPin::new_ch4(p.PB9, OutputType::PushPull);
-        let pwm = SimplePwm::new(p.TIM4, None, None, None, Some(pwm_pin), Hertz::hz(440), Default::default());
-        Buzzer::init(pwm, Channel::Ch4, gpiob_block, 9)
+    let strobe = outputs.take(outputs::LogicalOutput::Strobe);
+    #[cfg(feature="gcs")]
+    let strobe = None;
+    let leds = leds::Leds::new(led_red, led_yellow, led_green, strobe);
+
+    let buzzer = Buzzer::init(board.buzzer.init());
+
+    #[cfg(all(feature="aprs", not(feature="gcs")))]
+    let aprs = {
+        use embassy_stm32::gpio::OutputType;
+        use embassy_stm32::timer::simple_pwm::{PwmPin, SimplePwm};
+
+        let pwm_pin = PwmPin::new_ch1(p.PA8, OutputType::PushPull);
+        let pwm = SimplePwm::new(p.TIM1, Some(pwm_pin), None, None, None, Hertz::hz(1200), Default::default());
+        let ptt = Output::new(p.PB14, Level::Low, Speed::Low);
+        aprs::AprsTransmitter::init(pwm, ptt)
     };
 
-    #[cfg(not(feature="rev1"))]
-    let buzzer = {
-        let gpioc_block = p.PC7.block();
-        let pwm_pin = PwmPin::new_ch2(p.PC7, OutputType::PushPull);
-        let pwm = SimplePwm::new(p.TIM3, None, Some(pwm_pin), None, None, Hertz::hz(440), Default::default());
-        Buzzer::init(pwm, Channel::Ch2, gpioc_block, 7)
+    #[cfg(all(feature="servo", not(feature="gcs")))]
+    let servos = {
+        use embassy_stm32::gpio::OutputType;
+        use embassy_stm32::timer::simple_pwm::{PwmPin, SimplePwm};
+
+        let ch2 = PwmPin::new_ch2(p.PA9, OutputType::PushPull);
+        let ch3 = PwmPin::new_ch3(p.PA10, OutputType::PushPull);
+        let pwm = SimplePwm::new(p.TIM1, None, Some(ch2), Some(ch3), None, Hertz::hz(50), Default::default());
+        servo::Servos::init(pwm, parameters.servos)
     };
 
+    #[cfg(all(feature="engine", not(feature="gcs")))]
+    let engine = engine::EngineController::init(
+        Output::new(p.PA8, Level::Low, Speed::Low),
+        Output::new(p.PB2, Level::Low, Speed::Low),
+        Output::new(p.PB3, Level::Low, Speed::Low),
+    );
+
     iwdg.unleash();
 
     #[cfg(not(feature="gcs"))]
@@ -203,12 +332,26 @@ async fn main(low_priority_spawner: Spawner) {
         radio,
         flash_handle,
         can_handle,
+        rtc,
         leds,
         buzzer,
-        recovery,
+        outputs,
         settings,
+        calibration,
+        link_config,
+        parameters,
+        reset_cause,
     );
+    #[cfg(all(feature="tank_pressure", not(feature="gcs")))]
+    let vehicle = vehicle.with_tank_pressure(tank_pressure::TankPressure::init(p.PA0, p.PB1));
+    #[cfg(all(feature="umbilical", not(feature="gcs")))]
+    let vehicle = {
+        let (presence, breakwire) = board.umbilical.init();
+        vehicle.with_umbilical(umbilical::Umbilical::init(presence, breakwire))
+    };
 
+    #[cfg(feature="frontend")]
+    let radio = radio.with_frontend(board.frontend.init());
     #[cfg(feature="gcs")]
     let gcs = GroundControlStation::init(usb, radio, leds, buzzer);
 
@@ -227,6 +370,16 @@ async fn main(low_priority_spawner: Spawner) {
         medium_priority_spawner.spawn(can::run_rx(can_rx)).unwrap();
         medium_priority_spawner.spawn(drivers::sensors::gps::run(gps)).unwrap(); // TODO: priority?
         medium_priority_spawner.spawn(flash::run(flash)).unwrap();
+        #[cfg(feature="aprs")]
+        medium_priority_spawner.spawn(aprs::run(aprs)).unwrap();
+        #[cfg(feature="loadcell")]
+        medium_priority_spawner.spawn(drivers::sensors::hx711::run(hx711)).unwrap();
+        #[cfg(feature="thermocouple")]
+        medium_priority_spawner.spawn(drivers::sensors::max31855::run(thermocouple)).unwrap();
+        #[cfg(feature="servo")]
+        medium_priority_spawner.spawn(servo::run(servos)).unwrap();
+        #[cfg(feature="engine")]
+        medium_priority_spawner.spawn(engine::run(engine)).unwrap();
     }
 
     #[cfg(feature="gcs")]
@@ -0,0 +1,220 @@
+//! Mock implementations of the interfaces in `traits.rs`, for testing flight logic on the host.
+//! Everything is deterministic: mocks only report what the test set up, and record everything
+//! sent to them for inspection.
+//!
+//! Only available with the `std` feature.
+
+use std::collections::VecDeque;
+
+use shared_types::can::BatteryTelemetryMessage;
+use shared_types::*;
+
+use crate::traits::*;
+
+/// LoRa link that records sent messages and delivers queued commands, one per tick.
+pub struct MockRadio {
+    pub sent: Vec<(u32, DownlinkMessage)>,
+    pub commands: VecDeque<Command>,
+    pub transmit_power: TransmitPower,
+    pub rssi: u8,
+    time: u32,
+}
+
+impl Default for MockRadio {
+    fn default() -> Self {
+        Self {
+            sent: Vec::new(),
+            commands: VecDeque::new(),
+            transmit_power: TransmitPower::P14dBm,
+            rssi: 255,
+            time: 0,
+        }
+    }
+}
+
+impl TelemetryRadio for MockRadio {
+    type Error = ();
+
+    async fn send(&mut self, msg: DownlinkMessage) -> Result<(), Self::Error> {
+        self.sent.push((self.time, msg));
+        Ok(())
+    }
+
+    async fn tick(&mut self, time: u32) -> Option<Command> {
+        self.time = time;
+        self.commands.pop_front()
+    }
+
+    fn set_transmit_power(&mut self, power: TransmitPower) {
+        self.transmit_power = power;
+    }
+
+    fn set_max_transmit_power(&mut self) {
+        self.transmit_power = TransmitPower::P22dBm;
+    }
+
+    fn transmit_power(&self) -> TransmitPower {
+        self.transmit_power
+    }
+
+    fn rssi(&self) -> u8 {
+        self.rssi
+    }
+}
+
+/// Flash that keeps logged messages and settings in memory. Log data is serialized like on the
+/// vehicle, so `log` can be compared against real flash dumps.
+#[derive(Default)]
+pub struct MockFlash {
+    pub messages: Vec<DownlinkMessage>,
+    pub log: Vec<u8>,
+    pub settings: Option<Settings>,
+    /// Regions requested via `read` or `dump`
+    pub reads: Vec<(u32, u32)>,
+    pub erase_count: u32,
+    /// Whether a self test was requested and its result not taken yet
+    pub self_test_pending: bool,
+}
+
+impl LogStorage for MockFlash {
+    async fn tick(&mut self) {}
+
+    fn pointer(&self) -> u32 {
+        self.log.len() as u32
+    }
+
+    fn size(&self) -> u32 {
+        FLASH_SIZE
+    }
+
+    fn write_message(&mut self, msg: DownlinkMessage) -> Result<(), ()> {
+        self.log.extend(msg.serialize().unwrap_or_default());
+        self.messages.push(msg);
+        Ok(())
+    }
+
+    fn write_settings(&mut self, settings: Settings) -> Result<(), ()> {
+        self.settings = Some(settings);
+        Ok(())
+    }
+
+    fn read(&mut self, address: u32, size: u32) -> Result<(), ()> {
+        self.reads.push((address, size));
+        Ok(())
+    }
+
+    fn dump(&mut self, address: u32, size: u32) -> Result<(), ()> {
+        self.reads.push((address, size));
+        Ok(())
+    }
+
+    fn erase(&mut self) -> Result<(), ()> {
+        self.messages.clear();
+        self.log.clear();
+        self.erase_count += 1;
+        Ok(())
+    }
+
+    fn self_test(&mut self) -> Result<(), ()> {
+        self.self_test_pending = true;
+        Ok(())
+    }
+
+    fn self_test_result(&mut self) -> Option<(bool, bool)> {
+        core::mem::take(&mut self.self_test_pending).then_some((true, true))
+    }
+}
+
+/// GPS receiver reporting whatever datum was last set.
+#[derive(Default)]
+pub struct MockGps {
+    datum: Option<GPSDatum>,
+    new_datum: bool,
+}
+
+impl MockGps {
+    /// Sets the current datum, as if it had just been received. `None` simulates a lost receiver.
+    pub fn set_datum(&mut self, datum: Option<GPSDatum>) {
+        self.new_datum = datum.is_some();
+        self.datum = datum;
+    }
+}
+
+impl GpsReceiver for MockGps {
+    fn datum(&mut self) -> Option<GPSDatum> {
+        self.datum.clone()
+    }
+
+    fn new_datum(&mut self) -> Option<GPSDatum> {
+        let d = self.new_datum.then(|| self.datum()).flatten();
+        self.new_datum = false;
+        d
+    }
+
+    fn latitude(&mut self) -> Option<f32> {
+        self.datum.as_ref().and_then(|d| d.latitude)
+    }
+
+    fn longitude(&mut self) -> Option<f32> {
+        self.datum.as_ref().and_then(|d| d.longitude)
+    }
+
+    fn altitude(&mut self) -> Option<f32> {
+        self.datum.as_ref().and_then(|d| d.altitude)
+    }
+
+    fn fix(&mut self) -> Option<GPSFixType> {
+        self.datum.as_ref().map(|d| d.fix)
+    }
+
+    fn hdop(&mut self) -> Option<u16> {
+        self.datum.as_ref().map(|d| d.hdop)
+    }
+
+    fn num_satellites(&mut self) -> Option<u8> {
+        self.datum.as_ref().map(|d| d.num_satellites)
+    }
+}
+
+/// Power monitor with fixed readings, set via the public fields.
+#[derive(Default)]
+pub struct MockPower {
+    pub battery_voltage: Option<u16>,
+    pub battery_current: Option<i32>,
+    pub arm_voltage: Option<u16>,
+    pub charge_voltage: Option<u16>,
+    pub temperature: Option<f32>,
+    pub can_messages: Vec<BatteryTelemetryMessage>,
+}
+
+impl PowerSupply for MockPower {
+    fn tick(&mut self) {}
+
+    fn battery_voltage(&self) -> Option<u16> {
+        self.battery_voltage
+    }
+
+    fn battery_status(&self) -> Option<BatteryStatus> {
+        self.battery_voltage.map(BatteryStatus::from_voltage)
+    }
+
+    fn battery_current(&self) -> Option<i32> {
+        self.battery_current
+    }
+
+    fn arm_voltage(&self) -> Option<u16> {
+        self.arm_voltage
+    }
+
+    fn charge_voltage(&self) -> Option<u16> {
+        self.charge_voltage
+    }
+
+    fn temperature(&self) -> Option<f32> {
+        self.temperature
+    }
+
+    fn handle_battery_can_msg(&mut self, msg: BatteryTelemetryMessage) {
+        self.can_messages.push(msg);
+    }
+}
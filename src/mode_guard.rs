@@ -0,0 +1,133 @@
+//! Validation of flight mode changes commanded via uplink. The vehicle switches modes on its own
+//! during a flight, but a commanded mode change could skip the safety states in between, e.g. go
+//! from Idle straight to Burn, or fire the recovery outputs on the pad after a mistyped command.
+//! Such commands are rejected, and the reason is downlinked (see `lora.rs`), so the ground station
+//! can show why the vehicle didn't follow.
+//!
+//! Mode changes made by the vehicle itself, e.g. on launch or apogee detection, are not checked.
+
+use serde::{Deserialize, Serialize};
+
+use defmt::Format;
+
+use shared_types::FlightMode;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub enum RejectionReason {
+    /// ArmedLaunchImminent is only entered from Armed
+    NotArmed,
+    /// Burn is only entered from ArmedLaunchImminent
+    LaunchNotImminent,
+    /// Recovery modes are only entered after launch
+    NotLaunched,
+    /// Landed is only entered from the recovery modes
+    NotRecovering,
+    /// The vehicle doesn't return to the pre-flight modes before landing
+    InFlight,
+    /// Recovery only moves on, from the drogue to the main parachute to Landed
+    Backwards,
+    /// The geofence's distance limit needs the pad position before arming, see `geofence.rs`
+    NoPadPosition,
+}
+
+impl RejectionReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NotArmed => "not armed",
+            Self::LaunchNotImminent => "launch not imminent",
+            Self::NotLaunched => "not launched",
+            Self::NotRecovering => "not recovering",
+            Self::InFlight => "in flight",
+            Self::Backwards => "recovery can't go backwards",
+            Self::NoPadPosition => "no pad position for geofence",
+        }
+    }
+}
+
+/// A rejected mode change, as downlinked to the ground station
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModeRejection {
+    pub from: FlightMode,
+    pub to: FlightMode,
+    pub reason: RejectionReason,
+}
+
+/// Checks whether the vehicle may be commanded from one mode to another.
+#[cfg(not(feature = "gcs"))]
+pub fn check(from: FlightMode, to: FlightMode) -> Result<(), ModeRejection> {
+    let reject = |reason| Err(ModeRejection { from, to, reason });
+    // After landing, the vehicle is back on the ground and can be reset for the next flight.
+    let launched = from >= FlightMode::Burn && from != FlightMode::Landed;
+
+    match to {
+        _ if to == from => Ok(()),
+        _ if launched && to < FlightMode::Burn => reject(RejectionReason::InFlight),
+        _ if launched && to < from => reject(RejectionReason::Backwards),
+        FlightMode::ArmedLaunchImminent if from != FlightMode::Armed => reject(RejectionReason::NotArmed),
+        FlightMode::Burn if from != FlightMode::ArmedLaunchImminent => reject(RejectionReason::LaunchNotImminent),
+        _ if !launched && to > FlightMode::Burn => reject(RejectionReason::NotLaunched),
+        // During the ascent, the recovery can be triggered manually, but the vehicle can't be
+        // declared landed.
+        FlightMode::Landed if from < FlightMode::RecoveryDrogue => reject(RejectionReason::NotRecovering),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(all(test, not(feature = "gcs")))]
+mod tests {
+    use super::*;
+
+    use FlightMode::*;
+
+    const MODES: [FlightMode; 9] = [Idle, HardwareArmed, Armed, ArmedLaunchImminent, Burn, Coast, RecoveryDrogue, RecoveryMain, Landed];
+
+    #[test]
+    fn transitions() {
+        let cases = [
+            (Idle, Armed, None),
+            (Idle, ArmedLaunchImminent, Some(RejectionReason::NotArmed)),
+            (Idle, Burn, Some(RejectionReason::LaunchNotImminent)),
+            (Armed, Burn, Some(RejectionReason::LaunchNotImminent)),
+            (Armed, ArmedLaunchImminent, None),
+            (ArmedLaunchImminent, Burn, None),
+            (ArmedLaunchImminent, Armed, None),
+            (Armed, RecoveryDrogue, Some(RejectionReason::NotLaunched)),
+            (Idle, Landed, Some(RejectionReason::NotLaunched)),
+            (Coast, RecoveryDrogue, None),
+            (Burn, RecoveryMain, None),
+            (Coast, Landed, Some(RejectionReason::NotRecovering)),
+            (Coast, Idle, Some(RejectionReason::InFlight)),
+            (RecoveryDrogue, Coast, Some(RejectionReason::Backwards)),
+            (RecoveryMain, RecoveryDrogue, Some(RejectionReason::Backwards)),
+            (RecoveryMain, Landed, None),
+            (Landed, Idle, None),
+            (Landed, Burn, Some(RejectionReason::LaunchNotImminent)),
+        ];
+
+        for (from, to, reason) in cases {
+            let expected = reason.map(|reason| ModeRejection { from, to, reason }).map_or(Ok(()), Err);
+            assert_eq!(check(from, to), expected, "{:?} -> {:?}", from, to);
+        }
+    }
+
+    #[test]
+    fn staying_in_mode() {
+        for mode in MODES {
+            assert_eq!(check(mode, mode), Ok(()), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn recovery_main_only_to_landed() {
+        for to in MODES {
+            assert_eq!(check(RecoveryMain, to).is_ok(), to == RecoveryMain || to == Landed, "RecoveryMain -> {:?}", to);
+        }
+    }
+
+    #[test]
+    fn launch_only_via_launch_imminent() {
+        for from in MODES.into_iter().filter(|mode| *mode < Burn) {
+            assert_eq!(check(from, Burn).is_ok(), from == ArmedLaunchImminent, "{:?} -> Burn", from);
+        }
+    }
+}
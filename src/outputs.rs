@@ -0,0 +1,77 @@
+//! Mapping of logical outputs to physical pins. The flight logic only drives logical outputs
+//! (drogue, main, separation, camera, strobe), while the board file for each revision decides
+//! which pin, if any, each of them is wired to (see `OutputResources` in `board.rs`). That way,
+//! different harness layouts and board revisions only need a different mapping.
+//!
+//! Outputs that aren't mapped on a board are silently ignored.
+
+use embassy_stm32::gpio::{AnyPin, Level, Output, Speed};
+
+use defmt::Format;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum LogicalOutput {
+    /// Drogue parachute deployment
+    Drogue,
+    /// Main parachute deployment
+    Main,
+    /// Separation charge, fired together with the drogue, for harnesses with a dedicated channel
+    Separation,
+    /// On-board camera, following the commanded state of the recovery cameras
+    Camera,
+    /// High-power recovery strobe, driven by `Leds`
+    Strobe,
+}
+
+impl LogicalOutput {
+    pub const ALL: [Self; 5] = [Self::Drogue, Self::Main, Self::Separation, Self::Camera, Self::Strobe];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Drogue => "drogue",
+            Self::Main => "main",
+            Self::Separation => "separation",
+            Self::Camera => "camera",
+            Self::Strobe => "strobe",
+        }
+    }
+}
+
+pub struct Outputs {
+    pins: [Option<Output<'static, AnyPin>>; 5],
+}
+
+impl Outputs {
+    pub fn new() -> Self {
+        Self { pins: [None, None, None, None, None] }
+    }
+
+    /// Maps a logical output to a pin, which is initialized low.
+    pub fn map(&mut self, output: LogicalOutput, pin: AnyPin) {
+        self.pins[output as usize] = Some(Output::new(pin, Level::Low, Speed::Low));
+    }
+
+    /// Removes the pin mapped to a logical output, for drivers that own their output, such as the
+    /// strobe.
+    pub fn take(&mut self, output: LogicalOutput) -> Option<Output<'static, AnyPin>> {
+        self.pins[output as usize].take()
+    }
+
+    /// Current level of a logical output, or None if it isn't mapped.
+    pub fn level(&self, output: LogicalOutput) -> Option<bool> {
+        self.pins[output as usize].as_ref().map(|pin| pin.is_set_high())
+    }
+
+    pub fn set(&mut self, output: LogicalOutput, high: bool) {
+        if let Some(pin) = self.pins[output as usize].as_mut() {
+            pin.set_level(high.into());
+        }
+    }
+
+    /// Sets all pyro outputs low.
+    pub fn safe(&mut self) {
+        for output in [LogicalOutput::Drogue, LogicalOutput::Main, LogicalOutput::Separation] {
+            self.set(output, false);
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! Firmware parameters, i.e. thresholds and limits of the firmware's own subsystems. The settings
+//! are defined in shared_types, which this firmware can't extend, so these are kept in a record
+//! of their own in the flash (see `flash.rs`), next to the link configuration.
+//!
+//! The parameters are read once at startup and handed to the subsystems. They can be shown and
+//! changed individually via the `param` console command, changes are written to flash right away
+//! and take effect after the next reboot. Each parameter is accessed as a single number, flags are
+//! 0 or 1, and optional limits are disabled with 0.
+//!
+//! The stored data is prefixed with the schema version. Whenever `FirmwareParameters` changes,
+//! the version has to be incremented, which reverts the parameters to their defaults.
+
+use serde::{Deserialize, Serialize};
+
+use crate::backup_apogee::BackupApogeeConfig;
+use crate::baro_lag::BaroLagConfig;
+use crate::baro_speed::BaroSpeedConfig;
+use crate::countdown::CountdownConfig;
+use crate::geofence::{GeofenceAction, GeofenceConfig};
+use crate::shock::ShockConfig;
+use crate::thermal::ThermalConfig;
+
+/// Current schema version of the stored parameters
+pub const PARAMETERS_VERSION: u8 = 2;
+
+/// Number of servo outputs, see `servo.rs`
+pub const NUM_SERVOS: usize = 2;
+
+pub const PARAMETERS_HELP_TEXT: &[&str] = &[
+    "param                   show firmware parameters",
+    "param <name> <value>    set and store a firmware parameter, applied after reboot",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FirmwareParameters {
+    pub geofence: GeofenceConfig,
+    pub baro_lag: BaroLagConfig,
+    pub baro_speed: BaroSpeedConfig,
+    pub backup_apogee: BackupApogeeConfig,
+    pub shock: ShockConfig,
+    pub thermal: ThermalConfig,
+    pub countdown: CountdownConfig,
+    /// Per-channel servo configuration, adjust to the mechanism connected. Defined here rather
+    /// than in `servo.rs`, since the servo outputs are only built with the `servo` feature.
+    pub servos: [ServoConfig; NUM_SERVOS],
+    /// Magnetic declination (deg, east positive) at the launch site, added to obtain true azimuths
+    pub magnetic_declination: f32,
+    /// Angle (deg) from vertical beyond which engine ignition is inhibited in flight
+    /// configurations
+    pub max_ignition_tilt: f32,
+    /// Whether records such as the settings and the calibration are read back after writing them
+    pub verify_records: bool,
+    /// Slots a record is written to before giving up, if it doesn't read back correctly
+    pub record_write_attempts: u32,
+}
+
+impl Default for FirmwareParameters {
+    fn default() -> Self {
+        Self {
+            geofence: GeofenceConfig::default(),
+            baro_lag: BaroLagConfig::default(),
+            baro_speed: BaroSpeedConfig::default(),
+            backup_apogee: BackupApogeeConfig::default(),
+            shock: ShockConfig::default(),
+            thermal: ThermalConfig::default(),
+            countdown: CountdownConfig::default(),
+            servos: [ServoConfig::default(); NUM_SERVOS],
+            magnetic_declination: 0.0,
+            max_ignition_tilt: 20.0,
+            verify_records: true,
+            record_write_attempts: 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServoConfig {
+    /// Pulse width (us) at position 0.0
+    pub min_pulse: u32,
+    /// Pulse width (us) at position 1.0
+    pub max_pulse: u32,
+    /// Position on disarm, faults or missing commands
+    pub failsafe: f32,
+    /// Maximum change in position per second
+    pub max_slew_rate: f32,
+    /// Time (ms) after which the channel returns to failsafe if not commanded again
+    pub command_timeout: Option<u32>,
+}
+
+impl Default for ServoConfig {
+    fn default() -> Self {
+        Self {
+            min_pulse: 1000,
+            max_pulse: 2000,
+            failsafe: 0.0,
+            max_slew_rate: 2.0,
+            command_timeout: None,
+        }
+    }
+}
+
+/// A parameter as accessed via the console
+pub struct Parameter {
+    pub name: &'static str,
+    get: fn(&FirmwareParameters) -> f32,
+    set: fn(&mut FirmwareParameters, f32),
+}
+
+fn flag(value: f32) -> bool {
+    value != 0.0
+}
+
+fn limit(value: f32) -> Option<f32> {
+    Some(value).filter(|v| *v > 0.0)
+}
+
+pub const PARAMETERS: &[Parameter] = &[
+    Parameter {
+        name: "geofence.max_altitude",
+        get: |p| p.geofence.max_altitude.unwrap_or(0.0),
+        set: |p, v| p.geofence.max_altitude = limit(v),
+    },
+    Parameter {
+        name: "geofence.max_distance",
+        get: |p| p.geofence.max_distance.unwrap_or(0.0),
+        set: |p, v| p.geofence.max_distance = limit(v),
+    },
+    Parameter {
+        name: "geofence.drogue",
+        get: |p| (p.geofence.action == GeofenceAction::Drogue) as u8 as f32,
+        set: |p, v| p.geofence.action = if flag(v) { GeofenceAction::Drogue } else { GeofenceAction::Warn },
+    },
+    Parameter {
+        name: "baro_lag.time_constant",
+        get: |p| p.baro_lag.time_constant,
+        set: |p, v| p.baro_lag.time_constant = v,
+    },
+    Parameter {
+        name: "baro_lag.estimate",
+        get: |p| p.baro_lag.estimate as u8 as f32,
+        set: |p, v| p.baro_lag.estimate = flag(v),
+    },
+    Parameter {
+        name: "baro_lag.max_time_constant",
+        get: |p| p.baro_lag.max_time_constant,
+        set: |p, v| p.baro_lag.max_time_constant = v,
+    },
+    Parameter {
+        name: "baro_lag.gain",
+        get: |p| p.baro_lag.gain,
+        set: |p, v| p.baro_lag.gain = v,
+    },
+    Parameter {
+        name: "baro_speed.cutoff",
+        get: |p| p.baro_speed.cutoff,
+        set: |p, v| p.baro_speed.cutoff = v,
+    },
+    Parameter {
+        name: "baro_speed.fallback",
+        get: |p| p.baro_speed.fallback as u8 as f32,
+        set: |p, v| p.baro_speed.fallback = flag(v),
+    },
+    Parameter {
+        name: "baro_speed.imu_timeout",
+        get: |p| p.baro_speed.imu_timeout as f32,
+        set: |p, v| p.baro_speed.imu_timeout = v as u32,
+    },
+    Parameter {
+        name: "baro_speed.min_time_to_apogee",
+        get: |p| p.baro_speed.min_time_to_apogee as f32,
+        set: |p, v| p.baro_speed.min_time_to_apogee = v as u32,
+    },
+    Parameter {
+        name: "baro_speed.min_ascent_speed",
+        get: |p| p.baro_speed.min_ascent_speed,
+        set: |p, v| p.baro_speed.min_ascent_speed = v,
+    },
+    Parameter {
+        name: "baro_speed.confidence",
+        get: |p| p.baro_speed.confidence,
+        set: |p, v| p.baro_speed.confidence = v,
+    },
+    Parameter {
+        name: "backup_apogee.min_time_to_apogee",
+        get: |p| p.backup_apogee.min_time_to_apogee as f32,
+        set: |p, v| p.backup_apogee.min_time_to_apogee = v as u32,
+    },
+    Parameter {
+        name: "backup_apogee.min_ascent_speed",
+        get: |p| p.backup_apogee.min_ascent_speed,
+        set: |p, v| p.backup_apogee.min_ascent_speed = v,
+    },
+    Parameter {
+        name: "shock.acceleration",
+        get: |p| p.shock.acceleration,
+        set: |p, v| p.shock.acceleration = v,
+    },
+    Parameter {
+        name: "shock.current",
+        get: |p| p.shock.current as f32,
+        set: |p, v| p.shock.current = v as i32,
+    },
+    Parameter {
+        name: "shock.min_supply_voltage",
+        get: |p| p.shock.min_supply_voltage,
+        set: |p, v| p.shock.min_supply_voltage = v,
+    },
+    Parameter {
+        name: "shock.supply_tolerance",
+        get: |p| p.shock.supply_tolerance,
+        set: |p, v| p.shock.supply_tolerance = v,
+    },
+    Parameter {
+        name: "shock.settle_time",
+        get: |p| p.shock.settle_time as f32,
+        set: |p, v| p.shock.settle_time = v as u32,
+    },
+    Parameter {
+        name: "shock.max_deferral",
+        get: |p| p.shock.max_deferral as f32,
+        set: |p, v| p.shock.max_deferral = v as u32,
+    },
+    Parameter {
+        name: "thermal.warn_temperature",
+        get: |p| p.thermal.warn_temperature,
+        set: |p, v| p.thermal.warn_temperature = v,
+    },
+    Parameter {
+        name: "thermal.derate_temperature",
+        get: |p| p.thermal.derate_temperature,
+        set: |p, v| p.thermal.derate_temperature = v,
+    },
+    Parameter {
+        name: "thermal.hysteresis",
+        get: |p| p.thermal.hysteresis,
+        set: |p, v| p.thermal.hysteresis = v,
+    },
+    Parameter {
+        name: "thermal.min_supply_voltage",
+        get: |p| p.thermal.min_supply_voltage,
+        set: |p, v| p.thermal.min_supply_voltage = v,
+    },
+    Parameter {
+        name: "countdown.duration",
+        get: |p| p.countdown.duration as f32,
+        set: |p, v| p.countdown.duration = v as u32,
+    },
+    Parameter {
+        name: "countdown.require_gps_fix",
+        get: |p| p.countdown.require_gps_fix as u8 as f32,
+        set: |p, v| p.countdown.require_gps_fix = flag(v),
+    },
+    Parameter {
+        name: "servo0.min_pulse",
+        get: |p| p.servos[0].min_pulse as f32,
+        set: |p, v| p.servos[0].min_pulse = v as u32,
+    },
+    Parameter {
+        name: "servo0.max_pulse",
+        get: |p| p.servos[0].max_pulse as f32,
+        set: |p, v| p.servos[0].max_pulse = v as u32,
+    },
+    Parameter {
+        name: "servo0.failsafe",
+        get: |p| p.servos[0].failsafe,
+        set: |p, v| p.servos[0].failsafe = v.clamp(0.0, 1.0),
+    },
+    Parameter {
+        name: "servo0.max_slew_rate",
+        get: |p| p.servos[0].max_slew_rate,
+        set: |p, v| p.servos[0].max_slew_rate = v,
+    },
+    Parameter {
+        name: "servo0.command_timeout",
+        get: |p| p.servos[0].command_timeout.unwrap_or(0) as f32,
+        set: |p, v| p.servos[0].command_timeout = limit(v).map(|t| t as u32),
+    },
+    Parameter {
+        name: "servo1.min_pulse",
+        get: |p| p.servos[1].min_pulse as f32,
+        set: |p, v| p.servos[1].min_pulse = v as u32,
+    },
+    Parameter {
+        name: "servo1.max_pulse",
+        get: |p| p.servos[1].max_pulse as f32,
+        set: |p, v| p.servos[1].max_pulse = v as u32,
+    },
+    Parameter {
+        name: "servo1.failsafe",
+        get: |p| p.servos[1].failsafe,
+        set: |p, v| p.servos[1].failsafe = v.clamp(0.0, 1.0),
+    },
+    Parameter {
+        name: "servo1.max_slew_rate",
+        get: |p| p.servos[1].max_slew_rate,
+        set: |p, v| p.servos[1].max_slew_rate = v,
+    },
+    Parameter {
+        name: "servo1.command_timeout",
+        get: |p| p.servos[1].command_timeout.unwrap_or(0) as f32,
+        set: |p, v| p.servos[1].command_timeout = limit(v).map(|t| t as u32),
+    },
+    Parameter {
+        name: "magnetic_declination",
+        get: |p| p.magnetic_declination,
+        set: |p, v| p.magnetic_declination = v,
+    },
+    Parameter {
+        name: "max_ignition_tilt",
+        get: |p| p.max_ignition_tilt,
+        set: |p, v| p.max_ignition_tilt = v,
+    },
+    Parameter {
+        name: "flash.verify_records",
+        get: |p| p.verify_records as u8 as f32,
+        set: |p, v| p.verify_records = flag(v),
+    },
+    Parameter {
+        name: "flash.record_write_attempts",
+        get: |p| p.record_write_attempts as f32,
+        set: |p, v| p.record_write_attempts = (v as u32).max(1),
+    },
+];
+
+impl Parameter {
+    pub fn find(name: &str) -> Option<&'static Self> {
+        PARAMETERS.iter().find(|p| p.name == name)
+    }
+
+    pub fn get(&self, parameters: &FirmwareParameters) -> f32 {
+        (self.get)(parameters)
+    }
+
+    /// Sets the parameter, returning false for values that aren't finite.
+    pub fn set(&self, parameters: &mut FirmwareParameters, value: f32) -> bool {
+        if !value.is_finite() {
+            return false;
+        }
+
+        (self.set)(parameters, value);
+        true
+    }
+}
@@ -0,0 +1,131 @@
+//! Profiling of the main loop. Uses the DWT cycle counter to measure how much time the individual
+//! subsystems take per iteration, and keeps track of averages and worst-case values so timing
+//! regressions show up in the logs.
+
+use cortex_m::peripheral::DWT;
+
+use defmt::*;
+
+/// Core clock frequency in MHz, see clock configuration in `main.rs`.
+const CYCLES_PER_MICROSECOND: u32 = 84;
+
+const NUM_SECTIONS: usize = 7;
+
+/// Parts of the main loop we measure separately.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Section {
+    Sensors = 0,
+    Can = 1,
+    Estimator = 2,
+    Commands = 3,
+    Outputs = 4,
+    Radio = 5,
+    Logging = 6,
+}
+
+const SECTIONS: [Section; NUM_SECTIONS] = [
+    Section::Sensors,
+    Section::Can,
+    Section::Estimator,
+    Section::Commands,
+    Section::Outputs,
+    Section::Radio,
+    Section::Logging,
+];
+
+pub struct Profiler {
+    loop_start: u32,
+    section_start: u32,
+    last_loop_cycles: u32,
+    iterations: u32,
+    // accumulated since last report
+    loop_cycles_sum: u64,
+    loop_cycles_max: u32,
+    section_cycles: [u32; NUM_SECTIONS],
+    section_cycles_sum: [u64; NUM_SECTIONS],
+    section_cycles_max: [u32; NUM_SECTIONS],
+}
+
+impl Profiler {
+    /// Creates a new profiler. Requires the DWT cycle counter to be enabled (see `main.rs`).
+    pub fn new() -> Self {
+        Self {
+            loop_start: 0,
+            section_start: 0,
+            last_loop_cycles: 0,
+            iterations: 0,
+            loop_cycles_sum: 0,
+            loop_cycles_max: 0,
+            section_cycles: [0; NUM_SECTIONS],
+            section_cycles_sum: [0; NUM_SECTIONS],
+            section_cycles_max: [0; NUM_SECTIONS],
+        }
+    }
+
+    pub fn start_loop(&mut self) {
+        let now = DWT::cycle_count();
+        self.loop_start = now;
+        self.section_start = now;
+        self.section_cycles = [0; NUM_SECTIONS];
+    }
+
+    /// Attributes the time since the end of the previous section to the given section. Sections
+    /// can be ended multiple times per iteration, in which case the times are added up.
+    pub fn end_section(&mut self, section: Section) {
+        let now = DWT::cycle_count();
+        self.section_cycles[section as usize] += now.wrapping_sub(self.section_start);
+        self.section_start = now;
+    }
+
+    pub fn end_loop(&mut self) {
+        let cycles = DWT::cycle_count().wrapping_sub(self.loop_start);
+        self.last_loop_cycles = cycles;
+        self.iterations += 1;
+        self.loop_cycles_sum += cycles as u64;
+        self.loop_cycles_max = u32::max(self.loop_cycles_max, cycles);
+
+        for i in 0..NUM_SECTIONS {
+            self.section_cycles_sum[i] += self.section_cycles[i] as u64;
+            self.section_cycles_max[i] = u32::max(self.section_cycles_max[i], self.section_cycles[i]);
+        }
+    }
+
+    /// Runtime of the last loop iteration in milliseconds.
+    pub fn loop_time(&self) -> f32 {
+        (self.last_loop_cycles as f32) / (CYCLES_PER_MICROSECOND as f32) / 1000.0
+    }
+
+    /// Worst-case runtime of a loop iteration since the last report in milliseconds.
+    pub fn worst_loop_time(&self) -> f32 {
+        (self.loop_cycles_max as f32) / (CYCLES_PER_MICROSECOND as f32) / 1000.0
+    }
+
+    fn mean_us(sum: u64, iterations: u32) -> u32 {
+        (sum / u64::max(iterations as u64, 1)) as u32 / CYCLES_PER_MICROSECOND
+    }
+
+    /// Logs the per-section breakdown accumulated since the last report and starts over.
+    pub fn report(&mut self) {
+        info!(
+            "Loop: mean={}us, max={}us ({} iterations)",
+            Self::mean_us(self.loop_cycles_sum, self.iterations),
+            self.loop_cycles_max / CYCLES_PER_MICROSECOND,
+            self.iterations
+        );
+
+        for (i, section) in SECTIONS.iter().enumerate() {
+            info!(
+                "  {}: mean={}us, max={}us",
+                section,
+                Self::mean_us(self.section_cycles_sum[i], self.iterations),
+                self.section_cycles_max[i] / CYCLES_PER_MICROSECOND
+            );
+        }
+
+        self.iterations = 0;
+        self.loop_cycles_sum = 0;
+        self.loop_cycles_max = 0;
+        self.section_cycles_sum = [0; NUM_SECTIONS];
+        self.section_cycles_max = [0; NUM_SECTIONS];
+    }
+}
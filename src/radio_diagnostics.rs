@@ -0,0 +1,153 @@
+//! Health diagnostics of the LLCC68 transceiver, for tracking down the intermittent `Busy` errors
+//! and failed reconfigurations. On request, the status, device errors and packet statistics are
+//! read along with the registers we change from their defaults (see `drivers/lora.rs`).
+//!
+//! The `radio` console command reads the local transceiver. On the FC, the result is also
+//! downlinked and written to the flash log (see `flash_log.rs`). The ground station additionally
+//! requests the FC's diagnostics via uplink, so both ends of the link can be checked from the
+//! ground. Requests and results are tagged LoRa payloads (see `lora.rs`), since the message types
+//! are defined in shared_types.
+//!
+//! Alongside, each end keeps statistics of its own transmissions (see `TxStats`), which are only
+//! shown locally.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RadioDiagnostics {
+    /// Chip mode and status of the last command, as returned by GetStatus
+    pub status: u8,
+    /// Calibration and PLL errors since the last reset, as returned by GetDeviceErrors
+    pub device_errors: u16,
+    /// Packet statistics since the last reset, as returned by GetStats
+    pub packets_received: u16,
+    pub crc_errors: u16,
+    pub header_errors: u16,
+    /// Raw contents of the sync word registers
+    pub sync_word: [u8; 2],
+    pub rx_gain: u8,
+    pub tx_clamp: u8,
+    pub iq_polarity: u8,
+}
+
+impl RadioDiagnostics {
+    /// Chip mode from the status byte (13.5.1, p. 95)
+    pub fn chip_mode(&self) -> &'static str {
+        match (self.status >> 4) & 0x7 {
+            0x2 => "standby rc",
+            0x3 => "standby xosc",
+            0x4 => "fs",
+            0x5 => "rx",
+            0x6 => "tx",
+            _ => "unknown",
+        }
+    }
+
+    /// Status of the last command from the status byte (13.5.1, p. 95)
+    pub fn command_status(&self) -> &'static str {
+        match (self.status >> 1) & 0x7 {
+            0x2 => "data available",
+            0x3 => "timeout",
+            0x4 => "processing error",
+            0x5 => "execution failure",
+            0x6 => "tx done",
+            _ => "ok",
+        }
+    }
+
+    /// Prints the diagnostics line by line, each prefixed by `name`.
+    pub fn print(&self, name: &str, mut print: impl FnMut(core::fmt::Arguments)) {
+        print(format_args!(
+            "{}: mode {}, last command {}, errors {}",
+            name,
+            self.chip_mode(),
+            self.command_status(),
+            DeviceErrors(self.device_errors)
+        ));
+        print(format_args!(
+            "{}: {} received, {} crc errors, {} header errors",
+            name,
+            self.packets_received,
+            self.crc_errors,
+            self.header_errors
+        ));
+        print(format_args!(
+            "{}: sync word {:02x}{:02x}, rx gain {:02x}, tx clamp {:02x}, iq polarity {:02x}",
+            name,
+            self.sync_word[0],
+            self.sync_word[1],
+            self.rx_gain,
+            self.tx_clamp,
+            self.iq_polarity
+        ));
+    }
+}
+
+/// Statistics of the transmissions since startup. The transceiver is switched back to RX as soon
+/// as the TxDone interrupt is seen, so the time on air is measured until then, including up to a
+/// tick of polling delay.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TxStats {
+    /// Transmissions that ended with the TxDone interrupt
+    pub packets: u32,
+    /// Sum and maximum of their time on air (us)
+    pub total_time_on_air: u64,
+    pub max_time_on_air: u32,
+    /// Transmissions that ended with the TX timeout interrupt, or without any interrupt being seen
+    pub timeouts: u32,
+    /// Commands that timed out waiting for the transceiver's busy line, see `drivers/lora.rs`
+    pub busy_timeouts: u32,
+}
+
+impl TxStats {
+    pub fn packet_sent(&mut self, time_on_air: u32) {
+        self.packets += 1;
+        self.total_time_on_air += time_on_air as u64;
+        self.max_time_on_air = self.max_time_on_air.max(time_on_air);
+    }
+
+    pub fn average_time_on_air(&self) -> u32 {
+        self.total_time_on_air.checked_div(self.packets as u64).unwrap_or(0) as u32
+    }
+
+    pub fn print(&self, name: &str, mut print: impl FnMut(core::fmt::Arguments)) {
+        print(format_args!(
+            "{}: {} sent, time on air {}us average, {}us max, {} timeouts, {} busy timeouts",
+            name,
+            self.packets,
+            self.average_time_on_air(),
+            self.max_time_on_air,
+            self.timeouts,
+            self.busy_timeouts
+        ));
+    }
+}
+
+/// Names of the flags set in the GetDeviceErrors result (13.6.1, p. 97)
+struct DeviceErrors(u16);
+
+impl core::fmt::Display for DeviceErrors {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        const NAMES: [(u16, &str); 8] = [
+            (0x001, "rc64k calibration"),
+            (0x002, "rc13m calibration"),
+            (0x004, "pll calibration"),
+            (0x008, "adc calibration"),
+            (0x010, "image calibration"),
+            (0x020, "xosc start"),
+            (0x040, "pll lock"),
+            (0x100, "pa ramp"),
+        ];
+
+        if self.0 == 0 {
+            return core::write!(f, "none");
+        }
+
+        let mut first = true;
+        for (_, name) in NAMES.iter().filter(|(bit, _)| self.0 & bit != 0) {
+            core::write!(f, "{}{}", if first { "" } else { ", " }, name)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
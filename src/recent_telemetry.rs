@@ -0,0 +1,174 @@
+//! RAM ring buffers of recently generated telemetry, independent of the flash log.
+//!
+//! `RecentTelemetry` holds the telemetry of the last `RECENT_TELEMETRY_DURATION` ms, both the LoRa
+//! messages and the messages generated by the flash schedule, which are generated even while not
+//! logging. After an error, the buffer is copied to the flash log, so the moments before the
+//! error are available at full detail, even on the pad.
+//!
+//! `LoRaHistory` only holds the LoRa messages, but for `RETRANSMIT_HISTORY` s, so they can be
+//! retransmitted on request (see `retransmission.rs`). It is sized in messages, for the fastest
+//! downlink profile.
+//!
+//! Messages are stored serialized, since most are much smaller than `DownlinkMessage` itself. The
+//! number of messages and bytes is limited as well, so the buffer may cover less time while the
+//! telemetry rates are high. Together, both buffers take about 28KB of RAM, so the vehicle keeps
+//! them in statics rather than building them on the stack.
+
+use heapless::Deque;
+
+use shared_types::DownlinkMessage;
+
+use crate::retransmission::RETRANSMIT_HISTORY;
+
+/// Time (ms) messages are kept for in `RecentTelemetry`
+const RECENT_TELEMETRY_DURATION: u32 = 2_000;
+/// Maximum number of messages kept in `RecentTelemetry`, enough for the flash and LoRa schedules
+const MAX_MESSAGES: usize = 384;
+/// Maximum size (bytes) of the serialized messages kept in `RecentTelemetry`
+const MAX_BYTES: usize = 10_240;
+/// Maximum number of messages kept in `LoRaHistory`, enough for `RETRANSMIT_HISTORY` s of the
+/// fast downlink profile (25 messages/s, see `telemetry.rs`)
+const LORA_HISTORY_MESSAGES: usize = 512;
+/// Maximum size (bytes) of the serialized messages kept in `LoRaHistory`. LoRa messages fit a
+/// downlink packet, leaving at most 22 bytes each, so the number of messages is the limit.
+const LORA_HISTORY_BYTES: usize = LORA_HISTORY_MESSAGES * 22;
+/// Largest serialized message kept, larger ones are skipped
+const MAX_MESSAGE_SIZE: usize = 256;
+
+/// Buffer of the last few seconds of telemetry, for snapshots
+pub type RecentTelemetry = TelemetryBuffer<MAX_MESSAGES, MAX_BYTES>;
+/// Buffer of the LoRa telemetry sent during the last `RETRANSMIT_HISTORY` s, for retransmissions
+pub type LoRaHistory = TelemetryBuffer<LORA_HISTORY_MESSAGES, LORA_HISTORY_BYTES>;
+
+/// Schedule a buffered message was generated by, see `telemetry.rs`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    LoRa,
+    Flash,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// Vehicle time (ms) of the message
+    time: u32,
+    len: u16,
+    source: Source,
+}
+
+pub struct TelemetryBuffer<const MESSAGES: usize, const BYTES: usize> {
+    /// Time (ms) messages are kept for
+    duration: u32,
+    entries: Deque<Entry, MESSAGES>,
+    data: Deque<u8, BYTES>,
+    /// Id of the oldest message. Ids count up with every message, so they stay valid while
+    /// older messages are dropped.
+    first_id: u32,
+}
+
+impl RecentTelemetry {
+    pub fn new() -> Self {
+        TelemetryBuffer::with_duration(RECENT_TELEMETRY_DURATION)
+    }
+}
+
+impl LoRaHistory {
+    pub fn new() -> Self {
+        TelemetryBuffer::with_duration(RETRANSMIT_HISTORY * 1000)
+    }
+}
+
+impl<const MESSAGES: usize, const BYTES: usize> TelemetryBuffer<MESSAGES, BYTES> {
+    fn with_duration(duration: u32) -> Self {
+        Self {
+            duration,
+            entries: Deque::new(),
+            data: Deque::new(),
+            first_id: 0,
+        }
+    }
+
+    fn drop_oldest(&mut self) {
+        if let Some(entry) = self.entries.pop_front() {
+            for _ in 0..entry.len {
+                self.data.pop_front();
+            }
+            self.first_id = self.first_id.wrapping_add(1);
+        }
+    }
+
+    /// Adds a message, dropping messages that are too old or don't leave enough space.
+    pub fn push(&mut self, source: Source, msg: &DownlinkMessage) {
+        let mut buffer = [0u8; MAX_MESSAGE_SIZE];
+        let Ok(serialized) = postcard::to_slice(msg, &mut buffer) else {
+            return;
+        };
+
+        let time = msg.time();
+        while self.entries.front().map(|e| time.wrapping_sub(e.time) > self.duration).unwrap_or(false)
+            || self.entries.is_full()
+            || self.data.capacity() - self.data.len() < serialized.len()
+        {
+            self.drop_oldest();
+        }
+
+        for byte in serialized.iter() {
+            let _ = self.data.push_back(*byte);
+        }
+        let _ = self.entries.push_back(Entry { time, len: serialized.len() as u16, source });
+    }
+
+    /// Id of the newest message, if any.
+    pub fn newest_id(&self) -> Option<u32> {
+        let len = self.entries.len() as u32;
+        (len > 0).then(|| self.first_id.wrapping_add(len - 1))
+    }
+
+    /// Returns the oldest message newer than the given id, whose vehicle time (ms) and source
+    /// pass the filter, along with its id.
+    pub fn find_after(&self, after: Option<u32>, filter: impl Fn(u32, Source) -> bool) -> Option<(u32, DownlinkMessage)> {
+        let mut offset = 0;
+        for (i, entry) in self.entries.iter().enumerate() {
+            let id = self.first_id.wrapping_add(i as u32);
+            let newer = after.map(|a| id.wrapping_sub(a) as i32 > 0).unwrap_or(true);
+            if newer && filter(entry.time, entry.source) {
+                let mut buffer = [0u8; MAX_MESSAGE_SIZE];
+                for (b, byte) in buffer.iter_mut().zip(self.data.iter().skip(offset).take(entry.len as usize)) {
+                    *b = *byte;
+                }
+                if let Ok(msg) = postcard::from_bytes(&buffer[..entry.len as usize]) {
+                    return Some((id, msg));
+                }
+            }
+            offset += entry.len as usize;
+        }
+
+        None
+    }
+}
+
+/// Copy of the buffered messages to the flash log, made one message at a time.
+pub struct Snapshot {
+    /// Id of the message copied last
+    last: Option<u32>,
+    /// Id of the newest message at the start of the snapshot
+    until: u32,
+}
+
+impl Snapshot {
+    /// Starts a snapshot of all currently buffered messages, if any.
+    pub fn start(recent: &RecentTelemetry) -> Option<Self> {
+        recent.newest_id().map(|until| Self { last: None, until })
+    }
+
+    /// Returns the next message to copy, or `None` once the snapshot is complete. Messages
+    /// dropped from the buffer in the meantime are skipped.
+    pub fn next_message(&mut self, recent: &RecentTelemetry) -> Option<DownlinkMessage> {
+        let (id, msg) = recent.find_after(self.last, |_, _| true)?;
+        if id.wrapping_sub(self.until) as i32 > 0 {
+            return None;
+        }
+
+        self.last = Some(id);
+        Some(msg)
+    }
+}
@@ -0,0 +1,158 @@
+//! Support for flying two flight computers in the same vehicle. Both broadcast their flight mode
+//! via CAN bus anyway (see `Vehicle::broadcast_can_telemetry`), so each one listens for the
+//! other's broadcasts and only fires recovery outputs once both agree on the flight mode.
+//!
+//! If they disagree for too long, the primary FC (the one built without the `secondary` feature)
+//! fires regardless. If the partner goes stale, or was never heard from, each FC acts on its own,
+//! i.e. the secondary takes over. The broadcasts double as heartbeats: the partner counts as
+//! stale once its broadcasts stop, or once the time in them stops advancing, e.g. if it is stuck
+//! resending the same frame.
+//!
+//! Both FCs downlink the state of their partner (see `RedundancyStatus`), whenever it changes and
+//! periodically while a partner is present.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use serde::{Deserialize, Serialize};
+
+use defmt::*;
+
+use shared_types::can::TelemetryToPayloadMessage;
+use shared_types::FlightMode;
+
+use crate::clock::Instant;
+
+#[cfg(not(feature = "secondary"))]
+pub const FC_ID: u8 = 0;
+#[cfg(not(feature = "secondary"))]
+pub const PARTNER_FC_ID: u8 = 1;
+#[cfg(feature = "secondary")]
+pub const FC_ID: u8 = 1;
+#[cfg(feature = "secondary")]
+pub const PARTNER_FC_ID: u8 = 0;
+
+/// Partner is considered lost after not hearing from it for this long (ms). Broadcasts are sent
+/// every 100ms.
+const PARTNER_TIMEOUT: u32 = 500;
+/// Time (ms) after which the primary FC overrides a disagreeing partner.
+const DISAGREEMENT_TIMEOUT: u32 = 1000;
+
+/// Latest broadcast received from the partner FC, set by the CAN receive task.
+pub static PARTNER_SIGNAL: Signal<CriticalSectionRawMutex, TelemetryToPayloadMessage> = Signal::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub enum PartnerState {
+    /// Never heard from, e.g. flying a single FC
+    Absent,
+    /// Alive and in the same flight mode
+    Agreeing,
+    /// Alive, but in a different flight mode
+    Disagreeing,
+    /// Heard from before, but stale
+    Lost,
+}
+
+/// Redundancy state of an FC, downlinked via LoRa, see `Radio::send_redundancy_status`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RedundancyStatus {
+    /// 0 for the primary FC, 1 for the secondary
+    pub fc_id: u8,
+    pub partner: PartnerState,
+    /// Flight mode last reported by the partner, if ever heard from
+    pub partner_mode: Option<FlightMode>,
+    /// Whether this FC fires the recovery outputs without its partner's agreement, i.e. it is the
+    /// primary or the partner is stale
+    pub in_command: bool,
+}
+
+pub struct Partner {
+    last_message: Option<(Instant, TelemetryToPayloadMessage)>,
+    /// Our mode at the time the partner started disagreeing with it.
+    disagreement: Option<(FlightMode, Instant)>,
+    lost: bool,
+}
+
+impl Partner {
+    pub fn new() -> Self {
+        Self {
+            last_message: None,
+            disagreement: None,
+            lost: false,
+        }
+    }
+
+    pub fn tick(&mut self, time: Instant) {
+        if let Some(msg) = PARTNER_SIGNAL.try_take() {
+            // Repeated broadcasts are no sign of life, only the first one counts.
+            let advanced = self.last_message.as_ref().map(|(_, last)| msg.time != last.time).unwrap_or(true);
+            if advanced {
+                if self.last_message.is_none() || self.lost {
+                    info!("Partner FC present, in mode {:?}.", Debug2Format(&msg.mode));
+                }
+
+                self.last_message = Some((time, msg));
+                self.lost = false;
+            }
+        }
+
+        if !self.lost && self.last_message.is_some() && !self.alive(time) {
+            if FC_ID == 0 {
+                warn!("Partner FC lost, continuing alone.");
+            } else {
+                warn!("Primary FC lost, taking over.");
+            }
+            self.lost = true;
+        }
+    }
+
+    /// Current redundancy state, given our own flight mode.
+    pub fn status(&self, time: Instant, mode: FlightMode) -> RedundancyStatus {
+        let partner = match (self.last_message.as_ref(), self.mode(time)) {
+            (None, _) => PartnerState::Absent,
+            (Some(_), None) => PartnerState::Lost,
+            (Some(_), Some(m)) if m == mode => PartnerState::Agreeing,
+            (Some(_), Some(_)) => PartnerState::Disagreeing,
+        };
+
+        RedundancyStatus {
+            fc_id: FC_ID,
+            partner,
+            partner_mode: self.last_message.as_ref().map(|(_, msg)| msg.mode),
+            in_command: FC_ID == 0 || matches!(partner, PartnerState::Absent | PartnerState::Lost),
+        }
+    }
+
+    fn alive(&self, time: Instant) -> bool {
+        self.last_message.as_ref().map(|(t, _)| time.millis_since(*t) < PARTNER_TIMEOUT).unwrap_or(false)
+    }
+
+    /// Flight mode reported by the partner, if it is alive.
+    pub fn mode(&self, time: Instant) -> Option<FlightMode> {
+        self.alive(time).then(|| self.last_message.as_ref().map(|(_, msg)| msg.mode)).flatten()
+    }
+
+    /// Whether we are allowed to act on the given mode, i.e. fire the corresponding recovery
+    /// outputs.
+    pub fn agrees(&mut self, time: Instant, mode: FlightMode) -> bool {
+        let Some(partner_mode) = self.mode(time) else {
+            self.disagreement = None;
+            return true;
+        };
+
+        if partner_mode >= mode {
+            self.disagreement = None;
+            return true;
+        }
+
+        let since = match self.disagreement {
+            Some((m, t)) if m == mode => t,
+            _ => {
+                warn!("Partner FC disagrees: {:?} vs {:?}", Debug2Format(&partner_mode), Debug2Format(&mode));
+                self.disagreement = Some((mode, time));
+                time
+            }
+        };
+
+        FC_ID == 0 && time.millis_since(since) >= DISAGREEMENT_TIMEOUT
+    }
+}
@@ -0,0 +1,106 @@
+//! Retransmission of recent telemetry. After a short link outage, the ground station can request
+//! the messages sent within a time range (see the `retransmit` console command), which the FC
+//! then sends again from the buffer of recent telemetry (see `recent_telemetry.rs`). Only the
+//! messages originally sent via LoRa are retransmitted, and only in downlink slots the telemetry
+//! schedule leaves free, so they never delay current telemetry. This fills gaps in the received
+//! data without downloading the flash log.
+//!
+//! Requests and retransmitted messages are tagged LoRa payloads (see `lora.rs`), since the message
+//! types are defined in shared_types. Retransmitted messages keep their original time and carry no
+//! sequence number, so they affect neither the GCS's estimate of FC time nor its loss statistics.
+//! Messages that don't fit a packet together with the tag are skipped.
+//!
+//! The FC keeps the last `RETRANSMIT_HISTORY` s of LoRa telemetry. The GCS limits requests to
+//! that length, and the FC ignores requests for messages it no longer has.
+
+use serde::{Deserialize, Serialize};
+
+use defmt::Format;
+
+#[cfg(not(feature = "gcs"))]
+use shared_types::DownlinkMessage;
+
+#[cfg(not(feature = "gcs"))]
+use crate::recent_telemetry::{LoRaHistory, Source};
+
+/// Time (s) of LoRa telemetry kept for retransmission on the FC, see `recent_telemetry.rs`
+pub const RETRANSMIT_HISTORY: u32 = 20;
+
+#[cfg(feature = "gcs")]
+pub const RETRANSMIT_HELP_TEXT: &[&str] = &[
+    "retransmit <from> <to>  request downlink messages sent between two vehicle times (ms) again",
+    "retransmit              request the messages around the last downlink gap again",
+];
+
+/// Request to retransmit the messages sent from `start` (s, vehicle time) for `duration` s. Whole
+/// seconds keep the request small enough for an uplink packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub struct RetransmitRequest {
+    pub start: u32,
+    pub duration: u8,
+}
+
+impl RetransmitRequest {
+    /// Request covering the given range of vehicle time (ms), extended to whole seconds. Only the
+    /// last `RETRANSMIT_HISTORY` s of longer ranges are requested, the FC has no older messages.
+    #[cfg(feature = "gcs")]
+    pub fn new(from: u32, to: u32) -> Self {
+        let end = to.div_ceil(1000);
+        let start = (from / 1000).max(end.saturating_sub(RETRANSMIT_HISTORY));
+        Self { start, duration: end.saturating_sub(start) as u8 }
+    }
+
+    /// Whether a message sent at the given vehicle time (ms) was requested.
+    #[cfg(not(feature = "gcs"))]
+    fn contains(&self, time: u32) -> bool {
+        (self.start..self.start + self.duration as u32).contains(&(time / 1000))
+    }
+}
+
+#[cfg(not(feature = "gcs"))]
+pub struct Retransmitter {
+    request: Option<RetransmitRequest>,
+    /// Id of the message last retransmitted for the current request, see `RecentTelemetry`
+    last_sent: Option<u32>,
+}
+
+#[cfg(not(feature = "gcs"))]
+impl Retransmitter {
+    pub fn new() -> Self {
+        Self {
+            request: None,
+            last_sent: None,
+        }
+    }
+
+    /// Starts serving a request, replacing any running one. Requests ending before the history
+    /// kept at the given vehicle time (ms) are rejected, returning false.
+    pub fn start(&mut self, request: RetransmitRequest, time: u32) -> bool {
+        let history_start = (time / 1000).saturating_sub(RETRANSMIT_HISTORY);
+        if request.start + request.duration as u32 <= history_start {
+            return false;
+        }
+
+        self.request = Some(request);
+        self.last_sent = None;
+        true
+    }
+
+    /// Returns the next message to retransmit, if any. The request is finished once no message
+    /// in its range is left.
+    pub fn next_message(&mut self, history: &LoRaHistory) -> Option<DownlinkMessage> {
+        let request = self.request?;
+        let next = history.find_after(self.last_sent, |time, source| source == Source::LoRa && request.contains(time));
+
+        match next {
+            Some((id, msg)) => {
+                self.last_sent = Some(id);
+                Some(msg)
+            },
+            None => {
+                self.request = None;
+                None
+            },
+        }
+    }
+}
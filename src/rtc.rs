@@ -0,0 +1,115 @@
+//! Real-time clock, disciplined by GPS time once a fix is available. This allows flight data to be
+//! correlated with external sources such as video or range data.
+//!
+//! The STM32's RTC keeps running across resets, so after a reboot the time is available again
+//! before the GPS receiver has reacquired a fix, albeit only with second resolution. Its backup
+//! registers are retained across resets as well, and are used to mirror the flight-critical state
+//! (see `critical_state.rs`).
+
+use embassy_stm32::peripherals::RTC;
+use embassy_stm32::rtc::{DateTime, DayOfWeek, Rtc, RtcConfig};
+use embassy_time::Instant;
+
+use defmt::*;
+
+use crate::drivers::sensors::GPSTime;
+
+/// Only rewrite the RTC registers if the RTC is off by more than this.
+const MAX_RTC_DRIFT_MS: u64 = 1000;
+
+pub struct RealTimeClock {
+    rtc: Rtc,
+    /// Unix time in milliseconds at a given instant, used to extrapolate the current time.
+    reference: Option<(u64, Instant)>,
+    synchronized: bool,
+}
+
+impl RealTimeClock {
+    pub fn init(rtc: RTC) -> Self {
+        let rtc = Rtc::new(rtc, RtcConfig::default());
+
+        // If the RTC has been set before a reset, we can use it until GPS time is available.
+        let reference = rtc.now()
+            .ok()
+            .filter(|dt| dt.year() >= 2024)
+            .map(|dt| (Self::datetime_to_gps_time(&dt).unix_millis(), Instant::now()));
+
+        if reference.is_some() {
+            info!("RTC running, using it until GPS time is available.");
+        }
+
+        Self {
+            rtc,
+            reference,
+            synchronized: false,
+        }
+    }
+
+    fn datetime_to_gps_time(dt: &DateTime) -> GPSTime {
+        GPSTime {
+            year: dt.year(),
+            month: dt.month(),
+            day: dt.day(),
+            hour: dt.hour(),
+            minute: dt.minute(),
+            second: dt.second(),
+            millisecond: 0,
+        }
+    }
+
+    fn gps_time_to_datetime(time: &GPSTime) -> Option<DateTime> {
+        let day_of_week = match time.weekday() {
+            0 => DayOfWeek::Monday,
+            1 => DayOfWeek::Tuesday,
+            2 => DayOfWeek::Wednesday,
+            3 => DayOfWeek::Thursday,
+            4 => DayOfWeek::Friday,
+            5 => DayOfWeek::Saturday,
+            _ => DayOfWeek::Sunday,
+        };
+
+        DateTime::from(time.year, time.month, time.day, day_of_week, time.hour, time.minute, time.second).ok()
+    }
+
+    /// Updates the clock using a time received from the GPS at the given instant.
+    pub fn discipline(&mut self, time: GPSTime, received: Instant) {
+        let unix_millis = time.unix_millis();
+
+        // Writing the RTC registers takes a while, so we only do that if necessary.
+        let rtc_drift = self.rtc.now()
+            .map(|dt| Self::datetime_to_gps_time(&dt).unix_millis().abs_diff(unix_millis))
+            .unwrap_or(u64::MAX);
+        if rtc_drift > MAX_RTC_DRIFT_MS {
+            match Self::gps_time_to_datetime(&time).map(|dt| self.rtc.set_datetime(dt)) {
+                Some(Ok(())) => info!("RTC set to {}", time),
+                _ => error!("Failed to set RTC to {}", time),
+            }
+        }
+
+        if !self.synchronized {
+            info!("Clock synchronized to GPS time.");
+            self.synchronized = true;
+        }
+
+        self.reference = Some((unix_millis, received));
+    }
+
+    /// Current UTC time in milliseconds since the unix epoch, if known.
+    pub fn utc_millis(&self) -> Option<u64> {
+        self.reference.map(|(t, i)| t + i.elapsed().as_millis())
+    }
+
+    /// Whether the time is based on GPS time received since startup, as opposed to only the RTC.
+    pub fn synchronized(&self) -> bool {
+        self.synchronized
+    }
+
+    /// Reads one of the RTC's backup registers, which keep their value across resets.
+    pub fn read_backup_register(&self, register: usize) -> Option<u32> {
+        self.rtc.read_backup_register(register)
+    }
+
+    pub fn write_backup_register(&mut self, register: usize, value: u32) {
+        self.rtc.write_backup_register(register, value)
+    }
+}
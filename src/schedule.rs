@@ -0,0 +1,69 @@
+//! Timing of periodic activities in the main loop. Each activity gets an explicit interval and
+//! phase offset, instead of checking `time % interval == phase` directly. If a slot is missed,
+//! e.g. because the activity was not polled or time jumped ahead, it is taken on the next poll
+//! instead, after which the schedule is aligned to the original phase again.
+//!
+//! The LoRa protocol's timing (see `lora.rs`) is not handled here, since it has to stay aligned
+//! between vehicle and ground station.
+
+use shared_types::*;
+
+use crate::clock::Instant;
+
+/// A periodic activity, due every `interval` ms at `phase` ms into the interval.
+#[derive(Clone, Copy, Debug)]
+pub struct Periodic {
+    interval: u32,
+    phase: u32,
+    /// Next slot (ms)
+    next: Option<u64>,
+}
+
+impl Periodic {
+    pub const fn new(interval: u32, phase: u32) -> Self {
+        Self { interval, phase: phase % interval, next: None }
+    }
+
+    /// First slot (ms) at or after the given time (ms).
+    fn slot_at_or_after(&self, time: u64) -> u64 {
+        let (interval, phase) = (self.interval as u64, self.phase as u64);
+        let slot = time - (time % interval) + phase;
+        if slot < time {
+            slot + interval
+        } else {
+            slot
+        }
+    }
+
+    /// Returns true once per slot, as soon as the given time reaches it.
+    pub fn due(&mut self, time: Instant) -> bool {
+        let time = time.as_millis();
+        let next = self.next.unwrap_or_else(|| self.slot_at_or_after(time));
+        if time < next {
+            self.next = Some(next);
+            return false;
+        }
+
+        self.next = Some(self.slot_at_or_after(time + 1));
+        true
+    }
+}
+
+/// Table of telemetry messages with their timing. If several messages are due at the same time,
+/// the first one in the table is sent, and the others follow in the next iterations.
+pub struct TelemetrySchedule<const N: usize> {
+    slots: [(Periodic, fn(VehicleState) -> DownlinkMessage); N],
+}
+
+impl<const N: usize> TelemetrySchedule<N> {
+    pub const fn new(slots: [(Periodic, fn(VehicleState) -> DownlinkMessage); N]) -> Self {
+        Self { slots }
+    }
+
+    /// Returns the constructor for the next message due, if any, so the vehicle state only has to
+    /// be assembled when needed.
+    pub fn due(&mut self, time: Instant) -> Option<fn(VehicleState) -> DownlinkMessage> {
+        let i = self.slots.iter_mut().position(|(periodic, _)| periodic.due(time))?;
+        Some(self.slots[i].1)
+    }
+}
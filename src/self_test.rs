@@ -0,0 +1,60 @@
+//! Result of the self test, run via the `selftest` console command on the vehicle or uplinked by
+//! the ground station, see `Radio::request_self_test`. The vehicle prints the result on its own
+//! console and downlinks it, so it can be checked on the pad without a USB connection.
+
+use core::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use defmt::Format;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub struct SelfTestResult {
+    pub imu: bool,
+    pub acc: bool,
+    pub mag: bool,
+    pub baro: bool,
+    /// Whether the settings could be read back from flash, `None` if the flash didn't answer in
+    /// time
+    pub flash: Option<bool>,
+    /// Whether a scratch copy of the settings, written to the next slot of their region, read
+    /// back correctly. `None` if the flash didn't answer in time.
+    pub flash_write: Option<bool>,
+    /// Whether the LED test pattern was shown, skipped while the find-me strobe is active. The
+    /// LEDs themselves have to be checked by eye.
+    pub leds: bool,
+    /// Whether the test melody was played, even in quiet mode, skipped while a warning is playing
+    pub buzzer: bool,
+    /// Arm voltage (mV). The recovery outputs have no continuity sensing, so this is the only
+    /// thing to check.
+    pub arm_voltage: Option<u16>,
+}
+
+impl SelfTestResult {
+    pub fn passed(&self) -> bool {
+        self.imu && self.acc && self.mag && self.baro && self.flash == Some(true) && self.flash_write == Some(true)
+    }
+}
+
+impl fmt::Display for SelfTestResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let result = |ok: bool| if ok { "ok" } else { "FAIL" };
+        let done = |done: bool, what: &'static str| if done { what } else { "skipped" };
+        write!(
+            f,
+            "imu {}, acc {}, mag {}, baro {}, flash read {}, flash write {}, leds {}, buzzer {}, arm voltage ",
+            result(self.imu),
+            result(self.acc),
+            result(self.mag),
+            result(self.baro),
+            self.flash.map(result).unwrap_or("no answer"),
+            self.flash_write.map(result).unwrap_or("no answer"),
+            done(self.leds, "shown"),
+            done(self.buzzer, "played"),
+        )?;
+        match self.arm_voltage {
+            Some(voltage) => write!(f, "{}mV", voltage),
+            None => write!(f, "n/a"),
+        }
+    }
+}
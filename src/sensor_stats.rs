@@ -0,0 +1,136 @@
+//! Statistics of the raw sensor readings over each reporting interval. Raw sensor telemetry only
+//! contains a single sample every 50ms, while the sensors are read at the main loop frequency, so
+//! vibration and short spikes alias or disappear entirely in the ground software's sensor view.
+//! Instead, the minimum, maximum and mean of every axis are accumulated over the interval.
+//!
+//! The statistics are sent over USB using the regular framing (see `framing.rs`), with the payload
+//! prefixed by `SENSOR_STATS_FRAME_TAG`, alongside the regular raw sensor messages. They could be
+//! sent via LoRa too, but that requires a new downlink message in `shared_types`.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use nalgebra::Vector3;
+use serde::Serialize;
+
+/// First payload byte of sensor statistics frames. Never valid as the start of a serialized
+/// downlink message.
+pub const SENSOR_STATS_FRAME_TAG: u8 = 0xfc;
+
+/// Statistics waiting to be sent via USB.
+pub static SENSOR_STATS_CHANNEL: Channel<CriticalSectionRawMutex, SensorStats, 2> = Channel::new();
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Stats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SensorStats {
+    /// Vehicle time (ms) at the end of the interval
+    pub time: u32,
+    /// Number of main loop iterations the statistics cover
+    pub samples: u16,
+    pub gyroscope: [Option<Stats>; 3],
+    pub accelerometer1: [Option<Stats>; 3],
+    pub accelerometer2: [Option<Stats>; 3],
+    pub magnetometer: [Option<Stats>; 3],
+    pub pressure_baro: Option<Stats>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Accumulator {
+    min: f32,
+    max: f32,
+    sum: f32,
+    count: u16,
+}
+
+impl Accumulator {
+    const fn new() -> Self {
+        Self { min: f32::INFINITY, max: f32::NEG_INFINITY, sum: 0.0, count: 0 }
+    }
+
+    fn add(&mut self, value: f32) {
+        self.min = f32::min(self.min, value);
+        self.max = f32::max(self.max, value);
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Returns the statistics of the values added so far, if any, and starts over.
+    fn take(&mut self) -> Option<Stats> {
+        let acc = core::mem::replace(self, Self::new());
+        (acc.count > 0).then(|| Stats { min: acc.min, max: acc.max, mean: acc.sum / acc.count as f32 })
+    }
+}
+
+/// Accumulates the raw sensor readings of every main loop iteration.
+pub struct SensorStatsCollector {
+    samples: u16,
+    gyroscope: [Accumulator; 3],
+    accelerometer1: [Accumulator; 3],
+    accelerometer2: [Accumulator; 3],
+    magnetometer: [Accumulator; 3],
+    pressure_baro: Accumulator,
+}
+
+fn add_vector(accumulators: &mut [Accumulator; 3], value: Option<Vector3<f32>>) {
+    if let Some(value) = value {
+        for (acc, v) in accumulators.iter_mut().zip(value.iter()) {
+            acc.add(*v);
+        }
+    }
+}
+
+fn take_vector(accumulators: &mut [Accumulator; 3]) -> [Option<Stats>; 3] {
+    [accumulators[0].take(), accumulators[1].take(), accumulators[2].take()]
+}
+
+impl SensorStatsCollector {
+    pub fn new() -> Self {
+        Self {
+            samples: 0,
+            gyroscope: [Accumulator::new(); 3],
+            accelerometer1: [Accumulator::new(); 3],
+            accelerometer2: [Accumulator::new(); 3],
+            magnetometer: [Accumulator::new(); 3],
+            pressure_baro: Accumulator::new(),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        gyroscope: Option<Vector3<f32>>,
+        accelerometer1: Option<Vector3<f32>>,
+        accelerometer2: Option<Vector3<f32>>,
+        magnetometer: Option<Vector3<f32>>,
+        pressure_baro: Option<f32>,
+    ) {
+        self.samples = self.samples.saturating_add(1);
+        add_vector(&mut self.gyroscope, gyroscope);
+        add_vector(&mut self.accelerometer1, accelerometer1);
+        add_vector(&mut self.accelerometer2, accelerometer2);
+        add_vector(&mut self.magnetometer, magnetometer);
+        if let Some(pressure) = pressure_baro {
+            self.pressure_baro.add(pressure);
+        }
+    }
+
+    /// Queues the statistics of the finished interval to be sent via USB, and starts a new one.
+    /// Dropped if the USB link can't keep up, e.g. because no host is connected.
+    pub fn send(&mut self, time: u32) {
+        let stats = SensorStats {
+            time,
+            samples: core::mem::take(&mut self.samples),
+            gyroscope: take_vector(&mut self.gyroscope),
+            accelerometer1: take_vector(&mut self.accelerometer1),
+            accelerometer2: take_vector(&mut self.accelerometer2),
+            magnetometer: take_vector(&mut self.magnetometer),
+            pressure_baro: self.pressure_baro.take(),
+        };
+
+        let _ = SENSOR_STATS_CHANNEL.try_send(stats);
+    }
+}
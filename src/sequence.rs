@@ -0,0 +1,164 @@
+//! Scripted sequences of uplink commands for the ground station, so standard procedures are
+//! executed the same way every time, e.g. "arm, wait until the vehicle reports Armed, then switch
+//! to ArmedLaunchImminent". Steps are entered via the USB console (`seq ...`) and executed by the
+//! ground station's main loop, which queues the commands for the next uplink window.
+//!
+//! If the vehicle doesn't confirm a mode change in time, the rest of the sequence is discarded.
+
+use heapless::Deque;
+
+use shared_types::*;
+
+use crate::clock::Instant;
+
+pub const MAX_STEPS: usize = 16;
+
+pub const SEQUENCE_HELP_TEXT: &[&str] = &[
+    "seq mode <mode>              queue a flight mode change",
+    "seq wait <ms>                queue a delay",
+    "seq until <mode> <ms>        wait for the vehicle to report a mode, abort after timeout",
+    "seq start                    run the queued steps",
+    "seq abort                    stop and discard all steps",
+    "seq show                     list the queued steps",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Step {
+    /// Send a command in the next uplink window
+    Send(Command),
+    /// Wait for the given time (ms)
+    Wait(u32),
+    /// Wait until the vehicle reports the given mode, failing after the timeout (ms)
+    WaitForMode(FlightMode, u32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SequenceCommand {
+    Add(Step),
+    Start,
+    Abort,
+    Show,
+}
+
+/// Result of a sequence tick the ground station has to act on.
+#[derive(Debug)]
+pub enum SequenceEvent {
+    Send(Command),
+    Finished,
+    /// The vehicle did not reach the given mode in time.
+    TimedOut(FlightMode),
+}
+
+/// Names for flight modes as used on the console.
+fn parse_mode(name: &str) -> Option<FlightMode> {
+    match name {
+        "idle" => Some(FlightMode::Idle),
+        "hwarmed" => Some(FlightMode::HardwareArmed),
+        "armed" => Some(FlightMode::Armed),
+        "imminent" => Some(FlightMode::ArmedLaunchImminent),
+        "drogue" => Some(FlightMode::RecoveryDrogue),
+        "main" => Some(FlightMode::RecoveryMain),
+        "landed" => Some(FlightMode::Landed),
+        _ => None,
+    }
+}
+
+impl SequenceCommand {
+    /// Parses the arguments following `seq` on the console.
+    pub fn parse<'a>(cmd: &str, mut args: impl Iterator<Item = &'a str>) -> Option<Self> {
+        match cmd {
+            "mode" => args.next().and_then(parse_mode).map(|fm| Self::Add(Step::Send(Command::SetFlightMode(fm)))),
+            "wait" => args.next().and_then(|s| s.parse().ok()).map(|ms| Self::Add(Step::Wait(ms))),
+            "until" => args
+                .next()
+                .and_then(parse_mode)
+                .zip(args.next().and_then(|s| s.parse().ok()))
+                .map(|(fm, timeout)| Self::Add(Step::WaitForMode(fm, timeout))),
+            "start" => Some(Self::Start),
+            "abort" => Some(Self::Abort),
+            "show" => Some(Self::Show),
+            _ => None,
+        }
+    }
+}
+
+pub struct Sequence {
+    steps: Deque<Step, MAX_STEPS>,
+    running: bool,
+    /// Time at which the current step was started
+    step_started: Option<Instant>,
+}
+
+impl Sequence {
+    pub fn new() -> Self {
+        Self {
+            steps: Deque::new(),
+            running: false,
+            step_started: None,
+        }
+    }
+
+    /// Appends a step, returning it if the sequence is full.
+    pub fn push(&mut self, step: Step) -> Result<(), Step> {
+        self.steps.push_back(step)
+    }
+
+    pub fn start(&mut self) {
+        self.running = true;
+        self.step_started = None;
+    }
+
+    pub fn abort(&mut self) {
+        self.steps.clear();
+        self.running = false;
+        self.step_started = None;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn steps(&self) -> impl Iterator<Item = &Step> {
+        self.steps.iter()
+    }
+
+    /// Advances the sequence, given the flight mode last reported by the vehicle. Commands are
+    /// only returned once the previous uplink message has been sent, so none are overwritten.
+    pub fn tick(&mut self, time: Instant, mode: Option<FlightMode>, uplink_pending: bool) -> Option<SequenceEvent> {
+        if !self.running {
+            return None;
+        }
+
+        let started = *self.step_started.get_or_insert(time);
+        let elapsed = time.millis_since(started);
+        let step_done = match self.steps.front() {
+            None => {
+                self.running = false;
+                return Some(SequenceEvent::Finished);
+            }
+            Some(Step::Send(_)) => !uplink_pending,
+            Some(Step::Wait(duration)) => elapsed >= *duration,
+            Some(Step::WaitForMode(expected, timeout)) => {
+                if mode == Some(*expected) {
+                    true
+                } else if elapsed >= *timeout {
+                    let expected = *expected;
+                    self.abort();
+                    return Some(SequenceEvent::TimedOut(expected));
+                } else {
+                    false
+                }
+            }
+        };
+
+        if !step_done {
+            return None;
+        }
+
+        self.step_started = None;
+        match self.steps.pop_front() {
+            Some(Step::Send(cmd)) => Some(SequenceEvent::Send(cmd)),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Servo outputs, for airbrakes, payload doors or thrust vectoring experiments. Each channel
+//! outputs standard 50Hz servo pulses, with pulse widths calibrated per channel in the firmware
+//! parameters (see `parameters.rs`). Positions range from 0.0 to 1.0, and changes are
+//! slew-limited to avoid stressing mechanisms.
+//!
+//! Channels go to their failsafe position whenever the vehicle is disarmed (no arm voltage), when
+//! `failsafe` is called, or when a channel with a command timeout hasn't been commanded in time.
+//!
+//! Only built with the `servo` feature. The outputs are TIM1 CH2 (PA9) and CH3 (PA10). TIM1 is
+//! also used by the APRS transmitter, so the two features are mutually exclusive.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_stm32::peripherals::TIM1;
+use embassy_stm32::time::Hertz;
+use embassy_stm32::timer::simple_pwm::SimplePwm;
+use embassy_stm32::timer::Channel as PwmChannel;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Ticker};
+
+use defmt::*;
+
+use crate::parameters::{ServoConfig, NUM_SERVOS};
+
+#[cfg(feature = "aprs")]
+compile_error!("The servo and aprs features both use TIM1 and can't be enabled together.");
+
+const UPDATE_RATE: u32 = 50;
+/// Period of the servo signal (us)
+const PERIOD: u32 = 1_000_000 / UPDATE_RATE;
+
+const CHANNELS: [PwmChannel; NUM_SERVOS] = [PwmChannel::Ch2, PwmChannel::Ch3];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServoCommand {
+    /// Channel and position (0.0 to 1.0)
+    Set(usize, f32),
+    Failsafe,
+}
+
+static COMMANDS: Channel<CriticalSectionRawMutex, ServoCommand, 8> = Channel::new();
+static ARMED: AtomicBool = AtomicBool::new(false);
+
+fn send(cmd: ServoCommand) {
+    if COMMANDS.try_send(cmd).is_err() {
+        warn!("Servo command queue full");
+    }
+}
+
+/// Moves a channel to the given position (0.0 to 1.0), if armed.
+pub fn set(servo: usize, position: f32) {
+    send(ServoCommand::Set(servo, position));
+}
+
+/// Moves all channels to their failsafe positions.
+pub fn failsafe() {
+    send(ServoCommand::Failsafe);
+}
+
+/// Outputs only follow commands while armed, and are held at their failsafe positions otherwise.
+/// Commands received while disarmed are discarded.
+pub fn set_armed(armed: bool) {
+    ARMED.store(armed, Ordering::Relaxed);
+}
+
+pub struct Servos {
+    pwm: SimplePwm<'static, TIM1>,
+    config: [ServoConfig; NUM_SERVOS],
+    /// Commanded position and time of the command, `None` for failsafe
+    targets: [Option<(f32, Instant)>; NUM_SERVOS],
+    positions: [f32; NUM_SERVOS],
+}
+
+#[embassy_executor::task]
+pub async fn run(mut servos: Servos) -> ! {
+    servos.run().await
+}
+
+impl Servos {
+    pub fn init(mut pwm: SimplePwm<'static, TIM1>, config: [ServoConfig; NUM_SERVOS]) -> Self {
+        pwm.set_frequency(Hertz::hz(UPDATE_RATE));
+
+        let mut servos = Self {
+            pwm,
+            config,
+            targets: [None; NUM_SERVOS],
+            positions: config.map(|config| config.failsafe),
+        };
+
+        for (i, channel) in CHANNELS.iter().enumerate() {
+            servos.output(i);
+            servos.pwm.enable(*channel);
+        }
+
+        info!("Servos initialized");
+        servos
+    }
+
+    fn handle_command(&mut self, cmd: ServoCommand) {
+        match cmd {
+            ServoCommand::Set(servo, position) if servo < NUM_SERVOS => {
+                self.targets[servo] = Some((position.clamp(0.0, 1.0), Instant::now()));
+            },
+            ServoCommand::Set(servo, _) => warn!("Invalid servo channel {}", servo),
+            ServoCommand::Failsafe => self.targets = [None; NUM_SERVOS],
+        }
+    }
+
+    fn target(&self, servo: usize) -> f32 {
+        let config = &self.config[servo];
+        match (self.targets[servo], config.command_timeout) {
+            (Some((_, t)), Some(timeout)) if t.elapsed().as_millis() > timeout as u64 => config.failsafe,
+            (Some((position, _)), _) => position,
+            (None, _) => config.failsafe,
+        }
+    }
+
+    fn output(&mut self, servo: usize) {
+        let config = &self.config[servo];
+        let pulse = config.min_pulse as f32 + (config.max_pulse as f32 - config.min_pulse as f32) * self.positions[servo];
+        let duty = (pulse / PERIOD as f32) * self.pwm.get_max_duty() as f32;
+        self.pwm.set_duty(CHANNELS[servo], duty as u16);
+    }
+
+    async fn run(&mut self) -> ! {
+        let mut ticker = Ticker::every(Duration::from_hz(UPDATE_RATE as u64));
+        loop {
+            while let Ok(cmd) = COMMANDS.try_receive() {
+                self.handle_command(cmd);
+            }
+
+            // Forget commands on disarm, so outputs don't move unexpectedly when re-armed.
+            if !ARMED.load(Ordering::Relaxed) {
+                self.targets = [None; NUM_SERVOS];
+            }
+
+            for servo in 0..NUM_SERVOS {
+                let max_step = self.config[servo].max_slew_rate / UPDATE_RATE as f32;
+                let error = self.target(servo) - self.positions[servo];
+                self.positions[servo] += error.clamp(-max_step, max_step);
+                self.output(servo);
+            }
+
+            ticker.next().await;
+        }
+    }
+}
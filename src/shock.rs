@@ -0,0 +1,188 @@
+//! Detection of shock and current events, such as firing the recovery charges and the deployment
+//! shock that follows, during which the supply voltage can sag enough to corrupt flash writes.
+//! While such an event lasts, log writes are held back in RAM (see `flash.rs`), and resumed once
+//! the supply voltage has been stable for a while. Every deferral is noted in the log afterwards,
+//! so gaps in the page timing can be explained.
+
+use core::fmt::Write;
+
+use heapless::String;
+use nalgebra::Vector3;
+use num_traits::Float;
+use serde::{Deserialize, Serialize};
+
+use defmt::{warn, Format};
+
+use crate::clock::Instant;
+use crate::flash_log::{LogNote, LOG_NOTE_LENGTH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ShockConfig {
+    /// Specific force (m/s²) on either accelerometer above which a shock is detected
+    pub acceleration: f32,
+    /// Battery current (mA) above which a current event is detected
+    pub current: i32,
+    /// Supply voltage (mV) below which writes are deferred as well
+    pub min_supply_voltage: f32,
+    /// Writes resume once the supply voltage has stayed within this band (mV) for `settle_time`
+    /// ms after the event
+    pub supply_tolerance: f32,
+    pub settle_time: u32,
+    /// Writes resume after this long (ms) regardless, before the RAM buffer runs out, and are
+    /// only deferred again once the event has passed
+    pub max_deferral: u32,
+}
+
+impl Default for ShockConfig {
+    fn default() -> Self {
+        Self {
+            acceleration: 40.0 * 9.80665,
+            current: 5_000,
+            min_supply_voltage: 3150.0,
+            supply_tolerance: 30.0,
+            settle_time: 100,
+            max_deferral: 500,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum ShockCause {
+    Acceleration,
+    Current,
+    SupplySag,
+}
+
+impl ShockCause {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Acceleration => "shock",
+            Self::Current => "current",
+            Self::SupplySag => "supply sag",
+        }
+    }
+}
+
+/// A finished deferral of log writes
+#[derive(Clone, Copy, Debug)]
+pub struct Deferral {
+    pub cause: ShockCause,
+    /// Vehicle time at which writes were deferred, and for how long (ms)
+    pub start: Instant,
+    pub duration: u32,
+}
+
+impl Deferral {
+    /// Log entry documenting the deferral, at the time it started
+    pub fn note(&self) -> LogNote {
+        let mut text: String<LOG_NOTE_LENGTH> = String::new();
+        let _ = write!(text, "flash writes deferred for {}ms ({})", self.duration, self.cause.name());
+        LogNote::new(self.start.wire(), &text)
+    }
+}
+
+struct ActiveDeferral {
+    cause: ShockCause,
+    start: Instant,
+    /// Start of the current settling window and the supply voltage (mV) at that time
+    settling: Option<(Instant, f32)>,
+}
+
+pub struct ShockMonitor {
+    config: ShockConfig,
+    deferral: Option<ActiveDeferral>,
+    /// Whether the last deferral timed out while the event was still going on
+    timed_out: bool,
+    count: u32,
+}
+
+impl ShockMonitor {
+    pub fn new(config: ShockConfig) -> Self {
+        Self {
+            config,
+            deferral: None,
+            timed_out: false,
+            count: 0,
+        }
+    }
+
+    fn cause(
+        &self,
+        acc1: Option<Vector3<f32>>,
+        acc2: Option<Vector3<f32>>,
+        current: Option<i32>,
+        supply_voltage: Option<f32>,
+    ) -> Option<ShockCause> {
+        let acceleration = f32::max(acc1.map(|a| a.norm()).unwrap_or(0.0), acc2.map(|a| a.norm()).unwrap_or(0.0));
+        if acceleration > self.config.acceleration {
+            Some(ShockCause::Acceleration)
+        } else if current.map(|c| c.abs() > self.config.current).unwrap_or(false) {
+            Some(ShockCause::Current)
+        } else if supply_voltage.map(|v| v < self.config.min_supply_voltage).unwrap_or(false) {
+            Some(ShockCause::SupplySag)
+        } else {
+            None
+        }
+    }
+
+    /// Takes the accelerometer readings (m/s²), battery current (mA) and supply voltage (mV).
+    /// Returns the deferral once writes may resume.
+    pub fn tick(
+        &mut self,
+        time: Instant,
+        acc1: Option<Vector3<f32>>,
+        acc2: Option<Vector3<f32>>,
+        current: Option<i32>,
+        supply_voltage: Option<f32>,
+    ) -> Option<Deferral> {
+        let cause = self.cause(acc1, acc2, current, supply_voltage);
+
+        let Some(deferral) = self.deferral.as_mut() else {
+            self.timed_out &= cause.is_some();
+            if let (Some(cause), false) = (cause, self.timed_out) {
+                warn!("{:?} detected, deferring flash writes.", cause);
+                self.deferral = Some(ActiveDeferral { cause, start: time, settling: None });
+                self.count += 1;
+            }
+            return None;
+        };
+
+        let duration = time.millis_since(deferral.start);
+        let settled = match (cause, deferral.settling, supply_voltage) {
+            (None, Some((since, reference)), Some(voltage)) if (voltage - reference).abs() <= self.config.supply_tolerance => {
+                time.millis_since(since) >= self.config.settle_time
+            },
+            (None, _, Some(voltage)) => {
+                deferral.settling = Some((time, voltage));
+                false
+            },
+            // Without a supply voltage, we can only wait for the event to pass.
+            (None, _, None) => duration >= self.config.settle_time,
+            (Some(_), _, _) => {
+                deferral.settling = None;
+                false
+            },
+        };
+
+        if !settled && duration < self.config.max_deferral {
+            return None;
+        }
+
+        if !settled {
+            warn!("Supply did not settle within {}ms, resuming flash writes.", duration);
+            self.timed_out = cause.is_some();
+        }
+
+        let deferral = self.deferral.take()?;
+        Some(Deferral { cause: deferral.cause, start: deferral.start, duration })
+    }
+
+    pub fn deferring(&self) -> bool {
+        self.deferral.is_some()
+    }
+
+    /// Number of deferrals since startup
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
@@ -0,0 +1,696 @@
+//! Software-in-the-loop simulation for regression testing of the flight logic on the host.
+//!
+//! A simple vertical flight dynamics model (thrust curve, drag, parachutes) is used to generate
+//! sensor data, which is fed to the state estimator and flight mode logic at the same rate as on
+//! the vehicle. Recovery outputs act on the model according to the output settings. Telemetry is
+//! scheduled like on the vehicle (see `telemetry.rs`) and the flash log uses the same format as
+//! a real flight, so it can be loaded into the ground station for inspection.
+//!
+//! Sensor data from recorded flights can be replayed through the same flight logic using
+//! `replay`, e.g. the raw sensor data from a flash dump via `log_samples`. Both return a
+//! `FlightSummary` for checking apogee, deployment times and mode changes.
+//!
+//! Telemetry and the flash log go through the mock radio and flash in `mocks.rs`, i.e. the same
+//! interfaces the vehicle uses, see `traits.rs`.
+//!
+//! Only available with the `std` feature, e.g.
+//! `cargo test --lib --target x86_64-unknown-linux-gnu --no-default-features --features std`
+
+use std::num::Wrapping;
+
+use nalgebra::Vector3;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use shared_types::*;
+use state_estimator::StateEstimator;
+
+use crate::clock::Instant;
+use crate::flash_log::{LogDecoder, LogRecord};
+use crate::mocks::{MockFlash, MockRadio};
+use crate::schedule::TelemetrySchedule;
+use crate::telemetry;
+use crate::traits::{LogStorage, TelemetryRadio};
+
+const MAIN_LOOP_FREQUENCY: u32 = 1000;
+const TIME_STEP: u32 = 1_000 / MAIN_LOOP_FREQUENCY;
+
+const GRAVITY: f32 = 9.80665;
+const AIR_DENSITY_SEA_LEVEL: f32 = 1.225;
+const ATMOSPHERE_SCALE_HEIGHT: f32 = 8500.0;
+
+/// Simulated arm voltage (mV), well above the arming threshold.
+const ARM_VOLTAGE: u16 = 12_000;
+/// Constant magnetic field, pointing north and down, as seen by the magnetometer on the pad.
+const MAGNETIC_FIELD: Vector3<f32> = Vector3::new(0.0, 20.0, -45.0);
+
+/// Properties of the simulated vehicle.
+#[derive(Clone, Debug)]
+pub struct RocketModel {
+    /// Mass without propellant (kg)
+    pub dry_mass: f32,
+    /// Propellant mass (kg), assumed to be burned at a constant rate
+    pub propellant_mass: f32,
+    /// Thrust (N) over time since ignition (s), linearly interpolated
+    pub thrust_curve: Vec<(f32, f32)>,
+    /// Drag coefficient times reference area (m^2) of the vehicle itself
+    pub drag_area: f32,
+    /// Drag coefficient times area (m^2) of the drogue parachute
+    pub drogue_drag_area: f32,
+    /// Drag coefficient times area (m^2) of the main parachute
+    pub main_drag_area: f32,
+}
+
+impl Default for RocketModel {
+    fn default() -> Self {
+        Self {
+            dry_mass: 12.0,
+            propellant_mass: 2.5,
+            thrust_curve: vec![(0.0, 0.0), (0.1, 1800.0), (2.5, 1500.0), (3.0, 0.0)],
+            drag_area: 0.45 * 0.008,
+            drogue_drag_area: 0.3,
+            main_drag_area: 3.0,
+        }
+    }
+}
+
+impl RocketModel {
+    fn burn_time(&self) -> f32 {
+        self.thrust_curve.last().map(|(t, _)| *t).unwrap_or(0.0)
+    }
+
+    fn thrust(&self, t: f32) -> f32 {
+        self.thrust_curve
+            .windows(2)
+            .find(|w| t >= w[0].0 && t < w[1].0)
+            .map(|w| w[0].1 + (w[1].1 - w[0].1) * (t - w[0].0) / (w[1].0 - w[0].0))
+            .unwrap_or(0.0)
+    }
+
+    fn mass(&self, t: f32) -> f32 {
+        let burned = (t / self.burn_time()).clamp(0.0, 1.0);
+        self.dry_mass + self.propellant_mass * (1.0 - burned)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SimulationConfig {
+    pub rocket: RocketModel,
+    pub settings: Settings,
+    /// Altitude of the launch site above sea level (m)
+    pub ground_altitude_asl: f32,
+    /// Time (ms) spent armed on the pad before ignition
+    pub ignition_time: u32,
+    /// Simulation is stopped after this time (ms), even if the vehicle has not landed
+    pub max_duration: u32,
+    /// Time (ms) to keep simulating after touchdown
+    pub landed_duration: u32,
+    /// Seed for the sensor noise, so runs are reproducible
+    pub seed: u64,
+    /// Maximum barometer noise (m)
+    pub baro_noise: f32,
+    /// Maximum accelerometer noise (m/s^2)
+    pub accelerometer_noise: f32,
+    /// Maximum gyroscope noise (deg/s)
+    pub gyroscope_noise: f32,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            rocket: RocketModel::default(),
+            settings: Settings::default(),
+            ground_altitude_asl: 100.0,
+            ignition_time: 10_000,
+            max_duration: 600_000,
+            landed_duration: 10_000,
+            seed: 0,
+            baro_noise: 0.5,
+            accelerometer_noise: 0.2,
+            gyroscope_noise: 0.1,
+        }
+    }
+}
+
+/// Sensor readings for a single main loop iteration, either generated by the simulation or taken
+/// from a recorded flight.
+#[derive(Clone, Debug, Default)]
+pub struct SensorSample {
+    /// Time (ms) since the start of the recording
+    pub time: u32,
+    pub gyroscope: Option<Vector3<f32>>,
+    pub accelerometer1: Option<Vector3<f32>>,
+    pub accelerometer2: Option<Vector3<f32>>,
+    pub magnetometer: Option<Vector3<f32>>,
+    pub altitude_baro: Option<f32>,
+}
+
+impl SensorSample {
+    /// Sensor readings from a flash log record, if it is a raw sensor message. These are logged
+    /// at 100Hz, see `telemetry::flash_schedule`.
+    pub fn from_log_record(record: &LogRecord) -> Option<Self> {
+        let LogRecord::Message(msg @ DownlinkMessage::TelemetryRawSensors(_)) = record else {
+            return None;
+        };
+
+        let state: VehicleState = msg.clone().into();
+        Some(Self {
+            time: state.time,
+            gyroscope: state.gyroscope,
+            accelerometer1: state.accelerometer1,
+            accelerometer2: state.accelerometer2,
+            magnetometer: state.magnetometer,
+            // Same conversion as in the barometer driver, in case only the pressure was logged
+            altitude_baro: state.altitude_baro.or_else(|| {
+                state.pressure_baro.map(|p| 44330.769 * (1.0 - (p / 1012.5).powf(0.190223)))
+            }),
+        })
+    }
+}
+
+/// Raw sensor samples from the log region of a flash dump (see `LogDecoder::from_pages`), for
+/// `replay`. Records that can't be decoded are skipped. The vehicle time starts over on every
+/// boot, so only the first boot in the log is used.
+pub fn log_samples(pages: &[u8]) -> Vec<SensorSample> {
+    let mut samples: Vec<SensorSample> = Vec::new();
+    for record in LogDecoder::from_pages(pages).filter_map(Result::ok) {
+        let Some(sample) = SensorSample::from_log_record(&record) else {
+            continue;
+        };
+
+        if samples.last().is_some_and(|last| sample.time < last.time) {
+            break;
+        }
+        samples.push(sample);
+    }
+
+    samples
+}
+
+/// Key outcomes of a simulated or replayed flight, to compare against expectations.
+#[derive(Clone, Debug)]
+pub struct FlightSummary {
+    /// Highest estimated altitude above ground (m)
+    pub apogee_agl: f32,
+    /// Highest estimated vertical speed (m/s)
+    pub max_vertical_speed: f32,
+    /// Flight mode changes, with time (ms)
+    pub mode_changes: Vec<(u32, FlightMode)>,
+}
+
+impl FlightSummary {
+    /// Time (ms) at which the given mode was first entered
+    pub fn mode_entered(&self, mode: FlightMode) -> Option<u32> {
+        self.mode_changes.iter().find(|(_, m)| *m == mode).map(|(t, _)| *t)
+    }
+
+    /// Whether the flight mode only ever advanced, i.e. there were no spurious transitions back
+    /// to an earlier mode.
+    pub fn modes_monotonic(&self) -> bool {
+        self.mode_changes.windows(2).all(|w| w[1].1 > w[0].1)
+    }
+}
+
+/// State estimation and flight mode logic, as run in the vehicle's main loop.
+struct FlightLogic {
+    state_estimator: StateEstimator,
+    mode: FlightMode,
+    mode_entered: Instant,
+    mode_changes: Vec<(u32, FlightMode)>,
+    max_altitude_asl: f32,
+    max_vertical_speed: f32,
+}
+
+impl FlightLogic {
+    fn new(settings: &Settings) -> Self {
+        Self {
+            state_estimator: StateEstimator::new(MAIN_LOOP_FREQUENCY as f32, settings.clone()),
+            // Arming is a manual step, so we start out armed.
+            mode: FlightMode::Armed,
+            mode_entered: Instant::ZERO,
+            mode_changes: vec![(0, FlightMode::Armed)],
+            max_altitude_asl: f32::MIN,
+            max_vertical_speed: f32::MIN,
+        }
+    }
+
+    fn update(&mut self, time: Instant, sensors: &SensorSample, arm_voltage: u16) {
+        self.state_estimator.update(
+            Wrapping(time.wire()),
+            self.mode,
+            sensors.gyroscope,
+            sensors.accelerometer1,
+            sensors.accelerometer2,
+            sensors.magnetometer,
+            sensors.altitude_baro,
+            None,
+        );
+
+        if self.mode >= FlightMode::ArmedLaunchImminent && self.mode < FlightMode::Landed {
+            self.max_altitude_asl = f32::max(self.max_altitude_asl, self.state_estimator.altitude_asl());
+            self.max_vertical_speed = f32::max(self.max_vertical_speed, self.state_estimator.vertical_speed());
+        }
+
+        if let Some(fm) = self.state_estimator.new_mode(arm_voltage) {
+            if fm != self.mode {
+                self.mode = fm;
+                self.mode_entered = time;
+                self.mode_changes.push((time.wire(), fm));
+            }
+        }
+    }
+
+    fn summary(&self) -> FlightSummary {
+        FlightSummary {
+            apogee_agl: self.max_altitude_asl - self.state_estimator.altitude_ground,
+            max_vertical_speed: self.max_vertical_speed,
+            mode_changes: self.mode_changes.clone(),
+        }
+    }
+}
+
+/// Replays recorded sensor data through the flight logic. Samples recorded at a lower rate than
+/// the main loop are held until the next one, so e.g. the 100Hz raw sensor data from flash logs
+/// can be used directly.
+pub fn replay(settings: &Settings, samples: impl IntoIterator<Item = SensorSample>) -> FlightSummary {
+    let mut logic = FlightLogic::new(settings);
+    let mut samples = samples.into_iter().peekable();
+    let mut time = Instant::from_millis(samples.peek().map(|s| s.time).unwrap_or(0) as u64);
+
+    while let Some(sample) = samples.next() {
+        let next_time = samples.peek().map(|s| s.time).unwrap_or(sample.time + TIME_STEP);
+        while time.as_millis() < next_time as u64 {
+            logic.update(time, &sample, ARM_VOLTAGE);
+            time = time.plus_millis(TIME_STEP);
+        }
+    }
+
+    logic.summary()
+}
+
+pub struct Simulation {
+    config: SimulationConfig,
+    rng: ChaCha8Rng,
+    time: Instant,
+    // physical state
+    altitude: f32,
+    vertical_speed: f32,
+    vertical_accel: f32,
+    max_altitude: f32,
+    max_altitude_time: Instant,
+    drogue_deployed: bool,
+    main_deployed: bool,
+    touchdown: Option<Instant>,
+    // flight computer state
+    logic: FlightLogic,
+    sensors: SensorSample,
+    // outputs
+    lora_telemetry: TelemetrySchedule<6>,
+    flash_telemetry: TelemetrySchedule<4>,
+    radio: MockRadio,
+    flash: MockFlash,
+}
+
+impl Simulation {
+    pub fn new(config: SimulationConfig) -> Self {
+        let logic = FlightLogic::new(&config.settings);
+        let rng = ChaCha8Rng::seed_from_u64(config.seed);
+
+        Self {
+            config,
+            rng,
+            time: Instant::ZERO,
+            altitude: 0.0,
+            vertical_speed: 0.0,
+            vertical_accel: 0.0,
+            max_altitude: 0.0,
+            max_altitude_time: Instant::ZERO,
+            drogue_deployed: false,
+            main_deployed: false,
+            touchdown: None,
+            logic,
+            sensors: SensorSample::default(),
+            lora_telemetry: telemetry::lora_schedule(telemetry::DownlinkProfile::default()),
+            flash_telemetry: telemetry::flash_schedule(),
+            radio: MockRadio::default(),
+            flash: MockFlash::default(),
+        }
+    }
+
+    fn noise(&mut self, amplitude: f32) -> f32 {
+        self.rng.gen_range(-1.0..=1.0) * amplitude
+    }
+
+    fn noise_vector(&mut self, amplitude: f32) -> Vector3<f32> {
+        Vector3::new(self.noise(amplitude), self.noise(amplitude), self.noise(amplitude))
+    }
+
+    /// Advances the flight dynamics model by one time step.
+    fn step_dynamics(&mut self) {
+        let dt = TIME_STEP as f32 / 1000.0;
+        let rocket = &self.config.rocket;
+
+        let t = (self.time.as_millis() as f32 - self.config.ignition_time as f32) / 1000.0;
+        let (thrust, mass) = if t >= 0.0 {
+            (rocket.thrust(t), rocket.mass(t))
+        } else {
+            (0.0, rocket.mass(0.0))
+        };
+
+        let mut drag_area = rocket.drag_area;
+        if self.drogue_deployed {
+            drag_area += rocket.drogue_drag_area;
+        }
+        if self.main_deployed {
+            drag_area += rocket.main_drag_area;
+        }
+
+        let altitude_asl = self.config.ground_altitude_asl + self.altitude;
+        let density = AIR_DENSITY_SEA_LEVEL * (-altitude_asl / ATMOSPHERE_SCALE_HEIGHT).exp();
+        let drag = -0.5 * density * drag_area * self.vertical_speed * self.vertical_speed.abs();
+
+        let on_ground = self.altitude <= 0.0 && (self.touchdown.is_some() || thrust <= mass * GRAVITY);
+        self.vertical_accel = if on_ground {
+            0.0
+        } else {
+            (thrust + drag) / mass - GRAVITY
+        };
+
+        self.vertical_speed += self.vertical_accel * dt;
+        self.altitude += self.vertical_speed * dt;
+        if self.altitude > self.max_altitude {
+            self.max_altitude = self.altitude;
+            self.max_altitude_time = self.time;
+        }
+
+        if self.altitude <= 0.0 && (t > 0.0 || on_ground) {
+            if self.touchdown.is_none() && t > rocket.burn_time() {
+                self.touchdown = Some(self.time);
+            }
+
+            self.altitude = 0.0;
+            self.vertical_speed = 0.0;
+        }
+
+        // Sensors measure specific force, i.e. without gravity, unless resting on the ground.
+        // The vehicle is assumed to stay upright, with the z axis pointing up.
+        let specific_force = if on_ground { GRAVITY } else { self.vertical_accel + GRAVITY };
+        let accel_noise = self.config.accelerometer_noise;
+        let gyro_noise = self.config.gyroscope_noise;
+        let baro_noise = self.config.baro_noise;
+        let accelerometer = Vector3::new(0.0, 0.0, specific_force) + self.noise_vector(accel_noise);
+        self.sensors = SensorSample {
+            time: self.time.wire(),
+            gyroscope: Some(self.noise_vector(gyro_noise)),
+            accelerometer1: Some(accelerometer),
+            accelerometer2: Some(accelerometer),
+            magnetometer: Some(MAGNETIC_FIELD),
+            altitude_baro: Some(altitude_asl + self.noise(baro_noise)),
+        };
+    }
+
+    /// Same as the recovery outputs on the vehicle, but deploys the parachute instead.
+    fn update_recovery(&mut self) {
+        let elapsed = self.time.millis_since(self.logic.mode_entered);
+        match self.logic.mode {
+            FlightMode::RecoveryDrogue => {
+                self.drogue_deployed |= self.config.settings.drogue_output_settings.currently_high(elapsed);
+            }
+            FlightMode::RecoveryMain => {
+                self.main_deployed |= self.config.settings.main_output_settings.currently_high(elapsed);
+            }
+            _ => {}
+        }
+    }
+
+    fn vehicle_state(&self) -> VehicleState {
+        let state_estimator = &self.logic.state_estimator;
+        VehicleState {
+            time: self.time.wire(),
+            mode: Some(self.logic.mode),
+            orientation: state_estimator.orientation,
+            vertical_speed: Some(state_estimator.vertical_speed()),
+            vertical_accel: Some(state_estimator.vertical_acceleration()),
+            altitude_asl: Some(state_estimator.altitude_asl()),
+            altitude_ground_asl: Some(state_estimator.altitude_ground),
+            apogee_asl: state_estimator.apogee_asl(300.0),
+
+            gyroscope: self.sensors.gyroscope,
+            accelerometer1: self.sensors.accelerometer1,
+            accelerometer2: self.sensors.accelerometer2,
+            magnetometer: self.sensors.magnetometer,
+            altitude_baro: self.sensors.altitude_baro,
+
+            position_variance: Some(state_estimator.kalman.P.diagonal()[0]),
+            altitude_variance: Some(state_estimator.kalman.P.diagonal()[2]),
+            vertical_speed_variance: Some(state_estimator.kalman.P.diagonal()[5]),
+            barometer_variance: Some(state_estimator.kalman.R.diagonal()[0]),
+            accelerometer_variance: Some(state_estimator.kalman.R.diagonal()[3]),
+            gps_variance: Some(state_estimator.kalman.R.diagonal()[4]),
+
+            ..Default::default()
+        }
+    }
+
+    /// Runs a single iteration of the main loop.
+    pub fn tick(&mut self) {
+        self.step_dynamics();
+
+        self.logic.update(self.time, &self.sensors, ARM_VOLTAGE);
+        self.update_recovery();
+
+        // The mocks never wait, so their futures complete right away.
+        let _ = embassy_futures::block_on(self.radio.tick(self.time.wire()));
+        if let Some(message) = self.lora_telemetry.due(self.time) {
+            let _ = embassy_futures::block_on(self.radio.send(message(self.vehicle_state())));
+        }
+
+        if self.logic.mode >= FlightMode::ArmedLaunchImminent {
+            if let Some(message) = self.flash_telemetry.due(self.time) {
+                let _ = self.flash.write_message(message(self.vehicle_state()));
+            }
+        }
+
+        self.time = self.time.plus_millis(TIME_STEP);
+    }
+
+    /// Runs the simulation until some time after touchdown, or until the maximum duration is
+    /// exceeded.
+    pub fn run(&mut self) {
+        while self.time.as_millis() < self.config.max_duration as u64 {
+            if self.touchdown.map(|t| self.time.millis_since(t) > self.config.landed_duration).unwrap_or(false) {
+                break;
+            }
+
+            self.tick();
+        }
+    }
+
+    pub fn time(&self) -> u32 {
+        self.time.wire()
+    }
+
+    pub fn mode(&self) -> FlightMode {
+        self.logic.mode
+    }
+
+    /// True altitude above ground (m) of the simulated vehicle
+    pub fn altitude(&self) -> f32 {
+        self.altitude
+    }
+
+    /// True apogee above ground (m) of the simulated vehicle
+    pub fn apogee(&self) -> f32 {
+        self.max_altitude
+    }
+
+    /// Time (ms) at which the simulated vehicle reached its apogee
+    pub fn apogee_time(&self) -> u32 {
+        self.max_altitude_time.wire()
+    }
+
+    /// Time (ms) of touchdown, if the vehicle has landed
+    pub fn touchdown(&self) -> Option<u32> {
+        self.touchdown.map(|t| t.wire())
+    }
+
+    /// Outcome of the flight as seen by the flight computer
+    pub fn summary(&self) -> FlightSummary {
+        self.logic.summary()
+    }
+
+    /// Messages that would have been sent via LoRa, with time (ms)
+    pub fn downlink(&self) -> &[(u32, DownlinkMessage)] {
+        &self.radio.sent
+    }
+
+    /// Contents of the flash log, with the same records as on the vehicle but without the page
+    /// framing, see `flash_log::LogDecoder::from_stream`
+    pub fn flash_log(&self) -> &[u8] {
+        &self.flash.log
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nominal_flight() {
+        let mut sim = Simulation::new(SimulationConfig {
+            landed_duration: 30_000,
+            ..Default::default()
+        });
+        sim.run();
+
+        let summary = sim.summary();
+        assert!(sim.touchdown().is_some());
+        assert!(summary.modes_monotonic(), "{:?}", summary.mode_changes);
+        for mode in [FlightMode::Burn, FlightMode::Coast, FlightMode::RecoveryDrogue, FlightMode::RecoveryMain, FlightMode::Landed] {
+            assert!(summary.mode_entered(mode).is_some(), "{:?} not entered: {:?}", mode, summary.mode_changes);
+        }
+
+        let ignition = SimulationConfig::default().ignition_time;
+        assert!(summary.mode_entered(FlightMode::Burn).unwrap().abs_diff(ignition) < 500);
+
+        // Apogee is detected close to the actual one, after which drogue and main are deployed.
+        let drogue = summary.mode_entered(FlightMode::RecoveryDrogue).unwrap();
+        assert!(drogue.abs_diff(sim.apogee_time()) < 3_000, "drogue at {}, apogee at {}", drogue, sim.apogee_time());
+        assert!((summary.apogee_agl - sim.apogee()).abs() < 0.05 * sim.apogee());
+        assert!(sim.drogue_deployed && sim.main_deployed);
+        assert!(summary.mode_entered(FlightMode::Landed).unwrap() > sim.touchdown().unwrap());
+    }
+
+    #[test]
+    fn telemetry_and_log() {
+        let mut sim = Simulation::new(SimulationConfig::default());
+        sim.run();
+
+        // Telemetry is sent throughout, the flash log only covers the flight itself.
+        assert!(sim.downlink().first().unwrap().0 < 1_000);
+        assert!(sim.downlink().last().unwrap().0 > sim.touchdown().unwrap());
+        assert!(!sim.flash.messages.is_empty());
+
+        let records: Vec<_> = crate::flash_log::LogDecoder::from_stream(sim.flash_log()).collect();
+        assert_eq!(records.len(), sim.flash.messages.len());
+        assert!(records.iter().all(|r| matches!(r, Ok(crate::flash_log::LogRecord::Message(_)))));
+    }
+
+    /// Runs a simulation, returning the generated sensor data along with it.
+    fn record(config: SimulationConfig) -> (Simulation, Vec<SensorSample>) {
+        let mut sim = Simulation::new(config);
+        let mut samples = Vec::new();
+        while sim.touchdown().is_none() && sim.time() < sim.config.max_duration {
+            sim.tick();
+            samples.push(sim.sensors.clone());
+        }
+
+        (sim, samples)
+    }
+
+    #[test]
+    fn replay_matches_simulation() {
+        let (sim, samples) = record(SimulationConfig::default());
+        let summary = replay(&sim.config.settings, samples);
+
+        assert_eq!(summary.mode_changes, sim.summary().mode_changes);
+        assert_eq!(summary.apogee_agl, sim.summary().apogee_agl);
+    }
+
+    #[test]
+    fn replay_at_log_rate() {
+        // The raw sensor data in flash logs is only recorded at 100Hz.
+        let (sim, samples) = record(SimulationConfig::default());
+        let summary = replay(&sim.config.settings, samples.into_iter().step_by(10));
+
+        assert!(summary.modes_monotonic(), "{:?}", summary.mode_changes);
+        assert!((summary.apogee_agl - sim.apogee()).abs() < 0.05 * sim.apogee());
+        for mode in [FlightMode::Burn, FlightMode::RecoveryDrogue, FlightMode::RecoveryMain] {
+            let expected = sim.summary().mode_entered(mode).unwrap();
+            let replayed = summary.mode_entered(mode).unwrap();
+            assert!(replayed.abs_diff(expected) < 1_000, "{:?}: {} instead of {}", mode, replayed, expected);
+        }
+    }
+
+    /// Splits a record stream into pages the way the vehicle writes them to flash.
+    fn flash_pages(stream: &[u8]) -> Vec<u8> {
+        use crate::flash_log::{PAGE_DATA_SIZE, PAGE_SIZE};
+
+        let x25 = crc::Crc::<u16>::new(&crc::CRC_16_IBM_SDLC);
+        stream
+            .chunks(PAGE_DATA_SIZE)
+            .flat_map(|chunk| {
+                let mut page = vec![0u8; PAGE_SIZE];
+                page[1..(1 + chunk.len())].copy_from_slice(chunk);
+                let crc = x25.checksum(&page[1..(1 + PAGE_DATA_SIZE)]);
+                page[(PAGE_SIZE - 2)..].copy_from_slice(&crc.to_be_bytes());
+                page
+            })
+            .collect()
+    }
+
+    #[test]
+    fn samples_from_log() {
+        let (sim, recorded) = record(SimulationConfig::default());
+        let samples = log_samples(&flash_pages(sim.flash_log()));
+
+        let raw_messages = sim.flash.messages.iter().filter(|msg| matches!(msg, DownlinkMessage::TelemetryRawSensors(_))).count();
+        assert!(raw_messages > 0);
+        assert_eq!(samples.len(), raw_messages);
+        assert!(samples.windows(2).all(|w| w[1].time == w[0].time + 10));
+
+        for sample in &samples {
+            let expected = recorded.iter().find(|s| s.time == sample.time).unwrap();
+            let error = (sample.accelerometer1.unwrap() - expected.accelerometer1.unwrap()).norm();
+            assert!(error < 0.1, "accelerometer off by {} at {}ms", error, sample.time);
+        }
+    }
+
+    /// Replays a recorded flight from a flash dump's log region, given via `MITHRIL_REPLAY_LOG`,
+    /// e.g. `MITHRIL_REPLAY_LOG=flight.bin cargo test --lib --features std -- --ignored`
+    #[test]
+    #[ignore]
+    fn replay_recorded_log() {
+        let path = std::env::var("MITHRIL_REPLAY_LOG").expect("MITHRIL_REPLAY_LOG not set");
+        let samples = log_samples(&std::fs::read(path).unwrap());
+        assert!(!samples.is_empty());
+
+        let summary = replay(&Settings::default(), samples);
+        assert!(summary.modes_monotonic(), "{:?}", summary.mode_changes);
+        for mode in [FlightMode::Burn, FlightMode::RecoveryDrogue, FlightMode::RecoveryMain] {
+            assert!(summary.mode_entered(mode).is_some(), "{:?} not entered: {:?}", mode, summary.mode_changes);
+        }
+    }
+
+    #[test]
+    fn replay_on_pad() {
+        // Sitting on the pad for a minute, with some vibration, doesn't trigger a launch.
+        let (_, samples) = record(SimulationConfig {
+            ignition_time: u32::MAX,
+            max_duration: 60_000,
+            accelerometer_noise: 2.0,
+            ..Default::default()
+        });
+        let summary = replay(&Settings::default(), samples);
+
+        assert_eq!(summary.mode_changes, vec![(0, FlightMode::Armed)]);
+    }
+
+    #[test]
+    fn spurious_transitions() {
+        let summary = |mode_changes: Vec<(u32, FlightMode)>| FlightSummary {
+            apogee_agl: 0.0,
+            max_vertical_speed: 0.0,
+            mode_changes,
+        };
+
+        let nominal = summary(vec![(0, FlightMode::Armed), (100, FlightMode::Burn), (200, FlightMode::Coast)]);
+        assert!(nominal.modes_monotonic());
+        assert_eq!(nominal.mode_entered(FlightMode::Burn), Some(100));
+        assert_eq!(nominal.mode_entered(FlightMode::RecoveryDrogue), None);
+
+        let spurious = summary(vec![(0, FlightMode::Armed), (100, FlightMode::Burn), (150, FlightMode::Armed), (200, FlightMode::Burn)]);
+        assert!(!spurious.modes_monotonic());
+        assert_eq!(spurious.mode_entered(FlightMode::Burn), Some(100));
+    }
+}
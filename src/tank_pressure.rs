@@ -0,0 +1,149 @@
+//! Redundant tank pressure monitoring, using two analog pressure transducers on the same tank.
+//! Both are sampled via the power monitor's ADC and calibrated individually. Their readings are
+//! cross-checked, and the combined pressure is watched for overpressure and for pressure drops
+//! hinting at leaks.
+//!
+//! If the transducers disagree, the higher reading is used, erring on the side of overpressure
+//! protection. With the `engine` feature, overpressure aborts the engine controller (venting the
+//! tank), and leaks are only detected while it is holding a filled tank with all valves closed.
+//!
+//! Only built with the `tank_pressure` feature. The transducers are connected to PA0 and PB1.
+
+use embassy_stm32::peripherals::{ADC1, PA0, PB1};
+
+use defmt::*;
+
+use crate::clock::Instant;
+use crate::drivers::sensors::adc::{ChannelCalibration, Oversampled};
+use crate::drivers::sensors::PowerMonitor;
+
+// Conversion from the voltage at each pin (mV) to pressure (bar), for 0.5-4.5V, 0-100bar
+// transducers behind a 2:3 voltage divider.
+const CALIBRATIONS: [ChannelCalibration; 2] = [
+    ChannelCalibration::new(100.0 / 2667.0, -100.0 * 333.0 / 2667.0),
+    ChannelCalibration::new(100.0 / 2667.0, -100.0 * 333.0 / 2667.0),
+];
+/// Readings outside of this range (bar) indicate a disconnected or shorted transducer.
+const VALID_RANGE: (f32, f32) = (-5.0, 110.0);
+/// Maximum difference (bar) between the two transducers before they are considered inconsistent
+const MAX_DISAGREEMENT: f32 = 3.0;
+/// Pressure (bar) above which the tank is considered overpressurized
+const MAX_PRESSURE: f32 = 70.0;
+/// Interval (ms) over which the rate of change is determined
+const RATE_INTERVAL: u32 = 1000;
+/// Pressure drop (bar/s) considered a leak
+const LEAK_RATE: f32 = 0.2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Format)]
+pub struct TankPressureWarnings {
+    pub disagreement: bool,
+    pub overpressure: bool,
+    pub leak: bool,
+}
+
+pub struct TankPressure {
+    pins: (PA0, PB1),
+    samples: [Oversampled; 2],
+    readings: [Option<f32>; 2],
+    /// Pressure at the start of the current rate interval
+    rate_reference: Option<(Instant, f32)>,
+    /// Rate of change (bar/s) over the last interval
+    rate: Option<f32>,
+    warnings: TankPressureWarnings,
+}
+
+impl TankPressure {
+    pub fn init(pin_a: PA0, pin_b: PB1) -> Self {
+        Self {
+            pins: (pin_a, pin_b),
+            samples: [Oversampled::default(); 2],
+            readings: [None; 2],
+            rate_reference: None,
+            rate: None,
+            warnings: TankPressureWarnings::default(),
+        }
+    }
+
+    pub fn tick<H, L, A>(&mut self, time: Instant, power: &mut PowerMonitor<ADC1, H, L, A>) {
+        self.samples[0].add(power.read(&mut self.pins.0));
+        self.samples[1].add(power.read(&mut self.pins.1));
+
+        for i in 0..2 {
+            self.readings[i] = self.samples[i]
+                .value()
+                .and_then(|s| power.millivolts(s))
+                .map(|mv| CALIBRATIONS[i].apply(mv))
+                .filter(|p| *p > VALID_RANGE.0 && *p < VALID_RANGE.1);
+        }
+
+        let pressure = self.pressure();
+        match (self.rate_reference, pressure) {
+            (Some((t, reference)), Some(p)) if time.millis_since(t) >= RATE_INTERVAL => {
+                self.rate = Some((p - reference) * 1000.0 / time.millis_since(t) as f32);
+                self.rate_reference = Some((time, p));
+            },
+            (None, Some(p)) => self.rate_reference = Some((time, p)),
+            (_, None) => {
+                self.rate_reference = None;
+                self.rate = None;
+            },
+            _ => {},
+        }
+
+        self.update_warnings();
+    }
+
+    fn update_warnings(&mut self) {
+        #[cfg(feature = "engine")]
+        let holding = crate::engine::state() == crate::engine::EngineState::Ready;
+        #[cfg(not(feature = "engine"))]
+        let holding = true;
+
+        let warnings = TankPressureWarnings {
+            disagreement: self.disagreement(),
+            overpressure: self.pressure().map(|p| p > MAX_PRESSURE).unwrap_or(false),
+            leak: holding && self.rate.map(|r| r < -LEAK_RATE).unwrap_or(false),
+        };
+
+        if warnings != self.warnings {
+            warn!("Tank pressure warnings: {:?} (readings {:?}, rate {:?})", warnings, self.readings, self.rate);
+        }
+
+        #[cfg(feature = "engine")]
+        if warnings.overpressure && !self.warnings.overpressure {
+            crate::engine::command(crate::engine::EngineCommand::Abort);
+        }
+
+        self.warnings = warnings;
+    }
+
+    fn disagreement(&self) -> bool {
+        match self.readings {
+            [Some(a), Some(b)] => f32::max(a - b, b - a) > MAX_DISAGREEMENT,
+            _ => false,
+        }
+    }
+
+    /// Calibrated readings (bar) of the individual transducers, `None` if out of range
+    pub fn readings(&self) -> [Option<f32>; 2] {
+        self.readings
+    }
+
+    /// Combined tank pressure (bar), the higher reading if the transducers disagree
+    pub fn pressure(&self) -> Option<f32> {
+        match self.readings {
+            [Some(a), Some(b)] if self.disagreement() => Some(f32::max(a, b)),
+            [Some(a), Some(b)] => Some((a + b) / 2.0),
+            [a, b] => a.or(b),
+        }
+    }
+
+    /// Rate of change (bar/s) of the combined pressure
+    pub fn rate(&self) -> Option<f32> {
+        self.rate
+    }
+
+    pub fn warnings(&self) -> TankPressureWarnings {
+        self.warnings
+    }
+}
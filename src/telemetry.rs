@@ -0,0 +1,86 @@
+//! Schedules for the telemetry messages sent via USB and LoRa and stored in flash. These are
+//! shared between the vehicle and the simulation.
+
+use serde::{Deserialize, Serialize};
+
+use shared_types::*;
+
+use crate::schedule::{Periodic, TelemetrySchedule};
+
+pub fn usb_schedule() -> TelemetrySchedule<3> {
+    TelemetrySchedule::new([
+        //(Periodic::new(1000, 0), |vs| DownlinkMessage::TelemetryGPS(vs.into())),
+        (Periodic::new(50, 0), |vs| DownlinkMessage::TelemetryRawSensors(vs.into())),
+        (Periodic::new(50, 10), |vs| DownlinkMessage::TelemetryMain(vs.into())),
+        (Periodic::new(50, 30), |vs| DownlinkMessage::TelemetryDiagnostics(vs.into())),
+    ])
+}
+
+/// Selection of LoRa telemetry, to spend the downlink's bandwidth on whatever is needed at the
+/// moment. All profiles send at most one message per 25ms LoRa slot and stay clear of the uplink
+/// window (100ms into every 200ms), assuming the default `LinkConfig`.
+///
+/// Selected via the console or uplinked by the ground station, see `Radio::queue_downlink_profile`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DownlinkProfile {
+    /// Position and health only, e.g. for long waits on the pad
+    Minimal,
+    #[default]
+    Standard,
+    /// Fast telemetry at 20Hz, for flight
+    Fast,
+    /// Diagnostics and bus status at 5Hz, for hardware tests
+    Debug,
+}
+
+impl DownlinkProfile {
+    pub const ALL: [DownlinkProfile; 4] = [
+        DownlinkProfile::Minimal,
+        DownlinkProfile::Standard,
+        DownlinkProfile::Fast,
+        DownlinkProfile::Debug,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Minimal => "minimal",
+            Self::Standard => "standard",
+            Self::Fast => "fast",
+            Self::Debug => "debug",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|p| p.name() == name).copied()
+    }
+}
+
+pub fn lora_schedule(profile: DownlinkProfile) -> TelemetrySchedule<6> {
+    let timing = match profile {
+        DownlinkProfile::Minimal => [(1000, 0), (1000, 200), (5000, 400), (5000, 600), (5000, 800), (500, 50)],
+        DownlinkProfile::Standard => [(1000, 0), (1000, 200), (1000, 400), (1000, 600), (1000, 800), (100, 50)],
+        DownlinkProfile::Fast => [(1000, 0), (1000, 200), (1000, 400), (1000, 600), (1000, 800), (50, 25)],
+        DownlinkProfile::Debug => [(1000, 175), (200, 0), (1000, 75), (1000, 25), (200, 150), (200, 50)],
+    };
+    let periodic = |i: usize| Periodic::new(timing[i].0, timing[i].1);
+
+    TelemetrySchedule::new([
+        (periodic(0), |vs| DownlinkMessage::TelemetryGPS(vs.into())),
+        (periodic(1), |vs| DownlinkMessage::TelemetryDiagnostics(vs.into())),
+        (periodic(2), |vs| DownlinkMessage::TelemetryPressures(vs.into())),
+        (periodic(3), |vs| DownlinkMessage::TelemetryKalman(vs.into())),
+        (periodic(4), |vs| DownlinkMessage::TelemetryBus(vs.into())),
+        (periodic(5), |vs| DownlinkMessage::TelemetryFastCompressed(vs.into())),
+    ])
+}
+
+/// Everything is offset a little so that flash message writes don't coincide with LoRa message
+/// writes.
+pub fn flash_schedule() -> TelemetrySchedule<4> {
+    TelemetrySchedule::new([
+        (Periodic::new(100, 97), |vs| DownlinkMessage::TelemetryGPS(vs.into())),
+        (Periodic::new(100, 47), |vs| DownlinkMessage::TelemetryDiagnostics(vs.into())),
+        (Periodic::new(50, 17), |vs| DownlinkMessage::TelemetryMain(vs.into())),
+        (Periodic::new(10, 2), |vs| DownlinkMessage::TelemetryRawSensors(vs.into())),
+    ])
+}
@@ -0,0 +1,129 @@
+//! Monitoring of the microcontroller's die temperature and analog supply voltage, both measured
+//! using the ADC's internal channels (see `drivers/sensors/adc.rs`). Waiting on the pad in direct
+//! sunlight can heat up the electronics bay considerably, so if the die gets too hot before
+//! arming, the radio's transmit power is reduced until it has cooled down again. Once armed, the
+//! link takes priority, so there is no derating from then on.
+//!
+//! A sagging supply is only reported, since there is nothing to be done about it in software.
+
+use serde::{Deserialize, Serialize};
+
+use defmt::*;
+
+use shared_types::{FlightMode, TransmitPower};
+
+use crate::traits::TelemetryRadio;
+
+/// Transmit power while derated
+const DERATED_TRANSMIT_POWER: TransmitPower = TransmitPower::P14dBm;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ThermalConfig {
+    /// Die temperature (C) above which a warning is logged
+    pub warn_temperature: f32,
+    /// Die temperature (C) above which the transmit power is reduced before arming
+    pub derate_temperature: f32,
+    /// Amount (C) the temperature has to drop below the thresholds again to recover
+    pub hysteresis: f32,
+    /// Analog supply voltage (mV) below which a warning is logged
+    pub min_supply_voltage: f32,
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        Self {
+            warn_temperature: 70.0,
+            derate_temperature: 80.0,
+            hysteresis: 5.0,
+            min_supply_voltage: 3100.0,
+        }
+    }
+}
+
+pub struct ThermalMonitor {
+    config: ThermalConfig,
+    hot: bool,
+    supply_low: bool,
+    /// Transmit power to restore after derating, if currently derated
+    derated_from: Option<TransmitPower>,
+    max_temperature: Option<f32>,
+    min_supply_voltage: Option<f32>,
+}
+
+impl ThermalMonitor {
+    pub fn new(config: ThermalConfig) -> Self {
+        Self {
+            config,
+            hot: false,
+            supply_low: false,
+            derated_from: None,
+            max_temperature: None,
+            min_supply_voltage: None,
+        }
+    }
+
+    /// Takes the die temperature (C) and analog supply voltage (mV).
+    pub fn tick<R: TelemetryRadio>(
+        &mut self,
+        mode: FlightMode,
+        temperature: Option<f32>,
+        supply_voltage: Option<f32>,
+        radio: &mut R,
+    ) {
+        if let Some(voltage) = supply_voltage {
+            self.min_supply_voltage = Some(self.min_supply_voltage.map(|v| f32::min(v, voltage)).unwrap_or(voltage));
+
+            let low = voltage < self.config.min_supply_voltage;
+            if low && !self.supply_low {
+                warn!("Supply voltage low: {}mV", voltage);
+            }
+            self.supply_low = low;
+        }
+
+        let Some(temperature) = temperature else {
+            return;
+        };
+        self.max_temperature = Some(self.max_temperature.map(|t| f32::max(t, temperature)).unwrap_or(temperature));
+
+        let threshold = if self.hot { self.config.warn_temperature - self.config.hysteresis } else { self.config.warn_temperature };
+        let hot = temperature > threshold;
+        if hot && !self.hot {
+            warn!("Die temperature high: {}C", temperature);
+        }
+        self.hot = hot;
+
+        // Arming switches to maximum transmit power, which is kept from then on.
+        if mode >= FlightMode::Armed {
+            self.derated_from = None;
+            return;
+        }
+
+        match self.derated_from {
+            None if temperature > self.config.derate_temperature => {
+                warn!("Die temperature {}C, reducing transmit power.", temperature);
+                self.derated_from = Some(radio.transmit_power());
+                radio.set_transmit_power(DERATED_TRANSMIT_POWER);
+            },
+            Some(power) if temperature < self.config.derate_temperature - self.config.hysteresis => {
+                info!("Die temperature {}C, restoring transmit power.", temperature);
+                radio.set_transmit_power(power);
+                self.derated_from = None;
+            },
+            _ => {},
+        }
+    }
+
+    pub fn derated(&self) -> bool {
+        self.derated_from.is_some()
+    }
+
+    /// Highest die temperature (C) since startup
+    pub fn max_temperature(&self) -> Option<f32> {
+        self.max_temperature
+    }
+
+    /// Lowest analog supply voltage (mV) since startup
+    pub fn min_supply_voltage(&self) -> Option<f32> {
+        self.min_supply_voltage
+    }
+}
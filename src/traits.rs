@@ -0,0 +1,109 @@
+//! Interfaces between the main loop and the radio, flash, GPS and power monitoring, so these can
+//! be replaced by mock implementations (see `mocks.rs`) when testing flight logic on the host.
+//! Sensors with alternative parts across hardware revisions (magnetometer) also get a trait here.
+
+use nalgebra::Vector3;
+
+use shared_types::can::BatteryTelemetryMessage;
+use shared_types::*;
+
+const LOW_BATTERY_THRESHOLD: u16 = 7000;
+const NO_BATTERY_THRESHOLD: u16 = 5000;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum BatteryStatus{
+    Low,
+    High,
+    NoBatteryAttached
+}
+
+impl BatteryStatus {
+    pub fn from_voltage(voltage: u16) -> Self {
+        #[allow(overlapping_range_endpoints)] // we can't use experimental features yet
+        match voltage {
+            LOW_BATTERY_THRESHOLD.. => BatteryStatus::High,
+            NO_BATTERY_THRESHOLD..=LOW_BATTERY_THRESHOLD => BatteryStatus::Low,
+            _ => BatteryStatus::NoBatteryAttached
+        }
+    }
+}
+
+/// LoRa telemetry link, as seen by the vehicle.
+#[allow(async_fn_in_trait)]
+pub trait TelemetryRadio {
+    type Error: core::fmt::Debug;
+
+    /// Sends a downlink message. Messages may be dropped if the link is not ready.
+    async fn send(&mut self, msg: DownlinkMessage) -> Result<(), Self::Error>;
+    /// Advances the link, returning any command received from the ground station. Takes the
+    /// compact wire time (see `Instant::wire`), since the link protocol's timing is based on it.
+    async fn tick(&mut self, time: u32) -> Option<Command>;
+    fn set_transmit_power(&mut self, power: TransmitPower);
+    fn set_max_transmit_power(&mut self);
+    fn transmit_power(&self) -> TransmitPower;
+    fn rssi(&self) -> u8;
+}
+
+/// Flash storage for logs and settings. Requests are processed in the background, so errors only
+/// mean that a request could not be queued.
+#[allow(async_fn_in_trait)]
+pub trait LogStorage {
+    async fn tick(&mut self);
+    /// Current write position, i.e. the amount of data logged so far
+    fn pointer(&self) -> u32;
+    /// Total size (bytes) of the storage, depending on the chip that is fitted
+    fn size(&self) -> u32;
+    fn write_message(&mut self, msg: DownlinkMessage) -> Result<(), ()>;
+    fn write_settings(&mut self, settings: Settings) -> Result<(), ()>;
+    /// Sends the contents of the given flash region via USB.
+    fn read(&mut self, address: u32, size: u32) -> Result<(), ()>;
+    /// Prints a hex dump of the given flash region on the USB console.
+    fn dump(&mut self, address: u32, size: u32) -> Result<(), ()>;
+    fn erase(&mut self) -> Result<(), ()>;
+    /// Checks that the settings sector can be read and passes its checksum, and that a scratch
+    /// copy of the settings written to the next slot reads back. The result is printed on the USB
+    /// console, and available via `self_test_result` once done.
+    fn self_test(&mut self) -> Result<(), ()>;
+    /// Returns the result of the last self test as (read, write), once after it completed.
+    fn self_test_result(&mut self) -> Option<(bool, bool)>;
+}
+
+/// Magnetometer, implemented by the drivers for each supported part (see `board.rs`).
+#[allow(async_fn_in_trait)]
+pub trait Magnetometer {
+    async fn tick(&mut self);
+    fn set_offset(&mut self, offset: Vector3<f32>);
+    /// Latest reading (uT) in the vehicle frame, with the offset applied
+    fn magnetometer(&self) -> Option<Vector3<f32>>;
+    /// Checks that the sensor still responds with the expected ID.
+    async fn self_test(&mut self) -> bool;
+}
+
+/// GPS receiver.
+pub trait GpsReceiver {
+    fn datum(&mut self) -> Option<GPSDatum>;
+    /// Returns the current datum only if it has been received since this was called last.
+    fn new_datum(&mut self) -> Option<GPSDatum>;
+    fn latitude(&mut self) -> Option<f32>;
+    fn longitude(&mut self) -> Option<f32>;
+    fn altitude(&mut self) -> Option<f32>;
+    fn fix(&mut self) -> Option<GPSFixType>;
+    fn hdop(&mut self) -> Option<u16>;
+    fn num_satellites(&mut self) -> Option<u8>;
+}
+
+/// Battery and arming voltage monitoring. Voltages are in mV.
+pub trait PowerSupply {
+    fn tick(&mut self);
+    fn battery_voltage(&self) -> Option<u16>;
+    fn battery_status(&self) -> Option<BatteryStatus>;
+    fn battery_current(&self) -> Option<i32>;
+    fn arm_voltage(&self) -> Option<u16>;
+    fn charge_voltage(&self) -> Option<u16>;
+    fn temperature(&self) -> Option<f32>;
+    fn handle_battery_can_msg(&mut self, msg: BatteryTelemetryMessage);
+
+    fn armed(&self) -> bool {
+        self.arm_voltage().map(|v| v > 50).unwrap_or(false)
+    }
+}
@@ -0,0 +1,94 @@
+//! Umbilical and breakwire inputs, used as an additional launch detection signal. The umbilical
+//! connector pulls its presence pin low while plugged in, and the breakwire pulls its pin low
+//! until it is torn at liftoff. The umbilical carries USB, which provides external power and the
+//! usual binary protocol and console while on the pad.
+//!
+//! Either input may be left unconnected, so only inputs that were seen connected after arming
+//! count. Once all of them are released for `DEBOUNCE` ms while armed, launch is signalled,
+//! independent of the accelerometer-based detection in the state estimator.
+//!
+//! Only built with the `umbilical` feature. Pins differ between board revisions, see `board.rs`.
+
+use embassy_stm32::gpio::{AnyPin, Input};
+
+use defmt::*;
+
+use shared_types::FlightMode;
+
+use crate::clock::Instant;
+
+/// Time (ms) all inputs have to stay released before launch is signalled
+const DEBOUNCE: u32 = 20;
+
+pub struct Umbilical {
+    presence: Input<'static, AnyPin>,
+    breakwire: Input<'static, AnyPin>,
+    /// Whether the umbilical was connected since arming
+    connected_seen: bool,
+    /// Whether the breakwire was intact since arming
+    intact_seen: bool,
+    /// Time all inputs were first seen released
+    released_since: Option<Instant>,
+    launch_signalled: bool,
+}
+
+impl Umbilical {
+    pub fn init(presence: Input<'static, AnyPin>, breakwire: Input<'static, AnyPin>) -> Self {
+        Self {
+            presence,
+            breakwire,
+            connected_seen: false,
+            intact_seen: false,
+            released_since: None,
+            launch_signalled: false,
+        }
+    }
+
+    pub fn connected(&self) -> bool {
+        self.presence.is_low()
+    }
+
+    pub fn breakwire_intact(&self) -> bool {
+        self.breakwire.is_low()
+    }
+
+    /// Returns true once when launch is detected.
+    pub fn tick(&mut self, time: Instant, mode: FlightMode) -> bool {
+        if mode < FlightMode::Armed {
+            self.connected_seen = false;
+            self.intact_seen = false;
+            self.released_since = None;
+            self.launch_signalled = false;
+            return false;
+        }
+
+        if mode > FlightMode::ArmedLaunchImminent || self.launch_signalled {
+            return false;
+        }
+
+        let (connected, intact) = (self.connected(), self.breakwire_intact());
+        self.connected_seen |= connected;
+        self.intact_seen |= intact;
+
+        let released = (self.connected_seen || self.intact_seen)
+            && !(self.connected_seen && connected)
+            && !(self.intact_seen && intact);
+        if !released {
+            self.released_since = None;
+            return false;
+        }
+
+        let since = *self.released_since.get_or_insert(time);
+        if time.millis_since(since) < DEBOUNCE {
+            return false;
+        }
+
+        info!(
+            "Launch detected via umbilical (disconnected: {}, breakwire torn: {})",
+            self.connected_seen,
+            self.intact_seen
+        );
+        self.launch_signalled = true;
+        true
+    }
+}
@@ -1,7 +1,9 @@
 //! USB serial link implementation, handling regular telemetry, log messsages
-//! and uplink commands.
+//! and uplink commands, as well as the text console (see `usb_console.rs`).
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use heapless::Vec;
 use embassy_executor::Spawner;
 use embassy_stm32::{peripherals::{PA12, PA11, USB_OTG_FS}, usb_otg::Driver};
 use embassy_stm32::bind_interrupts;
@@ -14,6 +16,18 @@ use static_cell::StaticCell;
 
 use shared_types::*;
 
+use crate::errors::{report, ErrorKind, Subsystem};
+#[cfg(feature = "gcs")]
+use crate::capture::*;
+#[cfg(feature = "gcs")]
+use crate::events::*;
+use crate::framing::*;
+#[cfg(all(feature = "hil", not(feature = "gcs")))]
+use crate::hil::*;
+#[cfg(not(feature = "gcs"))]
+use crate::sensor_stats::*;
+use crate::usb_console::*;
+
 static EP_OUT_BUFFER: StaticCell<[u8; 256]> = StaticCell::new();
 static CONFIG_DESCRIPTOR_BUFFER: StaticCell<[u8; 256]> = StaticCell::new();
 static BOS_DESCRIPTOR_BUFFER: StaticCell<[u8; 256]> = StaticCell::new();
@@ -25,6 +39,28 @@ static CDC_ACM_STATE: StaticCell<State> = StaticCell::new();
 static UPLINK_CHANNEL: StaticCell<Channel::<CriticalSectionRawMutex, UplinkMessage, 3>> = StaticCell::new();
 static DOWNLINK_CHANNEL: StaticCell<Channel::<CriticalSectionRawMutex, DownlinkMessage, 3>> = StaticCell::new();
 static FLASH_DOWNLINK_CHANNEL: StaticCell<Channel::<CriticalSectionRawMutex, DownlinkMessage, 3>> = StaticCell::new();
+static CONSOLE_INPUT_CHANNEL: StaticCell<Channel::<CriticalSectionRawMutex, ConsoleCommand, 3>> = StaticCell::new();
+static CONSOLE_OUTPUT_CHANNEL: StaticCell<Channel::<CriticalSectionRawMutex, ConsoleLine, 16>> = StaticCell::new();
+
+/// Whether the serial port is currently used for the text console instead of the binary protocol.
+static CONSOLE_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Binary frames received from the host.
+enum HostFrame {
+    Uplink(UplinkMessage),
+    #[cfg(all(feature = "hil", not(feature = "gcs")))]
+    Hil(HilFrame),
+}
+
+impl HostFrame {
+    fn decode(payload: &[u8]) -> Result<Self, FrameError> {
+        match payload.split_first() {
+            #[cfg(all(feature = "hil", not(feature = "gcs")))]
+            Some((&HIL_FRAME_TAG, frame)) => postcard::from_bytes(frame).map(Self::Hil),
+            _ => postcard::from_bytes(payload).map(Self::Uplink),
+        }.map_err(|_| FrameError::Serialization)
+    }
+}
 
 bind_interrupts!(struct Irqs {
     OTG_FS => embassy_stm32::usb_otg::InterruptHandler<USB_OTG_FS>;
@@ -34,11 +70,14 @@ bind_interrupts!(struct Irqs {
 pub struct UsbHandle {
     downlink_sender: channel::Sender<'static, CriticalSectionRawMutex, DownlinkMessage, 3>,
     uplink_receiver: channel::Receiver<'static, CriticalSectionRawMutex, UplinkMessage, 3>,
+    console_receiver: channel::Receiver<'static, CriticalSectionRawMutex, ConsoleCommand, 3>,
+    console_sender: channel::Sender<'static, CriticalSectionRawMutex, ConsoleLine, 16>,
 }
 
 /// Handle given to flash implementation to allow faster flash download
 pub struct FlashUsbHandle {
     downlink_sender: channel::Sender<'static, CriticalSectionRawMutex, DownlinkMessage, 3>,
+    console_sender: channel::Sender<'static, CriticalSectionRawMutex, ConsoleLine, 16>,
 }
 
 impl UsbHandle {
@@ -46,6 +85,8 @@ impl UsbHandle {
         let uplink_channel = UPLINK_CHANNEL.init(Channel::new());
         let downlink_channel = DOWNLINK_CHANNEL.init(Channel::new());
         let flash_downlink_channel = FLASH_DOWNLINK_CHANNEL.init(Channel::new());
+        let console_input_channel = CONSOLE_INPUT_CHANNEL.init(Channel::new());
+        let console_output_channel = CONSOLE_OUTPUT_CHANNEL.init(Channel::new());
 
         let mut config = embassy_stm32::usb_otg::Config::default();
         config.vbus_detection = false;
@@ -80,16 +121,24 @@ impl UsbHandle {
 
         let spawner = Spawner::for_current_executor().await;
         spawner.spawn(run_usb(usb)).unwrap();
-        spawner.spawn(handle_usb_downlink(usb_sender, downlink_channel.receiver(), flash_downlink_channel.receiver())).unwrap();
-        spawner.spawn(handle_usb_uplink(usb_receiver, uplink_channel.sender())).unwrap();
+        spawner.spawn(handle_usb_downlink(
+            usb_sender,
+            downlink_channel.receiver(),
+            flash_downlink_channel.receiver(),
+            console_output_channel.receiver(),
+        )).unwrap();
+        spawner.spawn(handle_usb_uplink(usb_receiver, uplink_channel.sender(), console_input_channel.sender())).unwrap();
 
         let usb_handle = Self {
             downlink_sender: downlink_channel.sender(),
             uplink_receiver: uplink_channel.receiver(),
+            console_receiver: console_input_channel.receiver(),
+            console_sender: console_output_channel.sender(),
         };
 
         let flash_usb_handle = FlashUsbHandle {
             downlink_sender: downlink_channel.sender(),
+            console_sender: console_output_channel.sender(),
         };
 
         (usb_handle, flash_usb_handle)
@@ -104,12 +153,33 @@ impl UsbHandle {
     pub fn next_uplink_message(&mut self) -> Option<UplinkMessage> {
         self.uplink_receiver.try_receive().ok()
     }
+
+    pub fn next_console_command(&mut self) -> Option<ConsoleCommand> {
+        self.console_receiver.try_receive().ok()
+    }
+
+    /// Prints a line on the console. Lines are silently dropped if the console is not in use.
+    pub fn console_print(&mut self, args: core::fmt::Arguments) {
+        if CONSOLE_ACTIVE.load(Ordering::Relaxed) {
+            let mut line = ConsoleLine::new();
+            let _ = line.write_fmt(args);
+            let _ = line.push_str("\r\n");
+            let _ = self.console_sender.try_send(line);
+        }
+    }
 }
 
 impl FlashUsbHandle {
     pub async fn send_message(&mut self, msg: DownlinkMessage) {
         let _ = self.downlink_sender.try_send(msg);
     }
+
+    pub async fn console_print(&mut self, mut line: ConsoleLine) {
+        if CONSOLE_ACTIVE.load(Ordering::Relaxed) {
+            let _ = line.push_str("\r\n");
+            self.console_sender.send(line).await;
+        }
+    }
 }
 
 #[embassy_executor::task]
@@ -129,36 +199,92 @@ async fn write_message(class: &mut Sender<'static, Driver<'static, USB_OTG_FS>>,
     Ok(())
 }
 
+/// Encodes the next queued ground station event, captured or recorded packet, if any.
+#[cfg(feature = "gcs")]
+fn next_tagged_frame() -> Option<Result<heapless::Vec<u8, MAX_FRAME_SIZE>, FrameError>> {
+    if let Ok(event) = EVENT_CHANNEL.try_receive() {
+        Some(encode_frame(&(EVENT_FRAME_TAG, event)))
+    } else if let Ok(packet) = CAPTURE_CHANNEL.try_receive() {
+        Some(encode_frame(&(CAPTURE_FRAME_TAG, packet)))
+    } else if let Ok(recording) = RECORD_CHANNEL.try_receive() {
+        Some(encode_frame(&(RECORD_FRAME_TAG, recording)))
+    } else {
+        None
+    }
+}
+
+/// Encodes the next queued sensor statistics, if any.
+#[cfg(not(feature = "gcs"))]
+fn next_tagged_frame() -> Option<Result<heapless::Vec<u8, MAX_FRAME_SIZE>, FrameError>> {
+    SENSOR_STATS_CHANNEL.try_receive().ok().map(|stats| encode_frame(&(SENSOR_STATS_FRAME_TAG, stats)))
+}
+
 #[embassy_executor::task]
 async fn handle_usb_downlink(
     mut class: Sender<'static, Driver<'static, USB_OTG_FS>>,
     downlink_receiver: embassy_sync::channel::Receiver<'static, CriticalSectionRawMutex, DownlinkMessage, 3>,
-    flash_downlink_receiver: embassy_sync::channel::Receiver<'static, CriticalSectionRawMutex, DownlinkMessage, 3>
+    flash_downlink_receiver: embassy_sync::channel::Receiver<'static, CriticalSectionRawMutex, DownlinkMessage, 3>,
+    console_receiver: embassy_sync::channel::Receiver<'static, CriticalSectionRawMutex, ConsoleLine, 16>,
 ) -> ! {
     loop {
         class.wait_connection().await;
 
-        let msg = if let Ok(msg) = downlink_receiver.try_receive() {
-            msg
+        // In console mode, we only send text and discard binary messages.
+        if CONSOLE_ACTIVE.load(Ordering::Relaxed) {
+            while let Ok(_) = downlink_receiver.try_receive() {}
+            while let Ok(_) = flash_downlink_receiver.try_receive() {}
+            #[cfg(feature = "gcs")]
+            while let Ok(_) = EVENT_CHANNEL.try_receive() {}
+            #[cfg(feature = "gcs")]
+            while let Ok(_) = CAPTURE_CHANNEL.try_receive() {}
+            #[cfg(feature = "gcs")]
+            while let Ok(_) = RECORD_CHANNEL.try_receive() {}
+            #[cfg(not(feature = "gcs"))]
+            while let Ok(_) = SENSOR_STATS_CHANNEL.try_receive() {}
+
+            if let Ok(line) = console_receiver.try_receive() {
+                if let Err(TimeoutError) = with_timeout(Duration::from_millis(10), write_message(&mut class, line.as_bytes())).await {
+                    report(Subsystem::Usb, ErrorKind::Timeout, "writing console output");
+                }
+            } else {
+                Timer::after(Duration::from_millis(1)).await;
+            }
+
+            continue;
+        }
+
+        // Console output left over from the last session is no longer needed.
+        while let Ok(_) = console_receiver.try_receive() {}
+
+        let frame = if let Ok(msg) = downlink_receiver.try_receive() {
+            encode_frame(&msg)
         } else if let Ok(msg) = flash_downlink_receiver.try_receive() {
-            msg
+            encode_frame(&msg)
+        } else if let Some(frame) = next_tagged_frame() {
+            frame
         } else {
             Timer::after(Duration::from_millis(1)).await;
             continue;
         };
 
-        let serialized = msg.serialize().unwrap_or_default();
+        let serialized = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                report(Subsystem::Usb, e, "encoding downlink frame");
+                continue;
+            }
+        };
 
         match with_timeout(Duration::from_millis(10), write_message(&mut class, &serialized)).await {
             Ok(Ok(())) => {}
             Ok(Err(EndpointError::BufferOverflow)) => {
-                defmt::error!("buffer overflow");
+                report(Subsystem::Usb, ErrorKind::Overflow, "writing downlink frame");
             },
             Ok(Err(EndpointError::Disabled)) => {
-                defmt::error!("disabled");
+                report(Subsystem::Usb, ErrorKind::Bus, "writing downlink frame");
             }
             Err(TimeoutError) => {
-                defmt::error!("timeout");
+                report(Subsystem::Usb, ErrorKind::Timeout, "writing downlink frame");
                 Timer::after(Duration::from_millis(1000)).await;
                 // Clear the queue
                 while let Ok(_) = downlink_receiver.try_receive() {}
@@ -167,38 +293,68 @@ async fn handle_usb_downlink(
     }
 }
 
+fn is_line_end(byte: u8) -> bool {
+    byte == b'\r' || byte == b'\n'
+}
+
+fn trim_line_ends(line: &[u8]) -> &[u8] {
+    let start = line.iter().position(|b| !is_line_end(*b)).unwrap_or(line.len());
+    &line[start..]
+}
+
 #[embassy_executor::task]
 async fn handle_usb_uplink(
     mut class: Receiver<'static, Driver<'static, USB_OTG_FS>>,
-    uplink_sender: embassy_sync::channel::Sender<'static, CriticalSectionRawMutex, UplinkMessage, 3>
+    uplink_sender: embassy_sync::channel::Sender<'static, CriticalSectionRawMutex, UplinkMessage, 3>,
+    console_sender: embassy_sync::channel::Sender<'static, CriticalSectionRawMutex, ConsoleCommand, 3>,
 ) -> ! {
-    const UPLINK_BUFFER_SIZE: usize = 512;
-
-    let mut uplink_buffer: Vec<u8, UPLINK_BUFFER_SIZE> = Vec::new();
+    let mut decoder = FrameDecoder::new();
     let mut packet_buffer: [u8; 64] = [0; 64];
 
     loop {
         class.wait_connection().await;
 
         match class.read_packet(&mut packet_buffer).await {
-            Ok(n) => {
-                let packet = &packet_buffer[..usize::min(n, UPLINK_BUFFER_SIZE - uplink_buffer.len())];
-                let _ = uplink_buffer.extend_from_slice(packet);
+            Ok(n) => for byte in &packet_buffer[..n] {
+                let line_end = is_line_end(*byte);
+                let line = trim_line_ends(decoder.pending());
+
+                // Lines of plain text are console commands. Binary frames are terminated by a
+                // zero byte instead, and never consist of printable characters only, since they
+                // start with the protocol version.
+                if line_end && !line.is_empty() && is_console_line(line) {
+                    CONSOLE_ACTIVE.store(true, Ordering::Relaxed);
+                    let line = core::str::from_utf8(line).unwrap_or_default();
+                    match ConsoleCommand::parse(line) {
+                        Some(ConsoleCommand::Exit) => CONSOLE_ACTIVE.store(false, Ordering::Relaxed),
+                        Some(cmd) => if console_sender.try_send(cmd).is_err() {
+                            report(Subsystem::Usb, ErrorKind::QueueFull, "queueing console command");
+                        },
+                        None => {}
+                    }
 
-                if uplink_buffer.len() >= UPLINK_BUFFER_SIZE {
-                    uplink_buffer.truncate(0);
+                    decoder.reset();
+                    continue;
                 }
 
-                match postcard::take_from_bytes_cobs::<UplinkMessage>(&mut uplink_buffer.clone()) {
-                    Ok((msg, rest)) => {
-                        uplink_buffer = Vec::from_slice(rest).unwrap_or_default();
+                // Line endings are only stripped once it is clear they aren't part of a binary
+                // frame, in which they are valid COBS code bytes. Until then, only the last one
+                // of an empty line or a CRLF line ending is kept.
+                if line_end && line.is_empty() {
+                    decoder.reset();
+                } else if *byte == 0 {
+                    decoder.discard_prefix(is_line_end);
+                }
+
+                match decoder.push_with(*byte, HostFrame::decode) {
+                    Some(Ok(HostFrame::Uplink(msg))) => {
+                        CONSOLE_ACTIVE.store(false, Ordering::Relaxed);
                         uplink_sender.send(msg).await;
                     },
-                    Err(_) => {
-                        if uplink_buffer.iter().position(|b| *b == 0).is_some() {
-                            uplink_buffer.truncate(0);
-                        }
-                    }
+                    #[cfg(all(feature = "hil", not(feature = "gcs")))]
+                    Some(Ok(HostFrame::Hil(frame))) => HIL_CHANNEL.send(frame).await,
+                    Some(Err(e)) => report(Subsystem::Usb, e, "decoding uplink frame"),
+                    None => {}
                 }
             },
             Err(_e) => {
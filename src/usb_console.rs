@@ -0,0 +1,381 @@
+//! Text-based command console on the USB serial port, allowing bench work without a ground
+//! station. Connect using any serial terminal and type `help` for a list of commands.
+//!
+//! The console shares the CDC ACM interface with the regular binary protocol, since the STM32F401
+//! does not have enough USB endpoints for a second one. Receiving a line of plain text switches
+//! the port to console mode, in which no binary telemetry is sent. Receiving a valid binary
+//! message, or the `exit` command, switches back.
+
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+use nalgebra::Vector3;
+
+use defmt::Format;
+
+use crate::buzzer::Melody;
+use crate::lora::LinkConfig;
+#[cfg(feature = "gcs")]
+use crate::frontend::FrontendCommand;
+#[cfg(feature = "gcs")]
+use crate::sequence::SequenceCommand;
+#[cfg(all(feature = "servo", not(feature = "gcs")))]
+use crate::servo::ServoCommand;
+#[cfg(not(feature = "gcs"))]
+use crate::parameters::Parameter;
+use crate::telemetry::DownlinkProfile;
+
+pub const CONSOLE_LINE_LENGTH: usize = 128;
+pub type ConsoleLine = String<CONSOLE_LINE_LENGTH>;
+
+pub const HELP_TEXT: &[&str] = &[
+    "status                  show vehicle status",
+    "get <param>             show parameter value",
+    "set <param> <x> <y> <z> set parameter value (not persisted until 'save')",
+    "save                    write calibration and parameters to flash and reboot",
+    "sensors <on|off>        toggle live sensor view",
+    "flash                   show flash usage and wear",
+    "dump <address> <len>    hex dump of flash contents",
+    "summary                 show summary of the last flight",
+    "calibrate <gyro|acc>    determine sensor offsets, vehicle has to be upright and stationary",
+    "calibration             show stored sensor calibration",
+    "calibration clear       reset stored sensor calibration to defaults",
+    "play <melody>           play buzzer melody (startup, hwarmed, armed, landed, warning, ...)",
+    "volume <0-100>          set buzzer volume",
+    "quiet <on|off>          only play warnings and arming beeps",
+    "gps assist <lat> <lon> <alt> <unix time>  send rough position and time to GPS",
+    "reboot                  reboot flight computer",
+    "bootloader              reboot to DFU bootloader",
+    "exit                    return to binary protocol",
+];
+
+pub const RADIO_HELP_TEXT: &[&str] = &[
+    "scan                    measure noise on all LoRa channels, pausing telemetry",
+    "link                    show LoRa link timing",
+    "link <msg> <ul> <ofs>   set message interval, uplink interval and uplink offset (ms)",
+    "hops                    show the LoRa hop sequence",
+    "blacklist <ch>...       exclude LoRa channels from hopping, 'none' to clear",
+    "radio                   show LoRa transceiver errors, statistics and registers",
+    "abort                   safe the vehicle before launch, until it is armed again",
+    "downlink <profile>      select LoRa telemetry (minimal, standard, fast, debug)",
+    "note <text>             add a note to the flight log",
+    "findme <on|off>         sound the recovery siren and strobe",
+    "selftest                check sensors, flash, arm voltage and buzzer",
+];
+
+#[cfg(all(feature = "gcs", not(feature = "relay")))]
+pub const RELAY_HELP_TEXT: &[&str] = &[
+    "relay <on|off>          receive downlink via a relay instead of directly",
+];
+
+#[cfg(all(feature = "loadcell", not(feature = "gcs")))]
+pub const LOADCELL_HELP_TEXT: &[&str] = &[
+    "loadcell                show load cell reading",
+    "loadcell tare           zero the unloaded load cell",
+    "loadcell cal <N>        calibrate with a known force applied",
+    "loadcell stream <on|off> print every sample (time, raw, N)",
+];
+
+/// Parameters accessible via `get`/`set`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsoleParameter {
+    GyroOffset,
+    AccOffset,
+    Acc2Offset,
+    MagOffset,
+}
+
+impl ConsoleParameter {
+    pub const ALL: [ConsoleParameter; 4] = [
+        ConsoleParameter::GyroOffset,
+        ConsoleParameter::AccOffset,
+        ConsoleParameter::Acc2Offset,
+        ConsoleParameter::MagOffset,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::GyroOffset => "gyro_offset",
+            Self::AccOffset => "acc_offset",
+            Self::Acc2Offset => "acc2_offset",
+            Self::MagOffset => "mag_offset",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|p| p.name() == name).copied()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Calibration {
+    Gyroscope,
+    Accelerometer,
+}
+
+#[cfg(all(feature = "servo", not(feature = "gcs")))]
+pub const SERVO_HELP_TEXT: &[&str] = &[
+    "servo <n> <0-1>         move servo to position (only while armed)",
+    "servo failsafe          move all servos to failsafe positions",
+];
+
+#[cfg(all(feature = "engine", not(feature = "gcs")))]
+pub const ENGINE_HELP_TEXT: &[&str] = &[
+    "engine                  show engine controller state",
+    "engine <command>        fill, hold, vent, fire, abort or safe",
+];
+
+#[cfg(feature = "gcs")]
+pub const ENGINE_HELP_TEXT: &[&str] = &[
+    "engine <command>        uplink fill, hold, vent, fire, abort or safe to the engine controller",
+];
+
+/// Command for the engine controller (see `engine.rs`), shared with the ground station, which
+/// uplinks it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub enum EngineCommand {
+    Fill,
+    Hold,
+    Vent,
+    Fire,
+    Abort,
+    Safe,
+}
+
+impl EngineCommand {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fill" => Some(Self::Fill),
+            "hold" => Some(Self::Hold),
+            "vent" => Some(Self::Vent),
+            "fire" => Some(Self::Fire),
+            "abort" => Some(Self::Abort),
+            "safe" => Some(Self::Safe),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(feature = "loadcell", not(feature = "gcs")))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadCellCommand {
+    Show,
+    Tare,
+    /// Known force (N) currently applied
+    Calibrate(f32),
+    Stream(bool),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConsoleCommand {
+    Help,
+    Status,
+    Get(ConsoleParameter),
+    Set(ConsoleParameter, Vector3<f32>),
+    Save,
+    Sensors(bool),
+    Play(Melody),
+    Volume(u8),
+    Quiet(bool),
+    /// Recovery siren and strobe
+    FindMe(bool),
+    Flash,
+    Dump(u32, u32),
+    Summary,
+    /// Operator note for the flight log
+    Note(ConsoleLine),
+    Calibrate(Calibration),
+    ShowCalibration,
+    ClearCalibration,
+    SelfTest,
+    /// Position (deg, deg, m) and unix time (s) for the GPS receiver
+    GpsAssist(f32, f32, f32, u64),
+    Scan,
+    /// Link timing to use, or `None` to show the current one. The blacklist is kept.
+    Link(Option<LinkConfig>),
+    Hops,
+    /// Channels to exclude from hopping, one bit per channel, or `None` to show the current ones
+    Blacklist(Option<u16>),
+    /// Read the transceiver diagnostics, see `radio_diagnostics.rs`
+    Radio,
+    /// Firmware parameter to set and its new value, or `None` to show all of them, see
+    /// `parameters.rs`
+    #[cfg(not(feature = "gcs"))]
+    Param(Option<(&'static str, f32)>),
+    Downlink(DownlinkProfile),
+    #[cfg(feature = "gcs")]
+    Sequence(SequenceCommand),
+    #[cfg(feature = "gcs")]
+    Capture(bool),
+    #[cfg(feature = "gcs")]
+    Record(bool),
+    /// Range of vehicle time (ms) to request again, or `None` for the last downlink gap
+    #[cfg(feature = "gcs")]
+    Retransmit(Option<(u32, u32)>),
+    /// Receive downlink via a relay instead of directly
+    #[cfg(all(feature = "gcs", not(feature = "relay")))]
+    Relay(bool),
+    #[cfg(feature = "gcs")]
+    Frontend(FrontendCommand),
+    #[cfg(all(feature = "loadcell", not(feature = "gcs")))]
+    LoadCell(LoadCellCommand),
+    #[cfg(all(feature = "servo", not(feature = "gcs")))]
+    Servo(ServoCommand),
+    /// Engine controller command, or `None` to show its state
+    #[cfg(any(feature = "engine", feature = "gcs"))]
+    Engine(Option<EngineCommand>),
+    /// Safe the vehicle before launch, see `Vehicle::abort`
+    Abort,
+    Reboot,
+    Bootloader,
+    Exit,
+    Invalid(ConsoleLine),
+}
+
+fn parse_u32(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+impl ConsoleCommand {
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut args = line.split_whitespace();
+        let Some(cmd) = args.next() else {
+            return None;
+        };
+
+        let command = match (cmd, args.next()) {
+            ("help", _) => Some(Self::Help),
+            ("status", _) => Some(Self::Status),
+            ("get", Some(param)) => ConsoleParameter::parse(param).map(Self::Get),
+            ("set", Some(param)) => {
+                let mut values = args.by_ref().map(|s| s.parse::<f32>().ok());
+                let (x, y, z) = (values.next().flatten(), values.next().flatten(), values.next().flatten());
+                ConsoleParameter::parse(param)
+                    .zip(x.zip(y).zip(z))
+                    .map(|(p, ((x, y), z))| Self::Set(p, Vector3::new(x, y, z)))
+            }
+            ("save", _) => Some(Self::Save),
+            ("sensors", Some("on")) => Some(Self::Sensors(true)),
+            ("sensors", Some("off")) => Some(Self::Sensors(false)),
+            ("play", Some(name)) => Melody::from_name(name).map(Self::Play),
+            ("volume", Some(volume)) => volume.parse().ok().filter(|v| *v <= 100).map(Self::Volume),
+            ("quiet", Some("on")) => Some(Self::Quiet(true)),
+            ("quiet", Some("off")) => Some(Self::Quiet(false)),
+            ("findme", Some("on")) => Some(Self::FindMe(true)),
+            ("findme", Some("off")) => Some(Self::FindMe(false)),
+            ("flash", _) => Some(Self::Flash),
+            ("dump", Some(address)) => parse_u32(address)
+                .zip(args.next().and_then(parse_u32))
+                .map(|(address, len)| Self::Dump(address, len)),
+            ("summary", _) => Some(Self::Summary),
+            ("note", Some(_)) => line
+                .trim_start()
+                .strip_prefix("note")
+                .map(|text| Self::Note(String::try_from(text.trim()).unwrap_or_default())),
+            ("calibrate", Some("gyro")) => Some(Self::Calibrate(Calibration::Gyroscope)),
+            ("calibrate", Some("acc")) => Some(Self::Calibrate(Calibration::Accelerometer)),
+            ("calibration", None) => Some(Self::ShowCalibration),
+            ("calibration", Some("clear")) => Some(Self::ClearCalibration),
+            ("selftest", _) => Some(Self::SelfTest),
+            ("scan", _) => Some(Self::Scan),
+            ("link", None) => Some(Self::Link(None)),
+            ("link", Some(message_interval)) => {
+                let mut values = args.by_ref().map(|s| s.parse::<u32>().ok());
+                message_interval
+                    .parse()
+                    .ok()
+                    .zip(values.next().flatten())
+                    .zip(values.next().flatten())
+                    .map(|((message_interval, uplink_interval), uplink_offset)| {
+                        Self::Link(Some(LinkConfig { message_interval, uplink_interval, uplink_offset, blacklist: 0 }))
+                    })
+            }
+            ("hops", _) => Some(Self::Hops),
+            ("radio", _) => Some(Self::Radio),
+            ("blacklist", None) => Some(Self::Blacklist(None)),
+            ("blacklist", Some("none")) => Some(Self::Blacklist(Some(0))),
+            ("blacklist", Some(channel)) => core::iter::once(channel)
+                .chain(args.by_ref())
+                .map(|s| s.parse::<u16>().ok().filter(|c| *c < 16))
+                .try_fold(0u16, |blacklist, channel| channel.map(|c| blacklist | (1 << c)))
+                .map(|blacklist| Self::Blacklist(Some(blacklist))),
+            ("gps", Some("assist")) => {
+                let mut values = args.by_ref().map(|s| s.parse::<f32>().ok());
+                let (lat, lon, alt) = (values.next().flatten(), values.next().flatten(), values.next().flatten());
+                let time = args.next().and_then(|s| s.parse::<u64>().ok());
+                lat.zip(lon)
+                    .zip(alt)
+                    .zip(time)
+                    .map(|(((lat, lon), alt), time)| Self::GpsAssist(lat, lon, alt, time))
+            }
+            #[cfg(not(feature = "gcs"))]
+            ("param", None) => Some(Self::Param(None)),
+            #[cfg(not(feature = "gcs"))]
+            ("param", Some(name)) => Parameter::find(name)
+                .zip(args.next().and_then(|s| s.parse::<f32>().ok()))
+                .map(|(param, value)| Self::Param(Some((param.name, value)))),
+            ("downlink", Some(name)) => DownlinkProfile::from_name(name).map(Self::Downlink),
+            #[cfg(feature = "gcs")]
+            ("seq", Some(cmd)) => SequenceCommand::parse(cmd, args.by_ref()).map(Self::Sequence),
+            #[cfg(feature = "gcs")]
+            ("capture", Some("on")) => Some(Self::Capture(true)),
+            #[cfg(feature = "gcs")]
+            ("capture", Some("off")) => Some(Self::Capture(false)),
+            #[cfg(feature = "gcs")]
+            ("record", Some("on")) => Some(Self::Record(true)),
+            #[cfg(feature = "gcs")]
+            ("record", Some("off")) => Some(Self::Record(false)),
+            #[cfg(feature = "gcs")]
+            ("retransmit", None) => Some(Self::Retransmit(None)),
+            #[cfg(feature = "gcs")]
+            ("retransmit", Some(from)) => from
+                .parse()
+                .ok()
+                .zip(args.next().and_then(|s| s.parse().ok()))
+                .filter(|(from, to)| from < to)
+                .map(|range| Self::Retransmit(Some(range))),
+            #[cfg(all(feature = "gcs", not(feature = "relay")))]
+            ("relay", Some("on")) => Some(Self::Relay(true)),
+            #[cfg(all(feature = "gcs", not(feature = "relay")))]
+            ("relay", Some("off")) => Some(Self::Relay(false)),
+            #[cfg(feature = "gcs")]
+            ("frontend", part) => FrontendCommand::parse(part, args.by_ref()).map(Self::Frontend),
+            #[cfg(all(feature = "loadcell", not(feature = "gcs")))]
+            ("loadcell", sub) => match (sub, args.next()) {
+                (None, _) => Some(Self::LoadCell(LoadCellCommand::Show)),
+                (Some("tare"), _) => Some(Self::LoadCell(LoadCellCommand::Tare)),
+                (Some("cal"), Some(force)) => force.parse().ok().map(|f| Self::LoadCell(LoadCellCommand::Calibrate(f))),
+                (Some("stream"), Some("on")) => Some(Self::LoadCell(LoadCellCommand::Stream(true))),
+                (Some("stream"), Some("off")) => Some(Self::LoadCell(LoadCellCommand::Stream(false))),
+                _ => None,
+            },
+            #[cfg(all(feature = "servo", not(feature = "gcs")))]
+            ("servo", Some("failsafe")) => Some(Self::Servo(ServoCommand::Failsafe)),
+            #[cfg(all(feature = "servo", not(feature = "gcs")))]
+            ("servo", Some(servo)) => servo
+                .parse()
+                .ok()
+                .zip(args.next().and_then(|s| s.parse().ok()))
+                .map(|(servo, position)| Self::Servo(ServoCommand::Set(servo, position))),
+            #[cfg(all(feature = "engine", not(feature = "gcs")))]
+            ("engine", None) => Some(Self::Engine(None)),
+            #[cfg(any(feature = "engine", feature = "gcs"))]
+            ("engine", Some(name)) => EngineCommand::from_name(name).map(|cmd| Self::Engine(Some(cmd))),
+            ("abort", _) => Some(Self::Abort),
+            ("reboot", _) => Some(Self::Reboot),
+            ("bootloader", _) => Some(Self::Bootloader),
+            ("exit", _) => Some(Self::Exit),
+            _ => None,
+        };
+
+        Some(command.unwrap_or(Self::Invalid(String::try_from(cmd).unwrap_or_default())))
+    }
+}
+
+/// Whether a received line of bytes looks like console input rather than a binary message.
+pub fn is_console_line(line: &[u8]) -> bool {
+    line.iter().all(|b| (0x20..0x7f).contains(b) || *b == b'\t')
+}
@@ -1,86 +1,292 @@
 //! Main flight logic for flight computer.
 
+use core::fmt::Write;
 use core::num::Wrapping;
 
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
 use embassy_stm32::gpio::{Output, Input};
 use embassy_stm32::peripherals::*;
-use embassy_stm32::spi::Spi;
 use embassy_stm32::time::Hertz;
 use embassy_stm32::wdg::IndependentWatchdog;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_time::Instant;
 use embassy_time::{Ticker, Duration};
+use heapless::String;
+use nalgebra::Vector3;
+use static_cell::StaticCell;
 
 use defmt::*;
 
 use state_estimator::StateEstimator;
 use shared_types::*;
 
-use crate::buzzer::Buzzer as BuzzerDriver;
+use crate::arm::ArmDetector;
+use crate::backup_apogee::BackupApogeeDetector;
+use crate::baro_lag::BaroLagCompensator;
+use crate::baro_speed::BaroSpeed;
+use crate::bootloader::reboot_to_bootloader;
+use crate::board::{BuzzerTimer, MagnetometerDriver, SensorSpi};
+use crate::buzzer::{Buzzer as BuzzerDriver, Melody, PwmToneOutput};
+use crate::calibration::SensorCalibration;
 use crate::can::*;
+use crate::clock::Instant;
+use crate::countdown::{CheckResults, Countdown, CountdownStatus};
+use crate::critical_state::{CriticalState, CriticalStateMirror, ResetCause};
 use crate::drivers::sensors::*;
+use crate::errors::{report, Error, ErrorKind, ErrorMonitor, Subsystem, ERROR_KINDS, SUBSYSTEMS};
 use crate::lora::*;
 use crate::flash::*;
+use crate::flash_log::{LogNote, TimeReference, LOG_NOTE_LENGTH};
+use crate::flight_summary::{FlightSummaryRecorder, FlightSummaryReport};
+use crate::geofence::{Geofence, GeofenceAction};
+use crate::heap;
+#[cfg(feature = "hil")]
+use crate::hil::Hil;
+use crate::landing::LandingPredictor;
+use crate::launch_rail::LaunchRail;
+use crate::leds::Leds;
+use crate::mode_guard::{ModeRejection, RejectionReason};
+use crate::outputs::{LogicalOutput, Outputs};
+use crate::parameters::{FirmwareParameters, Parameter, PARAMETERS, PARAMETERS_HELP_TEXT};
+use crate::profiling::*;
+use crate::recent_telemetry::{LoRaHistory, RecentTelemetry, Snapshot, Source};
+use crate::redundancy::*;
+use crate::retransmission::Retransmitter;
+use crate::rtc::RealTimeClock;
+use crate::schedule::{Periodic, TelemetrySchedule};
+use crate::self_test::SelfTestResult;
+use crate::sensor_stats::SensorStatsCollector;
+use crate::shock::ShockMonitor;
+use crate::telemetry::{self, DownlinkProfile};
+use crate::thermal::ThermalMonitor;
+use crate::traits::*;
 use crate::usb::*;
+use crate::usb_console::*;
+use crate::version::{fingerprint, settings_fingerprint, Heartbeat, FIRMWARE_VERSION, GIT_HASH};
 
-type SpiInst = Spi<'static, SPI1, DMA2_CH3, DMA2_CH2>;
-type Imu = LSM6<SpiDevice<'static, CriticalSectionRawMutex, SpiInst, Output<'static, PB15>>>;
-type Accelerometer = H3LIS331DL<SpiDevice<'static, CriticalSectionRawMutex, SpiInst, Output<'static, PA4>>>;
-type Magnetometer = LIS3MDL<SpiDevice<'static, CriticalSectionRawMutex, SpiInst, Output<'static, PB10>>>;
-type Barometer = MS5611<SpiDevice<'static, CriticalSectionRawMutex, SpiInst, Output<'static, PC6>>>;
+type Imu = LSM6<SpiDevice<'static, CriticalSectionRawMutex, SensorSpi, Output<'static, PB15>>>;
+type Accelerometer = H3LIS331DL<SpiDevice<'static, CriticalSectionRawMutex, SensorSpi, Output<'static, PA4>>>;
+type Compass = MagnetometerDriver<SpiDevice<'static, CriticalSectionRawMutex, SensorSpi, Output<'static, PB10>>>;
+type Barometer = MS5611<SpiDevice<'static, CriticalSectionRawMutex, SensorSpi, Output<'static, PC6>>>;
 type Power = PowerMonitor<ADC1, PB0, PC5, PC4>;
 
-type RadioHandle = Radio<SpiDevice<'static, CriticalSectionRawMutex, Spi<'static, SPI1, DMA2_CH3, DMA2_CH2>, Output<'static, PA1>>, Input<'static, PC0>,Input<'static, PC1>>;
+type RadioHandle = Radio<SpiDevice<'static, CriticalSectionRawMutex, SensorSpi, Output<'static, PA1>>, Input<'static, PC0>,Input<'static, PC1>>;
 
-type LEDs = (Output<'static, PC13>, Output<'static, PC14>, Output<'static, PC15>);
-type Buzzer = BuzzerDriver<TIM3>;
-type Recovery = (Output<'static, PC8>, Output<'static, PC9>);
+type Buzzer = BuzzerDriver<PwmToneOutput<BuzzerTimer>>;
 
 const MAIN_LOOP_FREQUENCY: Hertz = Hertz::hz(1000);
 
+/// Interval between buzzer status chirps on the pad (ms)
+const STATUS_CHIRP_INTERVAL: u32 = 10_000;
+/// Duration (ms) of the find-me siren, long enough for a recovery team to walk to the vehicle
+const FIND_ME_DURATION: u32 = 10 * 60 * 1000;
+/// Time (ms) after gyroscope saturation during which the state estimator doesn't get gyroscope
+/// data, to let the sensor settle after violent events
+const GYRO_SATURATION_HOLDOFF: u32 = 50;
+/// Free flash space (bytes) below which we warn while logging, roughly a minute of flight data
+const FLASH_RESERVE: u32 = 512 * 1024;
+/// Time (ms) logging to flash continues after an abort or error, to capture the aftermath at full
+/// rate
+const SNAPSHOT_DURATION: u32 = 5_000;
+/// Minimum time (ms) between snapshots after errors, so recurring errors, e.g. caused by noise on
+/// the radio, don't fill the flash
+const ERROR_SNAPSHOT_INTERVAL: u32 = 60_000;
+/// Interval (ms) at which the firmware and configuration are downlinked, see `version::Heartbeat`
+const HEARTBEAT_INTERVAL: u32 = 10_000;
+/// Interval (ms) at which the state of the flash log is downlinked, see `flash::LoggingStatus`
+const LOGGING_STATUS_INTERVAL: u32 = 5_000;
+/// Interval (ms) at which the flight summary is downlinked while landed, repeated in case the
+/// ground station misses it
+const FLIGHT_SUMMARY_INTERVAL: u32 = 10_000;
+/// Time (ms) to wait for the flash task's part of the self test before reporting without it. The
+/// scratch write may have to erase the settings sector first.
+const SELF_TEST_TIMEOUT: u32 = 2_000;
+/// Interval (ms) at which the redundancy state is downlinked while a partner FC is present, in
+/// addition to every change, see `redundancy.rs`
+const REDUNDANCY_STATUS_INTERVAL: u32 = 10_000;
+/// Interval (ms) at which the time reference is downlinked once the clock is synchronized, see
+/// `flash_log::TimeReference`
+const TIME_REFERENCE_INTERVAL: u32 = 30_000;
+/// Barometric altitude (m AGL) above which a flight mode restored after a reset is considered
+/// confirmed, and the recovery outputs are enabled again
+const RESTORE_MIN_ALTITUDE: f32 = 50.0;
+/// Time (ms) after startup within which a restored flight mode has to be confirmed, otherwise the
+/// outputs stay inhibited until the vehicle is back on the pad
+const RESTORE_CHECK_TIME: u32 = 5_000;
+
+/// Timing of the periodic activities of the main loop.
+struct Timers {
+    log: Periodic,
+    status_chirp: Periodic,
+    #[cfg(feature = "aprs")]
+    aprs_beacon: Periodic,
+    /// ACS valve commands, at 50Hz
+    acs_outputs: Periodic,
+    recovery_cameras: Periodic,
+    payload_cameras: Periodic,
+    can_broadcast: Periodic,
+    live_sensor_view: Periodic,
+    /// Statistics of raw sensor readings, aligned with the raw sensor telemetry via USB
+    usb_sensor_stats: Periodic,
+    logging_health: Periodic,
+    heartbeat: Periodic,
+    logging_status: Periodic,
+    flight_summary: Periodic,
+    redundancy_status: Periodic,
+    time_reference: Periodic,
+    usb_telemetry: TelemetrySchedule<3>,
+    lora_telemetry: TelemetrySchedule<6>,
+    flash_telemetry: TelemetrySchedule<4>,
+}
+
+impl Timers {
+    fn new() -> Self {
+        Self {
+            log: Periodic::new(5000, 0),
+            status_chirp: Periodic::new(STATUS_CHIRP_INTERVAL, 0),
+            #[cfg(feature = "aprs")]
+            aprs_beacon: Periodic::new(crate::aprs::BEACON_INTERVAL, 0),
+            acs_outputs: Periodic::new(20, 0),
+            recovery_cameras: Periodic::new(500, 210),
+            payload_cameras: Periodic::new(500, 410),
+            can_broadcast: Periodic::new(100, 0),
+            live_sensor_view: Periodic::new(100, 0),
+            usb_sensor_stats: Periodic::new(50, 0),
+            logging_health: Periodic::new(1000, 0),
+            heartbeat: Periodic::new(HEARTBEAT_INTERVAL, 0),
+            // Offset from the heartbeat, so they don't replace the same message
+            logging_status: Periodic::new(LOGGING_STATUS_INTERVAL, 2_500),
+            flight_summary: Periodic::new(FLIGHT_SUMMARY_INTERVAL, 5_000),
+            redundancy_status: Periodic::new(REDUNDANCY_STATUS_INTERVAL, 7_500),
+            time_reference: Periodic::new(TIME_REFERENCE_INTERVAL, 1_250),
+            usb_telemetry: telemetry::usb_schedule(),
+            lora_telemetry: telemetry::lora_schedule(DownlinkProfile::default()),
+            flash_telemetry: telemetry::flash_schedule(),
+        }
+    }
+}
+
+const CALIBRATION_SAMPLES: u32 = 1000;
+const GRAVITY: f32 = 9.80665;
+
+// The telemetry buffers are too large to be built on the stack with the rest of the vehicle.
+static RECENT_TELEMETRY: StaticCell<RecentTelemetry> = StaticCell::new();
+static LORA_HISTORY: StaticCell<LoRaHistory> = StaticCell::new();
+
 pub struct Vehicle {
-    pub time: core::num::Wrapping<u32>,
+    pub time: Instant,
     // sensors
     imu: Imu,
     acc: Accelerometer,
-    mag: Magnetometer,
+    mag: Compass,
     baro: Barometer,
     gps: GPSHandle,
     power: Power,
+    #[cfg(feature = "loadcell")]
+    load_cell: LoadCellHandle,
+    #[cfg(feature = "thermocouple")]
+    thermocouple: ThermocoupleHandle,
+    #[cfg(feature = "tank_pressure")]
+    tank_pressure: Option<crate::tank_pressure::TankPressure>,
+    #[cfg(feature = "umbilical")]
+    umbilical: Option<crate::umbilical::Umbilical>,
     // other peripherals
     usb: UsbHandle,
     radio: RadioHandle,
     flash: FlashHandle,
     can: CanHandle,
+    rtc: RealTimeClock,
     // outputs
-    leds: LEDs,
+    leds: Leds,
     buzzer: Buzzer,
-    recovery: Recovery,
+    outputs: Outputs,
     // vehicle state
+    arm: ArmDetector,
     state_estimator: StateEstimator,
+    baro_lag: BaroLagCompensator,
+    baro_speed: BaroSpeed,
+    backup_apogee: BackupApogeeDetector,
+    flight_summary: FlightSummaryRecorder,
+    /// Summary of the last flight, downlinked while landed
+    flight_summary_report: Option<FlightSummaryReport>,
+    /// Self test waiting for the flash result, with the time it was started
+    self_test: Option<(Instant, SelfTestResult)>,
     mode: FlightMode,
-    loop_runtime: f32,
+    max_altitude_asl: f32,
+    max_vertical_speed: f32,
+    last_gyro_saturation: Option<Instant>,
+    geofence: Geofence,
+    landing: LandingPredictor,
+    launch_rail: LaunchRail,
+    sensor_stats: SensorStatsCollector,
+    thermal: ThermalMonitor,
+    shock: ShockMonitor,
+    critical_state: CriticalStateMirror,
+    countdown: Countdown,
+    recent_telemetry: &'static mut RecentTelemetry,
+    lora_history: &'static mut LoRaHistory,
+    retransmitter: Retransmitter,
+    profiler: Profiler,
+    errors: ErrorMonitor,
+    timers: Timers,
+    partner: Partner,
+    /// Redundancy state last downlinked
+    redundancy_status: Option<RedundancyStatus>,
+    /// Whether the time reference has been written to the flash log since logging started
+    time_reference_logged: bool,
+    /// Mode for which recovery outputs were permitted, and when
+    recovery_permitted: Option<(FlightMode, Instant)>,
+    /// Whether the drogue and main outputs have been fired, and whether they are inhibited after
+    /// a reset, either because they were fired before it or because the restored flight hasn't
+    /// been confirmed
+    pyros_fired: (bool, bool),
+    pyros_inhibited: (bool, bool),
+    /// Whether a flight mode was restored after a reset, and is still to be confirmed by the
+    /// barometer before the outputs are enabled
+    in_flight_check: bool,
+    /// Whether the vehicle was safed by an abort, in which case it stays in Idle until it is
+    /// armed again explicitly
+    safed: bool,
+    /// Start of the last snapshot after an abort or error, see `start_snapshot`
+    snapshot_since: Option<Instant>,
+    /// Buffered telemetry still being copied to flash
+    snapshot: Option<Snapshot>,
     settings: Settings,
+    sensor_calibration: SensorCalibration,
+    /// Firmware parameters as stored, only applied after a reboot
+    parameters: FirmwareParameters,
     data_rate: TelemetryDataRate,
+    // Flash logging health
+    last_flash_pointer: u32,
+    /// Growth of the flash log over the last second (bytes/s)
+    logging_rate: u32,
+    was_logging: bool,
     // IO board state
-    last_acs_message: Option<(Wrapping<u32>, u16, i16, i8)>,
-    last_recovery_message: Option<(Wrapping<u32>, u16, i16, i8)>,
-    last_payload_message: Option<(Wrapping<u32>, u16, i16, i8)>,
+    last_acs_message: Option<(Instant, u16, i16, i8)>,
+    last_recovery_message: Option<(Instant, u16, i16, i8)>,
+    last_payload_message: Option<(Instant, u16, i16, i8)>,
     // Acs
     acs_mode: AcsMode,
-    last_manual_thruster_input: Option<(Wrapping<u32>, ThrusterValveState)>,
+    last_manual_thruster_input: Option<(Instant, ThrusterValveState)>,
     last_thruster_valve_state: ThrusterValveState,
-    acs_tank_pressure: Option<(Wrapping<u32>, f32)>,
-    acs_regulator_pressure: Option<(Wrapping<u32>, f32)>,
-    acs_accel_valve_pressure: Option<(Wrapping<u32>, f32)>,
-    acs_decel_valve_pressure: Option<(Wrapping<u32>, f32)>,
+    acs_tank_pressure: Option<(Instant, f32)>,
+    acs_regulator_pressure: Option<(Instant, f32)>,
+    acs_accel_valve_pressure: Option<(Instant, f32)>,
+    acs_decel_valve_pressure: Option<(Instant, f32)>,
     // Recovery (and also payload)
-    recovery_pressure: Option<(Wrapping<u32>, f32)>,
-    main_release_sensor: Option<(Wrapping<u32>, bool)>,
+    recovery_pressure: Option<(Instant, f32)>,
+    main_release_sensor: Option<(Instant, bool)>,
     camera_state: [bool; 3], // R0, R1, P (TODO: this is awful)
     // Fins
-    last_fin_message: [Option<Wrapping<u32>>; 3],
+    last_fin_message: [Option<Instant>; 3],
+    // Sensor data injected for HIL tests
+    #[cfg(feature = "hil")]
+    hil: Hil,
+    // USB console
+    live_sensor_view: bool,
+    downlink_profile: DownlinkProfile,
+    calibration: Option<(Calibration, u32, Vector3<f32>)>,
+    #[cfg(feature = "loadcell")]
+    load_cell_stream: bool,
 }
 
 impl Into<VehicleState> for &mut Vehicle {
@@ -93,7 +299,7 @@ impl Into<VehicleState> for &mut Vehicle {
             &mut self.last_recovery_message,
             &mut self.last_payload_message,
         ] {
-            if io_module.map(|(t, _, _, _)| (self.time - t).0 > THRESHOLD).unwrap_or(false) {
+            if io_module.map(|(t, _, _, _)| self.time.millis_since(t) > THRESHOLD).unwrap_or(false) {
                 *io_module = None;
             }
         }
@@ -105,19 +311,19 @@ impl Into<VehicleState> for &mut Vehicle {
             &mut self.acs_decel_valve_pressure,
             &mut self.recovery_pressure
         ] {
-            if pressure_sensor.map(|(t, _)| (self.time - t).0 > THRESHOLD).unwrap_or(false) {
+            if pressure_sensor.map(|(t, _)| self.time.millis_since(t) > THRESHOLD).unwrap_or(false) {
                 *pressure_sensor = None;
             }
         }
 
-        if self.main_release_sensor.map(|(t, _v)| (self.time - t).0 > THRESHOLD).unwrap_or(false) {
+        if self.main_release_sensor.map(|(t, _v)| self.time.millis_since(t) > THRESHOLD).unwrap_or(false) {
             self.main_release_sensor = None;
         }
 
         let acs_tank_pressure = self.acs_tank_pressure.map(|(_t, v)| v);
 
         VehicleState {
-            time: self.time.0,
+            time: self.time.wire(),
             mode: Some(self.mode),
             orientation: self.state_estimator.orientation,
             vertical_speed: Some(self.state_estimator.vertical_speed()),
@@ -128,24 +334,24 @@ impl Into<VehicleState> for &mut Vehicle {
             latitude: self.state_estimator.latitude(),
             longitude: self.state_estimator.longitude(),
 
-            gyroscope: self.imu.gyroscope(),
-            accelerometer1: self.imu.accelerometer(),
-            accelerometer2: self.acc.accelerometer(),
-            magnetometer: self.mag.magnetometer(),
-            pressure_baro: self.baro.pressure(),
-            altitude_baro: self.baro.altitude(),
+            gyroscope: self.gyroscope(),
+            accelerometer1: self.accelerometer1(),
+            accelerometer2: self.accelerometer2(),
+            magnetometer: self.magnetometer(),
+            pressure_baro: self.pressure_baro(),
+            altitude_baro: self.altitude_baro(),
             temperature_baro: self.baro.temperature(),
 
             charge_voltage: self.power.charge_voltage(),
             battery_voltage: self.power.battery_voltage(),
             current: self.power.battery_current(),
 
-            lora_rssi: Some(self.radio.trx.rssi), // TODO
-            transmit_power: Some(self.radio.transmit_power),
+            lora_rssi: Some(self.radio.rssi()), // TODO
+            transmit_power: Some(self.radio.transmit_power()),
             data_rate: Some(self.data_rate),
 
-            cpu_utilization: Some(self.loop_runtime),
-            flash_pointer: Some(self.flash.pointer),
+            cpu_utilization: Some(self.profiler.loop_time()),
+            flash_pointer: Some(self.flash.pointer()),
 
             gps: self.gps.datum(),
 
@@ -159,9 +365,9 @@ impl Into<VehicleState> for &mut Vehicle {
             payload_current: Some(self.last_payload_message.map(|(_, _, c, _)| c)),
             payload_temperature: Some(self.last_payload_message.map(|(_, _, _, t)| t)),
             fins_present: Some([
-                self.last_fin_message[0].map(|t| (self.time - t).0 < THRESHOLD).unwrap_or(false),
-                self.last_fin_message[1].map(|t| (self.time - t).0 < THRESHOLD).unwrap_or(false),
-                self.last_fin_message[2].map(|t| (self.time - t).0 < THRESHOLD).unwrap_or(false),
+                self.last_fin_message[0].map(|t| self.time.millis_since(t) < THRESHOLD).unwrap_or(false),
+                self.last_fin_message[1].map(|t| self.time.millis_since(t) < THRESHOLD).unwrap_or(false),
+                self.last_fin_message[2].map(|t| self.time.millis_since(t) < THRESHOLD).unwrap_or(false),
             ]),
 
             acs_mode: Some(self.acs_mode),
@@ -205,29 +411,39 @@ impl Vehicle {
     pub fn init(
         mut imu: Imu,
         mut acc: Accelerometer,
-        mut mag: Magnetometer,
-        baro: Barometer,
+        mut mag: Compass,
+        mut baro: Barometer,
         gps: GPSHandle,
         power: Power,
         usb: UsbHandle,
         mut radio: RadioHandle,
         flash: FlashHandle,
         can: CanHandle,
-        leds: LEDs,
+        rtc: RealTimeClock,
+        leds: Leds,
         mut buzzer: Buzzer,
-        recovery: Recovery,
+        outputs: Outputs,
         settings: Settings,
+        sensor_calibration: SensorCalibration,
+        link_config: LinkConfig,
+        parameters: FirmwareParameters,
+        reset_cause: ResetCause,
     ) -> Self {
+        info!("Firmware {} ({}), settings {=u16:04x}", FIRMWARE_VERSION, GIT_HASH, settings_fingerprint(&settings));
         buzzer.apply_settings(&settings.drogue_output_settings, &settings.main_output_settings);
         radio.apply_settings(&settings.lora);
-        imu.set_offsets(settings.gyro_offset, settings.acc_offset);
-        acc.set_offset(settings.acc2_offset);
-        mag.set_offset(settings.mag_offset);
+        radio.set_link_config(link_config);
+        imu.set_offsets(sensor_calibration.gyro_bias, sensor_calibration.acc_offset);
+        acc.set_offset(sensor_calibration.acc2_offset);
+        mag.set_offset(sensor_calibration.mag_offset);
+        baro.set_offset(sensor_calibration.baro_offset);
+        #[cfg(feature = "engine")]
+        crate::engine::set_max_tilt(parameters.max_ignition_tilt);
 
         let data_rate = settings.default_data_rate;
 
-        Self {
-            time: core::num::Wrapping(0),
+        let mut vehicle = Self {
+            time: Instant::ZERO,
 
             imu,
             acc,
@@ -235,22 +451,69 @@ impl Vehicle {
             baro,
             gps,
             power,
+            #[cfg(feature = "loadcell")]
+            load_cell: LoadCellHandle::new(),
+            #[cfg(feature = "thermocouple")]
+            thermocouple: ThermocoupleHandle::default(),
+            #[cfg(feature = "tank_pressure")]
+            tank_pressure: None,
+            #[cfg(feature = "umbilical")]
+            umbilical: None,
 
             usb,
             radio,
             flash,
             can,
+            rtc,
 
             leds,
             buzzer,
-            recovery,
+            outputs,
 
+            arm: ArmDetector::new(),
             state_estimator: StateEstimator::new(MAIN_LOOP_FREQUENCY.0 as f32, settings.clone()),
+            baro_lag: BaroLagCompensator::new(parameters.baro_lag, MAIN_LOOP_FREQUENCY.0 as f32),
+            baro_speed: BaroSpeed::new(parameters.baro_speed, MAIN_LOOP_FREQUENCY.0 as f32),
+            backup_apogee: BackupApogeeDetector::new(parameters.backup_apogee, MAIN_LOOP_FREQUENCY.0 as f32),
+            flight_summary: FlightSummaryRecorder::new(),
+            flight_summary_report: None,
+            self_test: None,
             mode: FlightMode::Idle,
-
-            loop_runtime: 0.0,
+            max_altitude_asl: 0.0,
+            max_vertical_speed: 0.0,
+            last_gyro_saturation: None,
+            geofence: Geofence::new(parameters.geofence),
+            landing: LandingPredictor::new(),
+            launch_rail: LaunchRail::new(parameters.magnetic_declination),
+            sensor_stats: SensorStatsCollector::new(),
+            thermal: ThermalMonitor::new(parameters.thermal),
+            shock: ShockMonitor::new(parameters.shock),
+            critical_state: CriticalStateMirror::new(),
+            countdown: Countdown::new(parameters.countdown),
+            recent_telemetry: RECENT_TELEMETRY.init_with(RecentTelemetry::new),
+            lora_history: LORA_HISTORY.init_with(LoRaHistory::new),
+            retransmitter: Retransmitter::new(),
+
+            profiler: Profiler::new(),
+            errors: ErrorMonitor::new(),
+            timers: Timers::new(),
+            partner: Partner::new(),
+            redundancy_status: None,
+            time_reference_logged: false,
+            recovery_permitted: None,
+            pyros_fired: (false, false),
+            pyros_inhibited: (false, false),
+            in_flight_check: false,
+            safed: false,
+            snapshot_since: None,
+            snapshot: None,
             settings,
+            sensor_calibration,
+            parameters,
             data_rate,
+            last_flash_pointer: 0,
+            logging_rate: 0,
+            was_logging: false,
 
             last_acs_message: None,
             last_recovery_message: None,
@@ -270,16 +533,73 @@ impl Vehicle {
             camera_state: [false; 3],
 
             last_fin_message: [None; 3],
+
+            #[cfg(feature = "hil")]
+            hil: Hil::new(),
+            live_sensor_view: false,
+            downlink_profile: DownlinkProfile::default(),
+            calibration: None,
+            #[cfg(feature = "loadcell")]
+            load_cell_stream: false,
+        };
+
+        if let Some(state) = vehicle.critical_state.restore(&mut vehicle.rtc, reset_cause) {
+            vehicle.restore(state);
+        }
+
+        vehicle
+    }
+
+    /// Resumes from the state mirrored before a reset. The outputs stay inhibited until the
+    /// barometer confirms that the vehicle is actually in flight, see `check_in_flight`.
+    fn restore(&mut self, state: CriticalState) {
+        self.state_estimator.altitude_ground = state.altitude_ground;
+        self.max_altitude_asl = state.max_altitude_asl;
+        self.switch_mode(state.mode);
+        self.pyros_fired = (state.drogue_fired, state.main_fired);
+        self.in_flight_check = state.mode >= FlightMode::Burn && state.mode < FlightMode::Landed;
+        self.pyros_inhibited = if self.in_flight_check { (true, true) } else { self.pyros_fired };
+    }
+
+    /// Enables the outputs that haven't been fired before the reset once the barometer reads
+    /// well above the restored ground reference. If it doesn't within `RESTORE_CHECK_TIME`, the
+    /// vehicle is most likely on the ground, e.g. on the bench, and the outputs stay inhibited.
+    fn check_in_flight(&mut self) {
+        let altitude_agl = self.altitude_baro().map(|a| a - self.state_estimator.altitude_ground);
+        if let Some(altitude_agl) = altitude_agl.filter(|a| *a > RESTORE_MIN_ALTITUDE) {
+            warn!("Restored flight confirmed at {}m AGL, enabling outputs.", altitude_agl);
+            self.pyros_inhibited = self.pyros_fired;
+            self.in_flight_check = false;
+        } else if self.time.millis_since(Instant::ZERO) >= RESTORE_CHECK_TIME {
+            warn!("Restored flight not confirmed by barometer, outputs stay inhibited.");
+            self.in_flight_check = false;
         }
     }
 
+    /// Adds the tank pressure transducers, which share the power monitor's ADC.
+    #[cfg(feature = "tank_pressure")]
+    pub fn with_tank_pressure(mut self, tank_pressure: crate::tank_pressure::TankPressure) -> Self {
+        self.tank_pressure = Some(tank_pressure);
+        self
+    }
+
+    /// Adds the umbilical and breakwire inputs as an additional launch detection signal.
+    #[cfg(feature = "umbilical")]
+    pub fn with_umbilical(mut self, umbilical: crate::umbilical::Umbilical) -> Self {
+        self.umbilical = Some(umbilical);
+        self
+    }
+
     async fn tick(&mut self) {
-        if self.time.0 % 5000 == 0 {
+        if self.timers.log.due(self.time) {
             let alt_baro = self.baro.altitude().unwrap_or_default() * 100.0;
-            defmt::info!("t={}, alt_baro={}cm", self.time.0, alt_baro as u32);
+            let utc = self.rtc.utc_millis().unwrap_or_default();
+            defmt::info!("t={}, utc={}, alt_baro={}cm", self.time.as_millis(), utc, alt_baro as u32);
+            defmt::info!("cpu temperature: {}C, supply: {}mV", self.power.temperature(), self.power.vdda());
+            self.profiler.report();
         }
 
-        let start = Instant::now();
+        self.profiler.start_loop();
 
         // Query core sensors
         // TODO: should we separate these into separate tasks?
@@ -288,9 +608,40 @@ impl Vehicle {
         self.mag.tick().await;
         self.baro.tick().await;
         self.power.tick();
+        if self.arm.tick(self.time, self.arm_voltage()) {
+            self.buzzer.play(self.time, Melody::Warning);
+        }
+        self.thermal.tick(self.mode, self.power.temperature(), self.power.vdda(), &mut self.radio);
+        #[cfg(feature = "tank_pressure")]
+        if let Some(tank_pressure) = self.tank_pressure.as_mut() {
+            tank_pressure.tick(self.time, &mut self.power);
+        }
+        #[cfg(feature = "hil")]
+        self.hil.tick(self.time);
+        #[cfg(feature = "loadcell")]
+        self.tick_load_cell();
+        #[cfg(feature = "thermocouple")]
+        self.thermocouple.tick();
+
+        if self.imu.gyroscope_saturated() {
+            if !self.gyro_saturated() {
+                report(Subsystem::Sensors, ErrorKind::Overflow, "gyroscope saturated");
+            }
+            self.last_gyro_saturation = Some(self.time);
+        }
+
+        if let Some((time, received)) = self.gps.new_time() {
+            self.rtc.discipline(time, received);
+        }
+        self.profiler.end_section(Section::Sensors);
+
+        // Handle incoming CAN messages. We process everything received since the last iteration,
+        // but not more than that, in case messages arrive faster than we can handle them.
+        for _i in 0..INCOMING_QUEUE_SIZE {
+            let Some(msg) = self.can.receive() else {
+                break;
+            };
 
-        // Handle incoming CAN messages
-        if let Some(msg) = self.can.receive() {
             self.handle_can_bus_message(&msg);
             match msg {
                 FcReceivedCanBusMessage::BatteryTelemetry(_id, bat_msg) => {
@@ -341,117 +692,419 @@ impl Vehicle {
                 },
             }
         }
-
-        // Update state estimator
+        self.profiler.end_section(Section::Can);
+
+        // Update state estimator. While the gyroscope is saturated, the actual rates are unknown,
+        // so we withhold them and the estimator keeps its last orientation instead of integrating
+        // clipped values.
+        let gyroscope = self.gyroscope().filter(|_| !self.gyro_saturated());
+        let vertical_acceleration = self.state_estimator.vertical_acceleration();
+        let altitude_baro = self.baro_lag.tick(self.mode, self.altitude_baro(), vertical_acceleration);
         self.state_estimator.update(
-            self.time,
+            Wrapping(self.time.wire()),
             self.mode,
-            self.imu.gyroscope(),
-            self.imu.accelerometer(),
-            self.acc.accelerometer(),
-            self.mag.magnetometer(),
-            self.baro.altitude(),
+            gyroscope,
+            self.accelerometer1(),
+            self.accelerometer2(),
+            self.magnetometer(),
+            altitude_baro,
             self.gps.new_datum(),
         );
 
-        // Switch to new mode if necessary
-        let arm_voltage = self.power.arm_voltage().unwrap_or(0);
-        if let Some(fm) = self.state_estimator.new_mode(arm_voltage) {
+        if self.mode >= FlightMode::ArmedLaunchImminent && self.mode < FlightMode::Landed {
+            self.max_altitude_asl = f32::max(self.max_altitude_asl, self.state_estimator.altitude_asl());
+            self.max_vertical_speed = f32::max(self.max_vertical_speed, self.state_estimator.vertical_speed());
+        }
+
+        if self.in_flight_check {
+            self.check_in_flight();
+        }
+
+        // Switch to new mode if necessary. The state estimator applies its own threshold to the
+        // arm voltage, so it gets one that follows the debounced state instead of the measurement.
+        let arm_voltage = if self.arm.armed() { u16::MAX } else { 0 };
+        if let Some(fm) = self.state_estimator.new_mode(arm_voltage).filter(|_| !self.safed) {
             self.switch_mode(fm);
         }
+        let (acc1, acc2) = (self.accelerometer1(), self.accelerometer2());
+        if self.backup_apogee.tick(self.time, self.mode, self.state_estimator.orientation, acc1, acc2) {
+            warn!("Apogee detected from inertial data before barometer, deploying drogue.");
+            self.switch_mode(FlightMode::RecoveryDrogue);
+        }
+        let imu_reading = self.gyroscope().is_some() || acc1.is_some();
+        if self.baro_speed.tick(self.time, self.mode, self.altitude_baro(), imu_reading) {
+            warn!("IMU failed, apogee detected from barometer, deploying drogue.");
+            self.switch_mode(FlightMode::RecoveryDrogue);
+        }
+        #[cfg(feature = "umbilical")]
+        if self.umbilical.as_mut().map(|u| u.tick(self.time, self.mode)).unwrap_or(false) {
+            self.switch_mode(FlightMode::Burn);
+        }
+        if self.countdown.is_running() {
+            self.tick_countdown();
+        }
+
+        let altitude_agl = self.state_estimator.altitude_asl() - self.state_estimator.altitude_ground;
+        let position = self.state_estimator.latitude().zip(self.state_estimator.longitude());
+        if let Some((violation, action)) = self.geofence.tick(self.mode, altitude_agl, position) {
+            self.radio.send_geofence_violation(violation, action);
+            if action == GeofenceAction::Drogue {
+                self.switch_mode(FlightMode::RecoveryDrogue);
+            }
+        }
+        let vertical_speed = self.state_estimator.vertical_speed();
+        self.landing.tick(self.time, self.mode, altitude_agl, vertical_speed, position);
+        self.launch_rail.tick(self.time, self.mode, self.state_estimator.orientation);
+        let vertical_acceleration = self.state_estimator.vertical_acceleration();
+        if let Some(mut summary) = self.flight_summary.tick(self.time, self.mode, altitude_agl, vertical_speed, vertical_acceleration) {
+            summary.rail = self.launch_rail.orientation().map(|r| (r.azimuth, r.elevation));
+            info!("Flight summary: {:?}", Debug2Format(&summary));
+            let condensed = FlightSummaryReport::from(&summary);
+            self.flight_summary_report = Some(condensed);
+            self.radio.send_flight_summary(condensed);
+            if self.flash.write_flight_summary(summary).is_err() {
+                report(Subsystem::Flash, ErrorKind::QueueFull, "queueing flight summary");
+            }
+        }
+        if self.timers.flight_summary.due(self.time) && self.mode == FlightMode::Landed {
+            if let Some(summary) = self.flight_summary_report {
+                self.radio.send_flight_summary(summary);
+            }
+        }
+        self.profiler.end_section(Section::Estimator);
 
         // Process incoming commands, both from USB...
         if let Some(msg) = self.usb.next_uplink_message() {
             match msg {
                 UplinkMessage::Heartbeat => {},
-                // TODO: remove bootloader command?
-                UplinkMessage::Command(cmd) => if let Command::RebootToBootloader = cmd {
-                    //reboot_to_bootloader()
-                } else {
-                    self.handle_command(cmd).await
-                },
+                UplinkMessage::Command(Command::RebootToBootloader) if !self.reconfiguration_locked() => self.reboot(true),
+                UplinkMessage::Command(cmd) => self.handle_command(cmd).await,
                 UplinkMessage::ReadFlash(adress, size) => { let _ = self.flash.read(adress, size); }
                 UplinkMessage::ReadSettings => self.usb.send_message(DownlinkMessage::Settings(self.settings.clone())),
+                UplinkMessage::WriteSettings(_) if self.reconfiguration_locked() => {
+                    warn!("Settings rejected in {:?}.", Debug2Format(&self.mode));
+                },
                 UplinkMessage::WriteSettings(settings) => { let _ = self.flash.write_settings(settings); }
                 UplinkMessage::ApplyLoRaSettings(_) => {}
             }
         }
 
+        // ... as well as the USB console ...
+        if let Some(cmd) = self.usb.next_console_command() {
+            self.handle_console_command(cmd).await;
+        }
+        self.tick_console();
+        self.profiler.end_section(Section::Commands);
+
         // ... and via LoRa
-        if let Some(cmd) = self.radio.tick(self.time.0).await {
+        let cmd = self.radio.tick(self.time.wire()).await;
+        self.profiler.end_section(Section::Radio);
+        if let Some(cmd) = cmd {
             self.handle_command(cmd).await;
         }
+        if self.radio.take_abort() {
+            self.abort();
+        }
+        if let Some(blacklist) = self.radio.take_blacklist() {
+            info!("Received channel blacklist {:#06x}.", blacklist);
+            self.change_link_config(LinkConfig { blacklist, ..self.radio.link_config() });
+        }
+        if self.radio.take_diagnostics_request() {
+            self.report_radio_diagnostics().await;
+        }
+        if self.radio.take_self_test_request() {
+            self.self_test().await;
+        }
+        if let Some(enabled) = self.radio.take_find_me() {
+            info!("Find-me siren {} via uplink.", if enabled { "started" } else { "stopped" });
+            self.buzzer.find_me(self.time, enabled.then_some(FIND_ME_DURATION));
+        }
+        if let Some(profile) = self.radio.take_downlink_profile() {
+            info!("Switching to downlink profile {}.", profile.name());
+            self.set_downlink_profile(profile);
+        }
+        if let Some(cmd) = self.radio.take_engine_command() {
+            info!("Engine command received: {}", cmd);
+            #[cfg(feature = "engine")]
+            crate::engine::command(cmd);
+            #[cfg(not(feature = "engine"))]
+            warn!("No engine controller, ignoring engine command.");
+        }
+        self.tick_self_test();
+        if let Some(request) = self.radio.take_retransmit_request() {
+            if self.retransmitter.start(request, self.time.wire()) {
+                info!("Retransmitting {}s of telemetry from {}s.", request.duration, request.start);
+            } else {
+                warn!("Ignoring retransmission of {}s from {}s, no longer buffered.", request.duration, request.start);
+            }
+        }
+        if self.timers.heartbeat.due(self.time) {
+            self.radio.send_heartbeat(self.heartbeat());
+        }
+        if let Some(text) = self.radio.take_note() {
+            if self.flash.write_note(LogNote::new(self.time.wire(), &text)).is_err() {
+                report(Subsystem::Flash, ErrorKind::QueueFull, "queueing note");
+            }
+        }
+        self.profiler.end_section(Section::Commands);
+
+        // Set output according to flight mode, once a redundant FC (if present) agrees
+        self.partner.tick(self.time);
+        self.update_redundancy_status();
+        let recovery_mode = matches!(self.mode, FlightMode::RecoveryDrogue | FlightMode::RecoveryMain);
+        let permitted_since = match self.recovery_permitted {
+            Some((mode, t)) if mode == self.mode => Some(t),
+            _ if recovery_mode && self.partner.agrees(self.time, self.mode) => {
+                self.recovery_permitted = Some((self.mode, self.time));
+                Some(self.time)
+            }
+            _ => None,
+        };
+        let elapsed = permitted_since.map(|t| self.time.millis_since(t));
+        let drogue_high = self.mode == FlightMode::RecoveryDrogue && !self.pyros_inhibited.0 && elapsed.map(|e| self.settings.drogue_output_settings.currently_high(e)).unwrap_or(false);
+        let main_high = self.mode == FlightMode::RecoveryMain && !self.pyros_inhibited.1 && elapsed.map(|e| self.settings.main_output_settings.currently_high(e)).unwrap_or(false);
+        self.outputs.set(LogicalOutput::Drogue, drogue_high);
+        self.outputs.set(LogicalOutput::Separation, drogue_high);
+        self.outputs.set(LogicalOutput::Main, main_high);
+        self.outputs.set(LogicalOutput::Camera, self.camera_state[0]);
+        self.pyros_fired.0 |= drogue_high;
+        self.pyros_fired.1 |= main_high;
+
+        // Mirror the state that is needed to resume the flight after a reset.
+        self.critical_state.tick(&mut self.rtc, CriticalState {
+            mode: self.mode,
+            altitude_ground: self.state_estimator.altitude_ground,
+            max_altitude_asl: self.max_altitude_asl,
+            drogue_fired: self.pyros_fired.0,
+            main_fired: self.pyros_fired.1,
+        });
+        #[cfg(feature = "servo")]
+        crate::servo::set_armed(self.arm.armed());
+        #[cfg(feature = "engine")]
+        crate::engine::set_armed(self.arm.armed());
+        #[cfg(feature = "engine")]
+        crate::engine::set_tilt((self.mode >= FlightMode::ArmedLaunchImminent).then(|| self.tilt()));
+        #[cfg(feature = "engine")]
+        if let Some(decision) = crate::engine::take_gate_decision() {
+            if self.flash.write_note(decision.note(self.time.wire())).is_err() {
+                report(Subsystem::Flash, ErrorKind::QueueFull, "queueing note");
+            }
+        }
 
-        // Set output according to flight mode
-        let elapsed = self.state_estimator.time_in_mode();
-        let drogue_high = self.mode == FlightMode::RecoveryDrogue && self.settings.drogue_output_settings.currently_high(elapsed);
-        let main_high = self.mode == FlightMode::RecoveryMain && self.settings.main_output_settings.currently_high(elapsed);
-        self.recovery.0.set_level(drogue_high.into());
-        self.recovery.1.set_level(main_high.into());
-
-        let (r,y,g) = self.mode.led_state(self.time.0);
-        self.leds.0.set_level((!r).into());
-        self.leds.1.set_level((!y).into());
-        self.leds.2.set_level((!g).into());
+        self.leds.set_find_me(self.buzzer.find_me_remaining(self.time).is_some());
+        self.leds.tick(self.time, self.mode);
 
         // Send valve commands via CAN bus
         self.transmit_output_commands();
 
-        // Update buzzer
-        self.buzzer.tick(self.time.0, self.power.battery_status());
+        // Update buzzer, giving the pad crew a periodic status before launch
+        if self.timers.status_chirp.due(self.time) && self.mode <= FlightMode::Armed {
+            let gps_fix = !matches!(self.gps.fix(), None | Some(GPSFixType::NoFix));
+            self.buzzer.play_status(self.time, self.arm.armed(), gps_fix);
+        }
+        self.buzzer.tick(self.time, self.power.battery_status());
+
+        // Send APRS beacons after landing, with the last known position if the GPS lost its fix
+        #[cfg(feature = "aprs")]
+        if self.timers.aprs_beacon.due(self.time) && self.mode == FlightMode::Landed {
+            let gps_position = self.gps.latitude().zip(self.gps.longitude());
+            if let Some((latitude, longitude)) = gps_position.or(self.landing.resting_position()) {
+                crate::aprs::beacon(latitude, longitude, self.gps.altitude());
+            }
+        }
 
         // Send telemetry via USB
-        if let Some(msg) = self.next_usb_telem() {
-            self.usb.send_message(msg);
+        self.sensor_stats.add(
+            self.gyroscope(),
+            self.accelerometer1(),
+            self.accelerometer2(),
+            self.magnetometer(),
+            self.pressure_baro(),
+        );
+        if self.timers.usb_sensor_stats.due(self.time) {
+            self.sensor_stats.send(self.time.wire());
         }
+        if let Some(message) = self.timers.usb_telemetry.due(self.time) {
+            if heap::headroom() {
+                let msg = message(self.into());
+                self.usb.send_message(msg);
+            } else {
+                report(Subsystem::Memory, ErrorKind::OutOfMemory, "dropping USB telemetry");
+            }
+        }
+        self.profiler.end_section(Section::Outputs);
 
         // Send telemetry via Lora
-        if let Some(msg) = self.next_lora_telem() {
-            if let Err(e) = self.radio.send(msg).await {
-                error!("Failed to send downlink message: {:?}", Debug2Format(&e));
+        if let Some(message) = self.timers.lora_telemetry.due(self.time) {
+            if heap::headroom() {
+                let msg = message(self.into());
+                self.recent_telemetry.push(Source::LoRa, &msg);
+                self.lora_history.push(Source::LoRa, &msg);
+                if let Err(e) = self.radio.send(msg).await {
+                    report(Subsystem::Radio, e, "sending downlink message");
+                }
+            } else {
+                report(Subsystem::Memory, ErrorKind::OutOfMemory, "dropping LoRa telemetry");
+            }
+        } else if self.radio.downlink_slot_free() {
+            // Retransmissions only use the slots the schedule leaves free.
+            if let Some(msg) = self.retransmitter.next_message(&self.lora_history) {
+                if let Err(e) = self.radio.retransmit(&msg).await {
+                    report(Subsystem::Radio, e, "retransmitting message");
+                }
             }
         }
+        self.profiler.end_section(Section::Radio);
 
         // Store data in flash
         self.flash.tick().await;
-        if self.mode >= FlightMode::ArmedLaunchImminent {
-            if let Some(msg) = self.next_flash_telem() {
-                let _ = self.flash.write_message(msg);
+        self.tick_shock();
+        // Flash telemetry is generated even while not logging, for snapshots.
+        if let Some(message) = self.timers.flash_telemetry.due(self.time) {
+            if heap::headroom() {
+                let msg = message(self.into());
+                self.recent_telemetry.push(Source::Flash, &msg);
+                if self.logging() && self.flash.write_message(msg).is_err() {
+                    report(Subsystem::Flash, ErrorKind::QueueFull, "queueing message");
+                }
+            } else {
+                report(Subsystem::Memory, ErrorKind::OutOfMemory, "dropping flash telemetry");
             }
+        } else if let Some(snapshot) = self.snapshot.as_mut() {
+            // Snapshots only use iterations without a message of their own, to keep the queue
+            // clear.
+            match snapshot.next_message(&self.recent_telemetry) {
+                Some(msg) => if self.flash.write_message(msg).is_err() {
+                    report(Subsystem::Flash, ErrorKind::QueueFull, "queueing snapshot");
+                },
+                None => self.snapshot = None,
+            }
+        }
+        self.update_logging_health();
+        self.update_time_reference();
+        let errors = self.errors.total();
+        self.errors.tick(self.time);
+        while let Some((error, suppressed)) = self.errors.take_logged() {
+            self.log_error(error, suppressed);
         }
+        let recent_snapshot = self.snapshot_since.map(|t| self.time.millis_since(t) < ERROR_SNAPSHOT_INTERVAL).unwrap_or(false);
+        if self.errors.total() > errors && !recent_snapshot {
+            self.start_snapshot("error");
+        }
+        self.profiler.end_section(Section::Logging);
 
         // Broadcast telemetry to payloads
         self.broadcast_can_telemetry();
+        self.profiler.end_section(Section::Can);
 
         // Increase time for next iteration
-        self.time += 1_000 / MAIN_LOOP_FREQUENCY.0;
+        self.time = self.time.plus_millis(1_000 / MAIN_LOOP_FREQUENCY.0);
+
+        self.profiler.end_loop();
+    }
+
+    // Sensor readings used for state estimation and telemetry. These are replaced by injected
+    // values during HIL tests.
+
+    fn gyro_saturated(&self) -> bool {
+        self.last_gyro_saturation
+            .map(|t| self.time.millis_since(t) <= GYRO_SATURATION_HOLDOFF)
+            .unwrap_or(false)
+    }
+
+    fn gyroscope(&self) -> Option<Vector3<f32>> {
+        #[cfg(feature = "hil")]
+        if let Some(frame) = self.hil.frame() {
+            return frame.gyroscope();
+        }
+
+        self.imu.gyroscope()
+    }
+
+    fn accelerometer1(&self) -> Option<Vector3<f32>> {
+        #[cfg(feature = "hil")]
+        if let Some(frame) = self.hil.frame() {
+            return frame.accelerometer1();
+        }
+
+        self.imu.accelerometer()
+    }
+
+    fn accelerometer2(&self) -> Option<Vector3<f32>> {
+        #[cfg(feature = "hil")]
+        if let Some(frame) = self.hil.frame() {
+            return frame.accelerometer2();
+        }
+
+        self.acc.accelerometer()
+    }
+
+    fn magnetometer(&self) -> Option<Vector3<f32>> {
+        #[cfg(feature = "hil")]
+        if let Some(frame) = self.hil.frame() {
+            return frame.magnetometer();
+        }
+
+        self.mag.magnetometer().map(|m| self.sensor_calibration.correct_magnetometer(m))
+    }
+
+    fn pressure_baro(&self) -> Option<f32> {
+        #[cfg(feature = "hil")]
+        if let Some(frame) = self.hil.frame() {
+            return frame.pressure_baro;
+        }
+
+        self.baro.pressure()
+    }
+
+    fn altitude_baro(&self) -> Option<f32> {
+        #[cfg(feature = "hil")]
+        if let Some(frame) = self.hil.frame() {
+            return frame.altitude_baro;
+        }
+
+        self.baro.altitude()
+    }
+
+    /// Angle (deg) between the vehicle's longitudinal axis and vertical
+    #[cfg(feature = "engine")]
+    fn tilt(&self) -> Option<f32> {
+        use num_traits::Float;
+        let axis = self.state_estimator.orientation? * Vector3::z();
+        Some(axis.z.clamp(-1.0, 1.0).acos().to_degrees())
+    }
+
+    fn arm_voltage(&self) -> Option<u16> {
+        #[cfg(feature = "hil")]
+        if let Some(frame) = self.hil.frame() {
+            return frame.arm_voltage;
+        }
 
-        // get CPU usage
-        self.loop_runtime = (start.elapsed().as_micros() as f32) / 1000.0;
+        self.power.arm_voltage()
     }
 
     fn handle_can_bus_message(&mut self, msg: &FcReceivedCanBusMessage) {
         let msg = TelemetryCanBusMessage {
-            time: self.time.0,
+            time: self.time.wire(),
             msg: msg.clone(),
         };
         let msg = DownlinkMessage::TelemetryCanBusMessage(msg);
 
         let _ = self.usb.send_message(msg.clone());
-        if self.mode >= FlightMode::ArmedLaunchImminent {
-            let _ = self.flash.write_message(msg);
+        if self.mode >= FlightMode::ArmedLaunchImminent && self.flash.write_message(msg).is_err() {
+            report(Subsystem::Flash, ErrorKind::QueueFull, "queueing message");
         }
     }
 
     fn transmit_output_commands(&mut self) {
         // We send ACS valve output commands every 50Hz
-        if self.time.0 % 20 == 0 {
+        if self.timers.acs_outputs.due(self.time) {
             let valve_state = match self.acs_mode {
                 AcsMode::Disabled => ThrusterValveState::Closed,
                 AcsMode::Auto => self.state_estimator.thruster_valve(self.acs_tank_pressure.map(|(_t, v)| v).unwrap_or(300.0)),
                 AcsMode::Manual => match self.last_manual_thruster_input {
                     Some((time, state)) => {
-                        if (self.time - time).0 < 450 { // TODO
+                        if self.time.millis_since(time) < 450 { // TODO
                             state
                         } else {
                             self.last_manual_thruster_input = None;
@@ -482,7 +1135,7 @@ impl Vehicle {
         }
 
         // Recovery cameras
-        if self.time.0 % 500 == 210 {
+        if self.timers.recovery_cameras.due(self.time) {
             let mut outputs: [bool; 8] = [false; 8];
             outputs[0] = self.camera_state[0];
             outputs[1] = self.camera_state[0];
@@ -494,7 +1147,7 @@ impl Vehicle {
         }
 
         // Payload cameras
-        if self.time.0 % 500 == 410 {
+        if self.timers.payload_cameras.due(self.time) {
             let mut outputs: [bool; 8] = [false; 8];
             outputs[0] = self.camera_state[2];
             outputs[1] = self.camera_state[2];
@@ -507,26 +1160,49 @@ impl Vehicle {
     }
 
     fn broadcast_can_telemetry(&mut self) {
-        if self.time.0 % 100 != 0 {
+        if !self.timers.can_broadcast.due(self.time) {
             return;
         }
 
         let msg = TelemetryToPayloadMessage {
-            time: self.time.0,
+            time: self.time.wire(),
             mode: self.mode,
             altitude: (self.state_estimator.altitude_asl() * 10.0) as u16,
         };
 
-        let (id, msg) = msg.to_frame(CanBusMessageId::TelemetryBroadcast(0));
+        let (id, msg) = msg.to_frame(CanBusMessageId::TelemetryBroadcast(FC_ID));
         self.can.transmit(id, msg);
     }
 
     async fn handle_command(&mut self, cmd: Command) {
         info!("Received command: {:?}", Debug2Format(&cmd));
         match cmd {
-            Command::Reboot => cortex_m::peripheral::SCB::sys_reset(),
+            Command::Reboot | Command::RebootToBootloader if self.reconfiguration_locked() => {
+                warn!("Reboot rejected in {:?}.", Debug2Format(&self.mode));
+            },
+            Command::Reboot => self.reboot(false),
             Command::RebootToBootloader => {},
-            Command::SetFlightMode(fm) => self.switch_mode(fm),
+            Command::SetFlightMode(fm) => match crate::mode_guard::check(self.mode, fm).and_then(|()| self.geofence.check_arming(self.mode, fm)) {
+                Ok(()) if fm == FlightMode::ArmedLaunchImminent && self.mode != fm => {
+                    self.switch_mode(fm);
+                    if self.countdown.start(self.time) {
+                        info!("Countdown started.");
+                        self.timers.lora_telemetry = telemetry::lora_schedule(DownlinkProfile::Fast);
+                    }
+                },
+                Ok(()) => {
+                    if fm >= FlightMode::HardwareArmed && self.safed {
+                        info!("Re-armed after abort.");
+                        self.safed = false;
+                    }
+                    self.switch_mode(fm);
+                },
+                Err(rejection) => {
+                    warn!("Rejected mode change to {:?}: {}", Debug2Format(&fm), rejection.reason.name());
+                    self.usb.console_print(format_args!("mode: {:?} rejected, {}", fm, rejection.reason.name()));
+                    self.radio.send_mode_rejection(rejection);
+                },
+            },
             Command::SetTransmitPower(txp) => self.radio.set_transmit_power(txp),
             Command::SetDataRate(dr) => self.data_rate = dr,
             Command::SetAcsMode(am) => self.acs_mode = am,
@@ -546,11 +1222,738 @@ impl Vehicle {
         }
     }
 
+    fn console_parameter(&mut self, param: ConsoleParameter) -> &mut Vector3<f32> {
+        match param {
+            ConsoleParameter::GyroOffset => &mut self.sensor_calibration.gyro_bias,
+            ConsoleParameter::AccOffset => &mut self.sensor_calibration.acc_offset,
+            ConsoleParameter::Acc2Offset => &mut self.sensor_calibration.acc2_offset,
+            ConsoleParameter::MagOffset => &mut self.sensor_calibration.mag_offset,
+        }
+    }
+
+    fn apply_sensor_offsets(&mut self) {
+        self.imu.set_offsets(self.sensor_calibration.gyro_bias, self.sensor_calibration.acc_offset);
+        self.acc.set_offset(self.sensor_calibration.acc2_offset);
+        self.mag.set_offset(self.sensor_calibration.mag_offset);
+        self.baro.set_offset(self.sensor_calibration.baro_offset);
+    }
+
+    async fn handle_console_command(&mut self, cmd: ConsoleCommand) {
+        info!("Received console command: {:?}", Debug2Format(&cmd));
+        let reconfiguration = matches!(
+            cmd,
+            ConsoleCommand::Set(..)
+                | ConsoleCommand::Save
+                | ConsoleCommand::ClearCalibration
+                | ConsoleCommand::Param(Some(_))
+                | ConsoleCommand::Calibrate(_)
+                | ConsoleCommand::Reboot
+                | ConsoleCommand::Bootloader
+        );
+        if reconfiguration && self.reconfiguration_locked() {
+            warn!("Console command rejected in {:?}.", Debug2Format(&self.mode));
+            self.usb.console_print(format_args!("Not available in flight."));
+            return;
+        }
+
+        match cmd {
+            ConsoleCommand::Help => {
+                for line in HELP_TEXT.iter().chain(RADIO_HELP_TEXT).chain(PARAMETERS_HELP_TEXT) {
+                    self.usb.console_print(format_args!("{}", line));
+                }
+                #[cfg(feature = "loadcell")]
+                for line in LOADCELL_HELP_TEXT {
+                    self.usb.console_print(format_args!("{}", line));
+                }
+                #[cfg(feature = "servo")]
+                for line in SERVO_HELP_TEXT {
+                    self.usb.console_print(format_args!("{}", line));
+                }
+                #[cfg(feature = "engine")]
+                for line in ENGINE_HELP_TEXT {
+                    self.usb.console_print(format_args!("{}", line));
+                }
+            },
+            ConsoleCommand::Status => {
+                let utc = self.rtc.utc_millis();
+                let (sats, hdop) = (self.gps.num_satellites(), self.gps.hdop());
+                self.usb.console_print(format_args!("time: {}ms, utc: {:?}, mode: {:?}", self.time.as_millis(), utc, self.mode));
+                self.usb.console_print(format_args!(
+                    "firmware: {} ({}), settings: {:04x}, parameters: {:04x}, link: {:04x}",
+                    FIRMWARE_VERSION,
+                    GIT_HASH,
+                    settings_fingerprint(&self.settings),
+                    fingerprint(&self.parameters),
+                    fingerprint(&self.radio.link_config())
+                ));
+                self.usb.console_print(format_args!(
+                    "battery: {:?}mV, {:?}mA, arm: {:?}mV ({}, {} intermittent contacts)",
+                    self.power.battery_voltage(),
+                    self.power.battery_current(),
+                    self.power.arm_voltage(),
+                    if self.arm.armed() { "armed" } else { "disarmed" },
+                    self.arm.intermittent_count()
+                ));
+                self.usb.console_print(format_args!(
+                    "cpu: {:?}C (max {:?}C), supply: {:?}mV (min {:?}mV), derated: {}",
+                    self.power.temperature(),
+                    self.thermal.max_temperature(),
+                    self.power.vdda(),
+                    self.thermal.min_supply_voltage(),
+                    self.thermal.derated()
+                ));
+                self.usb.console_print(format_args!(
+                    "flash writes: {}, deferred {} times",
+                    if self.shock.deferring() { "deferred" } else { "normal" },
+                    self.shock.count()
+                ));
+                if let Some((state, cause)) = self.critical_state.restored() {
+                    self.usb.console_print(format_args!(
+                        "restored after {} reset: mode {:?}, ground {:.1}m, max {:.1}m, fired drogue {}, main {}, inhibited {:?}",
+                        cause.name(),
+                        state.mode,
+                        state.altitude_ground,
+                        state.max_altitude_asl,
+                        state.drogue_fired,
+                        state.main_fired,
+                        self.pyros_inhibited
+                    ));
+                }
+                if self.safed {
+                    self.usb.console_print(format_args!("safed after abort, arm to continue"));
+                }
+                self.usb.console_print(format_args!("gps: {:?} satellites, hdop {:?}", sats, hdop));
+                if let Some(rail) = self.launch_rail.orientation() {
+                    self.usb.console_print(format_args!(
+                        "launch rail: azimuth {:.1}deg, elevation {:.1}deg",
+                        rail.azimuth,
+                        rail.elevation
+                    ));
+                }
+                if let Some(interference) = self.gps.interference() {
+                    self.usb.console_print(format_args!(
+                        "gps interference: {:?}, jamming indicator {}, noise {}, agc {}",
+                        interference.jamming_state,
+                        interference.jamming_indicator,
+                        interference.noise_per_ms,
+                        interference.agc_count
+                    ));
+                }
+                #[cfg(feature = "thermocouple")]
+                if let Some(reading) = self.thermocouple.reading() {
+                    self.usb.console_print(format_args!(
+                        "thermocouple: {:?}C, cold junction {}C",
+                        reading.temperature,
+                        reading.internal_temperature
+                    ));
+                } else {
+                    self.usb.console_print(format_args!("thermocouple: no reading"));
+                }
+                let redundancy = self.partner.status(self.time, self.mode);
+                self.usb.console_print(format_args!(
+                    "partner fc: {:?}, mode {:?}, in command: {}",
+                    redundancy.partner,
+                    redundancy.partner_mode,
+                    redundancy.in_command
+                ));
+                self.usb.console_print(format_args!(
+                    "baro lag: {:.3}s ({})",
+                    self.baro_lag.time_constant(),
+                    if self.baro_lag.estimated() { "estimated" } else { "default" }
+                ));
+                self.usb.console_print(format_args!(
+                    "baro speed: {:?}m/s (+-{:.1}m/s), estimator {:.1}m/s",
+                    self.baro_speed.speed(self.time),
+                    self.baro_speed.uncertainty(),
+                    self.state_estimator.vertical_speed()
+                ));
+                let (inertial_apogee, baro_apogee) = self.backup_apogee.detections();
+                self.usb.console_print(format_args!(
+                    "apogee: inertial {:?}ms, baro {:?}ms, baro speed {:?}ms after launch, inertial speed {:.1}m/s",
+                    inertial_apogee,
+                    baro_apogee,
+                    self.baro_speed.apogee(),
+                    self.backup_apogee.speed()
+                ));
+                self.usb.console_print(format_args!(
+                    "geofence: pad {:?}, violation {:?}",
+                    self.geofence.pad(),
+                    self.geofence.violation()
+                ));
+                self.usb.console_print(format_args!(
+                    "landing: predicted {:?}, drift {:?}m/s, resting {:?}",
+                    self.landing.prediction(),
+                    self.landing.drift(),
+                    self.landing.resting_position()
+                ));
+                if let Some(remaining) = self.buzzer.find_me_remaining(self.time) {
+                    self.usb.console_print(format_args!("find-me siren: {}s remaining", remaining / 1000));
+                }
+                #[cfg(feature = "engine")]
+                self.usb.console_print(format_args!("engine: {:?}", crate::engine::state()));
+                #[cfg(feature = "umbilical")]
+                if let Some(umbilical) = self.umbilical.as_ref() {
+                    self.usb.console_print(format_args!(
+                        "umbilical: {}, breakwire: {}",
+                        if umbilical.connected() { "connected" } else { "disconnected" },
+                        if umbilical.breakwire_intact() { "intact" } else { "torn" }
+                    ));
+                }
+                #[cfg(feature = "tank_pressure")]
+                if let Some(tank_pressure) = self.tank_pressure.as_ref() {
+                    self.usb.console_print(format_args!(
+                        "tank pressure: {:?}bar ({:?}), {:?}bar/s, {:?}",
+                        tank_pressure.pressure(),
+                        tank_pressure.readings(),
+                        tank_pressure.rate(),
+                        tank_pressure.warnings()
+                    ));
+                }
+                self.usb.console_print(format_args!("flash pointer: 0x{:08x}", self.flash.pointer()));
+                self.usb.console_print(format_args!("downlink profile: {}", self.downlink_profile.name()));
+                // The strobe is owned by the LEDs.
+                for output in LogicalOutput::ALL.into_iter().filter(|o| *o != LogicalOutput::Strobe) {
+                    match self.outputs.level(output) {
+                        Some(high) => self.usb.console_print(format_args!("output {}: {}", output.name(), if high { "high" } else { "low" })),
+                        None => self.usb.console_print(format_args!("output {}: not mapped", output.name())),
+                    }
+                }
+                self.usb.console_print(format_args!("loop time: {:.3}ms", self.profiler.loop_time()));
+                let heap = heap::stats();
+                self.usb.console_print(format_args!(
+                    "heap: {}/{} bytes used, peak {}, {} failed allocations ({} fragmented)",
+                    heap.used,
+                    heap.size,
+                    heap.peak,
+                    heap.failed_allocations,
+                    heap.fragmented_allocations
+                ));
+                self.usb.console_print(format_args!("errors: {}", self.errors.total()));
+                for subsystem in SUBSYSTEMS.iter().filter(|s| self.errors.count(**s) > 0) {
+                    self.usb.console_print(format_args!("  {:?}: {}", subsystem, self.errors.count(*subsystem)));
+                    for kind in ERROR_KINDS.iter().filter(|k| self.errors.count_of(*subsystem, **k) > 0) {
+                        self.usb.console_print(format_args!("    {:?}: {}", kind, self.errors.count_of(*subsystem, *kind)));
+                    }
+                }
+            },
+            ConsoleCommand::Get(param) => {
+                let v = *self.console_parameter(param);
+                self.usb.console_print(format_args!("{} = {} {} {}", param.name(), v.x, v.y, v.z));
+            },
+            ConsoleCommand::Set(param, v) => {
+                *self.console_parameter(param) = v;
+                self.apply_sensor_offsets();
+                self.usb.console_print(format_args!("{} = {} {} {}", param.name(), v.x, v.y, v.z));
+            },
+            ConsoleCommand::Save => {
+                self.usb.console_print(format_args!("Saving calibration and settings, rebooting."));
+                // Requests are handled in order, so the calibration is written before the reboot.
+                let _ = self.flash.write_calibration(self.sensor_calibration.clone());
+                let _ = self.flash.write_settings(self.settings.clone());
+            },
+            ConsoleCommand::ShowCalibration => if self.flash.print_calibration().is_err() {
+                self.usb.console_print(format_args!("Flash busy."));
+            },
+            ConsoleCommand::ClearCalibration => if self.flash.clear_calibration().is_ok() {
+                self.sensor_calibration = SensorCalibration::default();
+                self.apply_sensor_offsets();
+                self.usb.console_print(format_args!("Calibration reset to defaults."));
+            } else {
+                self.usb.console_print(format_args!("Flash busy."));
+            },
+            ConsoleCommand::Sensors(enabled) => self.live_sensor_view = enabled,
+            ConsoleCommand::SelfTest => self.self_test().await,
+            ConsoleCommand::Scan => if self.mode == FlightMode::Idle {
+                self.radio.start_scan();
+                self.usb.console_print(format_args!("scan: started"));
+            } else {
+                self.usb.console_print(format_args!("scan: only possible in idle mode"));
+            },
+            ConsoleCommand::Radio => self.report_radio_diagnostics().await,
+            ConsoleCommand::Link(None) | ConsoleCommand::Blacklist(None) => {
+                self.usb.console_print(format_args!("link: {}", self.radio.link_config()));
+            },
+            ConsoleCommand::Link(Some(link)) => {
+                self.change_link_config(LinkConfig { blacklist: self.radio.link_config().blacklist, ..link });
+            },
+            ConsoleCommand::Blacklist(Some(blacklist)) => {
+                self.change_link_config(LinkConfig { blacklist, ..self.radio.link_config() });
+            },
+            ConsoleCommand::Hops => match self.radio.hop_table() {
+                Some(table) => for (i, (channel, frequency)) in table.iter().enumerate() {
+                    self.usb.console_print(format_args!("hop {}: channel {}, {}kHz", i, channel, frequency / 1_000));
+                },
+                None => self.usb.console_print(format_args!("hops: no channels enabled")),
+            },
+            ConsoleCommand::GpsAssist(latitude, longitude, altitude, time) => {
+                let time = GPSTime::from_unix_millis(time * 1000);
+                self.gps.assist(GpsAssistance { time, latitude, longitude, altitude });
+                self.usb.console_print(format_args!("gps: sending assistance data"));
+            },
+            ConsoleCommand::Param(None) => for param in PARAMETERS {
+                self.usb.console_print(format_args!("{} = {}", param.name, param.get(&self.parameters)));
+            },
+            ConsoleCommand::Param(Some((name, value))) => {
+                let Some(param) = Parameter::find(name) else {
+                    return;
+                };
+
+                let mut parameters = self.parameters;
+                if !param.set(&mut parameters, value) {
+                    self.usb.console_print(format_args!("{}: invalid value", name));
+                } else if self.flash.write_parameters(parameters).is_err() {
+                    self.usb.console_print(format_args!("Flash busy."));
+                } else {
+                    self.parameters = parameters;
+                    self.usb.console_print(format_args!("{} = {}, applied after reboot", name, param.get(&self.parameters)));
+                }
+            },
+            ConsoleCommand::Downlink(profile) => {
+                self.set_downlink_profile(profile);
+                self.usb.console_print(format_args!("downlink profile: {}", profile.name()));
+            },
+            ConsoleCommand::Play(melody) => self.buzzer.play(self.time, melody),
+            ConsoleCommand::Volume(volume) => self.buzzer.set_volume(volume),
+            ConsoleCommand::Quiet(quiet) => self.buzzer.set_quiet(quiet),
+            ConsoleCommand::FindMe(enabled) => self.buzzer.find_me(self.time, enabled.then_some(FIND_ME_DURATION)),
+            ConsoleCommand::Flash => {
+                let (pointer, size) = (self.flash.pointer(), self.flash.size());
+                self.usb.console_print(format_args!(
+                    "flash: 0x{:08x} of 0x{:08x} bytes used ({}%)",
+                    pointer,
+                    size,
+                    (pointer as u64 * 100) / (size as u64)
+                ));
+                self.usb.console_print(format_args!("free: {} bytes", size.saturating_sub(pointer)));
+                self.usb.console_print(format_args!("logging rate: {} bytes/s", self.logging_rate));
+                self.usb.console_print(format_args!("errors: {}", self.errors.count(Subsystem::Flash)));
+                if self.flash.print_wear().is_err() {
+                    self.usb.console_print(format_args!("Flash busy."));
+                }
+            },
+            ConsoleCommand::Dump(address, size) => if self.flash.dump(address, size).is_err() {
+                self.usb.console_print(format_args!("Flash busy."));
+            },
+            ConsoleCommand::Note(text) => if self.flash.write_note(LogNote::new(self.time.wire(), &text)).is_err() {
+                self.usb.console_print(format_args!("Flash busy."));
+            },
+            ConsoleCommand::Summary => if self.flash.print_flight_summary().is_err() {
+                self.usb.console_print(format_args!("Flash busy."));
+            },
+            ConsoleCommand::Calibrate(calibration) => {
+                self.usb.console_print(format_args!("Calibrating, keep vehicle upright and stationary."));
+                self.calibration = Some((calibration, 0, Vector3::zeros()));
+            },
+            #[cfg(feature = "loadcell")]
+            ConsoleCommand::LoadCell(cmd) => self.handle_load_cell_command(cmd),
+            #[cfg(feature = "servo")]
+            ConsoleCommand::Servo(crate::servo::ServoCommand::Set(servo, position)) => crate::servo::set(servo, position),
+            #[cfg(feature = "servo")]
+            ConsoleCommand::Servo(crate::servo::ServoCommand::Failsafe) => crate::servo::failsafe(),
+            #[cfg(feature = "engine")]
+            ConsoleCommand::Engine(Some(cmd)) => crate::engine::command(cmd),
+            #[cfg(feature = "engine")]
+            ConsoleCommand::Engine(None) => {
+                self.usb.console_print(format_args!("engine: {:?}", crate::engine::state()));
+            },
+            ConsoleCommand::Abort => self.abort(),
+            ConsoleCommand::Reboot => self.reboot(false),
+            ConsoleCommand::Bootloader => self.reboot(true),
+            ConsoleCommand::Exit => {},
+            ConsoleCommand::Invalid(cmd) => {
+                self.usb.console_print(format_args!("Unknown command '{}', try 'help'.", cmd));
+            }
+        }
+    }
+
+    /// Checks the sensors, flash, arm voltage, LEDs and buzzer, with the results printed on the
+    /// console. The recovery outputs have no continuity sensing, so only the arm voltage can be
+    /// checked. The result is downlinked once the flash task has answered, see `tick_self_test`.
+    async fn self_test(&mut self) {
+        // The sensor self tests disturb the readings the state estimator relies on.
+        if self.mode >= FlightMode::Armed {
+            warn!("Self test rejected in {:?}.", Debug2Format(&self.mode));
+            self.usb.console_print(format_args!("Not available while armed."));
+            return;
+        }
+
+        let result = |ok: bool| if ok { "ok" } else { "FAIL" };
+        let imu = self.imu.self_test().await;
+        let acc = self.acc.self_test().await;
+        let mag = self.mag.self_test().await;
+        let baro = self.baro.self_test();
+        info!("Self test: imu={}, acc={}, mag={}, baro={}", imu, acc, mag, baro);
+
+        self.usb.console_print(format_args!("imu: {}", result(imu)));
+        self.usb.console_print(format_args!("acc: {}", result(acc)));
+        self.usb.console_print(format_args!("mag: {}", result(mag)));
+        self.usb.console_print(format_args!("baro: {}", result(baro)));
+        self.usb.console_print(format_args!("arm voltage: {:?}mV", self.power.arm_voltage()));
+        if self.flash.self_test().is_err() {
+            self.usb.console_print(format_args!("flash: busy"));
+        }
+        let leds = self.leds.start_test(self.time);
+        let buzzer = self.buzzer.play_unmuted(self.time, Melody::Startup);
+        self.usb.console_print(format_args!("leds: {}", if leds { "shown" } else { "skipped" }));
+        self.usb.console_print(format_args!("buzzer: {}", if buzzer { "played" } else { "skipped" }));
+
+        let arm_voltage = self.power.arm_voltage();
+        self.self_test = Some((self.time, SelfTestResult {
+            imu,
+            acc,
+            mag,
+            baro,
+            flash: None,
+            flash_write: None,
+            leds,
+            buzzer,
+            arm_voltage,
+        }));
+    }
+
+    /// Downlinks the redundancy state on every change, and periodically while a partner FC is
+    /// present.
+    fn update_redundancy_status(&mut self) {
+        let status = self.partner.status(self.time, self.mode);
+        let due = self.timers.redundancy_status.due(self.time) && status.partner != PartnerState::Absent;
+        if due || self.redundancy_status.is_some_and(|s| s != status) {
+            self.radio.send_redundancy_status(status);
+        }
+        self.redundancy_status = Some(status);
+    }
+
+    /// Logs the UTC time once the clock has been synchronized to GPS time, and downlinks it
+    /// periodically.
+    fn update_time_reference(&mut self) {
+        let Some(utc) = self.rtc.utc_millis().filter(|_| self.rtc.synchronized()) else {
+            return;
+        };
+
+        let reference = TimeReference { time: self.time.wire(), utc };
+        if !self.time_reference_logged {
+            self.time_reference_logged = self.flash.write_time_reference(reference).is_ok();
+            self.radio.send_time_reference(reference);
+        } else if self.timers.time_reference.due(self.time) {
+            self.radio.send_time_reference(reference);
+        }
+    }
+
+    fn set_downlink_profile(&mut self, profile: DownlinkProfile) {
+        self.downlink_profile = profile;
+        self.timers.lora_telemetry = telemetry::lora_schedule(profile);
+    }
+
+    /// Completes a running self test with the flash result and downlinks it.
+    fn tick_self_test(&mut self) {
+        let Some((started, mut result)) = self.self_test else {
+            return;
+        };
+
+        if let Some((read, write)) = self.flash.self_test_result() {
+            result.flash = Some(read);
+            result.flash_write = Some(write);
+        }
+
+        if result.flash.is_some() || self.time.millis_since(started) >= SELF_TEST_TIMEOUT {
+            info!("Self test result: {}", result);
+            self.radio.send_self_test_result(result);
+            self.self_test = None;
+        }
+    }
+
+    /// Processes new load cell samples. These arrive at the HX711's output data rate, so while
+    /// streaming every one of them is printed, allowing thrust curves to be recorded via USB.
+    #[cfg(feature = "loadcell")]
+    fn tick_load_cell(&mut self) {
+        while let Some(sample) = self.load_cell.next_sample() {
+            if self.load_cell_stream {
+                let force = self.load_cell.force(&sample).unwrap_or(f32::NAN);
+                self.usb.console_print(format_args!("{},{},{:.2}", sample.time, sample.raw, force));
+            }
+        }
+    }
+
+    #[cfg(feature = "loadcell")]
+    fn handle_load_cell_command(&mut self, cmd: LoadCellCommand) {
+        match cmd {
+            LoadCellCommand::Show => {
+                let sample = self.load_cell.last_sample();
+                let force = sample.and_then(|s| self.load_cell.force(&s));
+                self.usb.console_print(format_args!(
+                    "load cell: raw {:?}, {:?}N (offset {}, scale {:?}/N)",
+                    sample.map(|s| s.raw),
+                    force,
+                    self.load_cell.offset(),
+                    self.load_cell.scale()
+                ));
+            },
+            LoadCellCommand::Tare => match self.load_cell.tare() {
+                Ok(offset) => self.usb.console_print(format_args!("load cell offset: {}", offset)),
+                Err(()) => self.usb.console_print(format_args!("Not enough load cell samples.")),
+            },
+            LoadCellCommand::Calibrate(force) => match self.load_cell.calibrate(force) {
+                Ok(scale) => self.usb.console_print(format_args!("load cell scale: {}/N", scale)),
+                Err(()) => self.usb.console_print(format_args!("Calibration failed, tare first and apply a load.")),
+            },
+            LoadCellCommand::Stream(enabled) => self.load_cell_stream = enabled,
+        }
+    }
+
+    fn tick_console(&mut self) {
+        if let Some(result) = self.radio.take_scan_result() {
+            for channel in result {
+                self.usb.console_print(format_args!(
+                    "{}kHz: average {:.1}dBm, peak {:.1}dBm",
+                    channel.frequency / 1_000,
+                    channel.average,
+                    channel.peak
+                ));
+            }
+        }
+
+        if self.timers.live_sensor_view.due(self.time) && self.live_sensor_view {
+            let gyro = self.imu.gyroscope().unwrap_or_default();
+            let acc = self.imu.accelerometer().unwrap_or_default();
+            let acc2 = self.acc.accelerometer().unwrap_or_default();
+            let mag = self.mag.magnetometer().unwrap_or_default();
+            self.usb.console_print(format_args!(
+                "gyro {:7.2} {:7.2} {:7.2} | acc {:6.2} {:6.2} {:6.2} | acc2 {:6.1} {:6.1} {:6.1} | mag {:6.1} {:6.1} {:6.1} | baro {:7.2}hPa",
+                gyro.x, gyro.y, gyro.z,
+                acc.x, acc.y, acc.z,
+                acc2.x, acc2.y, acc2.z,
+                mag.x, mag.y, mag.z,
+                self.baro.pressure().unwrap_or_default()
+            ));
+        }
+
+        let Some((calibration, samples, sum)) = self.calibration else {
+            return;
+        };
+
+        let sample = match calibration {
+            Calibration::Gyroscope => self.imu.gyroscope(),
+            Calibration::Accelerometer => self.imu.accelerometer(),
+        };
+        let Some(sample) = sample else {
+            return;
+        };
+
+        let (samples, sum) = (samples + 1, sum + sample);
+        if samples < CALIBRATION_SAMPLES {
+            self.calibration = Some((calibration, samples, sum));
+            return;
+        }
+
+        // Sensor readings already have the current offset removed, so we add the remaining error.
+        self.calibration = None;
+        let mean = sum / (samples as f32);
+        let (param, offset) = match calibration {
+            Calibration::Gyroscope => (ConsoleParameter::GyroOffset, mean),
+            Calibration::Accelerometer => {
+                let gravity = if mean.z < 0.0 { -GRAVITY } else { GRAVITY };
+                (ConsoleParameter::AccOffset, mean - Vector3::new(0.0, 0.0, gravity))
+            }
+        };
+
+        let v = self.console_parameter(param);
+        *v += offset;
+        let v = *v;
+        self.apply_sensor_offsets();
+        self.usb.console_print(format_args!("{} = {} {} {} (use 'save' to persist)", param.name(), v.x, v.y, v.z));
+    }
+
+    /// Defers flash writes during shock events, see `shock.rs`.
+    fn tick_shock(&mut self) {
+        let (acc1, acc2) = (self.accelerometer1(), self.accelerometer2());
+        let deferral = self.shock.tick(self.time, acc1, acc2, self.power.battery_current(), self.power.vdda());
+        self.flash.defer_writes(self.shock.deferring());
+
+        if let Some(deferral) = deferral {
+            if self.flash.write_note(deferral.note()).is_err() {
+                report(Subsystem::Flash, ErrorKind::QueueFull, "queueing note");
+            }
+        }
+    }
+
+    fn heartbeat(&self) -> Heartbeat {
+        Heartbeat::new(
+            settings_fingerprint(&self.settings),
+            fingerprint(&self.parameters),
+            fingerprint(&self.radio.link_config()),
+        )
+    }
+
+    /// Keeps track of how fast the flash log grows, and reports it if logging stalls or the flash
+    /// is about to fill up while we're supposed to be logging.
+    fn update_logging_health(&mut self) {
+        if !self.timers.logging_health.due(self.time) {
+            return;
+        }
+
+        let pointer = self.flash.pointer();
+        self.logging_rate = pointer.saturating_sub(self.last_flash_pointer);
+        self.last_flash_pointer = pointer;
+
+        // The first interval after we started logging may not have seen a write yet.
+        let logging = self.logging();
+        if logging && self.was_logging && self.logging_rate == 0 {
+            report(Subsystem::Flash, ErrorKind::Timeout, "logging stalled");
+        }
+        if logging && self.flash.size().saturating_sub(pointer) < FLASH_RESERVE {
+            report(Subsystem::Flash, ErrorKind::Overflow, "flash almost full");
+        }
+        // Repeated for each flight, the log may have been erased since the clock was synchronized.
+        if logging && !self.was_logging {
+            self.time_reference_logged = false;
+        }
+        self.was_logging = logging;
+
+        if self.timers.logging_status.due(self.time) {
+            let status = self.flash.logging_status(self.errors.count(Subsystem::Flash), self.logging_rate);
+            self.radio.send_logging_status(status);
+        }
+    }
+
+    fn tick_countdown(&mut self) {
+        let checks = CheckResults {
+            sensors: self.gyroscope().is_some() && self.accelerometer1().is_some() && self.altitude_baro().is_some(),
+            arming: self.arm.armed(),
+            gps_fix: !matches!(self.gps.fix(), None | Some(GPSFixType::NoFix)),
+            flash_space: self.flash.size().saturating_sub(self.flash.pointer()) >= FLASH_RESERVE,
+        };
+
+        let Some(status) = self.countdown.tick(self.time, checks) else {
+            return;
+        };
+
+        match status {
+            CountdownStatus::Running(remaining) => self.usb.console_print(format_args!("countdown: {}s", remaining.div_ceil(1000))),
+            CountdownStatus::Complete => {
+                info!("Countdown complete.");
+                self.usb.console_print(format_args!("countdown: complete"));
+            },
+            CountdownStatus::Aborted(check) => {
+                warn!("Countdown aborted, {} check failed.", check.name());
+                self.usb.console_print(format_args!("countdown: aborted, {} check failed", check.name()));
+                self.switch_mode(FlightMode::Armed);
+            },
+        }
+
+        self.radio.send_countdown_status(status);
+    }
+
+    /// Reads the transceiver's diagnostics, prints them on the console, logs them to flash and
+    /// downlinks them along with the error counts, see `radio_diagnostics.rs`.
+    async fn report_radio_diagnostics(&mut self) {
+        let diagnostics = match self.radio.trx.diagnostics().await {
+            Ok(diagnostics) => diagnostics,
+            Err(e) => {
+                report(Subsystem::Radio, e, "reading diagnostics");
+                self.usb.console_print(format_args!("radio: reading diagnostics failed"));
+                return;
+            }
+        };
+
+        let usb = &mut self.usb;
+        diagnostics.print("radio", |args| usb.console_print(args));
+        self.radio.tx_stats().print("radio", |args| usb.console_print(args));
+        if self.flash.write_radio_diagnostics(self.time.wire(), diagnostics).is_err() {
+            self.usb.console_print(format_args!("Flash busy."));
+        }
+        self.radio.send_diagnostics(diagnostics);
+        self.radio.send_error_counts(self.errors.counts());
+    }
+
+    /// Records an error in the flash log as a note, so it shows up alongside the flight data.
+    /// Errors are rate limited before being logged, see `errors.rs`.
+    fn log_error(&mut self, error: Error, suppressed: u32) {
+        // Truncated if it doesn't fit, the subsystem and context come first.
+        let mut text = String::<LOG_NOTE_LENGTH>::new();
+        let _ = write!(text, "error: {:?}: {} ({:?})", error.subsystem, error.context, error.kind);
+        if suppressed > 0 {
+            let _ = write!(text, ", {} more", suppressed);
+        }
+
+        // Not reported if the queue is full, since that would just cause another note.
+        let _ = self.flash.write_note(LogNote::new(self.time.wire(), &text));
+    }
+
+    /// Switches to a new link configuration and stores it, if it is valid and the vehicle is
+    /// idle. The GCS follows once it hears the announcement, see `lora.rs`.
+    fn change_link_config(&mut self, link: LinkConfig) {
+        if !link.is_valid() {
+            self.usb.console_print(format_args!("link: invalid configuration"));
+        } else if self.mode != FlightMode::Idle {
+            self.usb.console_print(format_args!("link: only possible in idle mode"));
+        } else {
+            self.radio.set_link_config(link);
+            if self.flash.write_link_config(link).is_err() {
+                self.usb.console_print(format_args!("Flash busy."));
+            }
+            self.usb.console_print(format_args!("link: {}", link));
+        }
+    }
+
+    /// Whether telemetry is logged to flash, which starts shortly before launch
+    fn logging(&self) -> bool {
+        let snapshot = self.snapshot_since.map(|t| self.time.millis_since(t) < SNAPSHOT_DURATION).unwrap_or(false);
+        self.mode >= FlightMode::ArmedLaunchImminent || snapshot
+    }
+
+    /// Makes sure the flash log shows the moments around an abort or error: unless already
+    /// logging, the buffered telemetry (see `recent_telemetry.rs`) is copied to flash, and
+    /// logging continues for `SNAPSHOT_DURATION`.
+    fn start_snapshot(&mut self, note: &str) {
+        if !self.logging() {
+            self.snapshot = Snapshot::start(&self.recent_telemetry);
+        }
+        self.snapshot_since = Some(self.time);
+
+        if self.flash.write_note(LogNote::new(self.time.wire(), note)).is_err() {
+            report(Subsystem::Flash, ErrorKind::QueueFull, "queueing note");
+        }
+    }
+
+    /// Safes the vehicle when something looks wrong before launch: the recovery outputs are
+    /// switched off right away, and the vehicle returns to Idle, where it stays until it is
+    /// armed again via a mode command. A snapshot is logged, so the flight log shows what led to
+    /// the abort. Once launched, aborts are rejected, since the recovery outputs are
+    /// needed to come down safely.
+    fn abort(&mut self) {
+        if self.mode >= FlightMode::Burn && self.mode != FlightMode::Landed {
+            warn!("Abort rejected in flight.");
+            self.usb.console_print(format_args!("abort: rejected in flight"));
+            self.radio.send_mode_rejection(ModeRejection {
+                from: self.mode,
+                to: FlightMode::Idle,
+                reason: RejectionReason::InFlight,
+            });
+            return;
+        }
+
+        warn!("Abort received in {:?}, safing.", Debug2Format(&self.mode));
+        self.outputs.safe();
+        self.start_snapshot("abort");
+        self.switch_mode(FlightMode::Idle);
+        self.safed = true;
+        self.usb.console_print(format_args!("abort: safed, arm to continue"));
+        self.buzzer.play(self.time, Melody::Warning);
+    }
+
     fn switch_mode(&mut self, new_mode: FlightMode) {
         if new_mode == self.mode {
             return;
         }
 
+        // The countdown only runs in ArmedLaunchImminent, and its faster downlink only lasts until
+        // the vehicle is back in Armed.
+        if new_mode != FlightMode::ArmedLaunchImminent {
+            self.countdown.cancel();
+        }
+        if new_mode < FlightMode::ArmedLaunchImminent && self.mode >= FlightMode::ArmedLaunchImminent {
+            self.timers.lora_telemetry = telemetry::lora_schedule(self.downlink_profile);
+        }
+
         // We are going to or beyond Armed, switch to max tx power and arm ACS
         if new_mode >= FlightMode::Armed && self.mode < FlightMode::Armed {
             self.radio.set_max_transmit_power();
@@ -561,74 +1964,37 @@ impl Vehicle {
             self.camera_state = [true; 3];
         }
 
+        if new_mode == FlightMode::Landed {
+            let apogee = self.max_altitude_asl - self.state_estimator.altitude_ground;
+            info!("Apogee: {}m AGL, max. vertical speed: {}m/s", apogee, self.max_vertical_speed);
+            self.buzzer.set_flight_report(apogee, self.max_vertical_speed);
+        }
+
+        // Back on the pad, the next flight fires the outputs again.
+        if new_mode <= FlightMode::Armed {
+            self.pyros_fired = (false, false);
+            self.pyros_inhibited = (false, false);
+        }
+
         self.mode = new_mode;
-        self.buzzer.switch_mode(self.time.0, new_mode);
-    }
-
-    // TODO: replace these with a more elegant system
-    #[cfg(not(feature = "gcs"))]
-    fn next_usb_telem(&mut self) -> Option<DownlinkMessage> {
-        /*if self.time.0 % 1000 == 0 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryGPS(vs.into()))
-        } else */if self.time.0 % 50 == 0 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryRawSensors(vs.into()))
-        } else if self.time.0 % 50 == 10 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryMain(vs.into()))
-        } else if self.time.0 % 50 == 30 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryDiagnostics(vs.into()))
-        } else {
-            None
-        }
-    }
-
-    #[cfg(not(feature = "gcs"))]
-    fn next_lora_telem(&mut self) -> Option<DownlinkMessage> {
-        if self.time.0 % 1000 == 0 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryGPS(vs.into()))
-        } else if self.time.0 % 1000 == 200 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryDiagnostics(vs.into()))
-        } else if self.time.0 % 1000 == 400 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryPressures(vs.into()))
-        } else if self.time.0 % 1000 == 600 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryKalman(vs.into()))
-        } else if self.time.0 % 1000 == 800 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryBus(vs.into()))
-        } else if self.time.0 % 100 == 50 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryFastCompressed(vs.into()))
-        } else {
-            None
-        }
-    }
-
-    #[cfg(not(feature = "gcs"))]
-    fn next_flash_telem(&mut self) -> Option<DownlinkMessage> {
-        // Offset everything a little so that flash message writes don't coincide
-        // with lora message writes.
-        let t = self.time.0 + 3;
-        if t % 100 == 0 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryGPS(vs.into()))
-        } else if t % 50 == 0 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryDiagnostics(vs.into()))
-        } else if t % 50 == 20 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryMain(vs.into()))
-        } else if t % 10 == 5 {
-            let vs: VehicleState = self.into();
-            Some(DownlinkMessage::TelemetryRawSensors(vs.into()))
+        self.buzzer.switch_mode(self.time, new_mode);
+    }
+
+    /// Whether commands that reboot the vehicle or change its calibration are rejected. Rebooting
+    /// deliberately discards the mirrored flight state, and a changed calibration throws off the
+    /// state estimate, so both are only possible on the ground.
+    fn reconfiguration_locked(&self) -> bool {
+        self.mode > FlightMode::Armed && self.mode != FlightMode::Landed
+    }
+
+    /// Reboots deliberately, without resuming from the mirrored state afterwards. Only to be
+    /// called while `reconfiguration_locked` is false.
+    fn reboot(&mut self, to_bootloader: bool) -> ! {
+        self.critical_state.clear(&mut self.rtc);
+        if to_bootloader {
+            reboot_to_bootloader()
         } else {
-            None
+            cortex_m::peripheral::SCB::sys_reset()
         }
     }
 }
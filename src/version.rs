@@ -0,0 +1,89 @@
+//! Identification of the firmware build and its configuration, so that mismatched firmware or
+//! settings on vehicles and ground station can be spotted before arming.
+//!
+//! The vehicle periodically downlinks this as a `Heartbeat`, see `Radio::send_heartbeat`.
+
+use core::fmt;
+
+use crc::{Crc, CRC_16_IBM_SDLC};
+use serde::{Deserialize, Serialize};
+
+use defmt::Format;
+
+use shared_types::Settings;
+
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Commit the firmware was built from, with a `-dirty` suffix for uncommitted changes
+pub const GIT_HASH: &str = env!("MITHRIL_GIT_HASH");
+
+const X25: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+
+/// Checksum of any serializable configuration.
+pub fn fingerprint<T: Serialize>(value: &T) -> u16 {
+    let mut buffer = [0u8; 512];
+    postcard::to_slice(value, &mut buffer).map(|s| X25.checksum(s)).unwrap_or_default()
+}
+
+/// Checksum of the active settings, which include the LoRa settings.
+#[cfg_attr(feature = "gcs", allow(dead_code))]
+pub fn settings_fingerprint(settings: &Settings) -> u16 {
+    fingerprint(settings)
+}
+
+/// Firmware build and configuration fingerprints of the vehicle, small enough for a single
+/// downlink packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub struct Heartbeat {
+    /// Major, minor and patch version
+    pub version: [u8; 3],
+    /// First 8 hex digits of the commit, zero if unknown
+    pub git_hash: [u8; 4],
+    /// Whether the firmware was built with uncommitted changes
+    pub dirty: bool,
+    /// Fingerprint of the settings, which include the LoRa settings
+    pub settings: u16,
+    /// Fingerprint of the firmware parameters, see `parameters.rs`
+    pub parameters: u16,
+    /// Fingerprint of the LoRa link configuration, see `LinkConfig`
+    pub link: u16,
+}
+
+impl Heartbeat {
+    /// Identifies this firmware build, along with the given configuration fingerprints.
+    pub fn new(settings: u16, parameters: u16, link: u16) -> Self {
+        let version = |part: &str| part.parse().unwrap_or_default();
+        let commit = GIT_HASH.trim_end_matches("-dirty");
+        // `git describe` prefixes the commit with the last tag, if there is one.
+        let commit = commit.rsplit("-g").next().unwrap_or(commit);
+        let git_hash = commit.get(..8).and_then(|hash| u32::from_str_radix(hash, 16).ok()).unwrap_or_default();
+
+        Self {
+            version: [
+                version(env!("CARGO_PKG_VERSION_MAJOR")),
+                version(env!("CARGO_PKG_VERSION_MINOR")),
+                version(env!("CARGO_PKG_VERSION_PATCH")),
+            ],
+            git_hash: git_hash.to_be_bytes(),
+            dirty: GIT_HASH.ends_with("-dirty"),
+            settings,
+            parameters,
+            link,
+        }
+    }
+
+    /// Whether the firmware build matches the given one, ignoring the configuration.
+    pub fn same_firmware(&self, other: &Self) -> bool {
+        (self.version, self.git_hash, self.dirty) == (other.version, other.git_hash, other.dirty)
+    }
+}
+
+impl fmt::Display for Heartbeat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [major, minor, patch] = self.version;
+        write!(f, "{}.{}.{} ({:08x}", major, minor, patch, u32::from_be_bytes(self.git_hash))?;
+        if self.dirty {
+            write!(f, "-dirty")?;
+        }
+        write!(f, "), settings {:04x}, parameters {:04x}, link {:04x}", self.settings, self.parameters, self.link)
+    }
+}